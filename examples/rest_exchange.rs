@@ -0,0 +1,295 @@
+//! A reference `Exchange` implementation for a centralised exchange with a REST API.
+//!
+//! The endpoints and JSON shapes below are deliberately generic, so copy this file and adapt the
+//! request and response types to the exchange being traded on. Run it with
+//! `REST_BASE_URL=https://... cargo run --example rest_exchange`.
+
+use async_trait::async_trait;
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use simple_market_maker::{
+    Balances, CancelOrderRequest, Config, Exchange, ExchangeError, MakeOrderRequest, Order,
+    OrderId, OrderSizing, OrderType, Price, PriceAnchor, Stats,
+};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+/// The URL of each endpoint used by `RestExchange`
+pub struct Endpoints {
+    /// `GET`, returning a `StatsResponse`
+    pub stats: String,
+    /// `GET`, returning a `BalancesResponse`
+    pub balances: String,
+    /// `POST` with a `NewOrder` body, returning a `NewOrderResponse`
+    pub orders: String,
+    /// `DELETE {orders}/{id}`, with any 2xx status meaning the order was cancelled
+    pub cancel_order: String,
+}
+
+impl Endpoints {
+    pub fn from_base_url(base_url: &str) -> Self {
+        let base_url = base_url.trim_end_matches('/');
+        Endpoints {
+            stats: format!("{base_url}/stats"),
+            balances: format!("{base_url}/balances"),
+            orders: format!("{base_url}/orders"),
+            cancel_order: format!("{base_url}/orders"),
+        }
+    }
+}
+
+pub struct RestExchange {
+    client: Client,
+    endpoints: Endpoints,
+    api_key: Option<String>,
+}
+
+impl RestExchange {
+    pub fn new(endpoints: Endpoints) -> Self {
+        RestExchange {
+            client: Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .expect("Failed to build HTTP client"),
+            endpoints,
+            api_key: None,
+        }
+    }
+
+    /// Sets a key which is sent as a bearer token with every request
+    pub fn with_api_key(mut self, api_key: String) -> Self {
+        self.api_key = Some(api_key);
+        self
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.api_key {
+            Some(api_key) => request.bearer_auth(api_key),
+            None => request,
+        }
+    }
+
+    async fn send(&self, request: RequestBuilder) -> Result<Response, ExchangeError> {
+        let response = self
+            .authorize(request)
+            .send()
+            .await
+            .map_err(|e| ExchangeError::Network(e.to_string()))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(to_exchange_error(status, body));
+        }
+        Ok(response)
+    }
+
+    async fn send_json<R: DeserializeOwned>(
+        &self,
+        request: RequestBuilder,
+    ) -> Result<R, ExchangeError> {
+        self.send(request)
+            .await?
+            .json()
+            .await
+            .map_err(|e| ExchangeError::Decode(e.to_string()))
+    }
+
+    async fn make_order(&self, order: MakeOrderRequest) -> Result<OrderId, ExchangeError> {
+        let body = NewOrder {
+            side: order.order_type.into(),
+            price: order.price.to_decimal(),
+            amount: order.amount,
+        };
+        let response: NewOrderResponse = self
+            .send_json(self.client.post(&self.endpoints.orders).json(&body))
+            .await?;
+
+        info!(?order, id = response.id, "Made order");
+        Ok(response.id.into())
+    }
+
+    async fn cancel_order(&self, order: CancelOrderRequest) -> Result<OrderId, ExchangeError> {
+        // The exchange's ids are text, which `OrderId` holds as its UTF-8 bytes
+        let id = order
+            .id
+            .as_text()
+            .ok_or_else(|| ExchangeError::Decode(format!("Invalid order id: {}", order.id)))?;
+        let url = format!("{}/{}", self.endpoints.cancel_order, id);
+        self.send(self.client.delete(url)).await?;
+
+        Ok(order.id)
+    }
+}
+
+#[async_trait]
+impl Exchange for RestExchange {
+    async fn stats(&self) -> Result<Stats, ExchangeError> {
+        let response: StatsResponse = self
+            .send_json(self.client.get(&self.endpoints.stats))
+            .await?;
+
+        Ok(Stats {
+            latest_price: Price::from_decimal(response.latest_price),
+            latest_trade_at: None,
+            volume_24h: None,
+            best_bid: response.best_bid.map(Price::from_decimal),
+            best_ask: response.best_ask.map(Price::from_decimal),
+            open_orders: response
+                .open_orders
+                .into_iter()
+                .map(|o| Order {
+                    order_type: o.side.into(),
+                    id: o.id.into(),
+                    price: Price::from_decimal(o.price),
+                    amount: o.amount,
+                    placed_at: o.placed_at,
+                })
+                .collect(),
+        })
+    }
+
+    async fn balances(&self) -> Result<Balances, ExchangeError> {
+        let response: BalancesResponse = self
+            .send_json(self.client.get(&self.endpoints.balances))
+            .await?;
+
+        Ok(Balances {
+            base: response.base,
+            quote: response.quote,
+        })
+    }
+
+    // Most exchanges rate limit order entry, so orders are made one at a time
+    async fn make_orders(
+        &self,
+        orders: Vec<MakeOrderRequest>,
+    ) -> Vec<Result<OrderId, ExchangeError>> {
+        let mut results = Vec::new();
+        for order in orders {
+            results.push(self.make_order(order).await);
+        }
+        results
+    }
+
+    async fn cancel_orders(
+        &self,
+        orders: Vec<CancelOrderRequest>,
+    ) -> Vec<Result<OrderId, ExchangeError>> {
+        let mut results = Vec::new();
+        for order in orders {
+            results.push(self.cancel_order(order).await);
+        }
+        results
+    }
+}
+
+// Server errors and rate limiting are treated as network errors so that the iteration is retried,
+// whereas a rejected API key stops the bot
+fn to_exchange_error(status: StatusCode, body: String) -> ExchangeError {
+    match status {
+        StatusCode::TOO_MANY_REQUESTS => ExchangeError::Network(body),
+        StatusCode::PAYMENT_REQUIRED => ExchangeError::InsufficientBalance(body),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ExchangeError::Fatal(body),
+        s if s.is_server_error() => ExchangeError::Network(format!("{s}: {body}")),
+        s => ExchangeError::Other(format!("{s}: {body}")),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Side {
+    Buy,
+    Sell,
+}
+
+impl From<OrderType> for Side {
+    fn from(value: OrderType) -> Self {
+        match value {
+            OrderType::Bid => Side::Buy,
+            OrderType::Ask => Side::Sell,
+        }
+    }
+}
+
+impl From<Side> for OrderType {
+    fn from(value: Side) -> Self {
+        match value {
+            Side::Buy => OrderType::Bid,
+            Side::Sell => OrderType::Ask,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct StatsResponse {
+    latest_price: f64,
+    best_bid: Option<f64>,
+    best_ask: Option<f64>,
+    open_orders: Vec<OpenOrder>,
+}
+
+#[derive(Deserialize)]
+struct OpenOrder {
+    id: String,
+    side: Side,
+    price: f64,
+    amount: u64,
+    // Nanoseconds since the Unix epoch
+    placed_at: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct BalancesResponse {
+    base: u64,
+    quote: u64,
+}
+
+#[derive(Serialize)]
+struct NewOrder {
+    side: Side,
+    price: f64,
+    amount: u64,
+}
+
+#[derive(Deserialize)]
+struct NewOrderResponse {
+    id: String,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tracing_subscriber::fmt::init();
+
+    let base_url = std::env::var("REST_BASE_URL")?;
+    let mut exchange = RestExchange::new(Endpoints::from_base_url(&base_url));
+    if let Ok(api_key) = std::env::var("REST_API_KEY") {
+        exchange = exchange.with_api_key(api_key);
+    }
+
+    let config = Config::builder()
+        .increment(Price::from_decimal(0.001))
+        .order_sizing(OrderSizing::FixedAmount(10000000))
+        .max_buy_price(Price::MAX)
+        .min_sell_price(Price::ZERO)
+        .fee_bps(10)
+        .min_order_size(1000000)
+        .order_size_tolerance(1000000)
+        .iteration_jitter(Duration::from_secs(1))
+        .price_anchor(PriceAnchor::BookMid)
+        .max_price_move_pct(10.0)
+        .build()?;
+
+    let token = CancellationToken::new();
+    let shutdown_token = token.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            shutdown_token.cancel();
+        }
+    });
+
+    simple_market_maker::run_until(&exchange, None, &config, token).await?;
+    Ok(())
+}
@@ -0,0 +1,249 @@
+use crate::{
+    plan_orders, start_cooldown, tick_cooldowns, Balances, CancelOrderRequest, Config, ConfigError,
+    Cooldowns, Order, OrderType, Plan, Position, Price,
+};
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug)]
+pub struct BacktestReport {
+    /// The final balances, including any funds still committed to open orders
+    pub balances: Balances,
+    /// The change in the base balance over the backtest
+    pub inventory_change: i128,
+    /// Profit in the quote token from fills which reduced the position built up by earlier fills,
+    /// measured against the average price of that position
+    pub realised_pnl: i128,
+    pub fills: u64,
+    /// The number of fills which reduced the position built up by earlier fills
+    pub round_trips: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BacktestError {
+    Config(ConfigError),
+    /// A balance grew too large to fit in a `u64`
+    Overflow,
+}
+
+impl Display for BacktestError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BacktestError::Config(error) => write!(f, "Invalid config: {error}"),
+            BacktestError::Overflow => write!(f, "Balance overflowed"),
+        }
+    }
+}
+
+impl std::error::Error for BacktestError {}
+
+impl From<ConfigError> for BacktestError {
+    fn from(error: ConfigError) -> Self {
+        BacktestError::Config(error)
+    }
+}
+
+/// Replays a series of `(timestamp, price)` pairs through the same order logic used by
+/// `run_until`, without touching the network. Timestamps are in nanoseconds since the Unix epoch.
+///
+/// Each price is treated as a trade, filling in full any open bids at or above it and any open
+/// asks at or below it, at the order's own price. Funds are committed to orders when they are
+/// made, as on ICDex, and orders which can't be funded are skipped. The circuit breaker and the
+/// `BookMid` price anchor aren't simulated.
+pub fn backtest(
+    prices: &[(u64, Price)],
+    config: &Config,
+    initial_balances: Balances,
+) -> Result<BacktestReport, BacktestError> {
+    config.validate()?;
+
+    let mut balances = initial_balances.clone();
+    let mut open_orders: Vec<Order> = Vec::new();
+    let mut position = Position::default();
+    let mut fills = 0;
+    let mut round_trips = 0;
+    let mut next_id = 0u64;
+    let mut fill_cooldowns = Cooldowns::new();
+
+    for &(timestamp, price) in prices {
+        let config = &*config.resolve_increment(price);
+        fill_cooldowns = tick_cooldowns(&fill_cooldowns);
+        let (filled, open): (Vec<_>, Vec<_>) = std::mem::take(&mut open_orders)
+            .into_iter()
+            .partition(|o| match o.order_type {
+                OrderType::Bid => o.price >= price,
+                OrderType::Ask => o.price <= price,
+            });
+        open_orders = open;
+        for o in filled {
+            match o.order_type {
+                OrderType::Bid => credit(&mut balances.base, u128::from(o.amount))?,
+                OrderType::Ask => credit(&mut balances.quote, o.price.wide_notional(o.amount))?,
+            }
+            fills += 1;
+            start_cooldown(&mut fill_cooldowns, &o, config);
+            if position.fill(o.order_type, o.price, o.amount) {
+                round_trips += 1;
+            }
+        }
+
+        let Plan {
+            mut orders_to_make,
+            mut orders_to_cancel,
+            orders_to_replace,
+            ..
+        } = plan_orders(
+            &open_orders,
+            price,
+            &balances,
+            &fill_cooldowns,
+            timestamp,
+            config,
+        );
+
+        // Replacements are simulated by cancelling the order and making its replacement
+        for replacement in orders_to_replace {
+            orders_to_make.push(replacement.to_make());
+            orders_to_cancel.push(CancelOrderRequest { id: replacement.id });
+        }
+
+        let ids_to_cancel: HashSet<_> = orders_to_cancel.into_iter().map(|o| o.id).collect();
+        let (cancelled, open): (Vec<_>, Vec<_>) = std::mem::take(&mut open_orders)
+            .into_iter()
+            .partition(|o| ids_to_cancel.contains(&o.id));
+        open_orders = open;
+        for order in &cancelled {
+            release(&mut balances, order)?;
+        }
+
+        for order in orders_to_make {
+            let committed = match order.order_type {
+                OrderType::Bid => &mut balances.quote,
+                OrderType::Ask => &mut balances.base,
+            };
+            let required = match order.order_type {
+                OrderType::Bid => order.price.wide_notional(order.amount),
+                OrderType::Ask => u128::from(order.amount),
+            };
+            // The required amount is at most the balance, so it fits in a `u64`
+            match u64::try_from(required) {
+                Ok(required) if *committed >= required => *committed -= required,
+                _ => continue,
+            }
+
+            open_orders.push(Order {
+                order_type: order.order_type,
+                id: next_id.to_string().into(),
+                price: order.price,
+                amount: order.amount,
+                placed_at: Some(timestamp),
+            });
+            next_id += 1;
+        }
+    }
+
+    for order in &open_orders {
+        release(&mut balances, order)?;
+    }
+
+    Ok(BacktestReport {
+        inventory_change: i128::from(balances.base) - i128::from(initial_balances.base),
+        balances,
+        realised_pnl: position.realised_pnl,
+        fills,
+        round_trips,
+    })
+}
+
+// Returns the funds committed to an order to the free balance
+fn release(balances: &mut Balances, order: &Order) -> Result<(), BacktestError> {
+    match order.order_type {
+        OrderType::Bid => credit(&mut balances.quote, order.price.wide_notional(order.amount)),
+        OrderType::Ask => credit(&mut balances.base, u128::from(order.amount)),
+    }
+}
+
+// Adds to a balance, failing rather than wrapping around if the total doesn't fit in a `u64`
+fn credit(balance: &mut u64, amount: u128) -> Result<(), BacktestError> {
+    *balance = u128::from(*balance)
+        .checked_add(amount)
+        .and_then(|total| u64::try_from(total).ok())
+        .ok_or(BacktestError::Overflow)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use crate::OrderSizing;
+
+    #[test]
+    fn round_trip_realises_the_spread() {
+        let config = Config {
+            increment: Price::from_decimal(0.01),
+            order_sizing: OrderSizing::FixedAmount(100000000),
+            min_order_size: 1,
+            min_orders_per_direction: 1,
+            max_orders_per_direction: 1,
+            ..test_config()
+        };
+        let balances = Balances {
+            base: 1000000000,
+            quote: 1000000000,
+        };
+
+        // The bid at 0.99 fills, then the ask at 1.00 placed around the new price fills
+        let prices = [
+            (0, Price::from_decimal(1.0)),
+            (1, Price::from_decimal(0.99)),
+            (2, Price::from_decimal(1.0)),
+        ];
+
+        let report = backtest(&prices, &config, balances).unwrap();
+
+        assert_eq!(report.fills, 2);
+        assert_eq!(report.round_trips, 1);
+        assert_eq!(report.realised_pnl, 1000000);
+        assert_eq!(report.inventory_change, 0);
+        assert_eq!(report.balances.base, 1000000000);
+        assert_eq!(report.balances.quote, 1001000000);
+    }
+
+    #[test]
+    fn fails_rather_than_wrapping_around_when_a_balance_overflows() {
+        let config = Config {
+            increment: Price::from_decimal(0.01),
+            order_sizing: OrderSizing::FixedAmount(100000000),
+            min_order_size: 1,
+            min_orders_per_direction: 1,
+            max_orders_per_direction: 1,
+            ..test_config()
+        };
+        let balances = Balances {
+            base: 1000000000,
+            quote: u64::MAX,
+        };
+
+        // The bid commits 0.99 of the quote token, then the ask fills for 1.01
+        let prices = [
+            (0, Price::from_decimal(1.0)),
+            (1, Price::from_decimal(1.01)),
+        ];
+
+        assert!(matches!(
+            backtest(&prices, &config, balances),
+            Err(BacktestError::Overflow)
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        let config = Config {
+            increment: Price::ZERO,
+            ..test_config()
+        };
+
+        assert!(backtest(&[], &config, Balances { base: 0, quote: 0 }).is_err());
+    }
+}
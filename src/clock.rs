@@ -0,0 +1,26 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The source of the current time for the logic which depends on it (order TTLs, the daily volume
+/// limit, stale price checks and the volatility pause), so that it can be controlled in tests
+pub trait Clock: Sync {
+    /// The current time in nanoseconds since the Unix epoch
+    fn now(&self) -> u64;
+}
+
+/// Reads the system clock
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64)
+    }
+}
+
+// Lets a clock be shared with the wrappers which take one by value, such as `DryRun`
+impl<C: Clock + ?Sized> Clock for &C {
+    fn now(&self) -> u64 {
+        (**self).now()
+    }
+}
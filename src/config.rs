@@ -0,0 +1,1743 @@
+use crate::{Balances, OrderType, Price, TradingLimits};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Can be deserialized using the same field names, in which case durations are written as strings
+/// such as "500ms", "5s", "2m" or "1h" and prices are scaled by 1e8. Fields which are left out take
+/// the same defaults as `ConfigBuilder`.
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "ConfigBuilder")]
+pub struct Config {
+    /// The spacing between bids, and between asks unless `ask_increment` is set. Left at zero when
+    /// `increment_pct` is used instead.
+    pub increment: Price,
+    /// The spacing between bids (and asks, unless `ask_increment` is set) as a percentage of the
+    /// anchor price, as an alternative to `increment`. It is recalculated each iteration and
+    /// rounded down to two significant figures, so that small price moves don't shift the grid.
+    pub increment_pct: Option<f64>,
+    /// The spacing between asks, if it should differ from the spacing between bids (`increment`)
+    pub ask_increment: Option<Price>,
+    /// How the levels beyond the innermost bid and ask are spaced. The innermost levels are always
+    /// placed using the increments.
+    pub spacing: Spacing,
+    /// The exchange's minimum price step, if it has one. Bids are rounded down and asks rounded up
+    /// to a multiple of it.
+    pub tick_size: Option<Price>,
+    /// The exchange's minimum amount step, if it has one. Order amounts are rounded down to a
+    /// multiple of it.
+    pub lot_size: Option<u64>,
+    /// The exchange's trading fee in basis points. The innermost bid and ask are kept at least
+    /// twice this far apart, relative to the anchor price, so that a round trip covers its fees.
+    pub fee_bps: u64,
+    pub order_sizing: OrderSizing,
+    /// If set, bids are sized by this rather than `order_sizing`
+    pub bid_order_sizing: Option<OrderSizing>,
+    /// If set, asks are sized by this rather than `order_sizing`
+    pub ask_order_sizing: Option<OrderSizing>,
+    /// If set, orders further from the anchor price are larger. The order `level` orders beyond the
+    /// innermost bid or ask is `1 + size_scaling * level` times the size from `order_sizing`.
+    pub size_scaling: Option<f64>,
+    /// If set, each order's amount is randomly scaled by up to this percentage either way, so that
+    /// the bot's orders aren't all the same size. The amount at each price is derived from
+    /// `size_jitter_seed`, so it stays the same from one iteration to the next.
+    pub size_jitter_pct: Option<f64>,
+    pub size_jitter_seed: u64,
+    /// If set, order sizes are scaled in proportion to the market's 24 hour volume, so that the bot
+    /// doesn't post disproportionately large orders in an illiquid market. Has no effect on
+    /// exchanges which don't report their volume.
+    pub volume_scaling: Option<VolumeScaling>,
+    pub min_order_size: u64,
+    /// How far the open orders at a target price may exceed the target amount before the excess
+    /// orders are cancelled
+    pub order_size_tolerance: u64,
+    /// If set, open orders which have been resting for longer than this are cancelled so that
+    /// they get replaced, even if their price still matches a target
+    pub max_order_age: Option<Duration>,
+    /// After an order is filled, no new order is made at its price for this many iterations, to
+    /// avoid being repeatedly picked off while the price is moving. Zero disables the cooldown.
+    pub fill_cooldown_iterations: u32,
+    /// Bids are only placed strictly below this price
+    pub max_buy_price: Price,
+    /// Asks are only placed strictly above this price
+    pub min_sell_price: Price,
+    /// The maximum total notional value, in the quote token, of all open bids
+    pub max_total_bid_notional: Option<u64>,
+    /// The maximum total notional value, in the quote token, of all open asks
+    pub max_total_ask_notional: Option<u64>,
+    /// The most of the base token which may be filled per UTC day. Once it is reached, every open
+    /// order is cancelled and no more are made until midnight UTC. Until then, the amount open on
+    /// each side is limited to what remains of it.
+    pub max_daily_volume: Option<u64>,
+    pub min_orders_per_direction: u64,
+    pub max_orders_per_direction: u64,
+    /// If set, no orders are placed further than this from the anchor price, even if that leaves
+    /// fewer than `max_orders_per_direction` (or `min_orders_per_direction`) orders, since orders
+    /// that far out would almost never fill
+    pub max_distance_from_mid: Option<MaxDistance>,
+    pub max_orders_to_make_per_iteration: usize,
+    pub max_orders_to_cancel_per_iteration: usize,
+    /// If set, once more than this fraction (between 0 and 1) of the open orders are to be
+    /// cancelled, `max_orders_to_cancel_per_iteration` is lifted so that the grid is recentered
+    /// in a single iteration, rather than a few orders at a time after a large price move
+    pub recenter_threshold: Option<f64>,
+    /// If set, open orders which have been outside the target grid for more than this many
+    /// consecutive iterations are treated as orphaned, eg. when left behind by a previous config
+    /// faster than `max_orders_to_cancel_per_iteration` can clear them
+    pub orphan_iterations: Option<u32>,
+    /// Whether orphaned orders are cancelled regardless of `max_orders_to_cancel_per_iteration`,
+    /// rather than only logged as a warning
+    pub cancel_orphans: bool,
+    /// The most orders which may be open at once across both sides, for exchanges which cap the
+    /// number of open orders per account. Orders closest to the anchor price are made first.
+    pub max_total_open_orders: Option<usize>,
+    pub iteration_interval: Duration,
+    /// A random delay of up to this duration is added to each `iteration_interval` so that
+    /// multiple instances don't poll in lockstep
+    pub iteration_jitter: Duration,
+    /// If set, an iteration (including its retries) which takes longer than this is abandoned, so
+    /// that a hung query can't stop the orders from being updated
+    pub iteration_timeout: Option<Duration>,
+    pub price_anchor: PriceAnchor,
+    /// On startup, no orders are made until this many prices have been sampled (one per
+    /// iteration), after which the first orders are placed around their median. Zero disables the
+    /// warm-up.
+    pub warmup_iterations: u32,
+    /// Which sides of the book to quote. Open orders on a side which isn't quoted are cancelled.
+    pub side: QuoteSide,
+    /// Whether to cancel all open orders when `run_until` is stopped
+    pub cancel_orders_on_shutdown: bool,
+    /// If set, the bot cancels all open orders and stops once a file exists at this path. It is
+    /// checked before each iteration.
+    pub kill_file: Option<PathBuf>,
+    /// If set, the open orders and the orders being made are saved to this file each iteration
+    /// and reconciled against the open orders after a restart
+    pub state_file: Option<PathBuf>,
+    /// How many times to retry an iteration which fails with a retryable error
+    pub max_retries: u32,
+    /// The delay before the first retry, doubling for each subsequent retry
+    pub retry_backoff: Duration,
+    /// If set, prices are shifted to push the base balance towards the target
+    pub inventory_skew: Option<InventorySkew>,
+    /// If set, orders are resized to push the share of the portfolio's value held in the base
+    /// token (between 0 and 1) towards this. Bids shrink and asks grow by however far the share is
+    /// above the target, and vice versa when it's below. The distance from the target is rounded
+    /// to the nearest 0.05, so that small drifts in the balances don't resize the orders, and
+    /// neither side is shrunk below a tenth of its usual size.
+    pub target_base_fraction: Option<f64>,
+    /// If the price moves by more than this percentage between iterations, all orders are
+    /// cancelled and no new orders are made until the price settles
+    pub max_price_move_pct: Option<f64>,
+    /// If set, no orders are made while the exchange's latest trade is older than this, since the
+    /// latest price may no longer reflect the market. Open orders are left in place. Has no effect
+    /// on exchanges which don't report when the latest trade happened.
+    pub max_price_age: Option<Duration>,
+    /// If set, the best bid and ask are read again just before orders are made or replaced, and
+    /// any order or replacement which would now trade more than this through the best price on the
+    /// opposite side of the book is skipped rather than sent, in case the market moved during the
+    /// iteration
+    pub max_slippage: Option<Price>,
+    /// If set, no orders are made for this long after the exchange rejects an order for
+    /// unacceptable volatility, rather than retrying each iteration. Open orders are left in place.
+    pub volatility_cooldown: Option<Duration>,
+    /// If set, no orders are made while the spread between the best bid and best ask is below
+    /// this, since joining a book which is already tighter than the bot's edge is unprofitable. The
+    /// book includes the bot's own orders, so this can't exceed `increment + ask_increment`.
+    pub min_book_spread: Option<Price>,
+    /// Whether to also cancel the open orders while the book spread is below `min_book_spread`
+    pub cancel_orders_when_book_tight: bool,
+}
+
+impl Config {
+    pub fn bid_increment(&self) -> Price {
+        self.increment
+    }
+
+    pub fn ask_increment(&self) -> Price {
+        self.ask_increment.unwrap_or(self.increment)
+    }
+
+    /// How orders on the given side are sized
+    pub fn sizing(&self, order_type: OrderType) -> OrderSizing {
+        match order_type {
+            OrderType::Bid => self.bid_order_sizing.unwrap_or(self.order_sizing),
+            OrderType::Ask => self.ask_order_sizing.unwrap_or(self.order_sizing),
+        }
+    }
+
+    /// The base amount of an order on the given side at the given price, `level` orders beyond
+    /// the innermost bid or ask, rounded down
+    pub fn order_amount(&self, order_type: OrderType, price: Price, level: u64) -> u64 {
+        let amount = self.sizing(order_type).amount(price);
+        let amount = match self.size_scaling {
+            Some(size_scaling) => (amount as f64 * (1.0 + size_scaling * level as f64)) as u64,
+            None => amount,
+        };
+        self.round_amount(match self.size_jitter_pct {
+            Some(size_jitter_pct) => self.jitter_amount(amount, price, size_jitter_pct),
+            None => amount,
+        })
+    }
+
+    // Rounds down to a multiple of `lot_size`, if it is set
+    pub(crate) fn round_amount(&self, amount: u64) -> u64 {
+        match self.lot_size {
+            Some(lot_size) if lot_size > 0 => amount - amount % lot_size,
+            _ => amount,
+        }
+    }
+
+    // Raises `min_order_size`, `tick_size` and `lot_size` just far enough to fit within the
+    // exchange's limits, so that orders aren't rejected for breaking them. A tick or lot size which
+    // isn't set is taken from the exchange.
+    pub(crate) fn apply_trading_limits(&self, limits: &TradingLimits) -> Cow<'_, Config> {
+        let min_order_size = self.min_order_size.max(limits.min_order_size.unwrap_or(0));
+        let tick_size = fit_to_step(
+            self.tick_size.map(|t| t.scaled()),
+            limits.tick_size.map(|t| t.scaled()),
+        )
+        .map(Price::from_scaled);
+        let lot_size = fit_to_step(self.lot_size, limits.lot_size);
+
+        if (min_order_size, tick_size, lot_size)
+            == (self.min_order_size, self.tick_size, self.lot_size)
+        {
+            return Cow::Borrowed(self);
+        }
+        Cow::Owned(Config {
+            min_order_size,
+            tick_size,
+            lot_size,
+            ..self.clone()
+        })
+    }
+
+    // Seeding from the price as well as `size_jitter_seed` keeps each level's amount the same
+    // between iterations, so that jitter alone never causes orders to be replaced. Jitter never
+    // takes an amount below `min_order_size` if it wasn't already.
+    fn jitter_amount(&self, amount: u64, price: Price, size_jitter_pct: f64) -> u64 {
+        let mut rng = StdRng::seed_from_u64(self.size_jitter_seed ^ price.scaled());
+        let factor = 1.0 + rng.gen_range(-size_jitter_pct..=size_jitter_pct) / 100.0;
+        let jittered = (amount as f64 * factor) as u64;
+        jittered.max(self.min_order_size.min(amount))
+    }
+
+    // Scales `order_sizing`, along with the per-side sizings which override it, by the market's 24
+    // hour volume, if `volume_scaling` is set and the exchange reported the volume
+    pub(crate) fn scale_to_volume(&self, volume_24h: Option<u64>) -> Cow<'_, Config> {
+        let (Some(volume_scaling), Some(volume_24h)) = (self.volume_scaling, volume_24h) else {
+            return Cow::Borrowed(self);
+        };
+
+        let factor = volume_scaling.factor(volume_24h);
+        Cow::Owned(Config {
+            order_sizing: self.order_sizing.scaled(factor),
+            bid_order_sizing: self.bid_order_sizing.map(|s| s.scaled(factor)),
+            ask_order_sizing: self.ask_order_sizing.map(|s| s.scaled(factor)),
+            ..self.clone()
+        })
+    }
+
+    // Scales each side's sizing by how far the base token's share of the portfolio is from
+    // `target_base_fraction`, so that fills move the balances towards the target. The deviation is
+    // rounded to a whole `REBALANCE_STEP`, so that the small drift in the balances from each fill
+    // leaves the target amounts unchanged rather than topping up every open order with a dust
+    // order (or replacing it, once the change exceeds `order_size_tolerance`). Neither side is ever
+    // scaled below `MIN_REBALANCE_FACTOR`, so that it keeps being quoted.
+    pub(crate) fn rebalance_sizing(&self, price: Price, balances: &Balances) -> Cow<'_, Config> {
+        let Some(target_base_fraction) = self.target_base_fraction else {
+            return Cow::Borrowed(self);
+        };
+        let base_value = price.wide_notional(balances.base) as f64;
+        let total_value = base_value + balances.quote as f64;
+        if total_value == 0.0 {
+            return Cow::Borrowed(self);
+        }
+
+        let deviation = base_value / total_value - target_base_fraction;
+        let deviation = (deviation / REBALANCE_STEP).round() * REBALANCE_STEP;
+        Cow::Owned(Config {
+            bid_order_sizing: Some(
+                self.sizing(OrderType::Bid)
+                    .scaled((1.0 - deviation).max(MIN_REBALANCE_FACTOR)),
+            ),
+            ask_order_sizing: Some(
+                self.sizing(OrderType::Ask)
+                    .scaled((1.0 + deviation).max(MIN_REBALANCE_FACTOR)),
+            ),
+            ..self.clone()
+        })
+    }
+
+    // Converts `increment_pct` into an absolute `increment` at the given price, so that the rest
+    // of the bot only has to deal with absolute increments
+    pub(crate) fn resolve_increment(&self, price: Price) -> Cow<'_, Config> {
+        let Some(increment_pct) = self.increment_pct else {
+            return Cow::Borrowed(self);
+        };
+
+        let increment = (price.scaled() as f64 * increment_pct / 100.0) as u64;
+        let magnitude = 10u64.pow(increment.checked_ilog10().unwrap_or(0).saturating_sub(1));
+        Cow::Owned(Config {
+            increment: Price::from_scaled((increment / magnitude * magnitude).max(1)),
+            increment_pct: None,
+            ..self.clone()
+        })
+    }
+}
+
+// The granularity of the adjustments made by `target_base_fraction`
+const REBALANCE_STEP: f64 = 0.05;
+// The smallest fraction of its usual size which `target_base_fraction` scales a side down to
+const MIN_REBALANCE_FACTOR: f64 = 0.1;
+
+impl Config {
+    /// Reads a `Config` from a TOML file, or a JSON file if the path ends with `.json`, and
+    /// validates it
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let contents =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Read(e.to_string()))?;
+
+        if path.extension().is_some_and(|e| e == "json") {
+            serde_json::from_str(&contents).map_err(|e| ConfigError::Parse(e.to_string()))
+        } else {
+            toml::from_str(&contents).map_err(|e| ConfigError::Parse(e.to_string()))
+        }
+    }
+
+    /// A builder which fills in defaults for the optional settings. `increment` (or
+    /// `increment_pct`), `order_sizing`, `max_buy_price` and `min_sell_price` must be set before
+    /// calling `build`.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigBuilder {
+    increment: Option<Price>,
+    increment_pct: Option<f64>,
+    order_sizing: Option<OrderSizing>,
+    bid_order_sizing: Option<OrderSizing>,
+    ask_order_sizing: Option<OrderSizing>,
+    size_scaling: Option<f64>,
+    size_jitter_pct: Option<f64>,
+    size_jitter_seed: u64,
+    volume_scaling: Option<VolumeScaling>,
+    max_buy_price: Option<Price>,
+    min_sell_price: Option<Price>,
+    ask_increment: Option<Price>,
+    spacing: Spacing,
+    tick_size: Option<Price>,
+    lot_size: Option<u64>,
+    fee_bps: u64,
+    min_order_size: u64,
+    order_size_tolerance: u64,
+    #[serde(with = "duration::option")]
+    max_order_age: Option<Duration>,
+    fill_cooldown_iterations: u32,
+    max_total_bid_notional: Option<u64>,
+    max_total_ask_notional: Option<u64>,
+    max_daily_volume: Option<u64>,
+    min_orders_per_direction: u64,
+    max_orders_per_direction: u64,
+    max_distance_from_mid: Option<MaxDistance>,
+    max_orders_to_make_per_iteration: usize,
+    max_orders_to_cancel_per_iteration: usize,
+    recenter_threshold: Option<f64>,
+    orphan_iterations: Option<u32>,
+    cancel_orphans: bool,
+    max_total_open_orders: Option<usize>,
+    #[serde(with = "duration")]
+    iteration_interval: Duration,
+    #[serde(with = "duration")]
+    iteration_jitter: Duration,
+    #[serde(with = "duration::option")]
+    iteration_timeout: Option<Duration>,
+    price_anchor: PriceAnchor,
+    warmup_iterations: u32,
+    side: QuoteSide,
+    cancel_orders_on_shutdown: bool,
+    kill_file: Option<PathBuf>,
+    state_file: Option<PathBuf>,
+    max_retries: u32,
+    #[serde(with = "duration")]
+    retry_backoff: Duration,
+    inventory_skew: Option<InventorySkew>,
+    target_base_fraction: Option<f64>,
+    max_price_move_pct: Option<f64>,
+    #[serde(with = "duration::option")]
+    max_price_age: Option<Duration>,
+    max_slippage: Option<Price>,
+    #[serde(with = "duration::option")]
+    volatility_cooldown: Option<Duration>,
+    min_book_spread: Option<Price>,
+    cancel_orders_when_book_tight: bool,
+}
+
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        ConfigBuilder {
+            increment: None,
+            increment_pct: None,
+            order_sizing: None,
+            bid_order_sizing: None,
+            ask_order_sizing: None,
+            size_scaling: None,
+            size_jitter_pct: None,
+            size_jitter_seed: 0,
+            volume_scaling: None,
+            max_buy_price: None,
+            min_sell_price: None,
+            ask_increment: None,
+            spacing: Spacing::Arithmetic,
+            tick_size: None,
+            lot_size: None,
+            fee_bps: 0,
+            min_order_size: 0,
+            order_size_tolerance: 0,
+            max_order_age: None,
+            fill_cooldown_iterations: 0,
+            max_total_bid_notional: None,
+            max_total_ask_notional: None,
+            max_daily_volume: None,
+            min_orders_per_direction: 5,
+            max_orders_per_direction: 10,
+            max_distance_from_mid: None,
+            max_orders_to_make_per_iteration: 10,
+            max_orders_to_cancel_per_iteration: 10,
+            recenter_threshold: None,
+            orphan_iterations: None,
+            cancel_orphans: false,
+            max_total_open_orders: None,
+            iteration_interval: Duration::from_secs(5),
+            iteration_jitter: Duration::ZERO,
+            iteration_timeout: None,
+            price_anchor: PriceAnchor::LatestPrice,
+            warmup_iterations: 0,
+            side: QuoteSide::Both,
+            cancel_orders_on_shutdown: true,
+            kill_file: None,
+            state_file: None,
+            max_retries: 3,
+            retry_backoff: Duration::from_millis(500),
+            inventory_skew: None,
+            target_base_fraction: None,
+            max_price_move_pct: None,
+            max_price_age: None,
+            max_slippage: None,
+            volatility_cooldown: None,
+            min_book_spread: None,
+            cancel_orders_when_book_tight: false,
+        }
+    }
+}
+
+impl ConfigBuilder {
+    pub fn increment(mut self, increment: Price) -> Self {
+        self.increment = Some(increment);
+        self
+    }
+
+    pub fn increment_pct(mut self, increment_pct: f64) -> Self {
+        self.increment_pct = Some(increment_pct);
+        self
+    }
+
+    pub fn order_sizing(mut self, order_sizing: OrderSizing) -> Self {
+        self.order_sizing = Some(order_sizing);
+        self
+    }
+
+    pub fn bid_order_sizing(mut self, bid_order_sizing: OrderSizing) -> Self {
+        self.bid_order_sizing = Some(bid_order_sizing);
+        self
+    }
+
+    pub fn ask_order_sizing(mut self, ask_order_sizing: OrderSizing) -> Self {
+        self.ask_order_sizing = Some(ask_order_sizing);
+        self
+    }
+
+    pub fn size_scaling(mut self, size_scaling: f64) -> Self {
+        self.size_scaling = Some(size_scaling);
+        self
+    }
+
+    pub fn size_jitter_pct(mut self, size_jitter_pct: f64) -> Self {
+        self.size_jitter_pct = Some(size_jitter_pct);
+        self
+    }
+
+    pub fn size_jitter_seed(mut self, size_jitter_seed: u64) -> Self {
+        self.size_jitter_seed = size_jitter_seed;
+        self
+    }
+
+    pub fn volume_scaling(mut self, volume_scaling: VolumeScaling) -> Self {
+        self.volume_scaling = Some(volume_scaling);
+        self
+    }
+
+    pub fn max_buy_price(mut self, max_buy_price: Price) -> Self {
+        self.max_buy_price = Some(max_buy_price);
+        self
+    }
+
+    pub fn min_sell_price(mut self, min_sell_price: Price) -> Self {
+        self.min_sell_price = Some(min_sell_price);
+        self
+    }
+
+    pub fn ask_increment(mut self, ask_increment: Price) -> Self {
+        self.ask_increment = Some(ask_increment);
+        self
+    }
+
+    pub fn spacing(mut self, spacing: Spacing) -> Self {
+        self.spacing = spacing;
+        self
+    }
+
+    pub fn tick_size(mut self, tick_size: Price) -> Self {
+        self.tick_size = Some(tick_size);
+        self
+    }
+
+    pub fn lot_size(mut self, lot_size: u64) -> Self {
+        self.lot_size = Some(lot_size);
+        self
+    }
+
+    pub fn fee_bps(mut self, fee_bps: u64) -> Self {
+        self.fee_bps = fee_bps;
+        self
+    }
+
+    pub fn min_order_size(mut self, min_order_size: u64) -> Self {
+        self.min_order_size = min_order_size;
+        self
+    }
+
+    pub fn order_size_tolerance(mut self, order_size_tolerance: u64) -> Self {
+        self.order_size_tolerance = order_size_tolerance;
+        self
+    }
+
+    pub fn max_order_age(mut self, max_order_age: Duration) -> Self {
+        self.max_order_age = Some(max_order_age);
+        self
+    }
+
+    pub fn fill_cooldown_iterations(mut self, fill_cooldown_iterations: u32) -> Self {
+        self.fill_cooldown_iterations = fill_cooldown_iterations;
+        self
+    }
+
+    pub fn max_total_bid_notional(mut self, max_total_bid_notional: u64) -> Self {
+        self.max_total_bid_notional = Some(max_total_bid_notional);
+        self
+    }
+
+    pub fn max_total_ask_notional(mut self, max_total_ask_notional: u64) -> Self {
+        self.max_total_ask_notional = Some(max_total_ask_notional);
+        self
+    }
+
+    pub fn max_daily_volume(mut self, max_daily_volume: u64) -> Self {
+        self.max_daily_volume = Some(max_daily_volume);
+        self
+    }
+
+    pub fn orders_per_direction(mut self, min: u64, max: u64) -> Self {
+        self.min_orders_per_direction = min;
+        self.max_orders_per_direction = max;
+        self
+    }
+
+    pub fn max_distance_from_mid(mut self, max_distance_from_mid: MaxDistance) -> Self {
+        self.max_distance_from_mid = Some(max_distance_from_mid);
+        self
+    }
+
+    pub fn max_orders_to_make_per_iteration(mut self, max: usize) -> Self {
+        self.max_orders_to_make_per_iteration = max;
+        self
+    }
+
+    pub fn max_orders_to_cancel_per_iteration(mut self, max: usize) -> Self {
+        self.max_orders_to_cancel_per_iteration = max;
+        self
+    }
+
+    pub fn recenter_threshold(mut self, recenter_threshold: f64) -> Self {
+        self.recenter_threshold = Some(recenter_threshold);
+        self
+    }
+
+    pub fn orphan_iterations(mut self, orphan_iterations: u32) -> Self {
+        self.orphan_iterations = Some(orphan_iterations);
+        self
+    }
+
+    pub fn cancel_orphans(mut self, cancel_orphans: bool) -> Self {
+        self.cancel_orphans = cancel_orphans;
+        self
+    }
+
+    pub fn max_total_open_orders(mut self, max: usize) -> Self {
+        self.max_total_open_orders = Some(max);
+        self
+    }
+
+    pub fn iteration_interval(mut self, iteration_interval: Duration) -> Self {
+        self.iteration_interval = iteration_interval;
+        self
+    }
+
+    pub fn iteration_jitter(mut self, iteration_jitter: Duration) -> Self {
+        self.iteration_jitter = iteration_jitter;
+        self
+    }
+
+    pub fn iteration_timeout(mut self, iteration_timeout: Duration) -> Self {
+        self.iteration_timeout = Some(iteration_timeout);
+        self
+    }
+
+    pub fn price_anchor(mut self, price_anchor: PriceAnchor) -> Self {
+        self.price_anchor = price_anchor;
+        self
+    }
+
+    pub fn warmup_iterations(mut self, warmup_iterations: u32) -> Self {
+        self.warmup_iterations = warmup_iterations;
+        self
+    }
+
+    pub fn side(mut self, side: QuoteSide) -> Self {
+        self.side = side;
+        self
+    }
+
+    pub fn cancel_orders_on_shutdown(mut self, cancel_orders_on_shutdown: bool) -> Self {
+        self.cancel_orders_on_shutdown = cancel_orders_on_shutdown;
+        self
+    }
+
+    pub fn kill_file(mut self, kill_file: PathBuf) -> Self {
+        self.kill_file = Some(kill_file);
+        self
+    }
+
+    pub fn state_file(mut self, state_file: PathBuf) -> Self {
+        self.state_file = Some(state_file);
+        self
+    }
+
+    pub fn retries(mut self, max_retries: u32, retry_backoff: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.retry_backoff = retry_backoff;
+        self
+    }
+
+    pub fn inventory_skew(mut self, inventory_skew: InventorySkew) -> Self {
+        self.inventory_skew = Some(inventory_skew);
+        self
+    }
+
+    pub fn target_base_fraction(mut self, target_base_fraction: f64) -> Self {
+        self.target_base_fraction = Some(target_base_fraction);
+        self
+    }
+
+    pub fn max_price_move_pct(mut self, max_price_move_pct: f64) -> Self {
+        self.max_price_move_pct = Some(max_price_move_pct);
+        self
+    }
+
+    pub fn max_price_age(mut self, max_price_age: Duration) -> Self {
+        self.max_price_age = Some(max_price_age);
+        self
+    }
+
+    pub fn max_slippage(mut self, max_slippage: Price) -> Self {
+        self.max_slippage = Some(max_slippage);
+        self
+    }
+
+    pub fn volatility_cooldown(mut self, volatility_cooldown: Duration) -> Self {
+        self.volatility_cooldown = Some(volatility_cooldown);
+        self
+    }
+
+    pub fn min_book_spread(mut self, min_book_spread: Price) -> Self {
+        self.min_book_spread = Some(min_book_spread);
+        self
+    }
+
+    pub fn cancel_orders_when_book_tight(mut self, cancel_orders_when_book_tight: bool) -> Self {
+        self.cancel_orders_when_book_tight = cancel_orders_when_book_tight;
+        self
+    }
+
+    /// Checks that the required settings were provided and that the resulting `Config` is valid
+    pub fn build(self) -> Result<Config, ConfigError> {
+        let config = Config {
+            increment: match (self.increment, self.increment_pct) {
+                (Some(increment), _) => increment,
+                (None, Some(_)) => Price::ZERO,
+                (None, None) => return Err(ConfigError::MissingField("increment")),
+            },
+            increment_pct: self.increment_pct,
+            ask_increment: self.ask_increment,
+            spacing: self.spacing,
+            tick_size: self.tick_size,
+            lot_size: self.lot_size,
+            fee_bps: self.fee_bps,
+            order_sizing: self
+                .order_sizing
+                .ok_or(ConfigError::MissingField("order_sizing"))?,
+            bid_order_sizing: self.bid_order_sizing,
+            ask_order_sizing: self.ask_order_sizing,
+            size_scaling: self.size_scaling,
+            size_jitter_pct: self.size_jitter_pct,
+            size_jitter_seed: self.size_jitter_seed,
+            volume_scaling: self.volume_scaling,
+            min_order_size: self.min_order_size,
+            order_size_tolerance: self.order_size_tolerance,
+            max_order_age: self.max_order_age,
+            fill_cooldown_iterations: self.fill_cooldown_iterations,
+            max_buy_price: self
+                .max_buy_price
+                .ok_or(ConfigError::MissingField("max_buy_price"))?,
+            min_sell_price: self
+                .min_sell_price
+                .ok_or(ConfigError::MissingField("min_sell_price"))?,
+            max_total_bid_notional: self.max_total_bid_notional,
+            max_total_ask_notional: self.max_total_ask_notional,
+            max_daily_volume: self.max_daily_volume,
+            min_orders_per_direction: self.min_orders_per_direction,
+            max_orders_per_direction: self.max_orders_per_direction,
+            max_distance_from_mid: self.max_distance_from_mid,
+            max_orders_to_make_per_iteration: self.max_orders_to_make_per_iteration,
+            max_orders_to_cancel_per_iteration: self.max_orders_to_cancel_per_iteration,
+            recenter_threshold: self.recenter_threshold,
+            orphan_iterations: self.orphan_iterations,
+            cancel_orphans: self.cancel_orphans,
+            max_total_open_orders: self.max_total_open_orders,
+            iteration_interval: self.iteration_interval,
+            iteration_jitter: self.iteration_jitter,
+            iteration_timeout: self.iteration_timeout,
+            price_anchor: self.price_anchor,
+            warmup_iterations: self.warmup_iterations,
+            side: self.side,
+            cancel_orders_on_shutdown: self.cancel_orders_on_shutdown,
+            kill_file: self.kill_file,
+            state_file: self.state_file,
+            max_retries: self.max_retries,
+            retry_backoff: self.retry_backoff,
+            inventory_skew: self.inventory_skew,
+            target_base_fraction: self.target_base_fraction,
+            max_price_move_pct: self.max_price_move_pct,
+            max_price_age: self.max_price_age,
+            max_slippage: self.max_slippage,
+            volatility_cooldown: self.volatility_cooldown,
+            min_book_spread: self.min_book_spread,
+            cancel_orders_when_book_tight: self.cancel_orders_when_book_tight,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+// Tracks a config file's modification time so that it is only parsed again after it changes
+pub(crate) struct ConfigFile {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl ConfigFile {
+    pub fn new(path: PathBuf) -> Self {
+        ConfigFile {
+            path,
+            modified: None,
+        }
+    }
+
+    pub fn load(&mut self) -> Result<Config, ConfigError> {
+        // The modification time is read first so that a write which lands while the file is being
+        // parsed is picked up by the next `reload`
+        self.modified = modified(&self.path);
+        Config::from_file(&self.path)
+    }
+
+    /// Returns the new config if the file has been modified since it was last loaded. An invalid
+    /// file is only reported once, rather than on every call until it is fixed.
+    pub fn reload(&mut self) -> Result<Option<Config>, ConfigError> {
+        if modified(&self.path) == self.modified {
+            return Ok(None);
+        }
+        self.load().map(Some)
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl TryFrom<ConfigBuilder> for Config {
+    type Error = ConfigError;
+
+    fn try_from(builder: ConfigBuilder) -> Result<Self, Self::Error> {
+        builder.build()
+    }
+}
+
+mod duration {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer};
+    use std::time::Duration;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        parse(&value).ok_or_else(|| D::Error::custom(format!("invalid duration: {value}")))
+    }
+
+    pub mod option {
+        use super::*;
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Duration>, D::Error> {
+            let value = Option::<String>::deserialize(deserializer)?;
+            value
+                .map(|v| {
+                    parse(&v).ok_or_else(|| D::Error::custom(format!("invalid duration: {v}")))
+                })
+                .transpose()
+        }
+    }
+
+    pub(super) fn parse(value: &str) -> Option<Duration> {
+        let split = value.find(|c: char| !c.is_ascii_digit())?;
+        let (number, unit) = value.split_at(split);
+        let number: u64 = number.parse().ok()?;
+
+        match unit.trim() {
+            "ms" => Some(Duration::from_millis(number)),
+            "s" => Some(Duration::from_secs(number)),
+            "m" => Some(Duration::from_secs(number.checked_mul(60)?)),
+            "h" => Some(Duration::from_secs(number.checked_mul(3600)?)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InventorySkew {
+    /// The base balance to aim for
+    pub target_base: u64,
+    /// The number of increments to shift prices by when the base balance is zero or double the
+    /// target. Prices are shifted down when holding more than the target and up when holding less.
+    pub skew_factor: f64,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VolumeScaling {
+    /// The 24 hour volume, in the base token's smallest unit, at which orders are the size from
+    /// `order_sizing`. Orders are proportionally smaller when the volume is lower.
+    pub reference_volume: u64,
+    /// The most that orders are scaled up by when the volume exceeds `reference_volume`. Set this
+    /// to 1 to only ever scale orders down.
+    pub max_factor: f64,
+    /// The most that orders are scaled down by, however low the volume, so that the bot keeps
+    /// quoting when the market has gone quiet rather than sizing its orders down to nothing.
+    /// Defaults to 0.1.
+    #[serde(default = "default_min_factor")]
+    pub min_factor: f64,
+}
+
+impl VolumeScaling {
+    // The factor to scale the order sizes by at the given volume
+    fn factor(&self, volume_24h: u64) -> f64 {
+        (volume_24h as f64 / self.reference_volume as f64)
+            .min(self.max_factor)
+            .max(self.min_factor)
+    }
+}
+
+fn default_min_factor() -> f64 {
+    0.1
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderSizing {
+    /// Every order is for this amount of the base token
+    FixedAmount(u64),
+    /// Every order is worth this amount of the quote token, so the base amount of each order
+    /// depends on its price
+    FixedNotional(u64),
+}
+
+impl OrderSizing {
+    /// The base amount of an order at the given price, rounded down
+    pub fn amount(&self, price: Price) -> u64 {
+        match *self {
+            OrderSizing::FixedAmount(amount) => amount,
+            OrderSizing::FixedNotional(notional) => price.amount_for_notional(notional),
+        }
+    }
+
+    // The same sizing with each order's size multiplied by `factor`, rounded down
+    fn scaled(&self, factor: f64) -> OrderSizing {
+        match *self {
+            OrderSizing::FixedAmount(amount) => {
+                OrderSizing::FixedAmount((amount as f64 * factor) as u64)
+            }
+            OrderSizing::FixedNotional(notional) => {
+                OrderSizing::FixedNotional((notional as f64 * factor) as u64)
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MaxDistance {
+    /// A number of increments, using `increment` for bids and `ask_increment` for asks
+    Increments(u64),
+    /// A percentage of the anchor price
+    Pct(f64),
+}
+
+impl MaxDistance {
+    /// The furthest an order with the given increment may be from `price`, rounded down
+    pub fn scaled(&self, price: Price, increment: Price) -> u64 {
+        match *self {
+            MaxDistance::Increments(increments) => increments.saturating_mul(increment.scaled()),
+            MaxDistance::Pct(pct) => (price.scaled() as f64 * pct / 100.0) as u64,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuoteSide {
+    Both,
+    BidsOnly,
+    AsksOnly,
+}
+
+impl QuoteSide {
+    pub fn includes(&self, order_type: OrderType) -> bool {
+        matches!(
+            (self, order_type),
+            (QuoteSide::Both, _)
+                | (QuoteSide::BidsOnly, OrderType::Bid)
+                | (QuoteSide::AsksOnly, OrderType::Ask)
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PriceAnchor {
+    /// The price of the most recent trade
+    LatestPrice,
+    /// The midpoint between the best bid and best ask, falling back to the latest price if either
+    /// side of the book is empty
+    BookMid,
+    /// `mid_weight * mid + (1 - mid_weight) * latest`, where `mid` is the midpoint between the
+    /// best bid and best ask. Falls back to the latest price if either side of the book is empty.
+    /// `mid_weight` must be between 0 and 1.
+    Blended { mid_weight: f64 },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Spacing {
+    /// Each level is `increment` (or `ask_increment`) beyond the previous one
+    Arithmetic,
+    /// Each level is this factor beyond the previous one, eg. 1.01 places each ask 1% above the
+    /// previous ask and each bid 1% below the previous bid. Must be greater than 1.
+    Geometric(f64),
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        match self.increment_pct {
+            Some(_) if self.increment != Price::ZERO => {
+                return Err(ConfigError::IncrementAndIncrementPct);
+            }
+            Some(increment_pct) if !increment_pct.is_finite() || increment_pct <= 0.0 => {
+                return Err(ConfigError::InvalidIncrementPct(increment_pct));
+            }
+            None if self.increment == Price::ZERO => return Err(ConfigError::ZeroIncrement),
+            _ => {}
+        }
+        if self.ask_increment == Some(Price::ZERO) {
+            return Err(ConfigError::ZeroIncrement);
+        }
+        if let Spacing::Geometric(factor) = self.spacing {
+            if !factor.is_finite() || factor <= 1.0 {
+                return Err(ConfigError::InvalidSpacingFactor(factor));
+            }
+        }
+        if self.tick_size == Some(Price::ZERO) {
+            return Err(ConfigError::ZeroTickSize);
+        }
+        if self.lot_size == Some(0) {
+            return Err(ConfigError::ZeroLotSize);
+        }
+        if self.iteration_timeout == Some(Duration::ZERO) {
+            return Err(ConfigError::ZeroIterationTimeout);
+        }
+        let sizings = [
+            Some(self.order_sizing),
+            self.bid_order_sizing,
+            self.ask_order_sizing,
+        ];
+        for order_sizing in sizings.into_iter().flatten() {
+            match order_sizing {
+                OrderSizing::FixedAmount(0) | OrderSizing::FixedNotional(0) => {
+                    return Err(ConfigError::ZeroOrderSize);
+                }
+                OrderSizing::FixedAmount(order_size) if self.min_order_size > order_size => {
+                    return Err(ConfigError::MinOrderSizeExceedsOrderSize {
+                        min_order_size: self.min_order_size,
+                        order_size,
+                    });
+                }
+                _ => {}
+            }
+        }
+        if let Some(size_scaling) = self.size_scaling {
+            if !size_scaling.is_finite() || size_scaling < 0.0 {
+                return Err(ConfigError::InvalidSizeScaling(size_scaling));
+            }
+        }
+        if let Some(size_jitter_pct) = self.size_jitter_pct {
+            if !(0.0..100.0).contains(&size_jitter_pct) {
+                return Err(ConfigError::InvalidSizeJitterPct(size_jitter_pct));
+            }
+        }
+        if let Some(volume_scaling) = self.volume_scaling {
+            if volume_scaling.reference_volume == 0 {
+                return Err(ConfigError::ZeroReferenceVolume);
+            }
+            if !volume_scaling.max_factor.is_finite() || volume_scaling.max_factor <= 0.0 {
+                return Err(ConfigError::InvalidVolumeScalingMaxFactor(
+                    volume_scaling.max_factor,
+                ));
+            }
+            if !(volume_scaling.min_factor > 0.0
+                && volume_scaling.min_factor <= volume_scaling.max_factor)
+            {
+                return Err(ConfigError::InvalidVolumeScalingMinFactor {
+                    min_factor: volume_scaling.min_factor,
+                    max_factor: volume_scaling.max_factor,
+                });
+            }
+        }
+        if self.min_orders_per_direction > self.max_orders_per_direction {
+            return Err(ConfigError::MinOrdersExceedsMaxOrders {
+                min_orders_per_direction: self.min_orders_per_direction,
+                max_orders_per_direction: self.max_orders_per_direction,
+            });
+        }
+        if let Some(skew) = self.inventory_skew {
+            if skew.target_base == 0 {
+                return Err(ConfigError::ZeroInventorySkewTarget);
+            }
+            if !skew.skew_factor.is_finite() || skew.skew_factor < 0.0 {
+                return Err(ConfigError::InvalidSkewFactor(skew.skew_factor));
+            }
+        }
+        match self.max_distance_from_mid {
+            Some(MaxDistance::Increments(0)) => return Err(ConfigError::ZeroMaxDistance),
+            Some(MaxDistance::Pct(pct)) if !pct.is_finite() || pct <= 0.0 => {
+                return Err(ConfigError::InvalidMaxDistancePct(pct))
+            }
+            _ => {}
+        }
+        if let Some(recenter_threshold) = self.recenter_threshold {
+            if !(0.0..=1.0).contains(&recenter_threshold) {
+                return Err(ConfigError::InvalidRecenterThreshold(recenter_threshold));
+            }
+        }
+        if let PriceAnchor::Blended { mid_weight } = self.price_anchor {
+            if !(0.0..=1.0).contains(&mid_weight) {
+                return Err(ConfigError::InvalidMidWeight(mid_weight));
+            }
+        }
+        if let Some(target_base_fraction) = self.target_base_fraction {
+            if !(0.0..=1.0).contains(&target_base_fraction) {
+                return Err(ConfigError::InvalidTargetBaseFraction(target_base_fraction));
+            }
+        }
+        if let Some(max_price_move_pct) = self.max_price_move_pct {
+            if max_price_move_pct.is_nan() || max_price_move_pct <= 0.0 {
+                return Err(ConfigError::InvalidMaxPriceMovePct(max_price_move_pct));
+            }
+        }
+        // With `increment_pct` the bot's own spread depends on the price, so can't be checked here
+        if let Some(min_book_spread) = self
+            .min_book_spread
+            .filter(|_| self.increment_pct.is_none())
+        {
+            // The bot's own innermost bid and ask are always at least this far apart, so a larger
+            // minimum would stop it from ever quoting once its own orders were on the book
+            let own_spread = self
+                .bid_increment()
+                .scaled()
+                .saturating_add(self.ask_increment().scaled());
+            if min_book_spread.scaled() > own_spread {
+                return Err(ConfigError::MinBookSpreadExceedsOwnSpread {
+                    min_book_spread,
+                    own_spread: Price::from_scaled(own_spread),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+// Rounds a step size up to a multiple of the exchange's step, or takes the exchange's if it isn't
+// set
+fn fit_to_step(step: Option<u64>, exchange_step: Option<u64>) -> Option<u64> {
+    match (step, exchange_step.filter(|s| *s > 0)) {
+        (Some(step), Some(exchange_step)) => {
+            Some(step.div_ceil(exchange_step).saturating_mul(exchange_step))
+        }
+        (step, exchange_step) => step.or(exchange_step),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigError {
+    Read(String),
+    Parse(String),
+    MissingField(&'static str),
+    ZeroIncrement,
+    IncrementAndIncrementPct,
+    InvalidIncrementPct(f64),
+    InvalidSpacingFactor(f64),
+    ZeroTickSize,
+    ZeroLotSize,
+    ZeroIterationTimeout,
+    ZeroOrderSize,
+    InvalidSizeScaling(f64),
+    InvalidSizeJitterPct(f64),
+    ZeroReferenceVolume,
+    InvalidVolumeScalingMaxFactor(f64),
+    InvalidVolumeScalingMinFactor {
+        min_factor: f64,
+        max_factor: f64,
+    },
+    MinOrderSizeExceedsOrderSize {
+        min_order_size: u64,
+        order_size: u64,
+    },
+    MinOrdersExceedsMaxOrders {
+        min_orders_per_direction: u64,
+        max_orders_per_direction: u64,
+    },
+    ZeroInventorySkewTarget,
+    InvalidSkewFactor(f64),
+    InvalidTargetBaseFraction(f64),
+    InvalidMaxPriceMovePct(f64),
+    InvalidMidWeight(f64),
+    InvalidRecenterThreshold(f64),
+    ZeroMaxDistance,
+    InvalidMaxDistancePct(f64),
+    MinBookSpreadExceedsOwnSpread {
+        min_book_spread: Price,
+        own_spread: Price,
+    },
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Read(error) => write!(f, "failed to read config file: {error}"),
+            ConfigError::Parse(error) => write!(f, "failed to parse config file: {error}"),
+            ConfigError::MissingField(field) => write!(f, "{field} must be set"),
+            ConfigError::ZeroIncrement => write!(f, "increment and ask_increment must be non-zero"),
+            ConfigError::IncrementAndIncrementPct => {
+                write!(f, "only one of increment and increment_pct may be set")
+            }
+            ConfigError::InvalidIncrementPct(increment_pct) => write!(
+                f,
+                "increment_pct ({increment_pct}) must be finite and greater than zero"
+            ),
+            ConfigError::InvalidSpacingFactor(factor) => write!(
+                f,
+                "spacing geometric factor ({factor}) must be finite and greater than 1"
+            ),
+            ConfigError::ZeroTickSize => write!(f, "tick_size must be non-zero"),
+            ConfigError::ZeroLotSize => write!(f, "lot_size must be non-zero"),
+            ConfigError::ZeroIterationTimeout => write!(f, "iteration_timeout must be non-zero"),
+            ConfigError::ZeroOrderSize => write!(f, "order_sizing must be non-zero"),
+            ConfigError::InvalidSizeScaling(size_scaling) => write!(
+                f,
+                "size_scaling ({size_scaling}) must be finite and non-negative"
+            ),
+            ConfigError::InvalidSizeJitterPct(size_jitter_pct) => write!(
+                f,
+                "size_jitter_pct ({size_jitter_pct}) must be at least 0 and less than 100"
+            ),
+            ConfigError::ZeroReferenceVolume => {
+                write!(f, "volume_scaling reference_volume must be non-zero")
+            }
+            ConfigError::InvalidVolumeScalingMaxFactor(max_factor) => write!(
+                f,
+                "volume_scaling max_factor ({max_factor}) must be finite and greater than zero"
+            ),
+            ConfigError::InvalidVolumeScalingMinFactor {
+                min_factor,
+                max_factor,
+            } => write!(
+                f,
+                "volume_scaling min_factor ({min_factor}) must be greater than zero and no more than max_factor ({max_factor})"
+            ),
+            ConfigError::MinOrderSizeExceedsOrderSize {
+                min_order_size,
+                order_size,
+            } => write!(
+                f,
+                "min_order_size ({min_order_size}) must not exceed order_size ({order_size})"
+            ),
+            ConfigError::MinOrdersExceedsMaxOrders {
+                min_orders_per_direction,
+                max_orders_per_direction,
+            } => write!(
+                f,
+                "min_orders_per_direction ({min_orders_per_direction}) must not exceed max_orders_per_direction ({max_orders_per_direction})"
+            ),
+            ConfigError::ZeroInventorySkewTarget => {
+                write!(f, "inventory_skew.target_base must be non-zero")
+            }
+            ConfigError::InvalidSkewFactor(skew_factor) => write!(
+                f,
+                "inventory_skew.skew_factor ({skew_factor}) must be finite and non-negative"
+            ),
+            ConfigError::InvalidTargetBaseFraction(target_base_fraction) => write!(
+                f,
+                "target_base_fraction ({target_base_fraction}) must be between 0 and 1"
+            ),
+            ConfigError::InvalidMaxPriceMovePct(max_price_move_pct) => write!(
+                f,
+                "max_price_move_pct ({max_price_move_pct}) must be greater than zero"
+            ),
+            ConfigError::InvalidMidWeight(mid_weight) => write!(
+                f,
+                "price_anchor mid_weight ({mid_weight}) must be between 0 and 1"
+            ),
+            ConfigError::InvalidRecenterThreshold(recenter_threshold) => write!(
+                f,
+                "recenter_threshold ({recenter_threshold}) must be between 0 and 1"
+            ),
+            ConfigError::ZeroMaxDistance => write!(f, "max_distance_from_mid must be non-zero"),
+            ConfigError::InvalidMaxDistancePct(pct) => write!(
+                f,
+                "max_distance_from_mid pct ({pct}) must be finite and greater than zero"
+            ),
+            ConfigError::MinBookSpreadExceedsOwnSpread {
+                min_book_spread,
+                own_spread,
+            } => write!(
+                f,
+                "min_book_spread ({min_book_spread}) must not exceed increment + ask_increment ({own_spread})"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[cfg(test)]
+pub(crate) fn test_config() -> Config {
+    Config {
+        increment: Price::from_scaled(10),
+        increment_pct: None,
+        ask_increment: None,
+        spacing: Spacing::Arithmetic,
+        tick_size: None,
+        lot_size: None,
+        fee_bps: 0,
+        order_sizing: OrderSizing::FixedAmount(100),
+        bid_order_sizing: None,
+        ask_order_sizing: None,
+        size_scaling: None,
+        size_jitter_pct: None,
+        size_jitter_seed: 0,
+        volume_scaling: None,
+        min_order_size: 10,
+        order_size_tolerance: 0,
+        max_order_age: None,
+        fill_cooldown_iterations: 0,
+        max_buy_price: Price::MAX,
+        min_sell_price: Price::ZERO,
+        max_total_bid_notional: None,
+        max_total_ask_notional: None,
+        max_daily_volume: None,
+        min_orders_per_direction: 1,
+        max_orders_per_direction: 5,
+        max_distance_from_mid: None,
+        max_orders_to_make_per_iteration: 10,
+        max_orders_to_cancel_per_iteration: 10,
+        recenter_threshold: None,
+        orphan_iterations: None,
+        cancel_orphans: false,
+        max_total_open_orders: None,
+        iteration_interval: Duration::from_secs(5),
+        iteration_jitter: Duration::ZERO,
+        iteration_timeout: None,
+        price_anchor: PriceAnchor::LatestPrice,
+        warmup_iterations: 0,
+        side: QuoteSide::Both,
+        cancel_orders_on_shutdown: false,
+        kill_file: None,
+        state_file: None,
+        max_retries: 0,
+        retry_backoff: Duration::ZERO,
+        inventory_skew: None,
+        target_base_fraction: None,
+        max_price_move_pct: None,
+        max_price_age: None,
+        max_slippage: None,
+        volatility_cooldown: None,
+        min_book_spread: None,
+        cancel_orders_when_book_tight: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test]
+    fn valid_config() {
+        assert_eq!(test_config().validate(), Ok(()));
+    }
+
+    #[test_case(|c| c.increment = Price::ZERO, ConfigError::ZeroIncrement)]
+    #[test_case(|c| c.increment_pct = Some(1.0), ConfigError::IncrementAndIncrementPct)]
+    #[test_case(
+        |c| { c.increment = Price::ZERO; c.increment_pct = Some(0.0) },
+        ConfigError::InvalidIncrementPct(0.0)
+    )]
+    #[test_case(|c| c.ask_increment = Some(Price::ZERO), ConfigError::ZeroIncrement)]
+    #[test_case(|c| c.tick_size = Some(Price::ZERO), ConfigError::ZeroTickSize)]
+    #[test_case(|c| c.lot_size = Some(0), ConfigError::ZeroLotSize)]
+    #[test_case(|c| c.iteration_timeout = Some(Duration::ZERO), ConfigError::ZeroIterationTimeout)]
+    #[test_case(|c| { c.order_sizing = OrderSizing::FixedAmount(0); c.min_order_size = 0 }, ConfigError::ZeroOrderSize)]
+    #[test_case(|c| c.order_sizing = OrderSizing::FixedNotional(0), ConfigError::ZeroOrderSize)]
+    #[test_case(
+        |c| c.min_order_size = 101,
+        ConfigError::MinOrderSizeExceedsOrderSize { min_order_size: 101, order_size: 100 }
+    )]
+    #[test_case(
+        |c| c.ask_order_sizing = Some(OrderSizing::FixedAmount(5)),
+        ConfigError::MinOrderSizeExceedsOrderSize { min_order_size: 10, order_size: 5 }
+    )]
+    #[test_case(|c| c.bid_order_sizing = Some(OrderSizing::FixedNotional(0)), ConfigError::ZeroOrderSize)]
+    #[test_case(
+        |c| c.min_orders_per_direction = 6,
+        ConfigError::MinOrdersExceedsMaxOrders { min_orders_per_direction: 6, max_orders_per_direction: 5 }
+    )]
+    #[test_case(
+        |c| c.inventory_skew = Some(InventorySkew { target_base: 0, skew_factor: 1.0 }),
+        ConfigError::ZeroInventorySkewTarget
+    )]
+    #[test_case(
+        |c| c.inventory_skew = Some(InventorySkew { target_base: 1, skew_factor: -1.0 }),
+        ConfigError::InvalidSkewFactor(-1.0)
+    )]
+    #[test_case(
+        |c| c.target_base_fraction = Some(1.5),
+        ConfigError::InvalidTargetBaseFraction(1.5)
+    )]
+    #[test_case(|c| c.max_price_move_pct = Some(0.0), ConfigError::InvalidMaxPriceMovePct(0.0))]
+    #[test_case(
+        |c| c.price_anchor = PriceAnchor::Blended { mid_weight: 1.5 },
+        ConfigError::InvalidMidWeight(1.5)
+    )]
+    #[test_case(|c| c.recenter_threshold = Some(1.5), ConfigError::InvalidRecenterThreshold(1.5))]
+    #[test_case(
+        |c| c.max_distance_from_mid = Some(MaxDistance::Increments(0)),
+        ConfigError::ZeroMaxDistance
+    )]
+    #[test_case(
+        |c| c.max_distance_from_mid = Some(MaxDistance::Pct(-1.0)),
+        ConfigError::InvalidMaxDistancePct(-1.0)
+    )]
+    #[test_case(|c| c.spacing = Spacing::Geometric(1.0), ConfigError::InvalidSpacingFactor(1.0))]
+    #[test_case(|c| c.size_scaling = Some(-0.5), ConfigError::InvalidSizeScaling(-0.5))]
+    #[test_case(|c| c.size_jitter_pct = Some(100.0), ConfigError::InvalidSizeJitterPct(100.0))]
+    #[test_case(|c| c.size_jitter_pct = Some(-1.0), ConfigError::InvalidSizeJitterPct(-1.0))]
+    #[test_case(
+        |c| c.volume_scaling = Some(VolumeScaling { reference_volume: 0, max_factor: 1.0, min_factor: 0.1 }),
+        ConfigError::ZeroReferenceVolume
+    )]
+    #[test_case(
+        |c| c.volume_scaling = Some(VolumeScaling { reference_volume: 1, max_factor: 0.0, min_factor: 0.1 }),
+        ConfigError::InvalidVolumeScalingMaxFactor(0.0)
+    )]
+    #[test_case(
+        |c| c.volume_scaling = Some(VolumeScaling { reference_volume: 1, max_factor: 1.0, min_factor: 0.0 }),
+        ConfigError::InvalidVolumeScalingMinFactor { min_factor: 0.0, max_factor: 1.0 }
+    )]
+    #[test_case(
+        |c| c.volume_scaling = Some(VolumeScaling { reference_volume: 1, max_factor: 1.0, min_factor: 2.0 }),
+        ConfigError::InvalidVolumeScalingMinFactor { min_factor: 2.0, max_factor: 1.0 }
+    )]
+    #[test_case(
+        |c| c.min_book_spread = Some(Price::from_scaled(21)),
+        ConfigError::MinBookSpreadExceedsOwnSpread {
+            min_book_spread: Price::from_scaled(21),
+            own_spread: Price::from_scaled(20),
+        }
+    )]
+    fn invalid_config(modify: fn(&mut Config), expected: ConfigError) {
+        let mut config = test_config();
+        modify(&mut config);
+
+        assert_eq!(config.validate(), Err(expected));
+    }
+
+    fn builder() -> ConfigBuilder {
+        Config::builder()
+            .increment(Price::from_scaled(10))
+            .order_sizing(OrderSizing::FixedAmount(100))
+            .max_buy_price(Price::from_scaled(1000))
+            .min_sell_price(Price::from_scaled(500))
+    }
+
+    #[test]
+    fn builder_sets_required_fields() {
+        let config = builder().build().unwrap();
+
+        assert_eq!(config.increment, Price::from_scaled(10));
+        assert_eq!(config.max_buy_price, Price::from_scaled(1000));
+        assert_eq!(config.min_sell_price, Price::from_scaled(500));
+        assert!(matches!(config.order_sizing, OrderSizing::FixedAmount(100)));
+    }
+
+    #[test_case(|_| Config::builder(), ConfigError::MissingField("increment"))]
+    #[test_case(
+        |_| Config::builder().increment(Price::from_scaled(10)),
+        ConfigError::MissingField("order_sizing")
+    )]
+    #[test_case(
+        |b| b.orders_per_direction(6, 5),
+        ConfigError::MinOrdersExceedsMaxOrders { min_orders_per_direction: 6, max_orders_per_direction: 5 }
+    )]
+    #[test_case(|b| b.tick_size(Price::ZERO), ConfigError::ZeroTickSize)]
+    #[test_case(|b| b.lot_size(0), ConfigError::ZeroLotSize)]
+    #[test_case(|b| b.increment_pct(0.5), ConfigError::IncrementAndIncrementPct)]
+    fn builder_errors(modify: fn(ConfigBuilder) -> ConfigBuilder, expected: ConfigError) {
+        assert_eq!(modify(builder()).build().err(), Some(expected));
+    }
+
+    #[test]
+    fn builder_accepts_increment_pct_instead_of_increment() {
+        let config = Config::builder()
+            .increment_pct(0.5)
+            .order_sizing(OrderSizing::FixedAmount(100))
+            .max_buy_price(Price::from_scaled(1000))
+            .min_sell_price(Price::from_scaled(500))
+            .build()
+            .unwrap();
+
+        assert_eq!(config.increment, Price::ZERO);
+        assert_eq!(config.increment_pct, Some(0.5));
+    }
+
+    #[test_case(None, 100000000, 10; "absolute increment")]
+    #[test_case(Some(0.5), 100000000, 500000; "percentage")]
+    #[test_case(Some(0.5), 100900000, 500000; "small move keeps the increment")]
+    #[test_case(Some(0.5), 123456789, 610000; "two significant figures")]
+    #[test_case(Some(1.0), 9900, 99; "two digit increment")]
+    #[test_case(Some(1.0), 50, 1; "at least one")]
+    fn resolve_increment_tests(increment_pct: Option<f64>, price: u64, expected: u64) {
+        let config = Config {
+            increment: if increment_pct.is_some() {
+                Price::ZERO
+            } else {
+                Price::from_scaled(10)
+            },
+            increment_pct,
+            ..test_config()
+        };
+
+        let resolved = config.resolve_increment(Price::from_scaled(price));
+
+        assert_eq!(resolved.increment, Price::from_scaled(expected));
+        assert_eq!(resolved.increment_pct, None);
+        assert_eq!(resolved.validate(), Ok(()));
+    }
+
+    #[test]
+    fn sample_config_is_valid() {
+        let config: Config = toml::from_str(include_str!("../config.example.toml")).unwrap();
+
+        assert_eq!(config.increment, Price::from_decimal(0.001));
+        assert_eq!(config.iteration_interval, Duration::from_secs(5));
+        assert_eq!(config.retry_backoff, Duration::from_millis(500));
+    }
+
+    #[test]
+    fn deserialize_json() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "increment": 10,
+                "order_sizing": { "fixed_notional": 100 },
+                "max_buy_price": 1000,
+                "min_sell_price": 500,
+                "max_order_age": "2m",
+                "price_anchor": { "blended": { "mid_weight": 0.5 } },
+                "side": "bids_only",
+                "spacing": { "geometric": 1.01 }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.max_order_age, Some(Duration::from_secs(120)));
+        assert_eq!(config.side, QuoteSide::BidsOnly);
+        assert_eq!(config.spacing, Spacing::Geometric(1.01));
+        assert!(matches!(
+            config.price_anchor,
+            PriceAnchor::Blended { mid_weight } if mid_weight == 0.5
+        ));
+    }
+
+    #[test_case("increment = 10", "order_sizing must be set")]
+    #[test_case("incremnt = 10", "unknown field")]
+    #[test_case(r#"iteration_interval = "5 weeks""#, "invalid duration")]
+    #[test_case(
+        "increment = 10\norder_sizing = { fixed_amount = 100 }\nmax_buy_price = 1\nmin_sell_price = 1\nmin_orders_per_direction = 20",
+        "must not exceed max_orders_per_direction"
+    )]
+    fn deserialize_errors(toml: &str, expected: &str) {
+        let error = toml::from_str::<Config>(toml).err().unwrap().to_string();
+
+        assert!(error.contains(expected), "{error}");
+    }
+
+    #[test]
+    fn config_file_reloads_when_modified() {
+        let path = std::env::temp_dir().join(format!("smm-config-{}.toml", std::process::id()));
+        // Sets an explicit modification time so that the test doesn't depend on the resolution of
+        // the filesystem's timestamps
+        let write = |contents: &str, secs: u64| {
+            std::fs::write(&path, contents).unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+                .unwrap();
+        };
+        let sample = include_str!("../config.example.toml");
+
+        write(sample, 1);
+        let mut config_file = ConfigFile::new(path.clone());
+        assert!(config_file.load().is_ok());
+        assert!(matches!(config_file.reload(), Ok(None)));
+
+        write("increment = 10", 2);
+        assert!(matches!(config_file.reload(), Err(ConfigError::Parse(_))));
+        assert!(matches!(config_file.reload(), Ok(None)));
+
+        write(
+            &sample.replace("increment = 100000", "increment = 200000"),
+            3,
+        );
+        let reloaded = config_file.reload();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            reloaded.unwrap().unwrap().increment,
+            Price::from_scaled(200000)
+        );
+    }
+
+    #[test_case("500ms", Some(Duration::from_millis(500)))]
+    #[test_case("5s", Some(Duration::from_secs(5)))]
+    #[test_case("2m", Some(Duration::from_secs(120)))]
+    #[test_case("1h", Some(Duration::from_secs(3600)))]
+    #[test_case("0s", Some(Duration::ZERO))]
+    #[test_case("5", None)]
+    #[test_case("s", None)]
+    #[test_case("1.5s", None)]
+    #[test_case("5d", None)]
+    fn parse_duration_tests(value: &str, expected: Option<Duration>) {
+        assert_eq!(duration::parse(value), expected);
+    }
+
+    #[test_case(None, 3, 100)]
+    #[test_case(Some(0.5), 0, 100)]
+    #[test_case(Some(0.5), 1, 150)]
+    #[test_case(Some(0.5), 3, 250)]
+    #[test_case(Some(0.25), 1, 125)]
+    fn order_amount_tests(size_scaling: Option<f64>, level: u64, expected: u64) {
+        let config = Config {
+            size_scaling,
+            ..test_config()
+        };
+
+        assert_eq!(
+            config.order_amount(OrderType::Bid, Price::from_scaled(1000), level),
+            expected
+        );
+    }
+
+    #[test_case(None, 100)]
+    #[test_case(Some(30), 90)]
+    #[test_case(Some(100), 100)]
+    #[test_case(Some(150), 0)]
+    fn order_amount_is_rounded_down_to_lot_size(lot_size: Option<u64>, expected: u64) {
+        let config = Config {
+            lot_size,
+            ..test_config()
+        };
+
+        assert_eq!(
+            config.order_amount(OrderType::Bid, Price::from_scaled(1000), 0),
+            expected
+        );
+    }
+
+    #[test_case(None, None, None, (10, None, None); "no limits")]
+    #[test_case(Some(50), None, None, (50, None, None); "min order size raised")]
+    #[test_case(Some(5), None, None, (10, None, None); "min order size kept")]
+    #[test_case(None, Some(20), None, (10, Some(20), None); "tick size taken from the exchange")]
+    #[test_case(None, None, Some(25), (10, None, Some(25)); "lot size taken from the exchange")]
+    fn apply_trading_limits_tests(
+        min_order_size: Option<u64>,
+        tick_size: Option<u64>,
+        lot_size: Option<u64>,
+        expected: (u64, Option<u64>, Option<u64>),
+    ) {
+        let limits = TradingLimits {
+            min_order_size,
+            tick_size: tick_size.map(Price::from_scaled),
+            lot_size,
+        };
+
+        let config = test_config();
+        let config = config.apply_trading_limits(&limits);
+
+        assert_eq!(
+            (
+                config.min_order_size,
+                config.tick_size.map(|t| t.scaled()),
+                config.lot_size
+            ),
+            expected
+        );
+    }
+
+    #[test_case(Some(30), Some(20), Some(40))]
+    #[test_case(Some(40), Some(20), Some(40))]
+    #[test_case(Some(30), None, Some(30))]
+    #[test_case(None, Some(20), Some(20))]
+    #[test_case(Some(30), Some(0), Some(30))]
+    fn fit_to_step_tests(step: Option<u64>, exchange_step: Option<u64>, expected: Option<u64>) {
+        assert_eq!(fit_to_step(step, exchange_step), expected);
+    }
+
+    #[test_case(OrderSizing::FixedAmount(100), None, 100)]
+    #[test_case(OrderSizing::FixedAmount(100), Some(0), 10)]
+    #[test_case(OrderSizing::FixedAmount(100), Some(50), 10)]
+    #[test_case(OrderSizing::FixedAmount(100), Some(500), 50)]
+    #[test_case(OrderSizing::FixedAmount(100), Some(1500), 150)]
+    #[test_case(OrderSizing::FixedAmount(100), Some(5000), 200)]
+    #[test_case(OrderSizing::FixedNotional(1000), Some(250), 250)]
+    fn scale_to_volume_tests(order_sizing: OrderSizing, volume_24h: Option<u64>, expected: u64) {
+        let config = Config {
+            order_sizing,
+            volume_scaling: Some(VolumeScaling {
+                reference_volume: 1000,
+                max_factor: 2.0,
+                min_factor: 0.1,
+            }),
+            ..test_config()
+        };
+
+        let scaled = config.scale_to_volume(volume_24h);
+
+        assert_eq!(
+            scaled.order_sizing.amount(Price::from_scaled(100_000_000)),
+            expected
+        );
+    }
+
+    #[test]
+    fn scale_to_volume_scales_per_side_sizings() {
+        let config = Config {
+            bid_order_sizing: Some(OrderSizing::FixedAmount(300)),
+            volume_scaling: Some(VolumeScaling {
+                reference_volume: 1000,
+                max_factor: 2.0,
+                min_factor: 0.1,
+            }),
+            ..test_config()
+        };
+
+        let scaled = config.scale_to_volume(Some(500));
+
+        let price = Price::from_scaled(100_000_000);
+        assert_eq!(scaled.sizing(OrderType::Bid).amount(price), 150);
+        assert_eq!(scaled.sizing(OrderType::Ask).amount(price), 50);
+    }
+
+    #[test_case(10.0, 10)]
+    #[test_case(10.0, 95)]
+    #[test_case(50.0, 60)]
+    fn order_amount_jitter_stays_within_bounds(size_jitter_pct: f64, min_order_size: u64) {
+        let config = Config {
+            size_jitter_pct: Some(size_jitter_pct),
+            size_jitter_seed: 42,
+            min_order_size,
+            ..test_config()
+        };
+
+        let amounts: Vec<_> = (900..1100)
+            .map(|p| config.order_amount(OrderType::Bid, Price::from_scaled(p), 0))
+            .collect();
+
+        let lower = (100.0 * (1.0 - size_jitter_pct / 100.0)) as u64;
+        let upper = (100.0 * (1.0 + size_jitter_pct / 100.0)) as u64;
+        assert!(amounts
+            .iter()
+            .all(|a| (lower.max(min_order_size)..=upper).contains(a)));
+        assert!(amounts.iter().any(|a| *a != 100));
+        // The same seed gives the same amounts
+        assert_eq!(
+            config.order_amount(OrderType::Bid, Price::from_scaled(950), 0),
+            amounts[50]
+        );
+    }
+
+    #[test_case(OrderSizing::FixedAmount(100), 1000, 100)]
+    #[test_case(OrderSizing::FixedNotional(100), 100000000, 100)]
+    #[test_case(OrderSizing::FixedNotional(100), 200000000, 50)]
+    #[test_case(OrderSizing::FixedNotional(100), 300000000, 33)]
+    #[test_case(OrderSizing::FixedNotional(100), 0, 0)]
+    #[test_case(OrderSizing::FixedNotional(u64::MAX), 1, u64::MAX)]
+    fn order_sizing_amount_tests(order_sizing: OrderSizing, price: u64, expected: u64) {
+        assert_eq!(order_sizing.amount(Price::from_scaled(price)), expected);
+    }
+}
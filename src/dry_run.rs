@@ -0,0 +1,165 @@
+use crate::{
+    Balances, CancelOrderRequest, Clock, Exchange, ExchangeError, MakeOrderRequest, Order, OrderId,
+    Stats, SystemClock, TradingLimits,
+};
+use async_trait::async_trait;
+use std::sync::Mutex;
+use tracing::info;
+
+/// Wraps an exchange so that its data is read as normal but any orders which would have been made
+/// or cancelled are logged rather than sent
+///
+/// The orders which would have been made are kept and reported as open alongside the exchange's
+/// own, so that later iterations see them as resting rather than as filled. They are stamped as
+/// placed at the time read from the clock.
+pub struct DryRun<E, C = SystemClock> {
+    inner: E,
+    orders: SimulatedOrders,
+    clock: C,
+}
+
+impl<E: Exchange> DryRun<E> {
+    pub fn new(inner: E) -> Self {
+        DryRun::with_clock(inner, SystemClock)
+    }
+}
+
+impl<E: Exchange, C: Clock> DryRun<E, C> {
+    /// Like `new`, but reads the time from `clock`, which should be the clock the bot is run with
+    pub fn with_clock(inner: E, clock: C) -> Self {
+        DryRun {
+            inner,
+            orders: SimulatedOrders::new("dry-run"),
+            clock,
+        }
+    }
+}
+
+#[async_trait]
+impl<E: Exchange + Sync, C: Clock> Exchange for DryRun<E, C> {
+    async fn stats(&self) -> Result<Stats, ExchangeError> {
+        self.inner.stats().await.map(|s| self.orders.add_to(s))
+    }
+
+    async fn balances(&self) -> Result<Balances, ExchangeError> {
+        self.inner.balances().await
+    }
+
+    async fn trading_limits(&self) -> Result<TradingLimits, ExchangeError> {
+        self.inner.trading_limits().await
+    }
+
+    async fn make_orders(
+        &self,
+        orders: Vec<MakeOrderRequest>,
+    ) -> Vec<Result<OrderId, ExchangeError>> {
+        orders
+            .into_iter()
+            .map(|order| {
+                info!(?order, "Dry run - skipped making order");
+                Ok(self.orders.make(&order, self.clock.now()))
+            })
+            .collect()
+    }
+
+    async fn cancel_orders(
+        &self,
+        orders: Vec<CancelOrderRequest>,
+    ) -> Vec<Result<OrderId, ExchangeError>> {
+        orders
+            .into_iter()
+            .map(|order| {
+                info!(?order, "Dry run - skipped cancelling order");
+                self.orders.cancel(&order.id);
+                Ok(order.id)
+            })
+            .collect()
+    }
+}
+
+// The orders which a wrapper pretended to make, each with a unique id so that they can be told
+// apart, and cancelled, like real orders. They never fill.
+pub(crate) struct SimulatedOrders {
+    id_prefix: &'static str,
+    state: Mutex<(u64, Vec<Order>)>,
+}
+
+impl SimulatedOrders {
+    pub(crate) fn new(id_prefix: &'static str) -> Self {
+        SimulatedOrders {
+            id_prefix,
+            state: Mutex::default(),
+        }
+    }
+
+    // `now` is when the order was made, in nanoseconds since the Unix epoch
+    pub(crate) fn make(&self, order: &MakeOrderRequest, now: u64) -> OrderId {
+        let mut state = self.state.lock().unwrap();
+        let (next_id, orders) = &mut *state;
+        let id = OrderId::from(format!("{}-{next_id}", self.id_prefix));
+        *next_id += 1;
+        orders.push(Order {
+            order_type: order.order_type,
+            id: id.clone(),
+            price: order.price,
+            amount: order.amount,
+            placed_at: Some(now),
+        });
+        id
+    }
+
+    pub(crate) fn cancel(&self, id: &OrderId) {
+        self.state.lock().unwrap().1.retain(|o| &o.id != id);
+    }
+
+    pub(crate) fn add_to(&self, mut stats: Stats) -> Stats {
+        stats
+            .open_orders
+            .extend(self.state.lock().unwrap().1.iter().cloned());
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use crate::testing::{MockClock, MockExchange};
+    use crate::{run_once, NoopEventSink, Price, State};
+    use itertools::Itertools;
+
+    #[tokio::test]
+    async fn orders_made_in_a_dry_run_stay_open_and_are_not_seen_as_filled() {
+        let clock = MockClock::new(1_700_000_000_000_000_000);
+        let exchange = DryRun::with_clock(MockExchange::at_price(Price::from_scaled(1000)), &clock);
+        let config = test_config();
+
+        let state = run_once(
+            &exchange,
+            None,
+            &config,
+            &State::default(),
+            &NoopEventSink,
+            &clock,
+        )
+        .await
+        .unwrap();
+        let open_orders = exchange.stats().await.unwrap().open_orders;
+        let state = run_once(&exchange, None, &config, &state, &NoopEventSink, &clock)
+            .await
+            .unwrap();
+
+        assert!(exchange.inner.made_orders().is_empty());
+        assert_eq!(
+            open_orders.len(),
+            2 * config.min_orders_per_direction as usize
+        );
+        assert!(open_orders.iter().map(|o| &o.id).all_unique());
+        assert!(open_orders
+            .iter()
+            .all(|o| o.placed_at == Some(1_700_000_000_000_000_000)));
+        assert_eq!(exchange.stats().await.unwrap().open_orders, open_orders);
+        assert_eq!(state.position.amount, 0);
+        assert!(state.fill_cooldowns.is_empty());
+    }
+}
@@ -0,0 +1,79 @@
+use crate::{MakeOrderRequest, Order, OrderId, Price};
+use serde::Serialize;
+use std::sync::Mutex;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Something which happened during an iteration of `run_until`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+    /// The latest trade price and the price which the orders were built around
+    PriceUpdated {
+        latest_price: Price,
+        anchor_price: Price,
+    },
+    /// The grid which the iteration is moving the open orders towards
+    Decision(Decision),
+    /// The orders which were successfully made, with the ids assigned by the exchange
+    OrdersMade(Vec<Order>),
+    /// The ids of the orders which were successfully cancelled
+    OrdersCancelled(Vec<OrderId>),
+    /// An order which was open at the end of the previous iteration but has since disappeared
+    /// without being cancelled by the bot, which is assumed to mean it was filled
+    FillDetected(Order),
+    /// An order which is still open but whose amount has dropped since the end of the previous
+    /// iteration, along with how much of it was filled in the meantime
+    PartialFillDetected { order: Order, filled: u64 },
+}
+
+/// The target grid computed by an iteration, along with the inputs it was built from, so that
+/// external tooling can see what the bot is aiming for without parsing its logs
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Decision {
+    pub latest_price: Price,
+    /// The price which the target orders were built around
+    pub anchor_price: Price,
+    /// Every order in the target grid, whether or not it is already open
+    pub target_orders: Vec<MakeOrderRequest>,
+    /// The open orders at the start of the iteration
+    pub open_orders: Vec<Order>,
+    /// The ids of the open orders which the iteration is cancelling. Orders which are being
+    /// replaced aren't included.
+    pub orders_to_cancel: Vec<OrderId>,
+}
+
+/// Receives the events published by `run_with_events_until`. `publish` is called inline by the
+/// iteration, so implementations should hand the event off rather than doing slow work.
+pub trait EventSink: Sync {
+    fn publish(&self, event: Event);
+}
+
+/// Discards every event
+pub struct NoopEventSink;
+
+impl EventSink for NoopEventSink {
+    fn publish(&self, _event: Event) {}
+}
+
+// Holds the events published by an attempt at an iteration until the attempt is known to have
+// succeeded
+#[derive(Default)]
+pub(crate) struct EventBuffer(Mutex<Vec<Event>>);
+
+impl EventBuffer {
+    pub(crate) fn into_events(self) -> Vec<Event> {
+        self.0.into_inner().unwrap()
+    }
+}
+
+impl EventSink for EventBuffer {
+    fn publish(&self, event: Event) {
+        self.0.lock().unwrap().push(event);
+    }
+}
+
+// Events are dropped once the receiver has gone away, since the bot shouldn't stop because of it
+impl EventSink for UnboundedSender<Event> {
+    fn publish(&self, event: Event) {
+        let _ = self.send(event);
+    }
+}
@@ -0,0 +1,187 @@
+use crate::{
+    Balances, CancelOrderRequest, Exchange, ExchangeError, MakeOrderRequest, OrderId,
+    ReplaceOrderRequest, Stats, TradingLimits,
+};
+use async_trait::async_trait;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+/// Continuously refreshes an exchange's `Stats` in a background task so that iterations can read
+/// the latest snapshot rather than waiting on a round trip to the exchange.
+///
+/// The handle is itself an `Exchange`, so it can be passed to `run_until` in place of the exchange
+/// it wraps. Until the feed has a snapshot which was requested after the most recent orders were
+/// made or cancelled through the handle, `stats` falls back to querying the exchange directly.
+/// The polling task is stopped when the handle is dropped.
+pub struct FeedHandle<E> {
+    inner: Arc<E>,
+    snapshot: Arc<Mutex<Option<Snapshot>>>,
+    last_orders_changed: Mutex<Option<Instant>>,
+    task: JoinHandle<()>,
+}
+
+struct Snapshot {
+    requested_at: Instant,
+    stats: Stats,
+}
+
+impl<E: Exchange + Send + Sync + 'static> FeedHandle<E> {
+    pub fn spawn(inner: Arc<E>, refresh_interval: Duration) -> Self {
+        let snapshot = Arc::new(Mutex::new(None));
+
+        let task = tokio::spawn({
+            let inner = inner.clone();
+            let snapshot = snapshot.clone();
+            async move {
+                loop {
+                    let requested_at = Instant::now();
+                    match inner.stats().await {
+                        Ok(stats) => {
+                            *snapshot.lock().unwrap() = Some(Snapshot {
+                                requested_at,
+                                stats,
+                            })
+                        }
+                        Err(error) => warn!(%error, "Failed to refresh feed"),
+                    }
+                    tokio::time::sleep(refresh_interval).await;
+                }
+            }
+        });
+
+        FeedHandle {
+            inner,
+            snapshot,
+            last_orders_changed: Mutex::default(),
+            task,
+        }
+    }
+}
+
+impl<E> FeedHandle<E> {
+    /// The most recent snapshot, or `None` if the feed hasn't produced one yet
+    pub fn latest(&self) -> Option<Stats> {
+        self.snapshot
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|s| s.stats.clone())
+    }
+
+    // Snapshots requested before orders were last made or cancelled don't reflect those changes,
+    // so using them could result in the same orders being made again
+    fn fresh_snapshot(&self) -> Option<Stats> {
+        let last_orders_changed = *self.last_orders_changed.lock().unwrap();
+
+        self.snapshot
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|s| last_orders_changed.map_or(true, |t| s.requested_at > t))
+            .map(|s| s.stats.clone())
+    }
+
+    fn record_orders_changed(&self) {
+        *self.last_orders_changed.lock().unwrap() = Some(Instant::now());
+    }
+}
+
+impl<E> Drop for FeedHandle<E> {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[async_trait]
+impl<E: Exchange + Send + Sync> Exchange for FeedHandle<E> {
+    async fn stats(&self) -> Result<Stats, ExchangeError> {
+        match self.fresh_snapshot() {
+            Some(stats) => Ok(stats),
+            None => self.inner.stats().await,
+        }
+    }
+
+    async fn balances(&self) -> Result<Balances, ExchangeError> {
+        self.inner.balances().await
+    }
+
+    async fn trading_limits(&self) -> Result<TradingLimits, ExchangeError> {
+        self.inner.trading_limits().await
+    }
+
+    async fn make_orders(
+        &self,
+        orders: Vec<MakeOrderRequest>,
+    ) -> Vec<Result<OrderId, ExchangeError>> {
+        let result = self.inner.make_orders(orders).await;
+        self.record_orders_changed();
+        result
+    }
+
+    async fn cancel_orders(
+        &self,
+        orders: Vec<CancelOrderRequest>,
+    ) -> Vec<Result<OrderId, ExchangeError>> {
+        let result = self.inner.cancel_orders(orders).await;
+        self.record_orders_changed();
+        result
+    }
+
+    async fn replace_orders(
+        &self,
+        orders: Vec<ReplaceOrderRequest>,
+    ) -> Vec<Result<OrderId, ExchangeError>> {
+        let result = self.inner.replace_orders(orders).await;
+        self.record_orders_changed();
+        result
+    }
+
+    async fn cancel_all_orders(&self) -> Result<(), ExchangeError> {
+        let result = self.inner.cancel_all_orders().await;
+        self.record_orders_changed();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockExchange;
+    use crate::Price;
+
+    fn stats(latest_price: u64) -> Stats {
+        Stats {
+            latest_price: Price::from_scaled(latest_price),
+            latest_trade_at: None,
+            volume_24h: None,
+            best_bid: None,
+            best_ask: None,
+            open_orders: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn uses_snapshot_until_orders_change() {
+        let exchange = Arc::new(MockExchange::new(
+            stats(1000),
+            Balances {
+                base: u64::MAX,
+                quote: u64::MAX,
+            },
+        ));
+        let feed = FeedHandle::spawn(exchange.clone(), Duration::from_secs(3600));
+
+        while feed.latest().is_none() {
+            tokio::task::yield_now().await;
+        }
+        exchange.set_stats(stats(1100));
+
+        assert_eq!(feed.stats().await.unwrap().latest_price.scaled(), 1000);
+
+        feed.make_orders(Vec::new()).await;
+
+        assert_eq!(feed.stats().await.unwrap().latest_price.scaled(), 1100);
+    }
+}
@@ -0,0 +1,60 @@
+use crate::ExchangeError;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Holds the result of a query for up to `ttl`, so that it isn't repeated every iteration. A zero
+/// TTL disables caching.
+pub(super) struct Cached<T> {
+    ttl: Duration,
+    value: Mutex<Option<(Instant, T)>>,
+}
+
+impl<T: Clone> Cached<T> {
+    pub(super) fn new(ttl: Duration) -> Self {
+        Cached {
+            ttl,
+            value: Mutex::default(),
+        }
+    }
+
+    /// Returns the cached value if it was fetched within the TTL, otherwise calls `fetch` and
+    /// caches its result if successful
+    pub(super) async fn get_or_fetch<F: Future<Output = Result<T, ExchangeError>>>(
+        &self,
+        fetch: impl FnOnce() -> F,
+    ) -> Result<T, ExchangeError> {
+        if let Some((fetched_at, value)) = self.value.lock().unwrap().as_ref() {
+            if fetched_at.elapsed() < self.ttl {
+                return Ok(value.clone());
+            }
+        }
+
+        let fetched_at = Instant::now();
+        let value = fetch().await?;
+        if !self.ttl.is_zero() {
+            *self.value.lock().unwrap() = Some((fetched_at, value.clone()));
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use test_case::test_case;
+
+    #[test_case(Duration::from_secs(60), 1)]
+    #[test_case(Duration::ZERO, 2)]
+    #[tokio::test]
+    async fn get_or_fetch_tests(ttl: Duration, expected_fetches: u64) {
+        let cached = Cached::new(ttl);
+        let fetches = AtomicU64::new(0);
+        let fetch = || async { Ok(fetches.fetch_add(1, Ordering::Relaxed)) };
+
+        assert_eq!(cached.get_or_fetch(fetch).await, Ok(0));
+        assert_eq!(cached.get_or_fetch(fetch).await, Ok(expected_fetches - 1));
+        assert_eq!(fetches.load(Ordering::Relaxed), expected_fetches);
+    }
+}
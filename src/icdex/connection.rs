@@ -0,0 +1,67 @@
+use crate::ExchangeError;
+use ic_agent::Agent;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::RwLock;
+use tracing::{error, info, warn};
+
+type BuildAgent = Box<dyn Fn() -> Result<Agent, ExchangeError> + Send + Sync>;
+
+// Holds the agent which calls are made through. If a rebuild is configured, the agent is replaced
+// with a freshly built one once enough consecutive calls have failed at the transport level, since
+// a transport which gets into a bad state (eg. after a network partition) may otherwise never
+// recover.
+pub(super) struct Connection {
+    agent: RwLock<Agent>,
+    rebuild: Option<(u32, BuildAgent)>,
+    transport_failures: AtomicU32,
+}
+
+impl Connection {
+    pub fn new(agent: Agent) -> Self {
+        Connection {
+            agent: RwLock::new(agent),
+            rebuild: None,
+            transport_failures: AtomicU32::new(0),
+        }
+    }
+
+    pub fn set_rebuild(&mut self, max_transport_failures: u32, build: BuildAgent) {
+        self.rebuild = Some((max_transport_failures.max(1), build));
+    }
+
+    // Agents are cheap to clone, and cloning means a rebuild never waits for calls in flight
+    pub fn agent(&self) -> Agent {
+        self.agent.read().unwrap().clone()
+    }
+
+    // Counts consecutive transport failures, rebuilding the agent once there have been
+    // `max_transport_failures` of them. Any other outcome shows that the transport is working.
+    pub fn record<T>(&self, result: &Result<T, ExchangeError>) {
+        let Some((max_transport_failures, build)) = &self.rebuild else {
+            return;
+        };
+        if !matches!(result, Err(ExchangeError::Transport(_))) {
+            self.transport_failures.store(0, Ordering::Relaxed);
+            return;
+        }
+
+        // Only the call which reaches the limit rebuilds the agent. The count starts again either
+        // way, so a failed rebuild is retried after another `max_transport_failures` failures.
+        let failures = self.transport_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures != *max_transport_failures {
+            return;
+        }
+        warn!(
+            failures,
+            "Too many consecutive transport failures. Rebuilding the agent"
+        );
+        match build() {
+            Ok(agent) => {
+                *self.agent.write().unwrap() = agent;
+                info!("Agent rebuilt");
+            }
+            Err(error) => error!(%error, "Failed to rebuild the agent"),
+        }
+        self.transport_failures.store(0, Ordering::Relaxed);
+    }
+}
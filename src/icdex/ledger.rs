@@ -0,0 +1,59 @@
+use super::{to_u64, Account};
+use crate::{query, ExchangeError};
+use async_trait::async_trait;
+use candid::{Nat, Principal};
+use ic_agent::Agent;
+
+/// A token ledger which `ICDex` reads the trader canister's balance of the base or quote token
+/// from. The trader canister makes all deposits and transfers itself, so only balances are needed.
+#[async_trait]
+pub trait Ledger: Send + Sync {
+    /// The balance of `owner`'s default account, in the token's smallest unit
+    async fn balance_of(&self, agent: &Agent, owner: Principal) -> Result<u64, ExchangeError>;
+}
+
+/// A ledger implementing the ICRC-1 standard, read through `icrc1_balance_of`
+pub struct Icrc1Ledger {
+    canister_id: Principal,
+}
+
+impl Icrc1Ledger {
+    pub fn new(canister_id: Principal) -> Self {
+        Icrc1Ledger { canister_id }
+    }
+}
+
+#[async_trait]
+impl Ledger for Icrc1Ledger {
+    async fn balance_of(&self, agent: &Agent, owner: Principal) -> Result<u64, ExchangeError> {
+        let account = Account {
+            owner,
+            subaccount: None,
+        };
+
+        let balance: Nat = query(agent, &self.canister_id, "icrc1_balance_of", (account,)).await?;
+
+        to_u64(balance)
+    }
+}
+
+/// A ledger implementing the DIP20 standard, read through `balanceOf`. DIP20 has no subaccounts,
+/// so the balance is always that of the principal itself.
+pub struct Dip20Ledger {
+    canister_id: Principal,
+}
+
+impl Dip20Ledger {
+    pub fn new(canister_id: Principal) -> Self {
+        Dip20Ledger { canister_id }
+    }
+}
+
+#[async_trait]
+impl Ledger for Dip20Ledger {
+    async fn balance_of(&self, agent: &Agent, owner: Principal) -> Result<u64, ExchangeError> {
+        let balance: Nat = query(agent, &self.canister_id, "balanceOf", (owner,)).await?;
+
+        to_u64(balance)
+    }
+}
@@ -1,113 +1,770 @@
 use crate::{
-    query, update, update_no_response, CancelOrderRequest, Exchange, MakeOrderRequest, Order,
-    OrderType, Stats,
+    query_args, to_exchange_error, update, Balances, CancelOrderRequest, Exchange, ExchangeError,
+    MakeOrderRequest, Order, OrderId, OrderType, Price, RateLimiter, RoundingMode, Stats,
+    TradingLimits,
 };
 use async_trait::async_trait;
-use candid::{CandidType, Nat, Principal};
+use cache::Cached;
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
+use candid::{CandidType, Int, Nat, Principal, Reserved};
+use connection::Connection;
+use futures::StreamExt;
+use ic_agent::agent::http_transport::ReqwestHttpReplicaV2Transport;
+use ic_agent::identity::BasicIdentity;
 use ic_agent::Agent;
+use itertools::Itertools;
+use nonce::NonceTracker;
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
+use std::fmt::Debug;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
+use tracing::{info, warn};
+
+mod cache;
+mod connection;
+mod ledger;
+mod nonce;
+
+pub use ledger::{Dip20Ledger, Icrc1Ledger, Ledger};
 
 pub struct ICDex {
-    agent: Agent,
+    connection: Connection,
     dex_canister_id: Principal,
     trader_canister_id: Principal,
+    base_ledger: Box<dyn Ledger>,
+    quote_ledger: Box<dyn Ledger>,
+    inter_order_delay: Duration,
+    concurrency: usize,
+    order_retries: u32,
+    order_retry_delay: Duration,
+    price_scale: u64,
+    price_rounding: RoundingMode,
+    batch_orders_supported: AtomicBool,
+    cancel_all_supported: AtomicBool,
+    nonces: NonceTracker,
+    // The latest price, when the latest trade happened and the 24 hour volume
+    latest_price_cache: Cached<(Price, Option<u64>, Option<u64>)>,
+    best_bid_and_ask_cache: Cached<(Option<Price>, Option<Price>)>,
+    rate_limiter: Option<Arc<RateLimiter>>,
 }
 
 impl ICDex {
-    pub fn new(agent: Agent, dex_canister_id: Principal, trader_canister_id: Principal) -> Self {
+    /// Both ledgers are assumed to implement ICRC-1. Use `with_base_ledger` or `with_quote_ledger`
+    /// for tokens which implement a different standard.
+    pub fn new(
+        agent: Agent,
+        dex_canister_id: Principal,
+        trader_canister_id: Principal,
+        base_ledger_canister_id: Principal,
+        quote_ledger_canister_id: Principal,
+    ) -> Self {
         ICDex {
-            agent,
+            connection: Connection::new(agent),
             dex_canister_id,
             trader_canister_id,
+            base_ledger: Box::new(Icrc1Ledger::new(base_ledger_canister_id)),
+            quote_ledger: Box::new(Icrc1Ledger::new(quote_ledger_canister_id)),
+            inter_order_delay: Duration::from_secs(2),
+            concurrency: 1,
+            order_retries: 2,
+            order_retry_delay: Duration::from_millis(500),
+            price_scale: 10,
+            price_rounding: RoundingMode::Round,
+            batch_orders_supported: AtomicBool::new(true),
+            cancel_all_supported: AtomicBool::new(true),
+            nonces: NonceTracker::default(),
+            latest_price_cache: Cached::new(Duration::ZERO),
+            best_bid_and_ask_cache: Cached::new(Duration::ZERO),
+            rate_limiter: None,
         }
     }
 
-    async fn latest_price(&self) -> Result<u64, String> {
-        let response: StatsResponse =
-            query(&self.agent, &self.dex_canister_id, "stats", ()).await?;
+    /// Sets how long to wait between each order made or cancelled (defaults to 2 seconds).
+    ///
+    /// ICDex tracks a nonce per account and each order must use the next one. Orders made one at a
+    /// time are given increasing nonces which are tracked locally (refetching the nonce and
+    /// retrying once if ICDex rejects it), so they don't rely on this delay to avoid reusing a
+    /// nonce. However ICDex still rejects orders with `TransactionBlocking` while the account's
+    /// previous order is being processed, so lowering the delay speeds up each iteration but
+    /// makes those rejections more likely.
+    pub fn with_inter_order_delay(mut self, delay: Duration) -> Self {
+        self.inter_order_delay = delay;
+        self
+    }
 
-        Ok((response.price * 100000000f64) as u64)
+    /// Sets the ledger which the base token balance is read from
+    pub fn with_base_ledger(mut self, ledger: impl Ledger + 'static) -> Self {
+        self.base_ledger = Box::new(ledger);
+        self
     }
 
-    async fn open_orders(&self) -> Result<Vec<Order>, String> {
-        let orders: TrieList = query(
-            &self.agent,
-            &self.dex_canister_id,
-            "pending",
-            (
-                self.trader_canister_id.to_string(),
-                Option::<Nat>::None,
-                Option::<Nat>::None,
-            ),
-        )
+    /// Sets the ledger which the quote token balance is read from
+    pub fn with_quote_ledger(mut self, ledger: impl Ledger + 'static) -> Self {
+        self.quote_ledger = Box::new(ledger);
+        self
+    }
+
+    /// Sets how many orders may be in flight at once when they are made individually rather than
+    /// in a batch (defaults to 1). Each order is still followed by the inter-order delay before
+    /// its slot is reused. ICDex processes an account's orders one at a time, so higher values
+    /// are more likely to see orders rejected with `TransactionBlocking` or a nonce error.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Sets how many times an order rejected with `TransactionBlocking` or a nonce error is
+    /// retried, and how long to wait before each retry (defaults to 2 retries, 500ms apart). These
+    /// rejections clear once ICDex finishes processing the account's previous order, whereas
+    /// others, such as an insufficient balance, are returned without retrying. Orders rejected
+    /// this way in a batch are resent one at a time.
+    pub fn with_order_retries(mut self, retries: u32, delay: Duration) -> Self {
+        self.order_retries = retries;
+        self.order_retry_delay = delay;
+        self
+    }
+
+    /// Sets the factor which prices reported by ICDex are multiplied by to convert them to the
+    /// scale used by `Order` (defaults to 10). ICDex currently reports prices 10 times smaller
+    /// than expected, so this should be set to 1 once that is fixed on their side.
+    pub fn with_price_scale(mut self, price_scale: u64) -> Self {
+        self.price_scale = price_scale;
+        self
+    }
+
+    /// Sets how the latest trade price, which ICDex reports as a float, is rounded (defaults to
+    /// rounding to the nearest representable price)
+    pub fn with_price_rounding(mut self, price_rounding: RoundingMode) -> Self {
+        self.price_rounding = price_rounding;
+        self
+    }
+
+    /// Sets how long the latest trade (its price and time) and the best bid and ask may be reused
+    /// for before they are queried again (both default to zero, meaning they are queried every
+    /// iteration). Open orders are always queried fresh since they change whenever orders are made
+    /// or cancelled.
+    pub fn with_cache_ttls(mut self, latest_price_ttl: Duration, order_book_ttl: Duration) -> Self {
+        self.latest_price_cache = Cached::new(latest_price_ttl);
+        self.best_bid_and_ask_cache = Cached::new(order_book_ttl);
+        self
+    }
+
+    /// Makes every query and update call wait for the rate limiter before it is sent (by default
+    /// calls are not limited). The same limiter can be shared by several `ICDex` instances so that
+    /// together they stay within the rate accepted by the boundary nodes.
+    pub fn with_rate_limiter(mut self, rate_limiter: Arc<RateLimiter>) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Builds an agent which calls the IC at `url` as the identity in `identity_pem`
+    pub fn build_agent(url: &str, identity_pem: &str) -> Result<Agent, ExchangeError> {
+        let transport = ReqwestHttpReplicaV2Transport::create(url)
+            .map_err(|e| ExchangeError::Fatal(e.to_string()))?;
+        let identity = BasicIdentity::from_pem(identity_pem.as_bytes())
+            .map_err(|e| ExchangeError::Fatal(format!("Invalid identity: {e}")))?;
+
+        Agent::builder()
+            .with_transport(transport)
+            .with_identity(identity)
+            .with_ingress_expiry(Some(Duration::from_secs(60 * 5)))
+            .build()
+            .map_err(|e| ExchangeError::Fatal(e.to_string()))
+    }
+
+    /// Rebuilds the agent from `url` and `identity_pem` after `max_transport_failures`
+    /// consecutive calls fail at the transport level (by default the agent is never rebuilt), so
+    /// that a transport which gets into a bad state, eg. after a network partition, recovers
+    /// without a restart
+    pub fn with_reconnect(
+        self,
+        url: String,
+        identity_pem: String,
+        max_transport_failures: u32,
+    ) -> Self {
+        self.with_agent_rebuild(max_transport_failures, move || {
+            ICDex::build_agent(&url, &identity_pem)
+        })
+    }
+
+    /// Like `with_reconnect`, but with the new agent built by `build`
+    pub fn with_agent_rebuild(
+        mut self,
+        max_transport_failures: u32,
+        build: impl Fn() -> Result<Agent, ExchangeError> + Send + Sync + 'static,
+    ) -> Self {
+        self.connection
+            .set_rebuild(max_transport_failures, Box::new(build));
+        self
+    }
+
+    /// Checks that ICDex can be queried and that the trader canister is controlled by the agent's
+    /// identity, so that a misconfigured identity or canister id is reported once at startup rather
+    /// than as a failure in every iteration
+    pub async fn healthcheck(&self) -> Result<(), ExchangeError> {
+        self.price_stats().await?;
+
+        let agent = self.connection.agent();
+        let principal = agent.get_principal().map_err(ExchangeError::Other)?;
+        self.throttle().await;
+        let controllers = agent
+            .read_state_canister_info(self.trader_canister_id, "controllers")
+            .await
+            .map_err(to_exchange_error);
+        self.connection.record(&controllers);
+        let controllers = controllers?;
+
+        if !decode_controllers(&controllers)?.contains(&principal) {
+            return Err(ExchangeError::Other(format!(
+                "Trader canister {} is not controlled by {principal}",
+                self.trader_canister_id
+            )));
+        }
+        Ok(())
+    }
+
+    // The latest price and, if the pair reports it, the amount of the base token traded in the
+    // last 24 hours
+    async fn price_stats(&self) -> Result<(Price, Option<u64>), ExchangeError> {
+        let response: StatsResponse = self.query(&self.dex_canister_id, "stats", ()).await?;
+
+        let price = Price::try_from_decimal(response.price, self.price_rounding)
+            .ok_or_else(|| ExchangeError::Decode(format!("Invalid price: {}", response.price)))?;
+        let volume_24h = response.vol24h.map(|v| to_u64(v.value0)).transpose()?;
+
+        Ok((price, volume_24h))
+    }
+
+    // When the pair's most recent trade happened, or `None` if there haven't been any trades or
+    // the pair doesn't have a `latestFilled` endpoint
+    async fn latest_trade_at(&self) -> Result<Option<u64>, ExchangeError> {
+        let filled: Vec<LatestFilled> =
+            match self.query(&self.dex_canister_id, "latestFilled", ()).await {
+                Ok(filled) => filled,
+                Err(ExchangeError::Unsupported(_)) => return Ok(None),
+                Err(error) => return Err(error),
+            };
+
+        latest_filled_at(filled)
+    }
+
+    async fn open_orders(&self) -> Result<Vec<Order>, ExchangeError> {
+        let orders = fetch_all_pages(|page| {
+            self.query(
+                &self.dex_canister_id,
+                "pending",
+                (
+                    self.trader_canister_id.to_string(),
+                    Some(Nat::from(page)),
+                    Option::<Nat>::None,
+                ),
+            )
+        })
         .await?;
 
-        Ok(orders.data.into_iter().map(|(_, o)| o.into()).collect())
+        Ok(orders
+            .into_iter()
+            .filter_map(|(_, o)| match o.into_order(self.price_scale) {
+                Ok(order) => Some(order),
+                Err(error) => {
+                    warn!(%error, "Skipping open order");
+                    None
+                }
+            })
+            .collect())
+    }
+
+    async fn best_bid_and_ask(&self) -> Result<(Option<Price>, Option<Price>), ExchangeError> {
+        let (_, order_book): (Nat, OrderBook) = self
+            .query_args(&self.dex_canister_id, "level10", ())
+            .await?;
+
+        let bid_prices: Vec<_> = order_book
+            .bid
+            .into_iter()
+            .map(|l| to_price(l.price, self.price_scale))
+            .try_collect()?;
+        let ask_prices: Vec<_> = order_book
+            .ask
+            .into_iter()
+            .map(|l| to_price(l.price, self.price_scale))
+            .try_collect()?;
+
+        let best_bid = bid_prices.into_iter().max();
+        let best_ask = ask_prices.into_iter().min();
+
+        Ok((best_bid, best_ask))
+    }
+
+    async fn balance(&self, ledger: &dyn Ledger) -> Result<u64, ExchangeError> {
+        self.throttle().await;
+        let result = ledger
+            .balance_of(&self.connection.agent(), self.trader_canister_id)
+            .await;
+        self.connection.record(&result);
+        result
+    }
+
+    async fn make_order(&self, order: MakeOrderRequest) -> Result<OrderId, ExchangeError> {
+        retry_transient(self.order_retries, self.order_retry_delay, || {
+            self.nonces.with_nonce(
+                || self.nonce(),
+                |nonce| self.make_order_with_nonce(&order, nonce),
+            )
+        })
+        .await
     }
 
-    async fn make_order(&self, order: MakeOrderRequest) -> Result<String, String> {
-        let price = order.price as f64 / 100000000f64;
+    async fn make_order_with_nonce(
+        &self,
+        order: &MakeOrderRequest,
+        nonce: u64,
+    ) -> Result<OrderId, ExchangeError> {
         let args = (
             self.dex_canister_id,
             Side::from(order.order_type),
-            price,
+            order.price.to_decimal(),
             Nat(order.amount.into()),
+            Some(Nat(nonce.into())),
         );
 
         let response: MakeOrderResponse =
-            update(&self.agent, &self.trader_canister_id, "order", args).await?;
+            self.update(&self.trader_canister_id, "order", args).await?;
 
-        match response {
-            MakeOrderResponse::Ok(r) => Ok(hex::encode(r.txid)),
-            MakeOrderResponse::Err(err) => Err(format!("{err:?}")),
+        response.into_result()
+    }
+
+    // The nonce which ICDex expects the trader canister's next order to use
+    async fn nonce(&self) -> Result<u64, ExchangeError> {
+        let (_, _, nonce, _): (Account, String, Nat, Vec<u8>) = self
+            .query_args(
+                &self.dex_canister_id,
+                "getTxAccount",
+                (self.trader_canister_id.to_text(),),
+            )
+            .await?;
+
+        to_u64(nonce)
+    }
+
+    /// Makes all of the orders in a single call to the trader canister's `orders` endpoint.
+    ///
+    /// On success, returns the txid or error of each order, in the same order as the requests.
+    /// Returns `ExchangeError::Unsupported` if the trader canister has no `orders` endpoint.
+    pub async fn make_orders_batch(
+        &self,
+        orders: Vec<MakeOrderRequest>,
+    ) -> Result<Vec<Result<OrderId, ExchangeError>>, ExchangeError> {
+        let args = (
+            self.dex_canister_id,
+            orders
+                .into_iter()
+                .map(|o| BatchOrderArgs {
+                    side: o.order_type.into(),
+                    price: o.price.to_decimal(),
+                    quantity: Nat(o.amount.into()),
+                })
+                .collect::<Vec<_>>(),
+        );
+
+        // Orders made in a batch consume nonces which aren't tracked locally
+        self.nonces.reset();
+
+        let responses: Vec<MakeOrderResponse> = self
+            .update(&self.trader_canister_id, "orders", args)
+            .await?;
+
+        Ok(responses
+            .into_iter()
+            .map(MakeOrderResponse::into_result)
+            .collect())
+    }
+
+    // Makes the orders in a batch if there is more than one and the trader canister supports it,
+    // otherwise one at a time
+    async fn send_orders(
+        &self,
+        orders: Vec<MakeOrderRequest>,
+    ) -> Vec<Result<OrderId, ExchangeError>> {
+        if orders.len() > 1 && self.batch_orders_supported.load(Ordering::Relaxed) {
+            match self.make_orders_batch(orders.clone()).await {
+                Ok(results) if self.order_retries == 0 => return results,
+                Ok(results) => {
+                    return resend_transient(orders, results, |order| async move {
+                        tokio::time::sleep(self.order_retry_delay).await;
+                        self.make_order(order).await
+                    })
+                    .await
+                }
+                Err(ExchangeError::Unsupported(_)) => {
+                    info!("Batch orders unsupported by the trader canister, making orders individually");
+                    self.batch_orders_supported.store(false, Ordering::Relaxed);
+                }
+                Err(error) => return orders.iter().map(|_| Err(error.clone())).collect(),
+            }
         }
+
+        self.make_orders_individually(orders).await
     }
 
-    async fn cancel_order(&self, order: CancelOrderRequest) -> Result<(), String> {
-        let id = hex::decode(order.id).unwrap();
+    // Makes each order with its own call, up to `concurrency` at a time. A failed order doesn't
+    // stop the remaining orders from being made.
+    async fn make_orders_individually(
+        &self,
+        orders: Vec<MakeOrderRequest>,
+    ) -> Vec<Result<OrderId, ExchangeError>> {
+        let mut results: Vec<_> = futures::stream::iter(orders.into_iter().enumerate())
+            .map(|(index, order)| async move {
+                let result = self.make_order(order).await;
+                tokio::time::sleep(self.inter_order_delay).await;
+                (index, result)
+            })
+            .buffer_unordered(self.concurrency)
+            .collect()
+            .await;
+
+        // Orders complete out of order, but the results must be in the order of the requests
+        results.sort_unstable_by_key(|(index, _)| *index);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
 
-        update_no_response(
-            &self.agent,
+    async fn cancel_order(&self, order: CancelOrderRequest) -> Result<(), ExchangeError> {
+        self.update_no_response(
             &self.trader_canister_id,
             "cancel",
-            (self.dex_canister_id, id),
+            (self.dex_canister_id, order.id.into_bytes()),
         )
         .await?;
 
         Ok(())
     }
+
+    /// Cancels all of the trader's open orders in a single call.
+    /// Returns `ExchangeError::Unsupported` if the trader canister has no `cancel_all` endpoint.
+    pub async fn cancel_all(&self) -> Result<(), ExchangeError> {
+        self.update_no_response(
+            &self.trader_canister_id,
+            "cancel_all",
+            (self.dex_canister_id,),
+        )
+        .await
+    }
+
+    // Waits for the rate limiter, if there is one, before a call is made to the IC
+    async fn throttle(&self) {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire().await;
+        }
+    }
+
+    async fn query<A: ArgumentEncoder + Debug, R: CandidType + DeserializeOwned>(
+        &self,
+        canister_id: &Principal,
+        method_name: &str,
+        args: A,
+    ) -> Result<R, ExchangeError> {
+        self.query_args(canister_id, method_name, args)
+            .await
+            .map(|(r,)| r)
+    }
+
+    async fn query_args<A: ArgumentEncoder + Debug, R: for<'a> ArgumentDecoder<'a>>(
+        &self,
+        canister_id: &Principal,
+        method_name: &str,
+        args: A,
+    ) -> Result<R, ExchangeError> {
+        self.throttle().await;
+        let result = query_args(&self.connection.agent(), canister_id, method_name, args).await;
+        self.connection.record(&result);
+        result
+    }
+
+    async fn update_no_response<A: ArgumentEncoder + Debug>(
+        &self,
+        canister_id: &Principal,
+        method_name: &str,
+        args: A,
+    ) -> Result<(), ExchangeError> {
+        self.update(canister_id, method_name, args).await
+    }
+
+    async fn update<A: ArgumentEncoder + Debug, R: CandidType + DeserializeOwned>(
+        &self,
+        canister_id: &Principal,
+        method_name: &str,
+        args: A,
+    ) -> Result<R, ExchangeError> {
+        self.throttle().await;
+        let result = update(&self.connection.agent(), canister_id, method_name, args).await;
+        self.connection.record(&result);
+        result
+    }
 }
 
 #[async_trait]
 impl Exchange for ICDex {
-    async fn stats(&self) -> Result<Stats, String> {
+    async fn stats(&self) -> Result<Stats, ExchangeError> {
         let open_orders = self.open_orders().await?;
-        let latest_price = self.latest_price().await?;
+        let (latest_price, latest_trade_at, volume_24h) = self
+            .latest_price_cache
+            .get_or_fetch(|| async {
+                let ((price, volume_24h), latest_trade_at) =
+                    futures::future::try_join(self.price_stats(), self.latest_trade_at()).await?;
+                Ok((price, latest_trade_at, volume_24h))
+            })
+            .await?;
+        let (best_bid, best_ask) = self
+            .best_bid_and_ask_cache
+            .get_or_fetch(|| self.best_bid_and_ask())
+            .await?;
 
         Ok(Stats {
             latest_price,
+            latest_trade_at,
+            volume_24h,
+            best_bid,
+            best_ask,
             open_orders,
         })
     }
 
-    async fn make_orders(&self, orders: Vec<MakeOrderRequest>) -> Result<(), String> {
-        for order in orders {
-            self.make_order(order).await?;
-            tokio::time::sleep(Duration::from_secs(2)).await;
+    async fn balances(&self) -> Result<Balances, ExchangeError> {
+        let (base, quote) = futures::future::try_join(
+            self.balance(self.base_ledger.as_ref()),
+            self.balance(self.quote_ledger.as_ref()),
+        )
+        .await?;
+
+        Ok(Balances { base, quote })
+    }
+
+    async fn trading_limits(&self) -> Result<TradingLimits, ExchangeError> {
+        let info: InfoResponse = self.query(&self.dex_canister_id, "info", ()).await?;
+
+        info.into_trading_limits(self.price_scale)
+    }
+
+    // ICDex has no post-only order type, so post-only orders are checked against the best bid and
+    // ask first and any which would cross the book are rejected without being sent
+    async fn make_orders(
+        &self,
+        orders: Vec<MakeOrderRequest>,
+    ) -> Vec<Result<OrderId, ExchangeError>> {
+        let (best_bid, best_ask) = if orders.iter().any(|o| o.post_only) {
+            match self
+                .best_bid_and_ask_cache
+                .get_or_fetch(|| self.best_bid_and_ask())
+                .await
+            {
+                Ok(best_bid_and_ask) => best_bid_and_ask,
+                Err(error) => return orders.iter().map(|_| Err(error.clone())).collect(),
+            }
+        } else {
+            (None, None)
+        };
+
+        let rejections = orders
+            .iter()
+            .map(|o| check_post_only(o, best_bid, best_ask).err())
+            .collect_vec();
+        let orders_to_make = orders
+            .iter()
+            .zip(&rejections)
+            .filter(|(_, rejection)| rejection.is_none())
+            .map(|(o, _)| o.clone())
+            .collect();
+
+        let mut results = self.send_orders(orders_to_make).await.into_iter();
+        let results = rejections
+            .into_iter()
+            .map(|rejection| match rejection {
+                Some(error) => Err(error),
+                // The batch endpoint could return fewer results than there were orders
+                None => results
+                    .next()
+                    .unwrap_or_else(|| Err(ExchangeError::Decode("missing result".into()))),
+            })
+            .collect_vec();
+        for (order, result) in orders.iter().zip(&results) {
+            log_make(order, result);
         }
-        Ok(())
+        results
     }
 
-    async fn cancel_orders(&self, orders: Vec<CancelOrderRequest>) -> Result<(), String> {
+    async fn cancel_orders(
+        &self,
+        orders: Vec<CancelOrderRequest>,
+    ) -> Vec<Result<OrderId, ExchangeError>> {
+        let mut results = Vec::new();
         for order in orders {
-            self.cancel_order(order).await?;
-            tokio::time::sleep(Duration::from_secs(2)).await;
+            let id = order.id.clone();
+            let result = self.cancel_order(order).await.map(|_| id.clone());
+            log_cancel(&id, &result);
+            results.push(result);
+            tokio::time::sleep(self.inter_order_delay).await;
+        }
+        results
+    }
+
+    async fn cancel_all_orders(&self) -> Result<(), ExchangeError> {
+        if self.cancel_all_supported.load(Ordering::Relaxed) {
+            match self.cancel_all().await {
+                Err(ExchangeError::Unsupported(_)) => {
+                    info!("Cancel all unsupported by the trader canister, cancelling orders individually");
+                    self.cancel_all_supported.store(false, Ordering::Relaxed);
+                }
+                result => return result,
+            }
         }
+
+        let stats = self.stats().await?;
+        let orders_to_cancel = stats
+            .open_orders
+            .into_iter()
+            .map(|o| CancelOrderRequest { id: o.id })
+            .collect();
+
+        self.cancel_orders(orders_to_cancel)
+            .await
+            .into_iter()
+            .try_for_each(|r| r.map(|_| ()))
+    }
+}
+
+// Every order made or cancelled is logged with its outcome as separate fields, so that what
+// happened to each order in an iteration can be reconstructed from JSON logs using the iteration's
+// correlation id
+fn log_make(order: &MakeOrderRequest, result: &Result<OrderId, ExchangeError>) {
+    let (order_type, price, amount) = (order.order_type, order.price, order.amount);
+    match result {
+        Ok(txid) => info!(action = "make", ?order_type, %price, amount, %txid, "Made order"),
+        Err(error) => {
+            info!(action = "make", ?order_type, %price, amount, %error, "Order not made")
+        }
+    }
+}
+
+fn log_cancel(id: &OrderId, result: &Result<OrderId, ExchangeError>) {
+    match result {
+        Ok(_) => info!(action = "cancel", txid = %id, "Cancelled order"),
+        Err(error) => info!(action = "cancel", txid = %id, %error, "Order not cancelled"),
+    }
+}
+
+// ICDex numbers pages from 1. Orders can move between pages if they are filled or cancelled while
+// the pages are being fetched, so any which appear on more than one page are only returned once.
+async fn fetch_all_pages<
+    F: FnMut(u64) -> Fut,
+    Fut: Future<Output = Result<TrieList, ExchangeError>>,
+>(
+    mut fetch_page: F,
+) -> Result<Vec<(Vec<u8>, TradingOrder)>, ExchangeError> {
+    let first_page = fetch_page(1).await?;
+    let total_pages = to_u64(first_page.total_page)?;
+    let mut data = first_page.data;
+
+    for page in 2..=total_pages {
+        data.extend(fetch_page(page).await?.data);
+    }
+
+    Ok(data
+        .into_iter()
+        .unique_by(|(_, o)| o.txid.clone())
+        .collect())
+}
+
+// Retries `f` up to `retries` times while it fails with an error which ICDex returns when the
+// account's previous order is still being processed
+async fn retry_transient<T, F: FnMut() -> Fut, Fut: Future<Output = Result<T, ExchangeError>>>(
+    retries: u32,
+    delay: Duration,
+    mut f: F,
+) -> Result<T, ExchangeError> {
+    let mut attempt = 0;
+    loop {
+        match f().await {
+            Err(error) if is_transient(&error) && attempt < retries => {
+                attempt += 1;
+                warn!(%error, attempt, "Order rejected, retrying");
+                tokio::time::sleep(delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+// Orders in a batch which failed with a transient error are sent again one at a time, so that each
+// is retried as it would have been had it been made on its own
+async fn resend_transient<
+    F: FnMut(MakeOrderRequest) -> Fut,
+    Fut: Future<Output = Result<OrderId, ExchangeError>>,
+>(
+    orders: Vec<MakeOrderRequest>,
+    results: Vec<Result<OrderId, ExchangeError>>,
+    mut make_order: F,
+) -> Vec<Result<OrderId, ExchangeError>> {
+    let mut resent = Vec::with_capacity(results.len());
+    for (order, result) in orders.into_iter().zip(results) {
+        resent.push(match result {
+            Err(error) if is_transient(&error) => {
+                warn!(%error, "Order rejected in a batch, resending it on its own");
+                make_order(order).await
+            }
+            result => result,
+        });
+    }
+    resent
+}
+
+// The errors which ICDex returns when the account's previous order is still being processed
+fn is_transient(error: &ExchangeError) -> bool {
+    matches!(error, ExchangeError::Busy(_) | ExchangeError::Nonce(_))
+}
+
+// Rejects a post-only order which would trade immediately against the opposite side of the book
+fn check_post_only(
+    order: &MakeOrderRequest,
+    best_bid: Option<Price>,
+    best_ask: Option<Price>,
+) -> Result<(), ExchangeError> {
+    let crosses = order.post_only
+        && match order.order_type {
+            OrderType::Bid => best_ask.is_some_and(|ask| order.price >= ask),
+            OrderType::Ask => best_bid.is_some_and(|bid| order.price <= bid),
+        };
+
+    if crosses {
+        Err(ExchangeError::WouldCross(format!(
+            "{:?} at {} crosses the best bid {best_bid:?} or ask {best_ask:?}",
+            order.order_type, order.price
+        )))
+    } else {
         Ok(())
     }
 }
 
+// The controllers are returned as a CBOR array of principals
+fn decode_controllers(bytes: &[u8]) -> Result<Vec<Principal>, ExchangeError> {
+    let invalid = || ExchangeError::Decode("Invalid controllers".to_string());
+
+    match serde_cbor::from_slice(bytes).map_err(|_| invalid())? {
+        serde_cbor::Value::Array(controllers) => controllers
+            .into_iter()
+            .map(|c| match c {
+                serde_cbor::Value::Bytes(bytes) => {
+                    Principal::try_from_slice(&bytes).map_err(|_| invalid())
+                }
+                _ => Err(invalid()),
+            })
+            .collect(),
+        _ => Err(invalid()),
+    }
+}
+
 #[derive(CandidType, Deserialize)]
 struct TrieList {
     data: Vec<(Vec<u8>, TradingOrder)>,
@@ -116,9 +773,62 @@ struct TrieList {
     total_page: Nat,
 }
 
+#[derive(CandidType, Deserialize, Debug)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+// Only the fields which are used are declared, the rest of the response is ignored
+#[derive(CandidType, Deserialize)]
+struct InfoResponse {
+    setting: DexSetting,
+}
+
+#[derive(CandidType, Deserialize)]
+struct DexSetting {
+    // Order amounts must be a multiple of this
+    #[serde(rename = "UNIT_SIZE")]
+    unit_size: Nat,
+}
+
+impl InfoResponse {
+    // Prices are whole numbers in ICDex's own units, so its tick size is a single unit
+    fn into_trading_limits(self, price_scale: u64) -> Result<TradingLimits, ExchangeError> {
+        let unit_size = to_u64(self.setting.unit_size)?;
+        Ok(TradingLimits {
+            min_order_size: Some(unit_size),
+            tick_size: Some(to_price(Nat::from(1u64), price_scale)?),
+            lot_size: Some(unit_size),
+        })
+    }
+}
+
+// (timestamp in seconds, txid, filled amounts, side). Only the timestamp is used.
+type LatestFilled = (Nat, Reserved, Reserved, Reserved);
+
+// The time of the most recent fill in nanoseconds since the Unix epoch
+fn latest_filled_at(filled: Vec<LatestFilled>) -> Result<Option<u64>, ExchangeError> {
+    let latest = filled
+        .into_iter()
+        .map(|(timestamp, ..)| to_u64(timestamp))
+        .fold_ok(None, |latest: Option<u64>, t| latest.max(Some(t)))?;
+
+    Ok(latest.map(|seconds| seconds.saturating_mul(1_000_000_000)))
+}
+
 #[derive(CandidType, Deserialize)]
 struct StatsResponse {
     price: f64,
+    // Older pairs don't report their volume
+    vol24h: Option<Vol>,
+}
+
+// Traded amounts of the base (`value0`) and quote (`value1`) tokens
+#[derive(CandidType, Deserialize)]
+struct Vol {
+    value0: Nat,
+    value1: Nat,
 }
 
 #[derive(CandidType, Debug)]
@@ -140,24 +850,41 @@ impl From<OrderType> for Side {
 struct TradingOrder {
     remaining: OrderPrice,
     txid: Vec<u8>,
+    // Nanoseconds since the Unix epoch
+    time: Int,
 }
 
-impl From<TradingOrder> for Order {
-    fn from(value: TradingOrder) -> Self {
-        let (order_type, amount) = match value.remaining.quantity {
+impl TradingOrder {
+    fn into_order(self, price_scale: u64) -> Result<Order, ExchangeError> {
+        let (order_type, amount) = match self.remaining.quantity {
             OrderQuantity::Buy(n, _) => (OrderType::Bid, n),
             OrderQuantity::Sell(n) => (OrderType::Ask, n),
         };
-        let price: u64 = value.remaining.price.0.try_into().unwrap();
-        Order {
+        let id = OrderId::from(self.txid);
+        let invalid = |e: ExchangeError| ExchangeError::Decode(format!("Invalid order {id}. {e}"));
+
+        Ok(Order {
             order_type,
-            id: hex::encode(value.txid),
-            price: price * 10, // TODO remove the '* 10' once fixed on their side
-            amount: amount.0.try_into().unwrap(),
-        }
+            price: to_price(self.remaining.price, price_scale).map_err(invalid)?,
+            amount: to_u64(amount).map_err(invalid)?,
+            placed_at: u64::try_from(&self.time.0).ok(),
+            id,
+        })
     }
 }
 
+fn to_price(price: Nat, price_scale: u64) -> Result<Price, ExchangeError> {
+    let price = to_u64(price)?;
+    price
+        .checked_mul(price_scale)
+        .map(Price::from_scaled)
+        .ok_or_else(|| ExchangeError::Decode(format!("Price too large: {price}")))
+}
+
+fn to_u64(value: Nat) -> Result<u64, ExchangeError> {
+    u64::try_from(&value.0).map_err(|_| ExchangeError::Decode(format!("Value too large: {value}")))
+}
+
 #[derive(CandidType, Deserialize)]
 enum OrderQuantity {
     Buy(Nat, Nat),
@@ -170,6 +897,25 @@ struct OrderPrice {
     quantity: OrderQuantity,
 }
 
+#[derive(CandidType, Deserialize)]
+struct OrderBook {
+    ask: Vec<PriceLevel>,
+    bid: Vec<PriceLevel>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct PriceLevel {
+    price: Nat,
+    quantity: Nat,
+}
+
+#[derive(CandidType, Debug)]
+struct BatchOrderArgs {
+    side: Side,
+    price: f64,
+    quantity: Nat,
+}
+
 #[derive(CandidType, Deserialize)]
 enum MakeOrderResponse {
     #[serde(rename = "ok")]
@@ -178,6 +924,15 @@ enum MakeOrderResponse {
     Err(MakeOrderError),
 }
 
+impl MakeOrderResponse {
+    fn into_result(self) -> Result<OrderId, ExchangeError> {
+        match self {
+            MakeOrderResponse::Ok(r) => Ok(OrderId::from(r.txid)),
+            MakeOrderResponse::Err(err) => Err(err.into()),
+        }
+    }
+}
+
 #[derive(CandidType, Deserialize)]
 struct MakeOrderSuccess {
     txid: Vec<u8>,
@@ -189,6 +944,20 @@ struct MakeOrderError {
     message: String,
 }
 
+impl From<MakeOrderError> for ExchangeError {
+    fn from(value: MakeOrderError) -> Self {
+        match value.code {
+            MakeOrderErrorCode::InsufficientBalance => {
+                ExchangeError::InsufficientBalance(value.message)
+            }
+            MakeOrderErrorCode::UnacceptableVolatility => ExchangeError::Volatility(value.message),
+            MakeOrderErrorCode::NonceError => ExchangeError::Nonce(value.message),
+            MakeOrderErrorCode::TransactionBlocking => ExchangeError::Busy(value.message),
+            code => ExchangeError::Other(format!("{code:?}: {}", value.message)),
+        }
+    }
+}
+
 #[derive(CandidType, Deserialize, Debug)]
 enum MakeOrderErrorCode {
     NonceError,
@@ -199,3 +968,419 @@ enum MakeOrderErrorCode {
     TransactionBlocking,
     UndefinedError,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    // Answers every query with `stats` if healthy, and otherwise fails as if the connection had
+    // dropped. Every other request fails, since the tests only make queries.
+    struct MockTransport {
+        healthy: bool,
+    }
+
+    type TransportFuture<'a, V> =
+        std::pin::Pin<Box<dyn Future<Output = Result<V, ic_agent::AgentError>> + Send + 'a>>;
+
+    impl ic_agent::agent::ReplicaV2Transport for MockTransport {
+        fn call(
+            &self,
+            _: Principal,
+            _: Vec<u8>,
+            _: ic_agent::RequestId,
+        ) -> TransportFuture<'_, ()> {
+            unsupported("call")
+        }
+
+        fn read_state(&self, _: Principal, _: Vec<u8>) -> TransportFuture<'_, Vec<u8>> {
+            unsupported("read_state")
+        }
+
+        fn query(&self, _: Principal, _: Vec<u8>) -> TransportFuture<'_, Vec<u8>> {
+            let healthy = self.healthy;
+            Box::pin(async move {
+                if !healthy {
+                    return Err(ic_agent::AgentError::TransportError(
+                        "connection reset".into(),
+                    ));
+                }
+                let arg = candid::encode_one(StatsResponse {
+                    price: 0.05,
+                    vol24h: None,
+                })
+                .unwrap();
+                let response = serde_cbor::Value::Map(
+                    [
+                        (
+                            serde_cbor::Value::Text("status".to_string()),
+                            serde_cbor::Value::Text("replied".to_string()),
+                        ),
+                        (
+                            serde_cbor::Value::Text("reply".to_string()),
+                            serde_cbor::Value::Map(
+                                [(
+                                    serde_cbor::Value::Text("arg".to_string()),
+                                    serde_cbor::Value::Bytes(arg),
+                                )]
+                                .into(),
+                            ),
+                        ),
+                    ]
+                    .into(),
+                );
+                Ok(serde_cbor::to_vec(&response).unwrap())
+            })
+        }
+
+        fn status(&self) -> TransportFuture<'_, Vec<u8>> {
+            unsupported("status")
+        }
+    }
+
+    fn unsupported<V>(request: &str) -> TransportFuture<'static, V> {
+        let error = ic_agent::AgentError::TransportError(
+            format!("MockTransport doesn't support {request}").into(),
+        );
+        Box::pin(async move { Err(error) })
+    }
+
+    fn mock_agent(healthy: bool) -> Agent {
+        Agent::builder()
+            .with_transport(MockTransport { healthy })
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn agent_is_rebuilt_after_consecutive_transport_failures() {
+        let builds = Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let id = Principal::anonymous();
+        let icdex = ICDex::new(mock_agent(false), id, id, id, id).with_agent_rebuild(2, {
+            let builds = builds.clone();
+            move || {
+                builds.fetch_add(1, Ordering::Relaxed);
+                Ok(mock_agent(true))
+            }
+        });
+
+        assert!(matches!(
+            icdex.price_stats().await,
+            Err(ExchangeError::Transport(_))
+        ));
+        assert_eq!(builds.load(Ordering::Relaxed), 0);
+        assert!(matches!(
+            icdex.price_stats().await,
+            Err(ExchangeError::Transport(_))
+        ));
+        assert_eq!(builds.load(Ordering::Relaxed), 1);
+
+        let (price, volume_24h) = icdex.price_stats().await.unwrap();
+        assert_eq!(price, Price::from_decimal(0.05));
+        assert_eq!(volume_24h, None);
+        assert_eq!(builds.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn trading_order_with_oversized_amount_fails_to_convert() {
+        let order = TradingOrder {
+            remaining: OrderPrice {
+                price: Nat::from(1000u64),
+                quantity: OrderQuantity::Sell(Nat::from(u64::MAX) + Nat::from(1u64)),
+            },
+            txid: vec![1, 2, 3],
+            time: Int::from(0),
+        };
+
+        assert!(matches!(
+            order.into_order(10),
+            Err(ExchangeError::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn trading_order_keeps_raw_txid_as_id() {
+        let order = TradingOrder {
+            remaining: OrderPrice {
+                price: Nat::from(1000u64),
+                quantity: OrderQuantity::Sell(Nat::from(100u64)),
+            },
+            txid: vec![0, 1, 255],
+            time: Int::from(0),
+        };
+
+        let order = order.into_order(10).unwrap();
+
+        assert_eq!(order.id.as_bytes(), [0, 1, 255]);
+        assert_eq!(order.id.to_hex(), "0001ff");
+    }
+
+    #[tokio::test]
+    async fn fetch_all_pages_fetches_every_page() {
+        let order = |txid: u8| {
+            (
+                vec![txid],
+                TradingOrder {
+                    remaining: OrderPrice {
+                        price: Nat::from(1000u64),
+                        quantity: OrderQuantity::Sell(Nat::from(100u64)),
+                    },
+                    txid: vec![txid],
+                    time: Int::from(0),
+                },
+            )
+        };
+        // Order 2 shifts onto the second page while the pages are being fetched
+        let pages = [vec![1, 2], vec![2, 3], vec![4]];
+        let requested = std::sync::Mutex::new(Vec::new());
+
+        let orders = fetch_all_pages(|page| {
+            requested.lock().unwrap().push(page);
+            let data = pages[page as usize - 1].iter().map(|&t| order(t)).collect();
+            async move {
+                Ok(TrieList {
+                    data,
+                    total: Nat::from(5u64),
+                    total_page: Nat::from(3u64),
+                })
+            }
+        })
+        .await
+        .unwrap();
+
+        let txids: Vec<_> = orders.into_iter().map(|(_, o)| o.txid[0]).collect();
+        assert_eq!(txids, [1, 2, 3, 4]);
+        assert_eq!(*requested.lock().unwrap(), [1, 2, 3]);
+    }
+
+    #[test_case(ExchangeError::Busy("blocked".to_string()), 3; "busy")]
+    #[test_case(ExchangeError::Nonce("expected 2".to_string()), 3; "nonce")]
+    #[test_case(ExchangeError::InsufficientBalance("empty".to_string()), 1; "insufficient balance")]
+    #[test_case(ExchangeError::Network("timeout".to_string()), 1; "network")]
+    #[tokio::test]
+    async fn retry_transient_only_retries_transient_errors(error: ExchangeError, attempts: u32) {
+        let calls = std::sync::atomic::AtomicU32::new(0);
+
+        let result: Result<(), _> = retry_transient(2, Duration::ZERO, || {
+            calls.fetch_add(1, Ordering::Relaxed);
+            let error = error.clone();
+            async move { Err(error) }
+        })
+        .await;
+
+        assert_eq!(result, Err(error));
+        assert_eq!(calls.load(Ordering::Relaxed), attempts);
+    }
+
+    #[tokio::test]
+    async fn retry_transient_returns_first_success() {
+        let calls = std::sync::atomic::AtomicU32::new(0);
+
+        let result = retry_transient(2, Duration::ZERO, || {
+            let call = calls.fetch_add(1, Ordering::Relaxed);
+            async move {
+                if call == 0 {
+                    Err(ExchangeError::Busy("blocked".to_string()))
+                } else {
+                    Ok(call)
+                }
+            }
+        })
+        .await;
+
+        assert_eq!(result, Ok(1));
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn resend_transient_only_resends_transient_failures() {
+        let order = |price| MakeOrderRequest {
+            order_type: OrderType::Bid,
+            price: Price::from_scaled(price),
+            amount: 100,
+            post_only: false,
+        };
+        let resent = std::sync::Mutex::new(Vec::new());
+
+        let results = resend_transient(
+            vec![order(990), order(980), order(970), order(960)],
+            vec![
+                Ok(OrderId::from(vec![1])),
+                Err(ExchangeError::Busy("blocked".to_string())),
+                Err(ExchangeError::InsufficientBalance("empty".to_string())),
+                Err(ExchangeError::Nonce("expected 2".to_string())),
+            ],
+            |order| {
+                resent.lock().unwrap().push(order.price.scaled());
+                async move { Ok(OrderId::from(format!("resent-{}", order.price.scaled()))) }
+            },
+        )
+        .await;
+
+        assert_eq!(
+            results,
+            vec![
+                Ok(OrderId::from(vec![1])),
+                Ok(OrderId::from("resent-980")),
+                Err(ExchangeError::InsufficientBalance("empty".to_string())),
+                Ok(OrderId::from("resent-960")),
+            ]
+        );
+        assert_eq!(*resent.lock().unwrap(), [980, 960]);
+    }
+
+    #[test_case(OrderType::Bid, 1000, true, false; "bid below best ask")]
+    #[test_case(OrderType::Bid, 1010, true, true; "bid at best ask")]
+    #[test_case(OrderType::Bid, 1020, true, true; "bid through best ask")]
+    #[test_case(OrderType::Bid, 1020, false, false; "bid through best ask not post only")]
+    #[test_case(OrderType::Ask, 1000, true, false; "ask above best bid")]
+    #[test_case(OrderType::Ask, 990, true, true; "ask at best bid")]
+    #[test_case(OrderType::Ask, 980, false, false; "ask through best bid not post only")]
+    fn check_post_only_tests(order_type: OrderType, price: u64, post_only: bool, rejected: bool) {
+        let order = MakeOrderRequest {
+            order_type,
+            price: Price::from_scaled(price),
+            amount: 100,
+            post_only,
+        };
+        let best_bid = Some(Price::from_scaled(990));
+        let best_ask = Some(Price::from_scaled(1010));
+
+        let result = check_post_only(&order, best_bid, best_ask);
+
+        assert_eq!(
+            matches!(result, Err(ExchangeError::WouldCross(_))),
+            rejected
+        );
+        assert!(check_post_only(&order, None, None).is_ok());
+    }
+
+    // Encodes the response from candid text, so that it doesn't depend on the Rust type's renames
+    fn decode_make_order_response(text: &str) -> MakeOrderResponse {
+        let bytes = text.parse::<candid::IDLArgs>().unwrap().to_bytes().unwrap();
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    #[test]
+    fn make_order_response_decodes_ok() {
+        let response =
+            decode_make_order_response(r#"(variant { ok = record { txid = blob "\01\02\ff" } })"#);
+
+        assert_eq!(response.into_result(), Ok(OrderId::from(vec![1, 2, 255])));
+    }
+
+    #[test_case("NonceError", ExchangeError::Nonce("failed".to_string()))]
+    #[test_case("InvalidAmount", ExchangeError::Other("InvalidAmount: failed".to_string()))]
+    #[test_case("InsufficientBalance", ExchangeError::InsufficientBalance("failed".to_string()))]
+    #[test_case("TransferException", ExchangeError::Other("TransferException: failed".to_string()))]
+    #[test_case("UnacceptableVolatility", ExchangeError::Volatility("failed".to_string()))]
+    #[test_case("TransactionBlocking", ExchangeError::Busy("failed".to_string()))]
+    #[test_case("UndefinedError", ExchangeError::Other("UndefinedError: failed".to_string()))]
+    fn make_order_response_decodes_each_error_code(code: &str, expected: ExchangeError) {
+        let response = decode_make_order_response(&format!(
+            r#"(variant {{ err = record {{ code = variant {{ {code} }}; message = "failed" }} }})"#
+        ));
+
+        assert_eq!(response.into_result(), Err(expected));
+    }
+
+    #[test]
+    fn make_order_response_rejects_unknown_error_code() {
+        let bytes = r#"(variant { err = record { code = variant { Unknown }; message = "" } })"#
+            .parse::<candid::IDLArgs>()
+            .unwrap()
+            .to_bytes()
+            .unwrap();
+
+        assert!(candid::decode_one::<MakeOrderResponse>(&bytes).is_err());
+    }
+
+    #[test_case("(vec {})", None; "no trades")]
+    #[test_case(
+        r#"(vec {
+            record { 1700000060 : nat; blob "\01"; record { time = 1 }; variant { Buy } };
+            record { 1700000000 : nat; blob "\02"; record { time = 2 }; variant { Sell } };
+        })"#,
+        Some(1_700_000_060_000_000_000);
+        "latest of several trades"
+    )]
+    fn latest_filled_at_tests(text: &str, expected: Option<u64>) {
+        let bytes = text.parse::<candid::IDLArgs>().unwrap().to_bytes().unwrap();
+        let filled: Vec<LatestFilled> = candid::decode_one(&bytes).unwrap();
+
+        assert_eq!(latest_filled_at(filled), Ok(expected));
+    }
+
+    #[test_case("(record { price = 0.05 : float64 })", None; "no volume")]
+    #[test_case(
+        "(record { price = 0.05 : float64; vol24h = record { value0 = 1200 : nat; value1 = 60 : nat } })",
+        Some(1200);
+        "base volume"
+    )]
+    fn stats_response_tests(text: &str, expected: Option<u64>) {
+        let bytes = text.parse::<candid::IDLArgs>().unwrap().to_bytes().unwrap();
+        let response: StatsResponse = candid::decode_one(&bytes).unwrap();
+
+        assert_eq!(response.price, 0.05);
+        assert_eq!(response.vol24h.map(|v| to_u64(v.value0).unwrap()), expected);
+    }
+
+    #[test]
+    fn info_response_converts_to_trading_limits() {
+        let info = InfoResponse {
+            setting: DexSetting {
+                unit_size: Nat::from(1000000u64),
+            },
+        };
+
+        assert_eq!(
+            info.into_trading_limits(10).unwrap(),
+            TradingLimits {
+                min_order_size: Some(1000000),
+                tick_size: Some(Price::from_scaled(10)),
+                lot_size: Some(1000000),
+            }
+        );
+    }
+
+    #[test]
+    fn decode_controllers_returns_every_principal() {
+        let controllers = [Principal::anonymous(), Principal::management_canister()];
+        let bytes = serde_cbor::to_vec(&serde_cbor::Value::Array(
+            controllers
+                .iter()
+                .map(|c| serde_cbor::Value::Bytes(c.as_slice().to_vec()))
+                .collect(),
+        ))
+        .unwrap();
+
+        assert_eq!(decode_controllers(&bytes).unwrap(), controllers);
+        // The replica prefixes the array with the self-describing CBOR tag
+        let tagged = [&[0xd9, 0xd9, 0xf7][..], &bytes].concat();
+        assert_eq!(decode_controllers(&tagged).unwrap(), controllers);
+        assert!(matches!(
+            decode_controllers(&[0xff]),
+            Err(ExchangeError::Decode(_))
+        ));
+    }
+
+    #[test_case(1)]
+    #[test_case(10)]
+    fn price_round_trips_through_trading_order(price_scale: u64) {
+        // A price of 0.05 quote per base, which ICDex reports divided by the price scale
+        let price = 5000000;
+        let order = TradingOrder {
+            remaining: OrderPrice {
+                price: Nat::from(price / price_scale),
+                quantity: OrderQuantity::Sell(Nat::from(100u64)),
+            },
+            txid: vec![1, 2, 3],
+            time: Int::from(0),
+        };
+
+        let order = order.into_order(price_scale).unwrap();
+
+        assert_eq!(order.price, Price::from_scaled(price));
+        assert_eq!(order.price.to_decimal(), 0.05);
+    }
+}
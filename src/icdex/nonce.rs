@@ -0,0 +1,115 @@
+use crate::ExchangeError;
+use std::future::Future;
+use std::sync::Mutex;
+
+/// Tracks the nonce to use for the next order made from the trader canister's account, so that
+/// sequential orders use increasing nonces without querying ICDex before each one
+#[derive(Default)]
+pub(super) struct NonceTracker {
+    next: Mutex<Option<u64>>,
+}
+
+impl NonceTracker {
+    // Returns the next nonce, if known, and advances the tracker past it
+    fn take(&self) -> Option<u64> {
+        let mut next = self.next.lock().unwrap();
+        let nonce = (*next)?;
+        *next = Some(nonce + 1);
+        Some(nonce)
+    }
+
+    fn take_from(&self, nonce: u64) -> u64 {
+        *self.next.lock().unwrap() = Some(nonce + 1);
+        nonce
+    }
+
+    /// Forgets the tracked nonce so that the next order refetches it
+    pub(super) fn reset(&self) {
+        *self.next.lock().unwrap() = None;
+    }
+
+    /// Calls `make` with the next nonce, fetching it first if it isn't known. If the nonce is
+    /// rejected, the current nonce is refetched and `make` is retried once.
+    pub(super) async fn with_nonce<T, Fetch, FetchFut, Make, MakeFut>(
+        &self,
+        fetch: Fetch,
+        make: Make,
+    ) -> Result<T, ExchangeError>
+    where
+        Fetch: Fn() -> FetchFut,
+        FetchFut: Future<Output = Result<u64, ExchangeError>>,
+        Make: Fn(u64) -> MakeFut,
+        MakeFut: Future<Output = Result<T, ExchangeError>>,
+    {
+        let nonce = match self.take() {
+            Some(nonce) => nonce,
+            None => self.take_from(fetch().await?),
+        };
+
+        let result = match make(nonce).await {
+            Err(ExchangeError::Nonce(_)) => {
+                let nonce = self.take_from(fetch().await?);
+                make(nonce).await
+            }
+            result => result,
+        };
+
+        // A failed order may or may not have consumed its nonce, so refetch it next time
+        if result.is_err() {
+            self.reset();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    // Simulates ICDex's nonce for an account which is also being used by another process
+    struct Account {
+        nonce: AtomicU64,
+        fetches: AtomicU64,
+    }
+
+    impl Account {
+        async fn fetch(&self) -> Result<u64, ExchangeError> {
+            self.fetches.fetch_add(1, Ordering::Relaxed);
+            Ok(self.nonce.load(Ordering::Relaxed))
+        }
+
+        async fn make(&self, nonce: u64) -> Result<u64, ExchangeError> {
+            match self.nonce.compare_exchange(
+                nonce,
+                nonce + 1,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => Ok(nonce),
+                Err(expected) => Err(ExchangeError::Nonce(format!("Expected {expected}"))),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn uses_increasing_nonces_and_retries_after_collision() {
+        let account = Account {
+            nonce: AtomicU64::new(5),
+            fetches: AtomicU64::new(0),
+        };
+        let tracker = NonceTracker::default();
+        let make = || tracker.with_nonce(|| account.fetch(), |n| account.make(n));
+
+        assert_eq!(make().await, Ok(5));
+        assert_eq!(make().await, Ok(6));
+        assert_eq!(account.fetches.load(Ordering::Relaxed), 1);
+
+        // Another order takes the next nonce, so the tracked nonce collides
+        account.nonce.store(8, Ordering::Relaxed);
+
+        assert_eq!(make().await, Ok(8));
+        assert_eq!(make().await, Ok(9));
+        assert_eq!(account.fetches.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,599 @@
+use crate::{
+    query, update, Balances, CancelOrderRequest, Exchange, ExchangeError, MakeOrderRequest, Order,
+    OrderId, OrderType, Price, Stats,
+};
+use async_trait::async_trait;
+use candid::{CandidType, Int, Nat, Principal};
+use ic_agent::Agent;
+use serde::Deserialize;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// An ICPSwap v3 pool, trading with the funds which the agent's principal holds in the pool.
+///
+/// ICPSwap is a concentrated liquidity AMM, so each order is a single-tick-spacing position just
+/// outside the current price, registered as a limit order so that the pool removes it once the
+/// price crosses it. Funds must already be deposited into the pool; orders are made from, and
+/// cancelled back into, the principal's unused balance. The pool has no order book, so `Stats`
+/// never has a best bid or ask.
+pub struct ICPSwap {
+    agent: Agent,
+    pool_canister_id: Principal,
+    principal: Principal,
+    base_is_token0: bool,
+    inter_order_delay: Duration,
+}
+
+impl ICPSwap {
+    /// `principal` must be the principal of the agent's identity. `base_is_token0` says which of
+    /// the pool's tokens is the base token.
+    pub fn new(
+        agent: Agent,
+        pool_canister_id: Principal,
+        principal: Principal,
+        base_is_token0: bool,
+    ) -> Self {
+        ICPSwap {
+            agent,
+            pool_canister_id,
+            principal,
+            base_is_token0,
+            inter_order_delay: Duration::from_secs(1),
+        }
+    }
+
+    /// Sets how long to wait between each order made or cancelled (defaults to 1 second)
+    pub fn with_inter_order_delay(mut self, delay: Duration) -> Self {
+        self.inter_order_delay = delay;
+        self
+    }
+
+    async fn metadata(&self) -> Result<PoolMetadata, ExchangeError> {
+        let response: PoolResult<PoolMetadata> =
+            query(&self.agent, &self.pool_canister_id, "metadata", ()).await?;
+
+        response.into_result()
+    }
+
+    async fn open_orders(&self) -> Result<Vec<Order>, ExchangeError> {
+        let response: PoolResult<UserLimitOrders> = query(
+            &self.agent,
+            &self.pool_canister_id,
+            "getUserLimitOrders",
+            (self.principal,),
+        )
+        .await?;
+        let limit_orders = response.into_result()?;
+
+        let mut orders = Vec::new();
+        let upper = limit_orders
+            .upper_limit_orders
+            .into_iter()
+            .map(|o| (true, o));
+        let lower = limit_orders
+            .lower_limit_orders
+            .into_iter()
+            .map(|o| (false, o));
+        for (sells_token0, (limit_order, position_id)) in upper.chain(lower) {
+            let response: PoolResult<UserPosition> = query(
+                &self.agent,
+                &self.pool_canister_id,
+                "getUserPosition",
+                (position_id.clone(),),
+            )
+            .await?;
+
+            match response
+                .into_result()
+                .and_then(|p| self.to_order(sells_token0, position_id.to_string().into(), &p))
+            {
+                Ok(order) => orders.push(Order {
+                    placed_at: u64::try_from(&limit_order.timestamp.0).ok(),
+                    ..order
+                }),
+                Err(error) => warn!(%error, "Skipping open order"),
+            }
+        }
+        Ok(orders)
+    }
+
+    // An order which sells token0 is a position above the current price, and one which sells
+    // token1 is a position below it. The price of the order is the edge of the position nearest
+    // the current price.
+    fn to_order(
+        &self,
+        sells_token0: bool,
+        id: OrderId,
+        position: &UserPosition,
+    ) -> Result<Order, ExchangeError> {
+        let tick_lower = to_i32(&position.tick_lower)?;
+        let tick_upper = to_i32(&position.tick_upper)?;
+        let liquidity = position
+            .liquidity
+            .0
+            .to_string()
+            .parse::<f64>()
+            .unwrap_or(0.0);
+
+        let (tick, amount_sold) = if sells_token0 {
+            (tick_lower, amount0(liquidity, tick_lower, tick_upper))
+        } else {
+            (tick_upper, amount1(liquidity, tick_lower, tick_upper))
+        };
+        let price = tick_to_price(tick, self.base_is_token0);
+
+        // Asks sell the base token, whereas bids sell the quote token to buy the base token
+        let order_type = if sells_token0 == self.base_is_token0 {
+            OrderType::Ask
+        } else {
+            OrderType::Bid
+        };
+        let amount = match order_type {
+            OrderType::Ask => amount_sold,
+            OrderType::Bid => price.amount_for_notional(amount_sold),
+        };
+
+        Ok(Order {
+            order_type,
+            id,
+            price,
+            amount,
+            placed_at: None,
+        })
+    }
+
+    async fn make_order(
+        &self,
+        metadata: &PoolMetadata,
+        order: &MakeOrderRequest,
+    ) -> Result<OrderId, ExchangeError> {
+        let tick_spacing = tick_spacing(&metadata.fee)?;
+        let sells_token0 = (order.order_type == OrderType::Ask) == self.base_is_token0;
+        let amount_sold = match order.order_type {
+            OrderType::Ask => order.amount,
+            OrderType::Bid => order.price.notional(order.amount),
+        };
+        let (tick_lower, tick_upper) = order_range(
+            price_to_tick(order.price, self.base_is_token0),
+            tick_spacing,
+            sells_token0,
+        );
+        let (amount0, amount1) = if sells_token0 {
+            (amount_sold, 0)
+        } else {
+            (0, amount_sold)
+        };
+
+        let args = MintArgs {
+            token0: metadata.token0.address.clone(),
+            token1: metadata.token1.address.clone(),
+            fee: metadata.fee.clone(),
+            tick_lower: Int::from(tick_lower),
+            tick_upper: Int::from(tick_upper),
+            amount0_desired: amount0.to_string(),
+            amount1_desired: amount1.to_string(),
+        };
+        let response: PoolResult<Nat> =
+            update(&self.agent, &self.pool_canister_id, "mint", (args,)).await?;
+        let position_id = response.into_result()?;
+
+        let tick_limit = if sells_token0 { tick_upper } else { tick_lower };
+        if let Err(error) = self.add_limit_order(&position_id, tick_limit).await {
+            // The position holds the order's funds but isn't a limit order, so it would never show
+            // up in the open orders and nothing would ever cancel it
+            return Err(match self.close_position(&position_id).await {
+                Ok(()) => error,
+                Err(close_error) => {
+                    error!(%position_id, %close_error, "Failed to close orphaned position");
+                    ExchangeError::Other(format!(
+                        "{error}. Position {position_id} was minted but couldn't be closed: \
+                         {close_error}"
+                    ))
+                }
+            });
+        }
+
+        info!(?order, %position_id, "Made order");
+        Ok(position_id.to_string().into())
+    }
+
+    async fn add_limit_order(
+        &self,
+        position_id: &Nat,
+        tick_limit: i32,
+    ) -> Result<(), ExchangeError> {
+        let args = LimitOrderArgs {
+            position_id: position_id.clone(),
+            tick_limit: Int::from(tick_limit),
+        };
+        let response: PoolResult<bool> = update(
+            &self.agent,
+            &self.pool_canister_id,
+            "addLimitOrder",
+            (args,),
+        )
+        .await?;
+        response.into_result().map(|_| ())
+    }
+
+    // Removes all of a position's liquidity, returning its funds to the principal's unused balance
+    async fn close_position(&self, position_id: &Nat) -> Result<(), ExchangeError> {
+        let response: PoolResult<UserPosition> = query(
+            &self.agent,
+            &self.pool_canister_id,
+            "getUserPosition",
+            (position_id.clone(),),
+        )
+        .await?;
+        let position = response.into_result()?;
+
+        let args = DecreaseLiquidityArgs {
+            position_id: position_id.clone(),
+            liquidity: position.liquidity.0.to_string(),
+        };
+        let response: PoolResult<DecreasedLiquidity> = update(
+            &self.agent,
+            &self.pool_canister_id,
+            "decreaseLiquidity",
+            (args,),
+        )
+        .await?;
+        response.into_result()?;
+
+        info!(%position_id, "Closed position after failing to add it as a limit order");
+        Ok(())
+    }
+
+    async fn cancel_order(&self, order: CancelOrderRequest) -> Result<OrderId, ExchangeError> {
+        // Position ids are held as their decimal text
+        let position_id: Nat = order
+            .id
+            .as_text()
+            .and_then(|id| id.parse().ok())
+            .ok_or_else(|| ExchangeError::Decode(format!("Invalid order id: {}", order.id)))?;
+
+        let response: PoolResult<RemovedLimitOrder> = update(
+            &self.agent,
+            &self.pool_canister_id,
+            "removeLimitOrder",
+            (position_id,),
+        )
+        .await?;
+        response.into_result()?;
+
+        Ok(order.id)
+    }
+}
+
+#[async_trait]
+impl Exchange for ICPSwap {
+    async fn stats(&self) -> Result<Stats, ExchangeError> {
+        let (metadata, open_orders) =
+            futures::future::try_join(self.metadata(), self.open_orders()).await?;
+
+        Ok(Stats {
+            latest_price: sqrt_price_x96_to_price(&metadata.sqrt_price_x96, self.base_is_token0),
+            latest_trade_at: None,
+            volume_24h: None,
+            best_bid: None,
+            best_ask: None,
+            open_orders,
+        })
+    }
+
+    async fn balances(&self) -> Result<Balances, ExchangeError> {
+        let response: PoolResult<UnusedBalance> = query(
+            &self.agent,
+            &self.pool_canister_id,
+            "getUserUnusedBalance",
+            (self.principal,),
+        )
+        .await?;
+        let balance = response.into_result()?;
+        let (base, quote) = if self.base_is_token0 {
+            (balance.balance0, balance.balance1)
+        } else {
+            (balance.balance1, balance.balance0)
+        };
+
+        Ok(Balances {
+            base: to_u64(base)?,
+            quote: to_u64(quote)?,
+        })
+    }
+
+    async fn make_orders(
+        &self,
+        orders: Vec<MakeOrderRequest>,
+    ) -> Vec<Result<OrderId, ExchangeError>> {
+        if orders.is_empty() {
+            return Vec::new();
+        }
+        let metadata = match self.metadata().await {
+            Ok(metadata) => metadata,
+            Err(error) => return orders.iter().map(|_| Err(error.clone())).collect(),
+        };
+
+        let mut results = Vec::new();
+        for order in orders {
+            results.push(self.make_order(&metadata, &order).await);
+            tokio::time::sleep(self.inter_order_delay).await;
+        }
+        results
+    }
+
+    async fn cancel_orders(
+        &self,
+        orders: Vec<CancelOrderRequest>,
+    ) -> Vec<Result<OrderId, ExchangeError>> {
+        let mut results = Vec::new();
+        for order in orders {
+            results.push(self.cancel_order(order).await);
+            tokio::time::sleep(self.inter_order_delay).await;
+        }
+        results
+    }
+}
+
+const TICK_BASE: f64 = 1.0001;
+
+// Pool prices are the amount of token1 per unit of token0, whereas `Price` is the amount of the
+// quote token per unit of the base token
+fn pool_price_to_price(pool_price: f64, base_is_token0: bool) -> Price {
+    Price::from_decimal(if base_is_token0 {
+        pool_price
+    } else {
+        1.0 / pool_price
+    })
+}
+
+fn sqrt_price_x96_to_price(sqrt_price_x96: &Nat, base_is_token0: bool) -> Price {
+    let sqrt_price = sqrt_price_x96.0.to_string().parse::<f64>().unwrap_or(0.0) / 2f64.powi(96);
+    pool_price_to_price(sqrt_price * sqrt_price, base_is_token0)
+}
+
+fn tick_to_price(tick: i32, base_is_token0: bool) -> Price {
+    pool_price_to_price(TICK_BASE.powi(tick), base_is_token0)
+}
+
+// Returns the tick nearest to the price, in terms of the pool price
+fn price_to_tick(price: Price, base_is_token0: bool) -> i32 {
+    let pool_price = if base_is_token0 {
+        price.to_decimal()
+    } else {
+        1.0 / price.to_decimal()
+    };
+    (pool_price.ln() / TICK_BASE.ln()).round() as i32
+}
+
+// The position for an order at `tick`, which is rounded away from the current price onto the tick
+// spacing so that the whole position is on the correct side of the price
+fn order_range(tick: i32, tick_spacing: i32, sells_token0: bool) -> (i32, i32) {
+    if sells_token0 {
+        let lower = tick.div_euclid(tick_spacing) * tick_spacing
+            + if tick.rem_euclid(tick_spacing) == 0 {
+                0
+            } else {
+                tick_spacing
+            };
+        (lower, lower + tick_spacing)
+    } else {
+        let upper = tick.div_euclid(tick_spacing) * tick_spacing;
+        (upper - tick_spacing, upper)
+    }
+}
+
+// The amount of token0 held by a position entirely above the current price
+fn amount0(liquidity: f64, tick_lower: i32, tick_upper: i32) -> u64 {
+    let sqrt_lower = TICK_BASE.powf(tick_lower as f64 / 2.0);
+    let sqrt_upper = TICK_BASE.powf(tick_upper as f64 / 2.0);
+    (liquidity * (1.0 / sqrt_lower - 1.0 / sqrt_upper)) as u64
+}
+
+// The amount of token1 held by a position entirely below the current price
+fn amount1(liquidity: f64, tick_lower: i32, tick_upper: i32) -> u64 {
+    let sqrt_lower = TICK_BASE.powf(tick_lower as f64 / 2.0);
+    let sqrt_upper = TICK_BASE.powf(tick_upper as f64 / 2.0);
+    (liquidity * (sqrt_upper - sqrt_lower)) as u64
+}
+
+// ICPSwap uses the same fee tiers as Uniswap v3
+fn tick_spacing(fee: &Nat) -> Result<i32, ExchangeError> {
+    match to_u64(fee.clone())? {
+        500 => Ok(10),
+        3000 => Ok(60),
+        10000 => Ok(200),
+        fee => Err(ExchangeError::Unsupported(format!(
+            "Unknown fee tier: {fee}"
+        ))),
+    }
+}
+
+fn to_i32(value: &Int) -> Result<i32, ExchangeError> {
+    i32::try_from(&value.0).map_err(|_| ExchangeError::Decode(format!("Invalid tick: {value}")))
+}
+
+fn to_u64(value: Nat) -> Result<u64, ExchangeError> {
+    u64::try_from(&value.0).map_err(|_| ExchangeError::Decode(format!("Value too large: {value}")))
+}
+
+#[derive(CandidType, Deserialize)]
+enum PoolResult<T> {
+    #[serde(rename = "ok")]
+    Ok(T),
+    #[serde(rename = "err")]
+    Err(PoolError),
+}
+
+impl<T> PoolResult<T> {
+    fn into_result(self) -> Result<T, ExchangeError> {
+        match self {
+            PoolResult::Ok(value) => Ok(value),
+            PoolResult::Err(error) => Err(error.into()),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum PoolError {
+    CommonError,
+    InternalError(String),
+    UnsupportedToken(String),
+    InsufficientFunds,
+}
+
+impl From<PoolError> for ExchangeError {
+    fn from(value: PoolError) -> Self {
+        match value {
+            PoolError::InsufficientFunds => {
+                ExchangeError::InsufficientBalance("Insufficient funds".to_string())
+            }
+            PoolError::InternalError(message) | PoolError::UnsupportedToken(message) => {
+                ExchangeError::Other(message)
+            }
+            PoolError::CommonError => ExchangeError::Other("Common error".to_string()),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+struct PoolMetadata {
+    fee: Nat,
+    #[serde(rename = "sqrtPriceX96")]
+    sqrt_price_x96: Nat,
+    token0: Token,
+    token1: Token,
+}
+
+#[derive(CandidType, Deserialize)]
+struct Token {
+    address: String,
+}
+
+#[derive(CandidType, Deserialize)]
+struct UserLimitOrders {
+    #[serde(rename = "upperLimitOrders")]
+    upper_limit_orders: Vec<(LimitOrderKey, Nat)>,
+    #[serde(rename = "lowerLimitOrders")]
+    lower_limit_orders: Vec<(LimitOrderKey, Nat)>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LimitOrderKey {
+    // Nanoseconds since the Unix epoch
+    timestamp: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+struct UserPosition {
+    #[serde(rename = "tickLower")]
+    tick_lower: Int,
+    #[serde(rename = "tickUpper")]
+    tick_upper: Int,
+    liquidity: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+struct UnusedBalance {
+    balance0: Nat,
+    balance1: Nat,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct MintArgs {
+    token0: String,
+    token1: String,
+    fee: Nat,
+    #[serde(rename = "tickLower")]
+    tick_lower: Int,
+    #[serde(rename = "tickUpper")]
+    tick_upper: Int,
+    #[serde(rename = "amount0Desired")]
+    amount0_desired: String,
+    #[serde(rename = "amount1Desired")]
+    amount1_desired: String,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct LimitOrderArgs {
+    #[serde(rename = "positionId")]
+    position_id: Nat,
+    #[serde(rename = "tickLimit")]
+    tick_limit: Int,
+}
+
+#[derive(CandidType, Deserialize)]
+struct RemovedLimitOrder {
+    amount0: Nat,
+    amount1: Nat,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct DecreaseLiquidityArgs {
+    #[serde(rename = "positionId")]
+    position_id: Nat,
+    liquidity: String,
+}
+
+#[derive(CandidType, Deserialize)]
+struct DecreasedLiquidity {
+    amount0: Nat,
+    amount1: Nat,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(0, true, 100000000)]
+    #[test_case(0, false, 100000000)]
+    #[test_case(6932, true, 200003632)]
+    #[test_case(6932, false, 49999092)]
+    #[test_case(-6932, true, 49999092)]
+    fn tick_to_price_tests(tick: i32, base_is_token0: bool, expected: u64) {
+        assert_eq!(
+            tick_to_price(tick, base_is_token0),
+            Price::from_scaled(expected)
+        );
+    }
+
+    #[test_case(-46055, true)]
+    #[test_case(0, true)]
+    #[test_case(6932, true)]
+    #[test_case(6932, false)]
+    #[test_case(23028, false)]
+    fn price_to_tick_round_trips(tick: i32, base_is_token0: bool) {
+        let price = tick_to_price(tick, base_is_token0);
+
+        assert_eq!(price_to_tick(price, base_is_token0), tick);
+    }
+
+    #[test]
+    fn sqrt_price_x96_at_tick_zero_is_one() {
+        let sqrt_price_x96 = Nat::from(1u128 << 96);
+
+        assert_eq!(
+            sqrt_price_x96_to_price(&sqrt_price_x96, true),
+            Price::from_scaled(100000000)
+        );
+    }
+
+    #[test_case(100, 60, true, (120, 180))]
+    #[test_case(120, 60, true, (120, 180))]
+    #[test_case(-100, 60, true, (-60, 0))]
+    #[test_case(100, 60, false, (0, 60))]
+    #[test_case(120, 60, false, (60, 120))]
+    #[test_case(-100, 60, false, (-180, -120))]
+    fn order_range_tests(tick: i32, tick_spacing: i32, sells_token0: bool, expected: (i32, i32)) {
+        assert_eq!(order_range(tick, tick_spacing, sells_token0), expected);
+    }
+
+    #[test]
+    fn position_amounts_match_liquidity() {
+        // Around tick zero, a position one tick spacing wide holds roughly
+        // liquidity * spacing / 20000 of either token
+        assert_eq!(amount0(1000000000.0, 0, 60), 2995354);
+        assert_eq!(amount1(1000000000.0, -60, 0), 2995354);
+    }
+}
@@ -1,330 +1,3784 @@
 use async_trait::async_trait;
-use candid::utils::ArgumentEncoder;
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
 use candid::{CandidType, Principal};
-use chrono::Local;
-use ic_agent::Agent;
+use config::ConfigFile;
+use events::EventBuffer;
+use ic_agent::{Agent, AgentError};
 use itertools::Itertools;
+use persistence::OrderState;
+use pnl::Position;
+use rand::Rng;
 use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::cmp::Reverse;
 use std::collections::btree_map::Entry::Occupied;
-use std::collections::{BTreeMap, HashSet};
-use std::fmt::Debug;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{Debug, Display, Formatter};
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
+mod backtest;
+mod clock;
+mod config;
+mod dry_run;
+mod events;
+mod feed;
 mod icdex;
-pub use icdex::ICDex;
+mod icpswap;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+mod observe_only;
+mod order_id;
+mod persistence;
+mod pnl;
+mod price;
+mod rate_limit;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
+pub use backtest::{backtest, BacktestError, BacktestReport};
+pub use clock::{Clock, SystemClock};
+pub use config::{
+    Config, ConfigBuilder, ConfigError, InventorySkew, MaxDistance, OrderSizing, PriceAnchor,
+    QuoteSide, Spacing, VolumeScaling,
+};
+pub use dry_run::DryRun;
+pub use events::{Decision, Event, EventSink, NoopEventSink};
+pub use feed::FeedHandle;
+pub use icdex::{Dip20Ledger, ICDex, Icrc1Ledger, Ledger};
+pub use icpswap::ICPSwap;
+pub use observe_only::ObserveOnly;
+pub use order_id::OrderId;
+pub use price::{Price, RoundingMode};
+pub use rate_limit::RateLimiter;
 
 #[async_trait]
-pub trait Exchange {
-    async fn stats(&self) -> Result<Stats, String>;
-    async fn make_orders(&self, orders: Vec<MakeOrderRequest>) -> Result<(), String>;
-    async fn cancel_orders(&self, orders: Vec<CancelOrderRequest>) -> Result<(), String>;
-}
-
-pub struct Config {
-    pub increment: u64,
-    pub order_size: u64,
-    pub min_order_size: u64,
-    pub max_buy_price: u64,
-    pub min_sell_price: u64,
-    pub min_orders_per_direction: u64,
-    pub max_orders_per_direction: u64,
-    pub max_orders_to_make_per_iteration: usize,
-    pub max_orders_to_cancel_per_iteration: usize,
-    pub iteration_interval: Duration,
-}
-
-#[derive(Debug)]
+pub trait Exchange: Sync {
+    async fn stats(&self) -> Result<Stats, ExchangeError>;
+    async fn balances(&self) -> Result<Balances, ExchangeError>;
+    /// Makes each order, returning one result per request, in the same order as the requests.
+    /// Successful results hold the id of the order (or the transaction which made it).
+    async fn make_orders(
+        &self,
+        orders: Vec<MakeOrderRequest>,
+    ) -> Vec<Result<OrderId, ExchangeError>>;
+    /// Cancels each order, returning one result per request, in the same order as the requests.
+    /// Successful results hold the id of the cancelled order.
+    async fn cancel_orders(
+        &self,
+        orders: Vec<CancelOrderRequest>,
+    ) -> Vec<Result<OrderId, ExchangeError>>;
+
+    /// Changes the price and amount of each order in place, returning one result per request, in
+    /// the same order as the requests. Successful results hold the id of the order once replaced,
+    /// which may differ from its original id. Exchanges which can't amend orders can rely on the
+    /// default, which fails every request with `ExchangeError::Unsupported` so that the orders are
+    /// cancelled and made again instead.
+    async fn replace_orders(
+        &self,
+        orders: Vec<ReplaceOrderRequest>,
+    ) -> Vec<Result<OrderId, ExchangeError>> {
+        orders
+            .iter()
+            .map(|_| {
+                Err(ExchangeError::Unsupported(
+                    "Replacing orders is not supported".to_string(),
+                ))
+            })
+            .collect()
+    }
+
+    /// The limits which the exchange places on orders. Exchanges which don't publish them can rely
+    /// on the default, which reports no limits.
+    async fn trading_limits(&self) -> Result<TradingLimits, ExchangeError> {
+        Ok(TradingLimits::default())
+    }
+
+    /// Cancels every open order, returning the first error if any couldn't be cancelled.
+    /// Exchanges which can cancel all of a trader's orders in a single call should override this.
+    async fn cancel_all_orders(&self) -> Result<(), ExchangeError> {
+        let stats = self.stats().await?;
+
+        let orders_to_cancel = stats
+            .open_orders
+            .into_iter()
+            .map(|o| CancelOrderRequest { id: o.id })
+            .collect();
+
+        self.cancel_orders(orders_to_cancel)
+            .await
+            .into_iter()
+            .try_for_each(|r| r.map(|_| ()))
+    }
+}
+
+/// A source of prices other than the exchange itself, such as another exchange or an oracle
+#[async_trait]
+pub trait PriceSource: Sync {
+    async fn latest_price(&self) -> Result<Price, ExchangeError>;
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ExchangeError {
+    /// The exchange could not be reached or the call was rejected
+    Network(String),
+    /// The request couldn't be sent or its response couldn't be received, eg. because the
+    /// connection failed
+    Transport(String),
+    /// The account does not hold enough funds to make the order
+    InsufficientBalance(String),
+    /// The exchange refused the order because the market is too volatile
+    Volatility(String),
+    /// The exchange's response could not be understood
+    Decode(String),
+    /// The exchange does not support the requested operation
+    Unsupported(String),
+    /// The order used a nonce which the exchange had already seen or wasn't expecting
+    Nonce(String),
+    /// The exchange is still processing a previous request from the account
+    Busy(String),
+    /// The order was post-only but would have traded immediately against the opposite side of
+    /// the book
+    WouldCross(String),
+    /// The order wasn't sent because the book moved during the iteration, so that it would now
+    /// trade further through the opposite side than `Config::max_slippage` allows
+    Slippage(String),
+    /// The exchange can't be used at all, eg. because the canister doesn't exist or the identity
+    /// isn't accepted, so retrying won't help
+    Fatal(String),
+    Other(String),
+}
+
+impl Display for ExchangeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExchangeError::Network(msg) => write!(f, "Network error: {msg}"),
+            ExchangeError::Transport(msg) => write!(f, "Transport error: {msg}"),
+            ExchangeError::InsufficientBalance(msg) => write!(f, "Insufficient balance: {msg}"),
+            ExchangeError::Volatility(msg) => write!(f, "Unacceptable volatility: {msg}"),
+            ExchangeError::Decode(msg) => write!(f, "Decode error: {msg}"),
+            ExchangeError::Unsupported(msg) => write!(f, "Unsupported: {msg}"),
+            ExchangeError::Nonce(msg) => write!(f, "Nonce error: {msg}"),
+            ExchangeError::Busy(msg) => write!(f, "Busy: {msg}"),
+            ExchangeError::WouldCross(msg) => write!(f, "Would cross the book: {msg}"),
+            ExchangeError::Slippage(msg) => write!(f, "Slippage too high: {msg}"),
+            ExchangeError::Fatal(msg) => write!(f, "Fatal error: {msg}"),
+            ExchangeError::Other(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl ExchangeError {
+    /// Whether the same request might succeed if retried shortly afterwards
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            ExchangeError::Network(_) | ExchangeError::Transport(_)
+        )
+    }
+
+    /// Whether the bot should stop rather than carry on with the next iteration
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, ExchangeError::Fatal(_))
+    }
+}
+
+impl std::error::Error for ExchangeError {}
+
+/// Why the bot stopped before it was asked to
+#[derive(Clone, Debug, PartialEq)]
+pub enum RunError {
+    /// The config was invalid, so no iterations were run
+    Config(ConfigError),
+    /// An iteration failed with an error which retrying won't fix
+    Fatal(ExchangeError),
+}
+
+impl Display for RunError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RunError::Config(error) => write!(f, "Invalid config: {error}"),
+            RunError::Fatal(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl std::error::Error for RunError {}
+
+impl From<ConfigError> for RunError {
+    fn from(error: ConfigError) -> Self {
+        RunError::Config(error)
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct Stats {
-    latest_price: u64,
-    open_orders: Vec<Order>,
+    /// The price of the most recent trade
+    pub latest_price: Price,
+    /// When the most recent trade happened, in nanoseconds since the Unix epoch, if known
+    pub latest_trade_at: Option<u64>,
+    /// The amount of the base token traded in the last 24 hours, if known
+    pub volume_24h: Option<u64>,
+    /// The highest bid in the order book, if there are any bids
+    pub best_bid: Option<Price>,
+    /// The lowest ask in the order book, if there are any asks
+    pub best_ask: Option<Price>,
+    /// The trader's own open orders
+    pub open_orders: Vec<Order>,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TradingLimits {
+    /// The smallest amount of the base token which an order may be for
+    pub min_order_size: Option<u64>,
+    /// The exchange's minimum price step
+    pub tick_size: Option<Price>,
+    /// Order amounts must be a multiple of this
+    pub lot_size: Option<u64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Balances {
+    pub base: u64,
+    pub quote: u64,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum OrderType {
     Bid,
     Ask,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Order {
-    order_type: OrderType,
-    id: String,
-    price: u64,
-    amount: u64,
+    pub order_type: OrderType,
+    pub id: OrderId,
+    pub price: Price,
+    pub amount: u64,
+    /// When the order was placed, in nanoseconds since the Unix epoch, if known
+    pub placed_at: Option<u64>,
 }
 
-#[derive(Clone, Debug)]
+impl Order {
+    /// The signed distance of the order's price from `mid`, measured in `increment`s. Orders
+    /// priced above `mid` have a positive distance and those below it a negative one.
+    pub fn distance_from_mid(&self, mid: Price, increment: Price) -> f64 {
+        (self.price.scaled() as f64 - mid.scaled() as f64) / increment.scaled() as f64
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct MakeOrderRequest {
-    order_type: OrderType,
-    price: u64,
-    amount: u64,
+    pub order_type: OrderType,
+    pub price: Price,
+    pub amount: u64,
+    /// Whether the order must only rest on the book. If it would trade immediately against the
+    /// opposite side of the book, the exchange should reject it rather than let it take liquidity.
+    #[serde(default)]
+    pub post_only: bool,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct CancelOrderRequest {
-    id: String,
+    pub id: OrderId,
 }
 
-pub async fn run<E: Exchange>(exchange: &E, config: &Config) {
-    loop {
-        log("Starting iteration");
-        if let Err(msg) = run_once(exchange, config).await {
-            log(&format!("Error: {msg}"));
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReplaceOrderRequest {
+    pub id: OrderId,
+    pub order_type: OrderType,
+    pub new_price: Price,
+    pub new_amount: u64,
+}
+
+impl ReplaceOrderRequest {
+    /// The order to make if the exchange can't replace the existing order
+    pub fn to_make(&self) -> MakeOrderRequest {
+        MakeOrderRequest {
+            order_type: self.order_type,
+            price: self.new_price,
+            amount: self.new_amount,
+            post_only: true,
         }
+    }
+}
 
-        sleep(config.iteration_interval).await;
+pub async fn run<E: Exchange>(exchange: &E, config: &Config) -> Result<(), RunError> {
+    run_until(exchange, None, config, CancellationToken::new()).await
+}
+
+/// Runs each market concurrently, each in its own task
+pub async fn run_many<E: Exchange + Send + Sync + 'static>(
+    markets: Vec<(E, Config)>,
+) -> Result<(), ConfigError> {
+    run_many_until(markets, CancellationToken::new()).await
+}
+
+/// Runs each market in its own task until the token is cancelled. Each market iterates
+/// independently at its own `iteration_interval`. If a market's task panics it is restarted after
+/// its `iteration_interval`, and if it stops with a fatal error it stays stopped, leaving the other
+/// markets unaffected either way.
+pub async fn run_many_until<E: Exchange + Send + Sync + 'static>(
+    markets: Vec<(E, Config)>,
+    token: CancellationToken,
+) -> Result<(), ConfigError> {
+    for (_, config) in &markets {
+        config.validate()?;
     }
+
+    let supervisors = markets.into_iter().enumerate().map(|(index, market)| {
+        let market = Arc::new(market);
+        let token = token.clone();
+        async move {
+            loop {
+                let task_market = market.clone();
+                let task_token = token.clone();
+                let task = async move {
+                    let (exchange, config) = &*task_market;
+                    run_until(exchange, None, config, task_token).await
+                };
+                #[cfg(feature = "metrics")]
+                let task = metrics::MARKET.scope(index, task);
+                let task = tokio::spawn(task.instrument(info_span!("market", index)));
+
+                match task.await {
+                    Ok(Ok(())) => break,
+                    Ok(Err(error)) => {
+                        error!(market = index, %error, "Market stopped");
+                        break;
+                    }
+                    Err(error) => {
+                        error!(market = index, %error, "Market task failed. Restarting");
+                        tokio::select! {
+                            _ = token.cancelled() => break,
+                            _ = sleep(market.1.iteration_interval) => {}
+                        }
+                    }
+                }
+            }
+        }
+    });
+
+    futures::future::join_all(supervisors).await;
+    Ok(())
 }
 
-pub fn log(message: &str) {
-    println!("{} {message}", Local::now().format("%Y-%m-%d %H:%M:%S"));
+/// Runs iterations until the token is cancelled or the config's `kill_file` appears. An iteration
+/// which is in progress when the token is cancelled is allowed to complete. Iterations which fail
+/// are logged and the next one is run as usual, unless the error is fatal, in which case the open
+/// orders are cancelled (if `cancel_orders_on_shutdown` is set) and the error is returned.
+///
+/// If a price source is provided, its price is used in place of the exchange's latest price.
+pub async fn run_until<E: Exchange>(
+    exchange: &E,
+    price_source: Option<&dyn PriceSource>,
+    config: &Config,
+    token: CancellationToken,
+) -> Result<(), RunError> {
+    run_with_events_until(exchange, price_source, config, &NoopEventSink, token).await
 }
 
-async fn run_once<E: Exchange>(exchange: &E, config: &Config) -> Result<(), String> {
-    let stats = exchange.stats().await?;
+/// Like `run_until`, but publishes an `Event` to `events` whenever orders are made, cancelled or
+/// filled, and each time the price is updated
+pub async fn run_with_events_until<E: Exchange>(
+    exchange: &E,
+    price_source: Option<&dyn PriceSource>,
+    config: &Config,
+    events: &dyn EventSink,
+    token: CancellationToken,
+) -> Result<(), RunError> {
+    run_with_clock_until(exchange, price_source, config, events, &SystemClock, token).await
+}
 
-    let (required_orders, optional_orders) = build_orders(stats.latest_price, config);
+/// Like `run_with_events_until`, but reads the time from `clock` rather than the system clock, for
+/// order TTLs, the daily volume limit, stale price checks and the volatility pause. Useful for
+/// simulations and tests which need to control how time passes.
+pub async fn run_with_clock_until<E: Exchange>(
+    exchange: &E,
+    price_source: Option<&dyn PriceSource>,
+    config: &Config,
+    events: &dyn EventSink,
+    clock: &dyn Clock,
+    token: CancellationToken,
+) -> Result<(), RunError> {
+    config.validate()?;
 
-    let orders_to_cancel = calculate_orders_to_cancel(
-        &stats.open_orders,
-        Vec::from_iter(required_orders.clone().into_iter().chain(optional_orders)),
-        stats.latest_price,
-        config.max_orders_to_cancel_per_iteration,
-        config.increment,
-    );
+    run_loop(exchange, price_source, config, None, events, clock, token)
+        .await
+        .map_err(RunError::Fatal)
+}
 
-    let orders_to_make = calculate_orders_to_make(
-        &stats.open_orders,
-        required_orders,
-        config.min_order_size,
-        config.max_orders_to_make_per_iteration,
-        config.increment,
-    );
+/// Like `run_until`, but reads the config from a file (see `Config::from_file`). The file is
+/// checked before each iteration and, if it has been modified, the new config is used from that
+/// iteration onwards. If the modified config is invalid, the error is logged and the previous
+/// config is kept.
+pub async fn run_with_reload_until<E: Exchange>(
+    exchange: &E,
+    price_source: Option<&dyn PriceSource>,
+    config_path: &Path,
+    token: CancellationToken,
+) -> Result<(), RunError> {
+    run_with_reload_and_events_until(exchange, price_source, config_path, &NoopEventSink, token)
+        .await
+}
 
-    log(&format!(
-        "Latest price: {}. Open orders: {}. Orders to make: {}. Orders to cancel: {}",
-        stats.latest_price,
-        stats.open_orders.len(),
-        orders_to_make.len(),
-        orders_to_cancel.len()
-    ));
-
-    futures::future::try_join(
-        exchange.make_orders(orders_to_make),
-        exchange.cancel_orders(orders_to_cancel),
+/// Like `run_with_reload_until`, but publishes events as `run_with_events_until` does
+pub async fn run_with_reload_and_events_until<E: Exchange>(
+    exchange: &E,
+    price_source: Option<&dyn PriceSource>,
+    config_path: &Path,
+    events: &dyn EventSink,
+    token: CancellationToken,
+) -> Result<(), RunError> {
+    run_with_reload_and_clock_until(
+        exchange,
+        price_source,
+        config_path,
+        events,
+        &SystemClock,
+        token,
     )
-    .await?;
+    .await
+}
 
-    Ok(())
+/// Like `run_with_reload_and_events_until`, but reads the time from `clock` as
+/// `run_with_clock_until` does
+pub async fn run_with_reload_and_clock_until<E: Exchange>(
+    exchange: &E,
+    price_source: Option<&dyn PriceSource>,
+    config_path: &Path,
+    events: &dyn EventSink,
+    clock: &dyn Clock,
+    token: CancellationToken,
+) -> Result<(), RunError> {
+    let mut config_file = ConfigFile::new(config_path.to_path_buf());
+    let config = config_file.load()?;
+
+    run_loop(
+        exchange,
+        price_source,
+        &config,
+        Some(config_file),
+        events,
+        clock,
+        token,
+    )
+    .await
+    .map_err(RunError::Fatal)
 }
 
-fn calculate_orders_to_make(
-    open_orders: &[Order],
-    target_orders: Vec<MakeOrderRequest>,
-    min_order_size: u64,
-    max_orders_to_make: usize,
-    increment: u64,
-) -> Vec<MakeOrderRequest> {
-    let mut bids_to_make = BTreeMap::new();
-    let mut asks_to_make = BTreeMap::new();
-    for order in target_orders {
-        match order.order_type {
-            OrderType::Bid => bids_to_make.insert(order.price, order),
-            OrderType::Ask => asks_to_make.insert(order.price, order),
-        };
-    }
+/// Runs a single iteration, retrying it if it fails with a retryable error, and returns its result.
+/// Individual orders which fail to be made or cancelled are logged but don't fail the iteration.
+pub async fn run_iteration<E: Exchange>(
+    exchange: &E,
+    price_source: Option<&dyn PriceSource>,
+    config: &Config,
+) -> Result<(), ExchangeError> {
+    run_iteration_with_events(exchange, price_source, config, &NoopEventSink).await
+}
 
-    for order in open_orders {
-        if let Occupied(mut e) = match order.order_type {
-            OrderType::Bid => {
-                bids_to_make.entry(round_to_nearest_increment(order.price, increment))
-            }
-            OrderType::Ask => {
-                asks_to_make.entry(round_to_nearest_increment(order.price, increment))
-            }
-        } {
-            let entry = e.get_mut();
-            entry.amount = entry.amount.saturating_sub(order.amount);
-            if entry.amount < min_order_size {
-                e.remove();
+/// Like `run_iteration`, but publishes the iteration's events to `events` once it has succeeded
+pub async fn run_iteration_with_events<E: Exchange>(
+    exchange: &E,
+    price_source: Option<&dyn PriceSource>,
+    config: &Config,
+    events: &dyn EventSink,
+) -> Result<(), ExchangeError> {
+    run_iteration_with_clock(exchange, price_source, config, events, &SystemClock).await
+}
+
+/// Like `run_iteration_with_events`, but reads the time from `clock` as `run_with_clock_until`
+/// does
+pub async fn run_iteration_with_clock<E: Exchange>(
+    exchange: &E,
+    price_source: Option<&dyn PriceSource>,
+    config: &Config,
+    events: &dyn EventSink,
+    clock: &dyn Clock,
+) -> Result<(), ExchangeError> {
+    let state = State {
+        restored_orders: load_order_state(config),
+        ..State::default()
+    };
+
+    match iterate(exchange, price_source, config, &state, clock).await {
+        Some(result) => result.map(|state| {
+            for event in state.events {
+                events.publish(event);
             }
-        }
+        }),
+        None => Err(ExchangeError::Other(format!(
+            "Iteration timed out after {:?}",
+            config.iteration_timeout.unwrap_or_default()
+        ))),
     }
-
-    bids_to_make
-        .into_values()
-        .rev()
-        .interleave(asks_to_make.into_values())
-        .take(max_orders_to_make)
-        .collect()
 }
 
-fn calculate_orders_to_cancel(
-    open_orders: &[Order],
-    target_orders: Vec<MakeOrderRequest>,
-    latest_price: u64,
-    max_orders_to_cancel: usize,
-    increment: u64,
-) -> Vec<CancelOrderRequest> {
-    let mut target_bid_prices = HashSet::new();
-    let mut target_ask_prices = HashSet::new();
-    for order in target_orders {
-        match order.order_type {
-            OrderType::Bid => target_bid_prices.insert(order.price),
-            OrderType::Ask => target_ask_prices.insert(order.price),
-        };
+// Runs an iteration, retrying it if needed. Returns `None` if it doesn't complete within the
+// config's `iteration_timeout`.
+//
+// The events published by each attempt are buffered in the returned state rather than published
+// straight away, since an attempt which is retried or abandoned may already have published some.
+async fn iterate<E: Exchange>(
+    exchange: &E,
+    price_source: Option<&dyn PriceSource>,
+    config: &Config,
+    state: &State,
+    clock: &dyn Clock,
+) -> Option<Result<State, ExchangeError>> {
+    // Each attempt gets its own correlation id, which every event logged during it (including the
+    // exchange's record of each order) carries through the span
+    let iteration = retry(config, || {
+        let correlation_id = format!("{:016x}", rand::random::<u64>());
+        async move {
+            let events = EventBuffer::default();
+            let next_state =
+                run_once(exchange, price_source, config, state, &events, clock).await?;
+            Ok(State {
+                events: events.into_events(),
+                ..next_state
+            })
+        }
+        .instrument(info_span!("run_once", %correlation_id))
+    });
+
+    match config.iteration_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, iteration).await.ok(),
+        None => Some(iteration.await),
     }
+}
 
-    let mut bids = Vec::new();
-    let mut asks = Vec::new();
-    for order in open_orders {
-        match order.order_type {
-            OrderType::Bid => {
-                if order.price < latest_price
-                    && !target_bid_prices
-                        .contains(&round_to_nearest_increment(order.price, increment))
-                {
-                    bids.push(order);
+async fn run_loop<E: Exchange>(
+    exchange: &E,
+    price_source: Option<&dyn PriceSource>,
+    initial_config: &Config,
+    mut config_file: Option<ConfigFile>,
+    events: &dyn EventSink,
+    clock: &dyn Clock,
+    token: CancellationToken,
+) -> Result<(), ExchangeError> {
+    let mut state = State {
+        restored_orders: load_order_state(initial_config),
+        warmup_samples: (initial_config.warmup_iterations > 0).then(Vec::new),
+        ..State::default()
+    };
+    let trading_limits = exchange.trading_limits().await.unwrap_or_else(|error| {
+        warn!(%error, "Failed to query trading limits");
+        TradingLimits::default()
+    });
+    let initial_config = fit_to_trading_limits(initial_config, &trading_limits);
+    let mut reloaded_config = None;
+    let mut killed = false;
+    let mut fatal_error = None;
+    while !token.is_cancelled() {
+        if let Some(config_file) = config_file.as_mut() {
+            match config_file.reload() {
+                Ok(Some(config)) => {
+                    info!("Config reloaded");
+                    reloaded_config =
+                        Some(fit_to_trading_limits(&config, &trading_limits).into_owned());
+                }
+                Ok(None) => {}
+                Err(error) => {
+                    error!(%error, "Failed to reload config. Keeping the previous config")
                 }
             }
-            OrderType::Ask => {
-                if order.price > latest_price
-                    && !target_ask_prices
-                        .contains(&round_to_nearest_increment(order.price, increment))
-                {
-                    asks.push(order);
+        }
+        let config = reloaded_config.as_ref().unwrap_or(&initial_config);
+
+        if config.kill_file.as_ref().is_some_and(|f| f.exists()) {
+            warn!("Kill file found. Stopping");
+            killed = true;
+            break;
+        }
+        debug!("Starting iteration");
+        #[cfg(feature = "metrics")]
+        let start = std::time::Instant::now();
+        match iterate(exchange, price_source, config, &state, clock).await {
+            Some(Ok(mut next_state)) => {
+                for event in std::mem::take(&mut next_state.events) {
+                    events.publish(event);
                 }
+                state = next_state;
             }
-        };
+            Some(Err(error)) if error.is_fatal() => {
+                error!(%error, "Iteration failed with a fatal error. Stopping");
+                fatal_error = Some(error);
+                break;
+            }
+            Some(Err(error)) => error!(%error, "Iteration failed"),
+            None => {
+                warn!(timeout = ?config.iteration_timeout, "Iteration timed out");
+                // Orders may have been made or cancelled without their results being seen. The
+                // next iteration still detects fills against the previous open orders, using the
+                // state file (if there is one) to tell orders which were being cancelled apart
+                // from fills, and reconciles the saved orders with the open orders.
+                state.restored_orders = load_order_state(config);
+            }
+        }
+        #[cfg(feature = "metrics")]
+        metrics::record_iteration_duration(start.elapsed());
+
+        tokio::select! {
+            _ = token.cancelled() => {}
+            _ = sleep(iteration_delay(config)) => {}
+        }
     }
 
-    bids.sort_unstable_by_key(|b| Reverse(b.price));
-    asks.sort_unstable_by_key(|a| a.price);
+    // The kill file is an emergency stop, so orders are cancelled regardless of
+    // `cancel_orders_on_shutdown`
+    let config = reloaded_config.as_ref().unwrap_or(&initial_config);
+    if killed || config.cancel_orders_on_shutdown {
+        info!("Cancelling all open orders");
+        if let Err(error) = exchange.cancel_all_orders().await {
+            error!(%error, "Failed to cancel all open orders");
+        }
+    }
 
-    bids.iter()
-        .interleave(asks.iter())
-        .take(max_orders_to_cancel)
-        .map(|o| CancelOrderRequest { id: o.id.clone() })
-        .collect()
+    info!("Stopped");
+    fatal_error.map_or(Ok(()), Err)
 }
 
-fn build_orders(
-    latest_price: u64,
-    config: &Config,
-) -> (Vec<MakeOrderRequest>, Vec<MakeOrderRequest>) {
-    let starting_bid = starting_bid(latest_price, config.increment);
-    let starting_ask = starting_ask(latest_price, config.increment);
-
-    let bids = (0..config.max_orders_per_direction)
-        .map(|i| starting_bid.saturating_sub(i * config.increment))
-        .take_while(|p| *p > 0)
-        .skip_while(|p| *p >= config.max_buy_price)
-        .map(|p| MakeOrderRequest {
-            order_type: OrderType::Bid,
-            price: p,
-            amount: config.order_size,
-        })
-        .enumerate()
-        .map(|(i, o)| (o, (i as u64) < config.min_orders_per_direction));
-
-    let asks = (0..config.max_orders_per_direction)
-        .map(|i| starting_ask.saturating_add(i * config.increment))
-        .skip_while(|p| *p <= config.min_sell_price)
-        .map(|p| MakeOrderRequest {
-            order_type: OrderType::Ask,
-            price: p,
-            amount: config.order_size,
-        })
-        .enumerate()
-        .map(|(i, o)| (o, (i as u64) < config.min_orders_per_direction));
+fn load_order_state(config: &Config) -> Option<OrderState> {
+    let path = config.state_file.as_ref()?;
+    OrderState::load(path).unwrap_or_else(|error| {
+        warn!(%error, "Failed to load order state");
+        None
+    })
+}
 
-    let mut required_orders = Vec::new();
-    let mut optional_orders = Vec::new();
-    for (order, required) in bids.chain(asks) {
-        if required {
-            required_orders.push(order);
-        } else {
-            optional_orders.push(order);
-        }
+// Orders which break the exchange's limits are rejected, so the config is fitted to them, logging
+// each setting which is changed to make the difference obvious
+fn fit_to_trading_limits<'a>(config: &'a Config, limits: &TradingLimits) -> Cow<'a, Config> {
+    for warning in trading_limit_warnings(config, limits) {
+        warn!("{warning}");
     }
-    (required_orders, optional_orders)
+    config.apply_trading_limits(limits)
 }
 
-fn round_to_nearest_increment(original: u64, increment: u64) -> u64 {
-    ((original + (increment / 2)) / increment) * increment
+/// Describes each setting in the config which doesn't fit within the exchange's limits, and how it
+/// is changed to fit them when the bot runs
+pub fn trading_limit_warnings(config: &Config, limits: &TradingLimits) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if let Some(min_order_size) = limits.min_order_size {
+        if config.min_order_size < min_order_size {
+            warnings.push(format!(
+                "min_order_size ({}) is below the exchange's minimum ({min_order_size}), so the minimum is used instead",
+                config.min_order_size
+            ));
+        }
+    }
+    if let Some(tick_size) = limits.tick_size.filter(|t| *t != Price::ZERO) {
+        match config.tick_size {
+            Some(t) if t.scaled() % tick_size.scaled() == 0 => {}
+            Some(t) => warnings.push(format!(
+                "tick_size ({t}) isn't a multiple of the exchange's tick size ({tick_size}), so it is rounded up to one"
+            )),
+            None => warnings.push(format!(
+                "tick_size isn't set, so the exchange's tick size ({tick_size}) is used"
+            )),
+        }
+    }
+    if let Some(lot_size) = limits.lot_size.filter(|l| *l > 0) {
+        match config.lot_size {
+            Some(l) if l % lot_size == 0 => {}
+            Some(l) => warnings.push(format!(
+                "lot_size ({l}) isn't a multiple of the exchange's lot size ({lot_size}), so it is rounded up to one"
+            )),
+            None => warnings.push(format!(
+                "lot_size isn't set, so the exchange's lot size ({lot_size}) is used"
+            )),
+        }
+    }
+    warnings
 }
 
-async fn query<A: ArgumentEncoder + Debug, R: CandidType + DeserializeOwned>(
-    agent: &Agent,
-    canister_id: &Principal,
-    method_name: &str,
-    args: A,
-) -> Result<R, String> {
-    agent
-        .query(canister_id, method_name)
-        .with_arg(candid::encode_args(args).unwrap())
-        .call()
-        .await
-        .map(|r| candid::decode_one::<R>(&r).unwrap())
-        .map_err(|e| e.to_string())
+/// The full grid of orders which the config targets when quoting off `price`, bids then asks, each
+/// from the innermost outwards. Nothing is queried, so this shows exactly what a config produces.
+pub fn order_grid(price: Price, balances: &Balances, config: &Config) -> Vec<MakeOrderRequest> {
+    let config = config.resolve_increment(price);
+    let (required_orders, optional_orders) = build_orders(price, balances, &config);
+    let (bids, asks): (Vec<_>, Vec<_>) = required_orders
+        .into_iter()
+        .chain(optional_orders)
+        .partition(|o| o.order_type == OrderType::Bid);
+    bids.into_iter()
+        .sorted_by_key(|o| Reverse(o.price))
+        .chain(asks.into_iter().sorted_by_key(|o| o.price))
+        .collect()
 }
 
-async fn update_no_response<A: ArgumentEncoder + Debug>(
-    agent: &Agent,
-    canister_id: &Principal,
-    method_name: &str,
-    args: A,
-) -> Result<(), String> {
-    update(agent, canister_id, method_name, args).await
+// Data carried over from one iteration to the next
+#[derive(Default)]
+struct State {
+    previous_price: Option<Price>,
+    // Whether the warning about the increments being tighter than the fee floor has been logged
+    fee_floor_warned: bool,
+    // The order state saved by a previous run, until it has been reconciled with the open orders
+    restored_orders: Option<OrderState>,
+    // The open orders as of the end of the previous iteration, used to detect fills
+    previous_open_orders: Vec<Order>,
+    // The ids of the orders in `previous_open_orders` which the previous iteration made or
+    // replaced. Their amounts are the amounts requested, which the exchange may have rounded, so
+    // they aren't used to detect partial fills until the exchange has reported them.
+    just_made: HashSet<OrderId>,
+    fill_cooldowns: Cooldowns,
+    // The position built up by the fills detected since the bot started
+    position: Position,
+    // The prices sampled so far while warming up, or `None` once the warm-up is complete
+    warmup_samples: Option<Vec<Price>>,
+    daily_volume: DailyVolume,
+    // How many consecutive iterations each open order has been outside the target grid
+    orphan_counts: HashMap<OrderId, u32>,
+    // When quoting can resume after the exchange rejected an order for volatility, in nanoseconds
+    // since the Unix epoch
+    volatility_paused_until: Option<u64>,
+    // The events published by the iteration which produced this state, which are held back until
+    // it has succeeded
+    events: Vec<Event>,
 }
 
-async fn update<A: ArgumentEncoder + Debug, R: CandidType + DeserializeOwned>(
-    agent: &Agent,
-    canister_id: &Principal,
-    method_name: &str,
-    args: A,
-) -> Result<R, String> {
-    log(&format!(
-        "Starting update call - {method_name}. Args - {args:?}"
-    ));
-    let bytes = agent
-        .update(canister_id, method_name)
-        .with_arg(candid::encode_args(args).unwrap())
-        .call_and_wait()
-        .await
-        .map_err(|e| e.to_string())?;
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
 
-    Ok(candid::decode_one(&bytes).unwrap())
+// The amount of the base token filled so far on a UTC day, counted in days since the Unix epoch
+#[derive(Clone, Copy, Default)]
+struct DailyVolume {
+    day: u64,
+    filled: u64,
 }
 
-fn starting_bid(latest_price: u64, increment: u64) -> u64 {
-    ((latest_price / increment) - 1) * increment
+impl DailyVolume {
+    // Starts counting from zero once the day has rolled over
+    fn on_day(self, day: u64) -> DailyVolume {
+        if day == self.day {
+            self
+        } else {
+            DailyVolume { day, filled: 0 }
+        }
+    }
 }
 
-fn starting_ask(latest_price: u64, increment: u64) -> u64 {
-    (((latest_price - 1) / increment) + 2) * increment
-}
+// The number of iterations, including the current one, for which orders won't be made at each
+// price where an order was recently filled
+type Cooldowns = HashMap<(OrderType, Price), u32>;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use test_case::test_case;
+async fn run_once<E: Exchange>(
+    exchange: &E,
+    price_source: Option<&dyn PriceSource>,
+    config: &Config,
+    state: &State,
+    events: &dyn EventSink,
+    clock: &dyn Clock,
+) -> Result<State, ExchangeError> {
+    let external_price = async {
+        match price_source {
+            Some(p) => p.latest_price().await.map(Some),
+            None => Ok(None),
+        }
+    };
 
-    #[test_case(100, 10, 90)]
-    #[test_case(1001, 100, 900)]
-    #[test_case(2999, 10, 2980)]
-    #[test_case(100011, 2, 100008)]
-    fn starting_bid_tests(latest_price: u64, increment: u64, expected: u64) {
-        assert_eq!(starting_bid(latest_price, increment), expected)
+    let (mut stats, balances, external_price) =
+        futures::future::try_join3(exchange.stats(), exchange.balances(), external_price).await?;
+
+    // The exchange's latest trade no longer says how fresh the price is
+    if let Some(external_price) = external_price {
+        stats.latest_price = external_price;
+        stats.latest_trade_at = None;
     }
 
-    #[test_case(100, 10, 110)]
-    #[test_case(1001, 100, 1200)]
-    #[test_case(2999, 10, 3010)]
-    #[test_case(100011, 2, 100014)]
-    fn starting_ask_tests(latest_price: u64, increment: u64, expected: u64) {
-        assert_eq!(starting_ask(latest_price, increment), expected)
+    #[cfg(feature = "metrics")]
+    metrics::record_stats(
+        stats.latest_price,
+        stats
+            .open_orders
+            .iter()
+            .filter(|o| o.order_type == OrderType::Bid)
+            .count(),
+        stats
+            .open_orders
+            .iter()
+            .filter(|o| o.order_type == OrderType::Ask)
+            .count(),
+    );
+
+    if let Some(restored_orders) = &state.restored_orders {
+        restored_orders.reconcile(&stats.open_orders).log();
+    }
+
+    let price = anchor_price(&stats, config.price_anchor);
+    let config = &*config.resolve_increment(price);
+    let config = &*config.scale_to_volume(stats.volume_24h);
+    events.publish(Event::PriceUpdated {
+        latest_price: stats.latest_price,
+        anchor_price: price,
+    });
+
+    // Orders which were open at the end of the previous iteration but have since disappeared are
+    // assumed to have been filled, and those whose amount has shrunk to have been partially filled
+    let mut fill_cooldowns = tick_cooldowns(&state.fill_cooldowns);
+    let mut position = state.position.clone();
+    let mut daily_volume = state.daily_volume.on_day(clock.now() / NANOS_PER_DAY);
+    let open_orders_by_id: HashMap<_, _> = stats.open_orders.iter().map(|o| (&o.id, o)).collect();
+    for order in &state.previous_open_orders {
+        match open_orders_by_id.get(&order.id) {
+            None if state
+                .restored_orders
+                .as_ref()
+                .is_some_and(|r| r.was_cancelling(&order.id)) =>
+            {
+                debug!(?order, "Order was being cancelled by a timed out iteration");
+            }
+            None => {
+                debug!(?order, "Order filled");
+                start_cooldown(&mut fill_cooldowns, order, config);
+                position.fill(order.order_type, order.price, order.amount);
+                daily_volume.filled = daily_volume.filled.saturating_add(order.amount);
+                events.publish(Event::FillDetected(order.clone()));
+            }
+            Some(open) if open.amount < order.amount && !state.just_made.contains(&order.id) => {
+                let filled = order.amount - open.amount;
+                info!(
+                    order_type = ?order.order_type,
+                    price = %order.price,
+                    filled,
+                    remaining = open.amount,
+                    "Order partially filled"
+                );
+                position.fill(order.order_type, order.price, filled);
+                daily_volume.filled = daily_volume.filled.saturating_add(filled);
+                events.publish(Event::PartialFillDetected {
+                    order: (*open).clone(),
+                    filled,
+                });
+            }
+            Some(_) => {}
+        }
+    }
+
+    let (price, warmup_samples) = match warm_up(state.warmup_samples.as_deref(), price, config) {
+        Ok(price) => (price, None),
+        Err(samples) => {
+            debug!(
+                samples = samples.len(),
+                warmup_iterations = config.warmup_iterations,
+                "Warming up"
+            );
+            return Ok(State {
+                previous_price: None,
+                fee_floor_warned: state.fee_floor_warned,
+                restored_orders: None,
+                previous_open_orders: stats.open_orders,
+                fill_cooldowns,
+                position,
+                warmup_samples: Some(samples),
+                daily_volume,
+                orphan_counts: HashMap::new(),
+                volatility_paused_until: state.volatility_paused_until,
+                events: Vec::new(),
+                just_made: HashSet::new(),
+            });
+        }
+    };
+
+    let increment_spread = config
+        .bid_increment()
+        .scaled()
+        .saturating_add(config.ask_increment().scaled());
+    let fee_floor = fee_floor(price.scaled(), config.fee_bps);
+    let fee_floor_warned = state.fee_floor_warned || increment_spread < fee_floor;
+    if !state.fee_floor_warned && fee_floor_warned {
+        warn!(
+            fee_bps = config.fee_bps,
+            %price,
+            fee_floor = %Price::from_scaled(fee_floor),
+            "Increments are tighter than the fee floor. The spread will be widened"
+        );
+    }
+
+    let mut next_state = State {
+        previous_price: Some(price),
+        fee_floor_warned,
+        restored_orders: None,
+        previous_open_orders: Vec::new(),
+        fill_cooldowns,
+        position,
+        warmup_samples,
+        daily_volume,
+        orphan_counts: state.orphan_counts.clone(),
+        // Carried over until it expires, so that iterations which return early don't end the pause
+        volatility_paused_until: state
+            .volatility_paused_until
+            .filter(|paused_until| clock.now() < *paused_until),
+        events: Vec::new(),
+        just_made: HashSet::new(),
+    };
+
+    if let (Some(previous_price), Some(max_price_move_pct)) =
+        (state.previous_price, config.max_price_move_pct)
+    {
+        let price_move_pct = price_move_pct(previous_price, price);
+        if price_move_pct > max_price_move_pct {
+            warn!(
+                %previous_price,
+                %price,
+                price_move_pct,
+                "Circuit breaker tripped. Cancelling all orders"
+            );
+            next_state.previous_open_orders =
+                cancel_open_orders(exchange, stats.open_orders, events).await;
+            return Ok(next_state);
+        }
+    }
+
+    let daily_volume_remaining = config
+        .max_daily_volume
+        .map(|max| max.saturating_sub(daily_volume.filled));
+    if daily_volume_remaining == Some(0) {
+        info!(
+            filled = daily_volume.filled,
+            max_daily_volume = config.max_daily_volume,
+            "Daily volume limit reached. Not making orders until midnight UTC"
+        );
+        next_state.previous_open_orders =
+            cancel_open_orders(exchange, stats.open_orders, events).await;
+        return Ok(next_state);
+    }
+
+    if let (Some(max_price_age), Some(latest_trade_at)) =
+        (config.max_price_age, stats.latest_trade_at)
+    {
+        let price_age = Duration::from_nanos(clock.now().saturating_sub(latest_trade_at));
+        if price_age > max_price_age {
+            warn!(
+                latest_price = %stats.latest_price,
+                ?price_age,
+                ?max_price_age,
+                "Latest price is stale. Not making orders"
+            );
+            next_state.previous_open_orders = stats.open_orders;
+            return Ok(next_state);
+        }
+    }
+
+    if let Some(paused_until) = state.volatility_paused_until {
+        if clock.now() < paused_until {
+            debug!("Paused after unacceptable volatility. Not making orders");
+            next_state.previous_open_orders = stats.open_orders;
+            return Ok(next_state);
+        }
+        info!("Volatility cooldown is over. Resuming quoting");
+    }
+
+    if let (Some(min_book_spread), Some(best_bid), Some(best_ask)) =
+        (config.min_book_spread, stats.best_bid, stats.best_ask)
+    {
+        let book_spread = best_ask.scaled().saturating_sub(best_bid.scaled());
+        if book_spread < min_book_spread.scaled() {
+            info!(
+                %best_bid,
+                %best_ask,
+                %min_book_spread,
+                "Book spread is below min_book_spread. Not making orders"
+            );
+            next_state.previous_open_orders = if config.cancel_orders_when_book_tight {
+                cancel_open_orders(exchange, stats.open_orders, events).await
+            } else {
+                stats.open_orders
+            };
+            return Ok(next_state);
+        }
+    }
+
+    let Plan {
+        mut orders_to_make,
+        mut orders_to_cancel,
+        mut orders_to_replace,
+        self_crossing_orders,
+        unwanted_orders,
+        recentering,
+        target_orders,
+        target_bid_range,
+        target_ask_range,
+    } = plan_orders(
+        &stats.open_orders,
+        price,
+        &balances,
+        &next_state.fill_cooldowns,
+        clock.now(),
+        config,
+    );
+    if recentering {
+        info!(
+            orders_to_cancel = orders_to_cancel.len(),
+            open_orders = stats.open_orders.len(),
+            "Most open orders are out of range. Recentering the grid"
+        );
+    }
+    for order in &self_crossing_orders {
+        warn!(
+            ?order,
+            "Order would cross the bot's own orders. Not making it"
+        );
+    }
+    if let Some(daily_volume_remaining) = daily_volume_remaining {
+        cap_to_daily_volume(
+            &stats.open_orders,
+            &mut orders_to_cancel,
+            &mut orders_to_replace,
+            &mut orders_to_make,
+            daily_volume_remaining,
+        );
+    }
+
+    if let Some(orphan_iterations) = config.orphan_iterations {
+        let (orphan_counts, orphans) =
+            find_orphans(&state.orphan_counts, &unwanted_orders, orphan_iterations);
+        for id in &orphans {
+            // Only warned about once, when the order first becomes orphaned
+            if !config.cancel_orphans && orphan_counts[id] == orphan_iterations + 1 {
+                let order = stats.open_orders.iter().find(|o| &o.id == id);
+                warn!(
+                    ?order,
+                    orphan_iterations, "Order has been outside the target grid"
+                );
+            }
+        }
+        if config.cancel_orphans {
+            let already_cancelling: HashSet<_> =
+                orders_to_cancel.iter().map(|o| o.id.clone()).collect();
+            orders_to_cancel.extend(
+                orphans
+                    .into_iter()
+                    .filter(|id| !already_cancelling.contains(id))
+                    .map(|id| CancelOrderRequest { id }),
+            );
+        }
+        next_state.orphan_counts = orphan_counts;
+    }
+
+    let decision = Decision {
+        latest_price: stats.latest_price,
+        anchor_price: price,
+        target_orders,
+        open_orders: stats.open_orders.clone(),
+        orders_to_cancel: orders_to_cancel.iter().map(|o| o.id.clone()).collect(),
+    };
+    #[cfg(feature = "metrics")]
+    metrics::record_decision(&decision);
+    events.publish(Event::Decision(decision));
+
+    // How far the outermost open orders on each side are from the anchor price, in increments
+    let distances = |order_type: OrderType, increment: Price| {
+        stats
+            .open_orders
+            .iter()
+            .filter(move |o| o.order_type == order_type)
+            .map(move |o| o.distance_from_mid(price, increment))
+    };
+    let furthest_bid = distances(OrderType::Bid, config.bid_increment()).reduce(f64::min);
+    let furthest_ask = distances(OrderType::Ask, config.ask_increment()).reduce(f64::max);
+
+    info!(
+        latest_price = %stats.latest_price,
+        best_bid = ?stats.best_bid.map(|p| p.to_decimal()),
+        best_ask = ?stats.best_ask.map(|p| p.to_decimal()),
+        anchor_price = %price,
+        base_balance = balances.base,
+        quote_balance = balances.quote,
+        open_orders = stats.open_orders.len(),
+        orders_to_make = orders_to_make.len(),
+        orders_to_cancel = orders_to_cancel.len(),
+        orders_to_replace = orders_to_replace.len(),
+        target_bids = ?target_bid_range,
+        target_asks = ?target_ask_range,
+        furthest_bid = ?furthest_bid,
+        furthest_ask = ?furthest_ask,
+        daily_volume_remaining = ?daily_volume_remaining,
+        inventory = next_state.position.amount,
+        realised_pnl = next_state.position.realised_pnl,
+        unrealised_pnl = next_state.position.unrealised_pnl(price),
+        "Iteration summary"
+    );
+
+    // Saved before the orders are made so that if their confirmation is lost they can still be
+    // identified after a restart. Replaced orders are recorded as being cancelled and made again.
+    let replace_cancels: Vec<_> = orders_to_replace
+        .iter()
+        .map(|r| CancelOrderRequest { id: r.id.clone() })
+        .collect();
+    if let Some(path) = &config.state_file {
+        let replace_makes = orders_to_replace.iter().map(ReplaceOrderRequest::to_make);
+        let order_state = OrderState::new(
+            &stats.open_orders,
+            &[orders_to_cancel.as_slice(), &replace_cancels].concat(),
+            &orders_to_make
+                .iter()
+                .cloned()
+                .chain(replace_makes)
+                .collect_vec(),
+        );
+        if let Err(error) = order_state.save(path) {
+            warn!(%error, "Failed to save order state");
+        }
+    }
+
+    // Replacements go first since any which the exchange doesn't support are cancelled along with
+    // the other orders instead, and then made again by the next iteration
+    let mut replaced = Vec::new();
+    if !orders_to_replace.is_empty() {
+        let span = info_span!("replace_orders", count = orders_to_replace.len());
+        let results = replace_orders_within_slippage(exchange, orders_to_replace.clone(), config)
+            .instrument(span)
+            .await;
+        for ((request, cancel), result) in
+            orders_to_replace.iter().zip(&replace_cancels).zip(results)
+        {
+            match result {
+                Ok(id) => replaced.push((request, cancel, id)),
+                Err(ExchangeError::Unsupported(_)) => orders_to_cancel.push(cancel.clone()),
+                Err(error) => warn!(?request, %error, "Failed to replace order"),
+            }
+        }
+    }
+
+    // A failed order doesn't affect the others, so failures are logged and the iteration carries
+    // on. Any orders which failed are retried as part of the next iteration's plan.
+    let make_span = info_span!("make_orders", count = orders_to_make.len());
+    let cancel_span = info_span!("cancel_orders", count = orders_to_cancel.len());
+    let (make_results, cancel_results) = futures::future::join(
+        make_orders_within_slippage(exchange, orders_to_make.clone(), config).instrument(make_span),
+        exchange
+            .cancel_orders(orders_to_cancel.clone())
+            .instrument(cancel_span),
+    )
+    .await;
+    if let Some(volatility_cooldown) = config.volatility_cooldown {
+        if make_results
+            .iter()
+            .any(|r| matches!(r, Err(ExchangeError::Volatility(_))))
+        {
+            warn!(
+                ?volatility_cooldown,
+                "Exchange reported unacceptable volatility. Pausing quoting"
+            );
+            next_state.volatility_paused_until = Some(
+                clock
+                    .now()
+                    .saturating_add(volatility_cooldown.as_nanos() as u64),
+            );
+        }
+    }
+    let made = log_failures(&orders_to_make, make_results, "Failed to make order");
+    let mut cancelled = log_failures(&orders_to_cancel, cancel_results, "Failed to cancel order");
+    debug!(
+        made = made.len(),
+        cancelled = cancelled.len(),
+        replaced = replaced.len(),
+        "Made, cancelled and replaced orders"
+    );
+
+    #[cfg(feature = "metrics")]
+    {
+        metrics::record_orders_made(made.len());
+        metrics::record_orders_cancelled(cancelled.len());
+    }
+
+    let mut made: Vec<_> = made
+        .into_iter()
+        .map(|(r, id)| Order {
+            order_type: r.order_type,
+            id,
+            price: r.price,
+            amount: r.amount,
+            placed_at: None,
+        })
+        .collect();
+    for (request, cancel, id) in replaced {
+        made.push(Order {
+            order_type: request.order_type,
+            id,
+            price: request.new_price,
+            amount: request.new_amount,
+            placed_at: None,
+        });
+        cancelled.push((cancel, cancel.id.clone()));
+    }
+    if !made.is_empty() {
+        events.publish(Event::OrdersMade(made.clone()));
+    }
+    publish_cancelled(events, &cancelled);
+
+    next_state.just_made = made.iter().map(|o| o.id.clone()).collect();
+    next_state.previous_open_orders = remaining_open_orders(stats.open_orders, &cancelled, made);
+    Ok(next_state)
+}
+
+// Reads the best bid and ask again just before the orders are made, since they may have moved
+// since the iteration's stats were read, and skips any order which would now trade further through
+// the opposite side of the book than `max_slippage` allows
+async fn make_orders_within_slippage<E: Exchange>(
+    exchange: &E,
+    orders: Vec<MakeOrderRequest>,
+    config: &Config,
+) -> Vec<Result<OrderId, ExchangeError>> {
+    send_within_slippage(
+        exchange,
+        orders,
+        MakeOrderRequest::clone,
+        config,
+        |orders| exchange.make_orders(orders),
+    )
+    .await
+}
+
+// As with orders being made, replacements are checked against the latest best bid and ask, since a
+// replacement can move an order's price as well as its amount
+async fn replace_orders_within_slippage<E: Exchange>(
+    exchange: &E,
+    orders: Vec<ReplaceOrderRequest>,
+    config: &Config,
+) -> Vec<Result<OrderId, ExchangeError>> {
+    send_within_slippage(
+        exchange,
+        orders,
+        ReplaceOrderRequest::to_make,
+        config,
+        |orders| exchange.replace_orders(orders),
+    )
+    .await
+}
+
+async fn send_within_slippage<
+    E: Exchange,
+    R,
+    F: FnOnce(Vec<R>) -> Fut,
+    Fut: Future<Output = Vec<Result<OrderId, ExchangeError>>>,
+>(
+    exchange: &E,
+    orders: Vec<R>,
+    to_make: fn(&R) -> MakeOrderRequest,
+    config: &Config,
+    send: F,
+) -> Vec<Result<OrderId, ExchangeError>> {
+    let Some(max_slippage) = config.max_slippage else {
+        return send(orders).await;
+    };
+    if orders.is_empty() {
+        return Vec::new();
+    }
+
+    let stats = match exchange.stats().await {
+        Ok(stats) => stats,
+        Err(error) => return orders.iter().map(|_| Err(error.clone())).collect(),
+    };
+    let rejections = orders
+        .iter()
+        .map(|o| check_slippage(&to_make(o), stats.best_bid, stats.best_ask, max_slippage).err())
+        .collect_vec();
+    let orders_to_send = orders
+        .into_iter()
+        .zip(&rejections)
+        .filter(|(_, rejection)| rejection.is_none())
+        .map(|(o, _)| o)
+        .collect();
+
+    let mut results = send(orders_to_send).await.into_iter();
+    rejections
+        .into_iter()
+        .map(|rejection| match rejection {
+            Some(error) => Err(error),
+            // The exchange could return fewer results than there were orders
+            None => results
+                .next()
+                .unwrap_or_else(|| Err(ExchangeError::Decode("missing result".into()))),
+        })
+        .collect()
+}
+
+// An order trades through the opposite side by however far its price is beyond the best price
+// there, so orders which don't cross the book are always within the limit
+fn check_slippage(
+    order: &MakeOrderRequest,
+    best_bid: Option<Price>,
+    best_ask: Option<Price>,
+    max_slippage: Price,
+) -> Result<(), ExchangeError> {
+    let slippage = match order.order_type {
+        OrderType::Bid => best_ask.map(|ask| order.price.scaled().saturating_sub(ask.scaled())),
+        OrderType::Ask => best_bid.map(|bid| bid.scaled().saturating_sub(order.price.scaled())),
+    };
+
+    match slippage {
+        Some(slippage) if slippage > max_slippage.scaled() => {
+            Err(ExchangeError::Slippage(format!(
+                "{:?} at {} is {} through the best bid {best_bid:?} or ask {best_ask:?}",
+                order.order_type,
+                order.price,
+                Price::from_scaled(slippage)
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Adds the price to the warm-up samples, returning the samples if there still aren't enough of them
+// or otherwise the price to quote off, which is the median of the samples once the warm-up completes
+fn warm_up(samples: Option<&[Price]>, price: Price, config: &Config) -> Result<Price, Vec<Price>> {
+    let Some(samples) = samples else {
+        return Ok(price);
+    };
+
+    let mut samples = samples.to_vec();
+    samples.push(price);
+    if samples.len() < config.warmup_iterations as usize {
+        return Err(samples);
+    }
+
+    samples.sort_unstable();
+    let median = samples[samples.len() / 2];
+    info!(samples = samples.len(), %median, "Warm-up complete");
+    Ok(median)
+}
+
+// Cancels all of the open orders, returning those which are still open afterwards
+async fn cancel_open_orders<E: Exchange>(
+    exchange: &E,
+    open_orders: Vec<Order>,
+    events: &dyn EventSink,
+) -> Vec<Order> {
+    let orders_to_cancel: Vec<_> = open_orders
+        .iter()
+        .map(|o| CancelOrderRequest { id: o.id.clone() })
+        .collect();
+
+    let span = info_span!("cancel_orders", count = orders_to_cancel.len());
+    let results = exchange
+        .cancel_orders(orders_to_cancel.clone())
+        .instrument(span)
+        .await;
+    let cancelled = log_failures(&orders_to_cancel, results, "Failed to cancel order");
+    debug!(cancelled = cancelled.len(), "Cancelled orders");
+
+    #[cfg(feature = "metrics")]
+    metrics::record_orders_cancelled(cancelled.len());
+    publish_cancelled(events, &cancelled);
+    remaining_open_orders(open_orders, &cancelled, Vec::new())
+}
+
+fn publish_cancelled(events: &dyn EventSink, cancelled: &[(&CancelOrderRequest, OrderId)]) {
+    if !cancelled.is_empty() {
+        let ids = cancelled.iter().map(|(r, _)| r.id.clone()).collect();
+        events.publish(Event::OrdersCancelled(ids));
+    }
+}
+
+// The open orders once the cancelled orders have been removed and the made orders added
+fn remaining_open_orders(
+    open_orders: Vec<Order>,
+    cancelled: &[(&CancelOrderRequest, OrderId)],
+    made: Vec<Order>,
+) -> Vec<Order> {
+    let cancelled_ids: HashSet<_> = cancelled.iter().map(|(r, _)| &r.id).collect();
+
+    open_orders
+        .into_iter()
+        .filter(|o| !cancelled_ids.contains(&o.id))
+        .chain(made)
+        .collect()
+}
+
+// Counts each cooldown down by one iteration, dropping those which have expired
+fn tick_cooldowns(cooldowns: &Cooldowns) -> Cooldowns {
+    cooldowns
+        .iter()
+        .filter(|(_, &remaining)| remaining > 1)
+        .map(|(&key, &remaining)| (key, remaining - 1))
+        .collect()
+}
+
+fn start_cooldown(cooldowns: &mut Cooldowns, filled_order: &Order, config: &Config) {
+    if config.fill_cooldown_iterations > 0 {
+        cooldowns.insert(
+            (filled_order.order_type, target_price(filled_order, config)),
+            config.fill_cooldown_iterations,
+        );
+    }
+}
+
+// Logs each request which failed, returning the successful requests along with their results
+fn log_failures<'a, R: Debug>(
+    requests: &'a [R],
+    results: Vec<Result<OrderId, ExchangeError>>,
+    message: &str,
+) -> Vec<(&'a R, OrderId)> {
+    if results.len() != requests.len() {
+        warn!(
+            requests = requests.len(),
+            results = results.len(),
+            "Exchange returned the wrong number of results"
+        );
+    }
+    let mut succeeded = Vec::new();
+    for (request, result) in requests.iter().zip(results) {
+        match result {
+            Ok(value) => succeeded.push((request, value)),
+            Err(error) => warn!(?request, %error, "{message}"),
+        }
+    }
+    succeeded
+}
+
+struct Plan {
+    orders_to_make: Vec<MakeOrderRequest>,
+    orders_to_cancel: Vec<CancelOrderRequest>,
+    orders_to_replace: Vec<ReplaceOrderRequest>,
+    // Orders which were dropped from `orders_to_make` since they would have traded with the bot's
+    // own orders
+    self_crossing_orders: Vec<MakeOrderRequest>,
+    // Every open order which should be cancelled, including those beyond the cancel cap
+    unwanted_orders: Vec<OrderId>,
+    // Whether the cancel cap was lifted to recenter the grid
+    recentering: bool,
+    // Every order in the target grid, including those which are already open
+    target_orders: Vec<MakeOrderRequest>,
+    target_bid_range: Option<(Price, Price)>,
+    target_ask_range: Option<(Price, Price)>,
+}
+
+// Decides which orders to make and cancel to move the open orders towards the target book around
+// `price`. This is kept free of any I/O so that it can be shared with the backtester.
+fn plan_orders(
+    open_orders: &[Order],
+    price: Price,
+    balances: &Balances,
+    fill_cooldowns: &Cooldowns,
+    now: u64,
+    config: &Config,
+) -> Plan {
+    let (required_orders, optional_orders) = build_orders(price, balances, config);
+    let target_orders = Vec::from_iter(required_orders.iter().chain(&optional_orders).cloned());
+    let target_bid_range = price_range(target_orders.iter(), OrderType::Bid);
+    let target_ask_range = price_range(target_orders.iter(), OrderType::Ask);
+
+    let mut orders_to_cancel =
+        calculate_orders_to_cancel(open_orders, target_orders.clone(), price, now, config);
+
+    // Normally only a few orders are cancelled per iteration, but once most of the open orders are
+    // outside the target set the whole grid is recentered in one go
+    let unwanted_orders = orders_to_cancel.iter().map(|o| o.id.clone()).collect();
+    let recentering = config.recenter_threshold.is_some_and(|threshold| {
+        !open_orders.is_empty()
+            && orders_to_cancel.len() > config.max_orders_to_cancel_per_iteration
+            && orders_to_cancel.len() as f64 / open_orders.len() as f64 > threshold
+    });
+    if !recentering {
+        orders_to_cancel.truncate(config.max_orders_to_cancel_per_iteration);
+    }
+
+    // Bids are always priced below the anchor price, so if the quote balance can't fund the
+    // smallest order at the anchor price then it can't fund any bid
+    let can_fund_bids = price
+        .checked_notional(config.min_order_size)
+        .is_some_and(|notional| balances.quote >= notional);
+    let can_fund_asks = balances.base >= config.min_order_size;
+
+    // Open orders at prices which are cooling down after a fill are left in place, but no new
+    // orders are made there until the cooldown expires
+    let required_orders: Vec<_> = required_orders
+        .into_iter()
+        .filter(|o| !fill_cooldowns.contains_key(&(o.order_type, o.price)))
+        .collect();
+
+    let can_fund = |o: &MakeOrderRequest| match o.order_type {
+        OrderType::Bid => can_fund_bids,
+        OrderType::Ask => can_fund_asks,
+    };
+
+    let orders_to_replace = calculate_orders_to_replace(
+        open_orders,
+        &mut orders_to_cancel,
+        required_orders
+            .iter()
+            .filter(|o| can_fund(o))
+            .cloned()
+            .collect(),
+        price,
+        config,
+    );
+
+    let orders_to_make = calculate_orders_to_make(open_orders, required_orders, price, config)
+        .into_iter()
+        .filter(can_fund)
+        .collect();
+    let (orders_to_make, self_crossing_orders) = remove_self_crossing(orders_to_make, open_orders);
+
+    Plan {
+        orders_to_make,
+        orders_to_cancel,
+        orders_to_replace,
+        self_crossing_orders,
+        unwanted_orders,
+        recentering,
+        target_orders,
+        target_bid_range,
+        target_ask_range,
+    }
+}
+
+// Limits the total amount open on each side to what can still be filled today, so that orders
+// resting on the book can't take the day's volume over `max_daily_volume`. If the orders which are
+// staying open are already over the limit, the outermost of them are cancelled. Replacements which
+// would grow an order past the limit are dropped, leaving the order as it is. Orders to make are
+// innermost first, and once one doesn't fit no further orders are made on that side.
+fn cap_to_daily_volume(
+    open_orders: &[Order],
+    orders_to_cancel: &mut Vec<CancelOrderRequest>,
+    orders_to_replace: &mut Vec<ReplaceOrderRequest>,
+    orders_to_make: &mut Vec<MakeOrderRequest>,
+    daily_volume_remaining: u64,
+) {
+    let [mut bid_capacity, mut ask_capacity] = [OrderType::Bid, OrderType::Ask].map(|order_type| {
+        let mut staying_open: Vec<_> = open_orders
+            .iter()
+            .filter(|o| {
+                o.order_type == order_type && !orders_to_cancel.iter().any(|c| c.id == o.id)
+            })
+            .collect();
+        // Outermost first
+        staying_open.sort_by_key(|o| o.price);
+        if order_type == OrderType::Ask {
+            staying_open.reverse();
+        }
+
+        let mut open_amount = staying_open
+            .iter()
+            .fold(0u64, |total, o| total.saturating_add(o.amount));
+        for order in staying_open {
+            if open_amount <= daily_volume_remaining {
+                break;
+            }
+            debug!(
+                ?order,
+                "Order exceeds the daily volume limit. Cancelling it"
+            );
+            orders_to_cancel.push(CancelOrderRequest {
+                id: order.id.clone(),
+            });
+            orders_to_replace.retain(|r| r.id != order.id);
+            open_amount -= order.amount;
+        }
+        daily_volume_remaining.saturating_sub(open_amount)
+    });
+
+    orders_to_replace.retain(|r| {
+        let current = open_orders
+            .iter()
+            .find(|o| o.id == r.id)
+            .map_or(0, |o| o.amount);
+        let increase = r.new_amount.saturating_sub(current);
+        let capacity = match r.order_type {
+            OrderType::Bid => &mut bid_capacity,
+            OrderType::Ask => &mut ask_capacity,
+        };
+        let fits = *capacity >= increase;
+        if fits {
+            *capacity -= increase;
+        } else {
+            debug!(
+                ?r,
+                "Replacement would exceed the daily volume limit. Not replacing it"
+            );
+        }
+        fits
+    });
+    orders_to_make.retain(|o| {
+        let capacity = match o.order_type {
+            OrderType::Bid => &mut bid_capacity,
+            OrderType::Ask => &mut ask_capacity,
+        };
+        let fits = *capacity >= o.amount;
+        if fits {
+            *capacity -= o.amount;
+        } else {
+            *capacity = 0;
+        }
+        fits
+    });
+}
+
+// Splits off any bids to make which are at or above the lowest of the bot's asks, and any asks to
+// make which are at or below the highest of its bids, since they would trade with each other. The
+// ladders never cross, so this only catches orders crossing open orders left from an earlier grid,
+// or a misconfiguration. Open orders count even if they're being cancelled, since the cancel and
+// the make are sent together, so the crossing orders are made by a later iteration instead.
+fn remove_self_crossing(
+    orders_to_make: Vec<MakeOrderRequest>,
+    open_orders: &[Order],
+) -> (Vec<MakeOrderRequest>, Vec<MakeOrderRequest>) {
+    let prices = open_orders
+        .iter()
+        .map(|o| (o.order_type, o.price))
+        .chain(orders_to_make.iter().map(|o| (o.order_type, o.price)))
+        .collect_vec();
+    let highest_bid = prices
+        .iter()
+        .filter(|(order_type, _)| *order_type == OrderType::Bid)
+        .map(|(_, price)| *price)
+        .max();
+    let lowest_ask = prices
+        .iter()
+        .filter(|(order_type, _)| *order_type == OrderType::Ask)
+        .map(|(_, price)| *price)
+        .min();
+
+    orders_to_make
+        .into_iter()
+        .partition(|o| match o.order_type {
+            OrderType::Bid => lowest_ask.map_or(true, |ask| o.price < ask),
+            OrderType::Ask => highest_bid.map_or(true, |bid| o.price > bid),
+        })
+}
+
+// Counts how many consecutive iterations each unwanted order has been outside the target grid,
+// returning the new counts along with the orders which have been outside it for more than
+// `orphan_iterations`. Orders which are back inside the grid, or no longer open, are forgotten.
+fn find_orphans(
+    previous_counts: &HashMap<OrderId, u32>,
+    unwanted_orders: &[OrderId],
+    orphan_iterations: u32,
+) -> (HashMap<OrderId, u32>, Vec<OrderId>) {
+    let counts: HashMap<_, _> = unwanted_orders
+        .iter()
+        .map(|id| {
+            let count = previous_counts.get(id).copied().unwrap_or(0);
+            (id.clone(), count.saturating_add(1))
+        })
+        .collect();
+    let orphans = unwanted_orders
+        .iter()
+        .filter(|id| counts[*id] > orphan_iterations)
+        .cloned()
+        .collect();
+    (counts, orphans)
+}
+
+// The (min, max) prices of the orders of the given type
+fn price_range<'a>(
+    orders: impl Iterator<Item = &'a MakeOrderRequest>,
+    order_type: OrderType,
+) -> Option<(Price, Price)> {
+    orders
+        .filter(|o| o.order_type == order_type)
+        .map(|o| o.price)
+        .minmax()
+        .into_option()
+}
+
+fn price_move_pct(previous_price: Price, price: Price) -> f64 {
+    let (previous_price, price) = (previous_price.scaled(), price.scaled());
+    if previous_price == 0 {
+        return if price == 0 { 0.0 } else { f64::INFINITY };
+    }
+    previous_price.abs_diff(price) as f64 * 100.0 / previous_price as f64
+}
+
+fn iteration_delay(config: &Config) -> Duration {
+    if config.iteration_jitter.is_zero() {
+        return config.iteration_interval;
+    }
+    let jitter = rand::thread_rng().gen_range(Duration::ZERO..config.iteration_jitter);
+    config.iteration_interval + jitter
+}
+
+// Retrying the whole iteration rather than individual calls means that any orders which were made
+// before the failure are picked up by the fresh `stats` call rather than being made again
+async fn retry<T, F: FnMut() -> Fut, Fut: Future<Output = Result<T, ExchangeError>>>(
+    config: &Config,
+    mut f: F,
+) -> Result<T, ExchangeError> {
+    let mut attempt = 0;
+    loop {
+        match f().await {
+            Err(error) if error.is_retryable() && attempt < config.max_retries => {
+                let backoff = config
+                    .retry_backoff
+                    .saturating_mul(2u32.saturating_pow(attempt));
+                warn!(%error, ?backoff, "Retrying after error");
+                sleep(backoff).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn anchor_price(stats: &Stats, anchor: PriceAnchor) -> Price {
+    let (Some(best_bid), Some(best_ask)) = (stats.best_bid, stats.best_ask) else {
+        return stats.latest_price;
+    };
+    let mid = Price::from_scaled(
+        ((u128::from(best_bid.scaled()) + u128::from(best_ask.scaled())) / 2) as u64,
+    );
+
+    match anchor {
+        PriceAnchor::LatestPrice => stats.latest_price,
+        PriceAnchor::BookMid => mid,
+        PriceAnchor::Blended { mid_weight } => Price::from_scaled(
+            (mid_weight * mid.scaled() as f64
+                + (1.0 - mid_weight) * stats.latest_price.scaled() as f64)
+                .round() as u64,
+        ),
+    }
+}
+
+// Open orders which are being cancelled only because their size is wrong are instead replaced by
+// the order which would be made at the same price once they were gone, so that they never leave
+// the book. The replaced orders are removed from `orders_to_cancel`.
+fn calculate_orders_to_replace(
+    open_orders: &[Order],
+    orders_to_cancel: &mut Vec<CancelOrderRequest>,
+    target_orders: Vec<MakeOrderRequest>,
+    mid_price: Price,
+    config: &Config,
+) -> Vec<ReplaceOrderRequest> {
+    let cancelled_ids: HashSet<_> = orders_to_cancel.iter().map(|o| &o.id).collect();
+    let (cancelled, remaining): (Vec<_>, Vec<_>) = open_orders
+        .iter()
+        .cloned()
+        .partition(|o| cancelled_ids.contains(&o.id));
+    let mut orders_to_make = calculate_orders_to_make(&remaining, target_orders, mid_price, config);
+
+    let mut orders_to_replace = Vec::new();
+    for order in cancelled {
+        let price = target_price(&order, config);
+        let Some(index) = orders_to_make.iter().position(|m| {
+            m.order_type == order.order_type && m.price == price && m.amount != order.amount
+        }) else {
+            continue;
+        };
+        let make = orders_to_make.remove(index);
+        orders_to_replace.push(ReplaceOrderRequest {
+            id: order.id,
+            order_type: make.order_type,
+            new_price: make.price,
+            new_amount: make.amount,
+        });
+    }
+
+    let replaced_ids: HashSet<_> = orders_to_replace.iter().map(|r| &r.id).collect();
+    orders_to_cancel.retain(|o| !replaced_ids.contains(&o.id));
+    orders_to_replace
+}
+
+// Orders closest to the mid price are made first since they are the most likely to be filled
+fn calculate_orders_to_make(
+    open_orders: &[Order],
+    target_orders: Vec<MakeOrderRequest>,
+    mid_price: Price,
+    config: &Config,
+) -> Vec<MakeOrderRequest> {
+    let mut bids_to_make = BTreeMap::new();
+    let mut asks_to_make = BTreeMap::new();
+    for order in target_orders {
+        match order.order_type {
+            OrderType::Bid => bids_to_make.insert(order.price, order),
+            OrderType::Ask => asks_to_make.insert(order.price, order),
+        };
+    }
+
+    for order in open_orders {
+        if let Occupied(mut e) = match order.order_type {
+            OrderType::Bid => bids_to_make.entry(target_price(order, config)),
+            OrderType::Ask => asks_to_make.entry(target_price(order, config)),
+        } {
+            let entry = e.get_mut();
+            entry.amount = config.round_amount(entry.amount.saturating_sub(order.amount));
+            // `min_order_size` may be 0, but there is never anything to make at a fully covered price
+            if entry.amount == 0 || entry.amount < config.min_order_size {
+                e.remove();
+            }
+        }
+    }
+
+    // The sort is stable, so when a bid and an ask are equidistant from the mid price the bid
+    // remains first
+    let mut orders: Vec<_> = bids_to_make
+        .into_values()
+        .rev()
+        .interleave(asks_to_make.into_values())
+        .collect();
+    orders.sort_by_key(|o| o.price.scaled().abs_diff(mid_price.scaled()));
+
+    // Orders are already sorted with the innermost first, so any which would take the number of
+    // open orders over the cap are the outermost
+    let max_to_make =
+        config
+            .max_total_open_orders
+            .map_or(config.max_orders_to_make_per_iteration, |max_total| {
+                max_total
+                    .saturating_sub(open_orders.len())
+                    .min(config.max_orders_to_make_per_iteration)
+            });
+
+    // Once an order would take a side's total notional (including its open orders) over the cap,
+    // no further orders are made on that side
+    let mut bid_capacity = remaining_notional(open_orders, OrderType::Bid, config);
+    let mut ask_capacity = remaining_notional(open_orders, OrderType::Ask, config);
+    orders
+        .into_iter()
+        .filter(|o| {
+            let capacity = match o.order_type {
+                OrderType::Bid => &mut bid_capacity,
+                OrderType::Ask => &mut ask_capacity,
+            };
+            let notional = o.price.wide_notional(o.amount);
+            match capacity {
+                Some(remaining) if *remaining >= notional => {
+                    *remaining -= notional;
+                    true
+                }
+                Some(remaining) => {
+                    *remaining = 0;
+                    false
+                }
+                None => true,
+            }
+        })
+        .take(max_to_make)
+        .collect()
+}
+
+// The notional which can still be committed to a side before reaching its cap, or `None` if the
+// side is uncapped. Summed as `u128`s since the open orders' total may not fit in a `u64`.
+fn remaining_notional(
+    open_orders: &[Order],
+    order_type: OrderType,
+    config: &Config,
+) -> Option<u128> {
+    let max_total_notional = match order_type {
+        OrderType::Bid => config.max_total_bid_notional,
+        OrderType::Ask => config.max_total_ask_notional,
+    }?;
+
+    let open_notional = open_orders
+        .iter()
+        .filter(|o| o.order_type == order_type)
+        .map(|o| o.price.wide_notional(o.amount))
+        .sum::<u128>();
+
+    Some(u128::from(max_total_notional).saturating_sub(open_notional))
+}
+
+// Returns every order which should be cancelled, innermost first. `plan_orders` applies the
+// per-iteration cap.
+fn calculate_orders_to_cancel(
+    open_orders: &[Order],
+    target_orders: Vec<MakeOrderRequest>,
+    latest_price: Price,
+    now: u64,
+    config: &Config,
+) -> Vec<CancelOrderRequest> {
+    // Orders which have been resting for longer than `max_order_age` are cancelled regardless of
+    // price so that they get refreshed
+    let is_expired = |order: &Order| match (config.max_order_age, order.placed_at) {
+        (Some(max_order_age), Some(placed_at)) => {
+            u128::from(now.saturating_sub(placed_at)) > max_order_age.as_nanos()
+        }
+        _ => false,
+    };
+
+    // The amount remaining at each target price which open orders may fill before being cancelled
+    let mut target_bid_amounts = HashMap::new();
+    let mut target_ask_amounts = HashMap::new();
+    for order in target_orders {
+        let amount = order.amount.saturating_add(config.order_size_tolerance);
+        match order.order_type {
+            OrderType::Bid => target_bid_amounts.insert(order.price, amount),
+            OrderType::Ask => target_ask_amounts.insert(order.price, amount),
+        };
+    }
+
+    let mut bids = Vec::new();
+    let mut asks = Vec::new();
+    for order in open_orders {
+        // Orders on a side which isn't being quoted are cancelled regardless of price
+        if is_expired(order) || !config.side.includes(order.order_type) {
+            match order.order_type {
+                OrderType::Bid => bids.push(order),
+                OrderType::Ask => asks.push(order),
+            }
+            continue;
+        }
+        match order.order_type {
+            OrderType::Bid => {
+                if order.price < latest_price
+                    && !try_fill_target(
+                        &mut target_bid_amounts,
+                        target_price(order, config),
+                        order.amount,
+                    )
+                {
+                    bids.push(order);
+                }
+            }
+            OrderType::Ask => {
+                if order.price > latest_price
+                    && !try_fill_target(
+                        &mut target_ask_amounts,
+                        target_price(order, config),
+                        order.amount,
+                    )
+                {
+                    asks.push(order);
+                }
+            }
+        };
+    }
+
+    bids.sort_unstable_by_key(|b| Reverse(b.price));
+    asks.sort_unstable_by_key(|a| a.price);
+
+    bids.iter()
+        .interleave(asks.iter())
+        .map(|o| CancelOrderRequest { id: o.id.clone() })
+        .collect()
+}
+
+// Returns true if there is a target at the price with enough remaining amount to cover the order,
+// deducting the order's amount from the target if so
+fn try_fill_target(target_amounts: &mut HashMap<Price, u64>, price: Price, amount: u64) -> bool {
+    match target_amounts.get_mut(&price) {
+        Some(remaining) if *remaining >= amount => {
+            *remaining -= amount;
+            true
+        }
+        _ => false,
+    }
+}
+
+fn build_orders(
+    latest_price: Price,
+    balances: &Balances,
+    config: &Config,
+) -> (Vec<MakeOrderRequest>, Vec<MakeOrderRequest>) {
+    let config = &*config.rebalance_sizing(latest_price, balances);
+    // Measured from the anchor price rather than the skewed one, since it limits how far orders
+    // are from the market
+    let (min_bid_price, max_ask_price) = match config.max_distance_from_mid {
+        Some(max_distance) => (
+            latest_price
+                .scaled()
+                .saturating_sub(max_distance.scaled(latest_price, config.bid_increment())),
+            latest_price
+                .scaled()
+                .saturating_add(max_distance.scaled(latest_price, config.ask_increment())),
+        ),
+        None => (0, u64::MAX),
+    };
+    let latest_price = skew_price(latest_price, balances, config).scaled();
+    let bid_increment = config.bid_increment().scaled();
+    let ask_increment = config.ask_increment().scaled();
+    let max_buy_price = config.max_buy_price.scaled();
+    let min_sell_price = config.min_sell_price.scaled();
+    let (starting_bid, starting_ask) = widen_to_fee_floor(
+        starting_bid(latest_price, bid_increment),
+        starting_ask(latest_price, ask_increment),
+        latest_price,
+        config,
+    );
+
+    // The first levels which satisfy `max_buy_price` and `min_sell_price`
+    let first_bid_level = first_level(
+        starting_bid,
+        max_buy_price,
+        bid_increment,
+        OrderType::Bid,
+        config.spacing,
+    );
+    let first_ask_level = first_level(
+        starting_ask,
+        min_sell_price,
+        ask_increment,
+        OrderType::Ask,
+        config.spacing,
+    );
+
+    let bid_levels = if config.side.includes(OrderType::Bid) {
+        level_count(first_bid_level, config)
+    } else {
+        0
+    };
+    let ask_levels = if config.side.includes(OrderType::Ask) {
+        level_count(first_ask_level, config)
+    } else {
+        0
+    };
+
+    // Levels are counted after skipping those which round to the same price, so that a tick size
+    // coarser than the increment doesn't leave the ladder short of orders
+    let asks: Vec<_> = ladder(
+        starting_ask,
+        first_ask_level,
+        ask_increment,
+        OrderType::Ask,
+        config,
+    )
+    .take_while(|(_, p)| *p <= max_ask_price)
+    .take(ask_levels)
+    .enumerate()
+    .map(|(i, (_, p))| {
+        let price = Price::from_scaled(p);
+        MakeOrderRequest {
+            order_type: OrderType::Ask,
+            price,
+            amount: config.order_amount(OrderType::Ask, price, i as u64),
+            post_only: true,
+        }
+    })
+    .filter(|o| o.amount > 0 && o.amount >= config.min_order_size)
+    .enumerate()
+    .map(|(i, o)| (o, (i as u64) < config.min_orders_per_direction))
+    .collect();
+
+    // Asks are in ascending order of price so the first is the innermost
+    let innermost_ask = asks.first().map(|(o, _)| o.price.scaled());
+    let min_spread = bid_increment.min(ask_increment);
+
+    let bids = ladder(
+        starting_bid,
+        first_bid_level,
+        bid_increment,
+        OrderType::Bid,
+        config,
+    )
+    .take_while(|(_, p)| *p > 0 && *p >= min_bid_price)
+    .take(bid_levels)
+    .filter(|(_, p)| !crosses_asks(*p, innermost_ask, min_spread))
+    .enumerate()
+    .map(|(i, (_, p))| {
+        let price = Price::from_scaled(p);
+        MakeOrderRequest {
+            order_type: OrderType::Bid,
+            price,
+            amount: config.order_amount(OrderType::Bid, price, i as u64),
+            post_only: true,
+        }
+    })
+    .filter(|o| o.amount > 0 && o.amount >= config.min_order_size)
+    .enumerate()
+    .map(|(i, o)| (o, (i as u64) < config.min_orders_per_direction));
+
+    let mut required_orders = Vec::new();
+    let mut optional_orders = Vec::new();
+    for (order, required) in bids.chain(asks) {
+        if required {
+            required_orders.push(order);
+        } else {
+            optional_orders.push(order);
+        }
+    }
+    (required_orders, optional_orders)
+}
+
+// Whether a bid at this price would be within `min_spread` of (or above) the innermost ask. The
+// ladders are built either side of the anchor price so this shouldn't happen, but if it ever did
+// then the bot would trade with itself.
+fn crosses_asks(bid_price: u64, innermost_ask: Option<u64>, min_spread: u64) -> bool {
+    innermost_ask.is_some_and(|ask| bid_price.saturating_add(min_spread) > ask)
+}
+
+// Orders are placed on the levels within `max_orders_per_direction` of the starting price, unless
+// the price limits would leave fewer than `min_orders_per_direction` orders, in which case the
+// ladder extends further out so that the minimum is still met
+fn level_count(first_level: u64, config: &Config) -> usize {
+    config
+        .max_orders_per_direction
+        .saturating_sub(first_level)
+        .max(config.min_orders_per_direction) as usize
+}
+
+fn skew_price(price: Price, balances: &Balances, config: &Config) -> Price {
+    let Some(skew) = config.inventory_skew.filter(|s| s.target_base > 0) else {
+        return price;
+    };
+
+    let target = skew.target_base as f64;
+    let imbalance = ((balances.base as f64 - target) / target).clamp(-1.0, 1.0);
+    let shift = (imbalance * skew.skew_factor * config.increment.scaled() as f64).round() as i64;
+
+    Price::from_scaled(price.scaled().saturating_add_signed(-shift))
+}
+
+// Rounds down instead if rounding up would overflow
+fn round_to_nearest_increment(original: Price, increment: u64) -> Price {
+    let rounded_down = original.scaled() / increment * increment;
+    let rounded = (u128::from(original.scaled()) + u128::from(increment / 2))
+        / u128::from(increment)
+        * u128::from(increment);
+    Price::from_scaled(u64::try_from(rounded).unwrap_or(rounded_down))
+}
+
+// Rounds bids down and asks up to a multiple of `tick_size`, so that rounding never moves an order
+// towards the mid price. Returns `None` if an ask can't be rounded up without overflowing.
+fn round_to_tick(price: u64, order_type: OrderType, config: &Config) -> Option<u64> {
+    let Some(tick_size) = config.tick_size.map(Price::scaled) else {
+        return Some(price);
+    };
+    match order_type {
+        OrderType::Bid => Some(price / tick_size * tick_size),
+        OrderType::Ask => price.div_ceil(tick_size).checked_mul(tick_size),
+    }
+}
+
+// The target price which an open order corresponds to. Orders placed at tick-rounded prices are
+// matched by rounding them to the tick again, since they may be further than half an increment
+// from the level they were generated from. Geometric levels aren't multiples of the increment, so
+// without a tick size those orders are matched at exactly the price they were placed at.
+fn target_price(order: &Order, config: &Config) -> Price {
+    match round_to_tick(order.price.scaled(), order.order_type, config) {
+        Some(price) if config.tick_size.is_some() => Price::from_scaled(price),
+        _ if matches!(config.spacing, Spacing::Geometric(_)) => order.price,
+        _ => {
+            let increment = match order.order_type {
+                OrderType::Bid => config.bid_increment(),
+                OrderType::Ask => config.ask_increment(),
+            };
+            round_to_nearest_increment(order.price, increment.scaled())
+        }
+    }
+}
+
+async fn query<A: ArgumentEncoder + Debug, R: CandidType + DeserializeOwned>(
+    agent: &Agent,
+    canister_id: &Principal,
+    method_name: &str,
+    args: A,
+) -> Result<R, ExchangeError> {
+    query_args(agent, canister_id, method_name, args)
+        .await
+        .map(|(r,)| r)
+}
+
+async fn query_args<A: ArgumentEncoder + Debug, R: for<'a> ArgumentDecoder<'a>>(
+    agent: &Agent,
+    canister_id: &Principal,
+    method_name: &str,
+    args: A,
+) -> Result<R, ExchangeError> {
+    let bytes = agent
+        .query(canister_id, method_name)
+        .with_arg(candid::encode_args(args).unwrap())
+        .call()
+        .await
+        .map_err(to_exchange_error)?;
+
+    candid::decode_args(&bytes).map_err(|e| ExchangeError::Decode(e.to_string()))
+}
+
+async fn update<A: ArgumentEncoder + Debug, R: CandidType + DeserializeOwned>(
+    agent: &Agent,
+    canister_id: &Principal,
+    method_name: &str,
+    args: A,
+) -> Result<R, ExchangeError> {
+    debug!(method_name, ?args, "Starting update call");
+    let bytes = agent
+        .update(canister_id, method_name)
+        .with_arg(candid::encode_args(args).unwrap())
+        .call_and_wait()
+        .await
+        .map_err(to_exchange_error)?;
+
+    candid::decode_one(&bytes).map_err(|e| ExchangeError::Decode(e.to_string()))
+}
+
+fn to_exchange_error(error: AgentError) -> ExchangeError {
+    match error {
+        AgentError::ReplicaError { reject_message, .. }
+            if reject_message.contains("has no update method")
+                || reject_message.contains("has no query method") =>
+        {
+            ExchangeError::Unsupported(reject_message)
+        }
+        // The canister doesn't exist. Other rejections with the same code, such as a canister which
+        // has run out of cycles, can clear up, so they are left to be retried.
+        AgentError::ReplicaError {
+            reject_code: 3,
+            reject_message,
+        } if is_canister_not_found(&reject_message) => ExchangeError::Fatal(reject_message),
+        AgentError::HttpError(payload) if matches!(payload.status, 401 | 403) => {
+            ExchangeError::Fatal(payload.to_string())
+        }
+        AgentError::TransportError(error) => ExchangeError::Transport(error.to_string()),
+        error => ExchangeError::Network(error.to_string()),
+    }
+}
+
+fn is_canister_not_found(reject_message: &str) -> bool {
+    let reject_message = reject_message.to_lowercase();
+    reject_message.contains("canister")
+        && (reject_message.contains("not found") || reject_message.contains("does not exist"))
+}
+
+// The narrowest spread between the innermost bid and ask at which a round trip covers the fees
+fn fee_floor(price: u64, fee_bps: u64) -> u64 {
+    (u128::from(price) * 2 * u128::from(fee_bps))
+        .div_ceil(10000)
+        .try_into()
+        .unwrap_or(u64::MAX)
+}
+
+// Moves the starting prices apart by whole increments until their spread is at least the fee floor
+fn widen_to_fee_floor(
+    starting_bid: u64,
+    starting_ask: u64,
+    price: u64,
+    config: &Config,
+) -> (u64, u64) {
+    let deficit =
+        fee_floor(price, config.fee_bps).saturating_sub(starting_ask.saturating_sub(starting_bid));
+    if deficit == 0 {
+        return (starting_bid, starting_ask);
+    }
+
+    let bid_increment = config.bid_increment().scaled();
+    let ask_increment = config.ask_increment().scaled();
+    let half = deficit.div_ceil(2);
+    (
+        starting_bid.saturating_sub(half.div_ceil(bid_increment).saturating_mul(bid_increment)),
+        starting_ask.saturating_add(half.div_ceil(ask_increment).saturating_mul(ask_increment)),
+    )
+}
+
+// Returns 0 if the price is too low to place a bid below it, in which case no bids are made
+fn starting_bid(latest_price: u64, increment: u64) -> u64 {
+    (latest_price / increment).saturating_sub(1) * increment
+}
+
+fn starting_ask(latest_price: u64, increment: u64) -> u64 {
+    ((latest_price.saturating_sub(1) / increment) + 2) * increment
+}
+
+// The price `level` levels beyond the starting bid or ask, before rounding to the tick size.
+// Returns `None` if an ask would overflow.
+fn ladder_price(
+    start: u64,
+    level: u64,
+    increment: u64,
+    order_type: OrderType,
+    spacing: Spacing,
+) -> Option<u64> {
+    match (spacing, order_type) {
+        (Spacing::Arithmetic, OrderType::Bid) => {
+            Some(start.saturating_sub(level.saturating_mul(increment)))
+        }
+        (Spacing::Arithmetic, OrderType::Ask) => level
+            .checked_mul(increment)
+            .and_then(|d| start.checked_add(d)),
+        (Spacing::Geometric(factor), OrderType::Bid) => {
+            Some((start as f64 / factor.powf(level as f64)).round() as u64)
+        }
+        (Spacing::Geometric(factor), OrderType::Ask) => {
+            let price = (start as f64 * factor.powf(level as f64)).round();
+            (price < u64::MAX as f64).then_some(price as u64)
+        }
+    }
+}
+
+// The levels from `from` outwards paired with their prices rounded to the tick size. Each level is
+// followed by the first level beyond its rounded price, so the levels in between, which would round
+// to the same price, are skipped rather than generated one by one, since there are tick_size /
+// increment of them per order when the tick size is much coarser than the increment.
+fn ladder(
+    start: u64,
+    from: u64,
+    increment: u64,
+    order_type: OrderType,
+    config: &Config,
+) -> impl Iterator<Item = (u64, u64)> + '_ {
+    let rounded_price = move |level| {
+        ladder_price(start, level, increment, order_type, config.spacing)
+            .and_then(|p| round_to_tick(p, order_type, config))
+            .map(|p| (level, p))
+    };
+    std::iter::successors(rounded_price(from), move |&(level, price)| {
+        let next = first_level(start, price, increment, order_type, config.spacing)
+            .max(level.saturating_add(1));
+        rounded_price(next)
+    })
+}
+
+// The first level whose price is strictly below `limit` for bids, or strictly above it for asks
+fn first_level(
+    start: u64,
+    limit: u64,
+    increment: u64,
+    order_type: OrderType,
+    spacing: Spacing,
+) -> u64 {
+    let inside = |level| {
+        ladder_price(start, level, increment, order_type, spacing).is_some_and(|p| match order_type
+        {
+            OrderType::Bid => p < limit,
+            OrderType::Ask => p > limit,
+        })
+    };
+    if inside(0) {
+        return 0;
+    }
+
+    match (spacing, order_type) {
+        (Spacing::Arithmetic, OrderType::Bid) => (start - limit) / increment + 1,
+        (Spacing::Arithmetic, OrderType::Ask) => (limit - start) / increment + 1,
+        (Spacing::Geometric(factor), _) => {
+            let ratio = match order_type {
+                OrderType::Bid => start as f64 / limit as f64,
+                OrderType::Ask => limit as f64 / start.max(1) as f64,
+            };
+            // Estimated using logarithms and then corrected for any floating point error. Far
+            // beyond the cap, every bid has fallen to zero and every ask has overflowed.
+            let estimate = (ratio.ln() / factor.ln()).min(u32::MAX as f64) as u64;
+            (estimate.saturating_sub(1)..=estimate + 2)
+                .find(|&level| inside(level))
+                .unwrap_or(estimate + 2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use test_case::test_case;
+
+    #[test]
+    fn to_exchange_error_classifies_agent_errors() {
+        let replica_error = |reject_code, message: &str| AgentError::ReplicaError {
+            reject_code,
+            reject_message: message.to_string(),
+        };
+        let http_error = |status| {
+            AgentError::HttpError(ic_agent::agent::agent_error::HttpErrorPayload {
+                status,
+                content_type: None,
+                content: Vec::new(),
+            })
+        };
+
+        assert!(matches!(
+            to_exchange_error(replica_error(3, "Canister has no update method 'orders'")),
+            ExchangeError::Unsupported(_)
+        ));
+        assert!(matches!(
+            to_exchange_error(replica_error(3, "Canister not found")),
+            ExchangeError::Fatal(_)
+        ));
+        assert!(matches!(
+            to_exchange_error(replica_error(3, "Requested canister does not exist")),
+            ExchangeError::Fatal(_)
+        ));
+        assert!(matches!(
+            to_exchange_error(replica_error(3, "Canister abc is out of cycles")),
+            ExchangeError::Network(_)
+        ));
+        assert!(matches!(
+            to_exchange_error(replica_error(5, "Canister trapped")),
+            ExchangeError::Network(_)
+        ));
+        assert!(to_exchange_error(http_error(403)).is_fatal());
+        assert!(to_exchange_error(http_error(503)).is_retryable());
+        let transport_error = to_exchange_error(AgentError::TransportError("reset".into()));
+        assert!(matches!(transport_error, ExchangeError::Transport(_)));
+        assert!(transport_error.is_retryable());
+    }
+
+    #[test_case(100, 10, 90)]
+    #[test_case(1001, 100, 900)]
+    #[test_case(2999, 10, 2980)]
+    #[test_case(100011, 2, 100008)]
+    #[test_case(50, 100000, 0)]
+    #[test_case(100000, 100000, 0)]
+    #[test_case(0, 100000, 0)]
+    fn starting_bid_tests(latest_price: u64, increment: u64, expected: u64) {
+        assert_eq!(starting_bid(latest_price, increment), expected)
+    }
+
+    #[test_case(100, 10, 110)]
+    #[test_case(1001, 100, 1200)]
+    #[test_case(2999, 10, 3010)]
+    #[test_case(100011, 2, 100014)]
+    #[test_case(50, 100000, 200000)]
+    #[test_case(100000, 100000, 200000)]
+    #[test_case(0, 100000, 200000)]
+    fn starting_ask_tests(latest_price: u64, increment: u64, expected: u64) {
+        assert_eq!(starting_ask(latest_price, increment), expected)
+    }
+
+    #[test_case(1000, 1000, 10, 0.0)]
+    #[test_case(970, 1000, 10, -3.0)]
+    #[test_case(1025, 1000, 10, 2.5)]
+    #[test_case(1000, 1010, 20, -0.5)]
+    fn distance_from_mid_tests(price: u64, mid: u64, increment: u64, expected: f64) {
+        let order = Order {
+            order_type: OrderType::Bid,
+            id: "1".into(),
+            price: Price::from_scaled(price),
+            amount: 100,
+            placed_at: None,
+        };
+
+        assert_eq!(
+            order.distance_from_mid(Price::from_scaled(mid), Price::from_scaled(increment)),
+            expected
+        );
+    }
+
+    #[test_case(Duration::ZERO)]
+    #[test_case(Duration::from_millis(1))]
+    #[test_case(Duration::from_secs(2))]
+    fn iteration_delay_tests(iteration_jitter: Duration) {
+        let config = Config {
+            iteration_jitter,
+            ..test_config()
+        };
+        for _ in 0..100 {
+            let delay = iteration_delay(&config);
+            assert!(delay >= config.iteration_interval);
+            assert!(delay <= config.iteration_interval + iteration_jitter);
+        }
+    }
+
+    #[test]
+    fn calculate_orders_to_make_prioritises_orders_closest_to_mid() {
+        let target_orders: Vec<_> = [
+            (OrderType::Bid, 970),
+            (OrderType::Bid, 990),
+            (OrderType::Bid, 980),
+            (OrderType::Ask, 1030),
+            (OrderType::Ask, 1005),
+            (OrderType::Ask, 1020),
+        ]
+        .into_iter()
+        .map(|(order_type, price)| MakeOrderRequest {
+            order_type,
+            price: Price::from_scaled(price),
+            amount: 100,
+            post_only: true,
+        })
+        .collect();
+
+        let config = Config {
+            ask_increment: Some(Price::from_scaled(5)),
+            max_orders_to_make_per_iteration: 2,
+            ..test_config()
+        };
+
+        let orders =
+            calculate_orders_to_make(&[], target_orders, Price::from_scaled(1000), &config);
+
+        assert_eq!(
+            orders.iter().map(|o| o.price.scaled()).collect_vec(),
+            [1005, 990]
+        );
+    }
+
+    #[test_case(None, 0, &[1005, 990, 980, 1020])]
+    #[test_case(Some(10), 0, &[1005, 990, 980, 1020])]
+    #[test_case(Some(3), 0, &[1005, 990, 980])]
+    #[test_case(Some(3), 1, &[1005, 990])]
+    #[test_case(Some(3), 3, &[])]
+    #[test_case(Some(3), 4, &[])]
+    fn calculate_orders_to_make_max_total_open_orders_tests(
+        max_total_open_orders: Option<usize>,
+        open_order_count: usize,
+        expected: &[u64],
+    ) {
+        let target_orders: Vec<_> = [
+            (OrderType::Bid, 980),
+            (OrderType::Bid, 990),
+            (OrderType::Ask, 1005),
+            (OrderType::Ask, 1020),
+        ]
+        .into_iter()
+        .map(|(order_type, price)| MakeOrderRequest {
+            order_type,
+            price: Price::from_scaled(price),
+            amount: 100,
+            post_only: true,
+        })
+        .collect();
+        // Open orders far from the targets, so they don't count towards any of them
+        let open_orders: Vec<_> = (0..open_order_count)
+            .map(|i| Order {
+                order_type: OrderType::Bid,
+                id: i.to_string().into(),
+                price: Price::from_scaled(100),
+                amount: 100,
+                placed_at: None,
+            })
+            .collect();
+        let config = Config {
+            max_orders_to_make_per_iteration: 4,
+            max_total_open_orders,
+            ..test_config()
+        };
+
+        let orders = calculate_orders_to_make(
+            &open_orders,
+            target_orders,
+            Price::from_scaled(1000),
+            &config,
+        );
+
+        assert_eq!(
+            orders.iter().map(|o| o.price.scaled()).collect_vec(),
+            expected
+        );
+    }
+
+    #[test_case(&[60, 30], &[(990, 10)])]
+    #[test_case(&[60, 40], &[])]
+    #[test_case(&[60, 35], &[])]
+    fn calculate_orders_to_make_sums_open_orders_at_the_same_price(
+        open_amounts: &[u64],
+        expected: &[(u64, u64)],
+    ) {
+        let open_orders: Vec<_> = open_amounts
+            .iter()
+            .enumerate()
+            .map(|(i, &amount)| Order {
+                order_type: OrderType::Bid,
+                id: i.to_string().into(),
+                price: Price::from_scaled(990),
+                amount,
+                placed_at: None,
+            })
+            .collect();
+        let target_orders = vec![MakeOrderRequest {
+            order_type: OrderType::Bid,
+            price: Price::from_scaled(990),
+            amount: 100,
+            post_only: true,
+        }];
+
+        let orders = calculate_orders_to_make(
+            &open_orders,
+            target_orders,
+            Price::from_scaled(1000),
+            &test_config(),
+        );
+
+        assert_eq!(
+            orders
+                .iter()
+                .map(|o| (o.price.scaled(), o.amount))
+                .collect_vec(),
+            expected
+        );
+    }
+
+    #[test_case(&[100], &[]; "fully covered")]
+    #[test_case(&[120], &[]; "over covered")]
+    #[test_case(&[99], &[(990, 1)]; "one short")]
+    fn calculate_orders_to_make_never_makes_empty_orders_without_a_min_order_size(
+        open_amounts: &[u64],
+        expected: &[(u64, u64)],
+    ) {
+        let open_orders: Vec<_> = open_amounts
+            .iter()
+            .map(|&amount| Order {
+                order_type: OrderType::Bid,
+                id: "1".into(),
+                price: Price::from_scaled(990),
+                amount,
+                placed_at: None,
+            })
+            .collect();
+        let target_orders = vec![MakeOrderRequest {
+            order_type: OrderType::Bid,
+            price: Price::from_scaled(990),
+            amount: 100,
+            post_only: true,
+        }];
+        let config = Config {
+            min_order_size: 0,
+            ..test_config()
+        };
+
+        let orders = calculate_orders_to_make(
+            &open_orders,
+            target_orders,
+            Price::from_scaled(1000),
+            &config,
+        );
+
+        assert_eq!(
+            orders
+                .iter()
+                .map(|o| (o.price.scaled(), o.amount))
+                .collect_vec(),
+            expected
+        );
+    }
+
+    #[test]
+    fn calculate_orders_to_make_rounds_the_remaining_amount_to_lot_size() {
+        let open_orders = vec![Order {
+            order_type: OrderType::Bid,
+            id: "0".to_string().into(),
+            price: Price::from_scaled(990),
+            amount: 35,
+            placed_at: None,
+        }];
+        let target_orders = vec![MakeOrderRequest {
+            order_type: OrderType::Bid,
+            price: Price::from_scaled(990),
+            amount: 100,
+            post_only: true,
+        }];
+        let config = Config {
+            lot_size: Some(30),
+            ..test_config()
+        };
+
+        let orders = calculate_orders_to_make(
+            &open_orders,
+            target_orders,
+            Price::from_scaled(1000),
+            &config,
+        );
+
+        assert_eq!(orders.iter().map(|o| o.amount).collect_vec(), [60]);
+    }
+
+    #[test_case(990, 150, &[(990, 100)], &[]; "oversized order is replaced")]
+    #[test_case(990, 100, &[], &["01"]; "order of the right size is still cancelled")]
+    #[test_case(950, 150, &[], &["01"]; "order without a target is still cancelled")]
+    fn calculate_orders_to_replace_tests(
+        open_price: u64,
+        open_amount: u64,
+        expected_replacements: &[(u64, u64)],
+        expected_cancels: &[&str],
+    ) {
+        let open_orders = [Order {
+            order_type: OrderType::Bid,
+            id: "01".into(),
+            price: Price::from_scaled(open_price),
+            amount: open_amount,
+            placed_at: None,
+        }];
+        let mut orders_to_cancel = vec![CancelOrderRequest { id: "01".into() }];
+        let target_orders = vec![MakeOrderRequest {
+            order_type: OrderType::Bid,
+            price: Price::from_scaled(990),
+            amount: 100,
+            post_only: true,
+        }];
+
+        let orders_to_replace = calculate_orders_to_replace(
+            &open_orders,
+            &mut orders_to_cancel,
+            target_orders,
+            Price::from_scaled(1000),
+            &test_config(),
+        );
+
+        assert_eq!(
+            orders_to_replace
+                .iter()
+                .map(|r| (r.new_price.scaled(), r.new_amount))
+                .collect_vec(),
+            expected_replacements
+        );
+        assert_eq!(
+            orders_to_cancel.into_iter().map(|o| o.id).collect_vec(),
+            expected_cancels
+                .iter()
+                .copied()
+                .map(OrderId::from)
+                .collect_vec()
+        );
+    }
+
+    #[test_case(None, None, &[99000000, 101000000, 98000000, 102000000])]
+    #[test_case(Some(200), None, &[99000000, 101000000, 102000000])]
+    #[test_case(Some(300), Some(110), &[99000000, 101000000, 98000000])]
+    #[test_case(Some(0), Some(0), &[])]
+    fn calculate_orders_to_make_notional_cap_tests(
+        max_total_bid_notional: Option<u64>,
+        max_total_ask_notional: Option<u64>,
+        expected_prices: &[u64],
+    ) {
+        // An open bid with a notional of 100 counts towards the bid cap
+        let open_orders = [Order {
+            order_type: OrderType::Bid,
+            id: "01".into(),
+            price: Price::from_scaled(97000000),
+            amount: 104,
+            placed_at: None,
+        }];
+        let target_orders: Vec<_> = [
+            (OrderType::Bid, 99000000),
+            (OrderType::Bid, 98000000),
+            (OrderType::Ask, 101000000),
+            (OrderType::Ask, 102000000),
+        ]
+        .into_iter()
+        .map(|(order_type, price)| MakeOrderRequest {
+            order_type,
+            price: Price::from_scaled(price),
+            amount: 100,
+            post_only: true,
+        })
+        .collect();
+        let config = Config {
+            increment: Price::from_scaled(1000000),
+            max_total_bid_notional,
+            max_total_ask_notional,
+            ..test_config()
+        };
+
+        let orders = calculate_orders_to_make(
+            &open_orders,
+            target_orders,
+            Price::from_scaled(100000000),
+            &config,
+        );
+
+        assert_eq!(
+            orders.iter().map(|o| o.price.scaled()).collect_vec(),
+            expected_prices
+        );
+    }
+
+    #[test_case(u64::MAX, 100000000, true; "notional of exactly u64 max")]
+    #[test_case(u64::MAX, 100000001, false; "notional just over u64 max")]
+    #[test_case(u64::MAX, u64::MAX, false; "max price and amount")]
+    fn calculate_orders_to_make_notional_cap_does_not_saturate_tests(
+        price: u64,
+        amount: u64,
+        expected_made: bool,
+    ) {
+        // Half of the cap is already taken by an open ask, so the orders' notional can only fit
+        // if it is compared without capping it at u64::MAX
+        let open_orders = [Order {
+            order_type: OrderType::Ask,
+            id: "01".into(),
+            price: Price::from_scaled(u64::MAX),
+            amount: 100000000,
+            placed_at: None,
+        }];
+        let target_orders = vec![MakeOrderRequest {
+            order_type: OrderType::Ask,
+            price: Price::from_scaled(price),
+            amount,
+            post_only: true,
+        }];
+        let config = Config {
+            max_total_ask_notional: Some(u64::MAX),
+            ..test_config()
+        };
+
+        let made =
+            !calculate_orders_to_make(&[], target_orders.clone(), Price::ZERO, &config).is_empty();
+        assert_eq!(made, expected_made);
+
+        // With the open ask the total is over the cap however large the order is
+        assert!(
+            calculate_orders_to_make(&open_orders, target_orders, Price::ZERO, &config).is_empty()
+        );
+    }
+
+    #[test_case(&[100], 0, &[])]
+    #[test_case(&[150], 0, &["0"])]
+    #[test_case(&[150], 50, &[])]
+    #[test_case(&[60, 60], 0, &["1"])]
+    #[test_case(&[60, 60], 20, &[])]
+    fn calculate_orders_to_cancel_oversized_tests(
+        open_amounts: &[u64],
+        order_size_tolerance: u64,
+        expected_ids: &[&str],
+    ) {
+        let open_orders: Vec<_> = open_amounts
+            .iter()
+            .enumerate()
+            .map(|(i, &amount)| Order {
+                order_type: OrderType::Bid,
+                id: i.to_string().into(),
+                price: Price::from_scaled(990),
+                amount,
+                placed_at: None,
+            })
+            .collect();
+        let target_orders = vec![MakeOrderRequest {
+            order_type: OrderType::Bid,
+            price: Price::from_scaled(990),
+            amount: 100,
+            post_only: true,
+        }];
+
+        let config = Config {
+            order_size_tolerance,
+            ..test_config()
+        };
+
+        let ids: Vec<_> = calculate_orders_to_cancel(
+            &open_orders,
+            target_orders,
+            Price::from_scaled(1000),
+            0,
+            &config,
+        )
+        .into_iter()
+        .map(|o| o.id)
+        .collect();
+
+        assert_eq!(
+            ids,
+            expected_ids
+                .iter()
+                .copied()
+                .map(OrderId::from)
+                .collect_vec()
+        );
+    }
+
+    #[test_case(None, 1000, &[])]
+    #[test_case(Some(100), 1000, &[])]
+    #[test_case(Some(100), 1100, &[])]
+    #[test_case(Some(100), 1101, &["0"])]
+    fn calculate_orders_to_cancel_max_order_age_tests(
+        max_order_age_nanos: Option<u64>,
+        now: u64,
+        expected_ids: &[&str],
+    ) {
+        let open_orders = [Order {
+            order_type: OrderType::Bid,
+            id: "0".into(),
+            price: Price::from_scaled(990),
+            amount: 100,
+            placed_at: Some(1000),
+        }];
+        let target_orders = vec![MakeOrderRequest {
+            order_type: OrderType::Bid,
+            price: Price::from_scaled(990),
+            amount: 100,
+            post_only: true,
+        }];
+        let config = Config {
+            max_order_age: max_order_age_nanos.map(Duration::from_nanos),
+            ..test_config()
+        };
+
+        let ids: Vec<_> = calculate_orders_to_cancel(
+            &open_orders,
+            target_orders,
+            Price::from_scaled(1000),
+            now,
+            &config,
+        )
+        .into_iter()
+        .map(|o| o.id)
+        .collect();
+
+        assert_eq!(
+            ids,
+            expected_ids
+                .iter()
+                .copied()
+                .map(OrderId::from)
+                .collect_vec()
+        );
+    }
+
+    #[test]
+    fn find_orphans_counts_consecutive_iterations_outside_the_grid() {
+        let ids = |ids: &[&str]| ids.iter().copied().map(OrderId::from).collect_vec();
+
+        // "a" has been outside the grid for two iterations and "b" for one, while "c" was outside
+        // it but has since moved back inside
+        let previous_counts = HashMap::from([
+            (OrderId::from("a"), 2),
+            (OrderId::from("b"), 1),
+            (OrderId::from("c"), 5),
+        ]);
+
+        let (counts, orphans) = find_orphans(&previous_counts, &ids(&["a", "b", "d"]), 2);
+
+        assert_eq!(
+            counts,
+            HashMap::from([
+                (OrderId::from("a"), 3),
+                (OrderId::from("b"), 2),
+                (OrderId::from("d"), 1),
+            ])
+        );
+        assert_eq!(orphans, ids(&["a"]));
+    }
+
+    #[test_case(None, &[990, 980, 970], &[1010, 1020, 1030])]
+    #[test_case(Some(10), &[990, 980, 970], &[1010, 1020, 1030])]
+    #[test_case(Some(25), &[990, 980, 970], &[1025, 1050, 1075])]
+    fn build_orders_increment_tests(
+        ask_increment: Option<u64>,
+        expected_bids: &[u64],
+        expected_asks: &[u64],
+    ) {
+        let config = Config {
+            ask_increment: ask_increment.map(Price::from_scaled),
+            max_orders_per_direction: 3,
+            ..test_config()
+        };
+
+        let (required, optional) = build_orders(Price::from_scaled(1000), &balances(), &config);
+        let orders: Vec<_> = required.into_iter().chain(optional).collect();
+
+        assert_eq!(prices(&orders, OrderType::Bid), expected_bids);
+        assert_eq!(prices(&orders, OrderType::Ask), expected_asks);
+    }
+
+    #[test_case(1, None, 5, &[99999999, 99999998, 99999997, 99999996, 99999995], &[100000001, 100000002, 100000003, 100000004, 100000005]; "increment of one")]
+    #[test_case(2, None, 3, &[99999998, 99999996, 99999994], &[100000002, 100000004, 100000006]; "increment of two")]
+    #[test_case(1, Some(2), 3, &[99999998, 99999996, 99999994], &[100000002, 100000004, 100000006]; "tick size coarser than increment")]
+    fn build_orders_dense_grid_tests(
+        increment: u64,
+        tick_size: Option<u64>,
+        max_orders_per_direction: u64,
+        expected_bids: &[u64],
+        expected_asks: &[u64],
+    ) {
+        let config = Config {
+            increment: Price::from_scaled(increment),
+            tick_size: tick_size.map(Price::from_scaled),
+            max_buy_price: Price::from_scaled(u64::MAX),
+            min_sell_price: Price::from_scaled(1),
+            min_orders_per_direction: 0,
+            max_orders_per_direction,
+            ..test_config()
+        };
+
+        let (required, optional) =
+            build_orders(Price::from_scaled(100000000), &balances(), &config);
+        let orders: Vec<_> = required.into_iter().chain(optional).collect();
+
+        assert_eq!(prices(&orders, OrderType::Bid), expected_bids);
+        assert_eq!(prices(&orders, OrderType::Ask), expected_asks);
+    }
+
+    #[test_case(Spacing::Arithmetic, &[990, 980, 970, 960], &[1010, 1020, 1030, 1040]; "arithmetic")]
+    #[test_case(Spacing::Geometric(1.1), &[990, 900, 818, 744], &[1010, 1111, 1222, 1344]; "geometric")]
+    #[test_case(Spacing::Geometric(1.001), &[990, 989, 988, 987], &[1010, 1011, 1012, 1013]; "geometric factor below the increment")]
+    fn build_orders_spacing_tests(spacing: Spacing, expected_bids: &[u64], expected_asks: &[u64]) {
+        let config = Config {
+            spacing,
+            max_orders_per_direction: 4,
+            ..test_config()
+        };
+
+        let (required, optional) = build_orders(Price::from_scaled(1000), &balances(), &config);
+        let orders: Vec<_> = required.into_iter().chain(optional).collect();
+
+        assert_eq!(prices(&orders, OrderType::Bid), expected_bids);
+        assert_eq!(prices(&orders, OrderType::Ask), expected_asks);
+    }
+
+    #[test_case(Spacing::Arithmetic, 975, 1025, &[970, 960], &[1030, 1040])]
+    #[test_case(Spacing::Geometric(1.1), 900, 1111, &[818, 744], &[1222, 1344])]
+    #[test_case(Spacing::Geometric(1.1), 901, 1110, &[900, 818], &[1111, 1222])]
+    #[test_case(Spacing::Geometric(1.1), 0, 0, &[], &[1010, 1111])]
+    fn build_orders_spacing_price_limit_tests(
+        spacing: Spacing,
+        max_buy_price: u64,
+        min_sell_price: u64,
+        expected_bids: &[u64],
+        expected_asks: &[u64],
+    ) {
+        let config = Config {
+            spacing,
+            max_buy_price: Price::from_scaled(max_buy_price),
+            min_sell_price: Price::from_scaled(min_sell_price),
+            min_orders_per_direction: 2,
+            max_orders_per_direction: 2,
+            ..test_config()
+        };
+
+        let (required, optional) = build_orders(Price::from_scaled(1000), &balances(), &config);
+        let orders: Vec<_> = required.into_iter().chain(optional).collect();
+
+        assert_eq!(prices(&orders, OrderType::Bid), expected_bids);
+        assert_eq!(prices(&orders, OrderType::Ask), expected_asks);
+    }
+
+    #[test_case(None, None, 0; "no limits")]
+    #[test_case(Some(100), Some(10), 0; "within limits")]
+    #[test_case(Some(1000), None, 1; "order size too small")]
+    #[test_case(None, Some(20), 1; "tick size not a multiple")]
+    #[test_case(Some(1000), Some(20), 2; "both")]
+    fn trading_limit_warnings_tests(
+        min_order_size: Option<u64>,
+        tick_size: Option<u64>,
+        expected_warnings: usize,
+    ) {
+        let config = Config {
+            min_order_size: 100,
+            tick_size: Some(Price::from_scaled(30)),
+            ..test_config()
+        };
+        let limits = TradingLimits {
+            min_order_size,
+            tick_size: tick_size.map(Price::from_scaled),
+            lot_size: None,
+        };
+
+        let warnings = trading_limit_warnings(&config, &limits);
+
+        assert_eq!(warnings.len(), expected_warnings);
+    }
+
+    #[test]
+    fn trading_limit_warnings_requires_tick_size_when_exchange_has_one() {
+        let config = Config {
+            tick_size: None,
+            ..test_config()
+        };
+        let limits = TradingLimits {
+            min_order_size: None,
+            tick_size: Some(Price::from_scaled(10)),
+            lot_size: None,
+        };
+
+        assert_eq!(trading_limit_warnings(&config, &limits).len(), 1);
+    }
+
+    #[test_case(None, 5, 1000, Ok(1000); "warm-up complete")]
+    #[test_case(Some(&[]), 3, 1000, Err(vec![1000]); "first sample")]
+    #[test_case(Some(&[1000]), 3, 2000, Err(vec![1000, 2000]); "second sample")]
+    #[test_case(Some(&[1000, 2000]), 3, 1010, Ok(1010); "median of odd samples")]
+    #[test_case(Some(&[1000, 2000, 1010]), 4, 990, Ok(1010); "upper median of even samples")]
+    #[test_case(Some(&[1000, 2000]), 1, 3000, Ok(2000); "warm-up shortened")]
+    fn warm_up_tests(
+        samples: Option<&[u64]>,
+        warmup_iterations: u32,
+        price: u64,
+        expected: Result<u64, Vec<u64>>,
+    ) {
+        let config = Config {
+            warmup_iterations,
+            ..test_config()
+        };
+        let samples: Option<Vec<_>> =
+            samples.map(|s| s.iter().copied().map(Price::from_scaled).collect());
+
+        let result = warm_up(samples.as_deref(), Price::from_scaled(price), &config);
+
+        assert_eq!(
+            result.map(|p| p.scaled()),
+            expected.map_err(|s| s.into_iter().map(Price::from_scaled).collect())
+        );
+    }
+
+    #[test_case(990, Some(1010), 10, false)]
+    #[test_case(1000, Some(1010), 10, false)]
+    #[test_case(1001, Some(1010), 10, true)]
+    #[test_case(1010, Some(1010), 10, true)]
+    #[test_case(1020, Some(1010), 10, true)]
+    #[test_case(u64::MAX, None, 10, false)]
+    fn crosses_asks_tests(
+        bid_price: u64,
+        innermost_ask: Option<u64>,
+        min_spread: u64,
+        expected: bool,
+    ) {
+        assert_eq!(crosses_asks(bid_price, innermost_ask, min_spread), expected);
+    }
+
+    #[test]
+    fn remove_self_crossing_drops_orders_crossing_open_or_new_orders() {
+        let make = |order_type, price| MakeOrderRequest {
+            order_type,
+            price: Price::from_scaled(price),
+            amount: 100,
+            post_only: true,
+        };
+        let open_orders = [Order {
+            order_type: OrderType::Ask,
+            id: OrderId::from("1"),
+            price: Price::from_scaled(985),
+            amount: 100,
+            placed_at: None,
+        }];
+        let orders_to_make = vec![
+            make(OrderType::Bid, 990),
+            make(OrderType::Bid, 985),
+            make(OrderType::Bid, 980),
+            make(OrderType::Ask, 980),
+            make(OrderType::Ask, 1010),
+        ];
+
+        let (kept, dropped) = remove_self_crossing(orders_to_make, &open_orders);
+
+        // The bid at 980 and the ask at 980 cross each other, so both are dropped
+        assert_eq!(kept, vec![make(OrderType::Ask, 1010)]);
+        assert_eq!(prices(&dropped, OrderType::Bid), [990, 985, 980]);
+        assert_eq!(prices(&dropped, OrderType::Ask), [980]);
+    }
+
+    #[test_case(1000, 10, None)]
+    #[test_case(1005, 10, Some(1))]
+    #[test_case(1000, 100, Some(1))]
+    #[test_case(5, 10, Some(3))]
+    #[test_case(1, 1, None)]
+    fn build_orders_never_crosses_tests(price: u64, increment: u64, ask_increment: Option<u64>) {
+        // Price limits which, taken alone, would allow bids far above asks
+        let config = Config {
+            increment: Price::from_scaled(increment),
+            ask_increment: ask_increment.map(Price::from_scaled),
+            max_buy_price: Price::MAX,
+            min_sell_price: Price::ZERO,
+            min_orders_per_direction: 5,
+            ..test_config()
+        };
+
+        let (required, optional) = build_orders(Price::from_scaled(price), &balances(), &config);
+        let orders: Vec<_> = required.into_iter().chain(optional).collect();
+
+        let best_bid = prices(&orders, OrderType::Bid).into_iter().max();
+        let best_ask = prices(&orders, OrderType::Ask).into_iter().min();
+        if let (Some(best_bid), Some(best_ask)) = (best_bid, best_ask) {
+            assert!(
+                best_bid + config.bid_increment().min(config.ask_increment()).scaled() <= best_ask
+            );
+        }
+    }
+
+    #[test_case(1000, 10, 3)]
+    #[test_case(1005, 10, 7)]
+    #[test_case(1000, 10, 25)]
+    #[test_case(999, 7, 10)]
+    #[test_case(1000, 100, 1)]
+    fn build_orders_prices_are_multiples_of_tick_size_tests(
+        price: u64,
+        increment: u64,
+        tick_size: u64,
+    ) {
+        let config = Config {
+            increment: Price::from_scaled(increment),
+            tick_size: Some(Price::from_scaled(tick_size)),
+            ..test_config()
+        };
+
+        let (required, optional) = build_orders(Price::from_scaled(price), &balances(), &config);
+        let orders: Vec<_> = required.into_iter().chain(optional).collect();
+
+        assert!(!orders.is_empty());
+        assert!(orders.iter().all(|o| o.price.scaled() % tick_size == 0));
+        let best_bid = prices(&orders, OrderType::Bid).into_iter().max().unwrap();
+        let best_ask = prices(&orders, OrderType::Ask).into_iter().min().unwrap();
+        assert!(best_bid < best_ask);
+    }
+
+    #[test_case(MaxDistance::Increments(3), &[990, 980, 970], &[1010, 1020, 1030])]
+    #[test_case(MaxDistance::Pct(2.5), &[990, 980], &[1010, 1020])]
+    #[test_case(MaxDistance::Pct(0.5), &[], &[])]
+    fn build_orders_stops_at_max_distance_from_mid(
+        max_distance_from_mid: MaxDistance,
+        expected_bids: &[u64],
+        expected_asks: &[u64],
+    ) {
+        let config = Config {
+            max_distance_from_mid: Some(max_distance_from_mid),
+            ..test_config()
+        };
+
+        let (required, optional) = build_orders(Price::from_scaled(1000), &balances(), &config);
+        let orders: Vec<_> = required.into_iter().chain(optional).collect();
+
+        assert_eq!(prices(&orders, OrderType::Bid), expected_bids);
+        assert_eq!(prices(&orders, OrderType::Ask), expected_asks);
+    }
+
+    #[test]
+    fn build_orders_sizes_each_side_separately() {
+        let config = Config {
+            bid_order_sizing: Some(OrderSizing::FixedAmount(200)),
+            ask_order_sizing: Some(OrderSizing::FixedAmount(50)),
+            ..test_config()
+        };
+
+        let (required, optional) = build_orders(Price::from_scaled(1000), &balances(), &config);
+        let orders: Vec<_> = required.into_iter().chain(optional).collect();
+
+        assert!(orders.iter().any(|o| o.order_type == OrderType::Bid));
+        assert!(orders.iter().any(|o| o.order_type == OrderType::Ask));
+        for order in orders {
+            let expected = match order.order_type {
+                OrderType::Bid => 200,
+                OrderType::Ask => 50,
+            };
+            assert_eq!(order.amount, expected);
+        }
+    }
+
+    // At a price of 1000 (scaled), 10^8 of the base token is worth 1000 of the quote token
+    #[test_case(100000000, 1000, 100, 100; "on target")]
+    #[test_case(300000000, 1000, 75, 125; "too much base")]
+    #[test_case(0, 1000, 150, 50; "no base")]
+    #[test_case(0, 0, 100, 100; "empty")]
+    #[test_case(102000000, 1000, 100, 100; "small drift ignored")]
+    fn build_orders_rebalances_towards_target_base_fraction(
+        base: u64,
+        quote: u64,
+        expected_bid: u64,
+        expected_ask: u64,
+    ) {
+        let config = Config {
+            target_base_fraction: Some(0.5),
+            min_order_size: 0,
+            ..test_config()
+        };
+
+        let (required, _) =
+            build_orders(Price::from_scaled(1000), &Balances { base, quote }, &config);
+
+        for order in required {
+            let expected = match order.order_type {
+                OrderType::Bid => expected_bid,
+                OrderType::Ask => expected_ask,
+            };
+            assert_eq!(order.amount, expected);
+        }
+    }
+
+    #[test]
+    fn build_orders_never_rebalances_a_side_down_to_nothing() {
+        let config = Config {
+            target_base_fraction: Some(0.0),
+            min_order_size: 0,
+            ..test_config()
+        };
+        let balances = Balances {
+            base: 100000000,
+            quote: 0,
+        };
+
+        let (required, _) = build_orders(Price::from_scaled(1000), &balances, &config);
+
+        let amounts: Vec<_> = required.iter().map(|o| (o.order_type, o.amount)).collect();
+        assert_eq!(amounts, [(OrderType::Bid, 10), (OrderType::Ask, 200)]);
+    }
+
+    #[test]
+    fn order_grid_lists_bids_then_asks_from_the_innermost_outwards() {
+        let config = Config {
+            increment: Price::ZERO,
+            increment_pct: Some(1.0),
+            max_orders_per_direction: 2,
+            ..test_config()
+        };
+
+        let grid = order_grid(Price::from_scaled(1000), &balances(), &config);
+
+        assert_eq!(
+            grid.iter()
+                .map(|o| (o.order_type, o.price.scaled()))
+                .collect_vec(),
+            [
+                (OrderType::Bid, 990),
+                (OrderType::Bid, 980),
+                (OrderType::Ask, 1010),
+                (OrderType::Ask, 1020)
+            ]
+        );
+    }
+
+    #[test]
+    fn build_orders_rounds_bids_down_and_asks_up_to_tick_size() {
+        let config = Config {
+            tick_size: Some(Price::from_scaled(7)),
+            max_orders_per_direction: 3,
+            ..test_config()
+        };
+
+        let (required, optional) = build_orders(Price::from_scaled(1000), &balances(), &config);
+        let orders: Vec<_> = required.into_iter().chain(optional).collect();
+
+        assert_eq!(prices(&orders, OrderType::Bid), [987, 980, 966]);
+        assert_eq!(prices(&orders, OrderType::Ask), [1015, 1022, 1036]);
+    }
+
+    #[test]
+    fn build_orders_skips_levels_within_a_much_coarser_tick_size() {
+        // Ten million levels round to each tick, which would take seconds to walk one by one
+        let config = Config {
+            tick_size: Some(Price::from_scaled(100_000_000)),
+            max_orders_per_direction: 3,
+            ..test_config()
+        };
+
+        let (required, optional) =
+            build_orders(Price::from_scaled(10_000_000_000), &balances(), &config);
+        let orders: Vec<_> = required.into_iter().chain(optional).collect();
+
+        assert_eq!(
+            prices(&orders, OrderType::Bid),
+            [9_900_000_000, 9_800_000_000, 9_700_000_000]
+        );
+        assert_eq!(
+            prices(&orders, OrderType::Ask),
+            [10_100_000_000, 10_200_000_000, 10_300_000_000]
+        );
+    }
+
+    #[test]
+    fn plan_orders_matches_open_orders_at_tick_rounded_prices() {
+        let config = Config {
+            tick_size: Some(Price::from_scaled(7)),
+            max_orders_per_direction: 3,
+            min_orders_per_direction: 3,
+            ..test_config()
+        };
+        let price = Price::from_scaled(1000);
+
+        let plan = plan_orders(&[], price, &balances(), &Cooldowns::new(), 0, &config);
+        let open_orders: Vec<_> = plan
+            .orders_to_make
+            .into_iter()
+            .enumerate()
+            .map(|(i, o)| Order {
+                order_type: o.order_type,
+                id: i.to_string().into(),
+                price: o.price,
+                amount: o.amount,
+                placed_at: None,
+            })
+            .collect();
+        assert_eq!(open_orders.len(), 6);
+
+        let plan = plan_orders(
+            &open_orders,
+            price,
+            &balances(),
+            &Cooldowns::new(),
+            0,
+            &config,
+        );
+
+        assert!(plan.orders_to_make.is_empty());
+        assert!(plan.orders_to_cancel.is_empty());
+    }
+
+    #[test_case(0, &[990, 980], &[1010, 1020])]
+    #[test_case(100, &[990, 980], &[1010, 1020])]
+    #[test_case(150, &[980, 970], &[1020, 1030])]
+    #[test_case(500, &[950, 940], &[1050, 1060])]
+    fn build_orders_fee_floor_tests(fee_bps: u64, expected_bids: &[u64], expected_asks: &[u64]) {
+        let config = Config {
+            fee_bps,
+            max_orders_per_direction: 2,
+            ..test_config()
+        };
+
+        let (required, optional) = build_orders(Price::from_scaled(1000), &balances(), &config);
+        let orders: Vec<_> = required.into_iter().chain(optional).collect();
+
+        assert_eq!(prices(&orders, OrderType::Bid), expected_bids);
+        assert_eq!(prices(&orders, OrderType::Ask), expected_asks);
+        assert!(expected_asks[0] - expected_bids[0] >= fee_floor(1000, fee_bps));
+    }
+
+    #[test_case(QuoteSide::Both, &[990, 980], &[1010, 1020])]
+    #[test_case(QuoteSide::BidsOnly, &[990, 980], &[])]
+    #[test_case(QuoteSide::AsksOnly, &[], &[1010, 1020])]
+    fn build_orders_quote_side_tests(
+        side: QuoteSide,
+        expected_bids: &[u64],
+        expected_asks: &[u64],
+    ) {
+        let config = Config {
+            side,
+            max_orders_per_direction: 2,
+            ..test_config()
+        };
+
+        let (required, optional) = build_orders(Price::from_scaled(1000), &balances(), &config);
+        let orders: Vec<_> = required.into_iter().chain(optional).collect();
+
+        assert_eq!(prices(&orders, OrderType::Bid), expected_bids);
+        assert_eq!(prices(&orders, OrderType::Ask), expected_asks);
+    }
+
+    #[test_case(QuoteSide::Both, &[])]
+    #[test_case(QuoteSide::BidsOnly, &["1"])]
+    #[test_case(QuoteSide::AsksOnly, &["0"])]
+    fn calculate_orders_to_cancel_quote_side_tests(side: QuoteSide, expected_ids: &[&str]) {
+        let open_orders: Vec<_> = [(OrderType::Bid, 990), (OrderType::Ask, 1010)]
+            .into_iter()
+            .enumerate()
+            .map(|(i, (order_type, price))| Order {
+                order_type,
+                id: i.to_string().into(),
+                price: Price::from_scaled(price),
+                amount: 100,
+                placed_at: None,
+            })
+            .collect();
+        let target_orders = open_orders
+            .iter()
+            .map(|o| MakeOrderRequest {
+                order_type: o.order_type,
+                price: o.price,
+                amount: o.amount,
+                post_only: true,
+            })
+            .collect();
+        let config = Config {
+            side,
+            ..test_config()
+        };
+
+        let ids: Vec<_> = calculate_orders_to_cancel(
+            &open_orders,
+            target_orders,
+            Price::from_scaled(1000),
+            0,
+            &config,
+        )
+        .into_iter()
+        .map(|o| o.id)
+        .collect();
+
+        assert_eq!(
+            ids,
+            expected_ids
+                .iter()
+                .copied()
+                .map(OrderId::from)
+                .collect_vec()
+        );
+    }
+
+    #[test]
+    fn build_orders_sizes_orders_by_notional() {
+        let config = Config {
+            increment: Price::from_scaled(10000000),
+            order_sizing: OrderSizing::FixedNotional(100),
+            min_order_size: 85,
+            max_orders_per_direction: 2,
+            ..test_config()
+        };
+
+        let (required, optional) =
+            build_orders(Price::from_scaled(100000000), &balances(), &config);
+        let amounts: Vec<_> = required
+            .into_iter()
+            .chain(optional)
+            .map(|o| (o.price.scaled(), o.amount))
+            .collect();
+
+        // The ask at 120000000 would be for 83, which is below `min_order_size`
+        assert_eq!(amounts, [(90000000, 111), (110000000, 90), (80000000, 125)]);
+    }
+
+    #[test]
+    fn build_orders_drops_orders_too_small_to_make_without_a_min_order_size() {
+        let config = Config {
+            increment: Price::from_scaled(10000000),
+            order_sizing: OrderSizing::FixedNotional(1),
+            min_order_size: 0,
+            max_orders_per_direction: 2,
+            ..test_config()
+        };
+
+        let (required, optional) =
+            build_orders(Price::from_scaled(100000000), &balances(), &config);
+        let amounts: Vec<_> = required
+            .into_iter()
+            .chain(optional)
+            .map(|o| (o.price.scaled(), o.amount))
+            .collect();
+
+        // A notional of 1 buys a single unit below a price of 1, and nothing above it
+        assert_eq!(amounts, [(90000000, 1), (80000000, 1)]);
+    }
+
+    #[test_case(None, 0, &[(990, 100), (980, 100), (970, 100), (1010, 100), (1020, 100), (1030, 100)]; "flat")]
+    #[test_case(Some(0.5), 0, &[(990, 100), (980, 150), (970, 200), (1010, 100), (1020, 150), (1030, 200)]; "scaled")]
+    #[test_case(Some(0.5), 1015, &[(990, 100), (980, 150), (970, 200), (1020, 100), (1030, 150), (1040, 200)]; "scaled from the innermost ask beyond min_sell_price")]
+    fn build_orders_size_scaling_tests(
+        size_scaling: Option<f64>,
+        min_sell_price: u64,
+        expected: &[(u64, u64)],
+    ) {
+        let config = Config {
+            size_scaling,
+            min_sell_price: Price::from_scaled(min_sell_price),
+            min_order_size: 100,
+            min_orders_per_direction: 3,
+            max_orders_per_direction: 3,
+            ..test_config()
+        };
+
+        let (required, optional) = build_orders(Price::from_scaled(1000), &balances(), &config);
+        let orders: Vec<_> = required
+            .into_iter()
+            .chain(optional)
+            .map(|o| (o.price.scaled(), o.amount))
+            .collect();
+
+        assert_eq!(orders, expected);
+    }
+
+    #[test]
+    fn build_orders_skips_bids_when_price_below_increment() {
+        let config = Config {
+            increment: Price::from_scaled(100000),
+            ..test_config()
+        };
+
+        let (required, optional) = build_orders(Price::from_scaled(50), &balances(), &config);
+        let orders: Vec<_> = required.into_iter().chain(optional).collect();
+
+        assert!(prices(&orders, OrderType::Bid).is_empty());
+        assert_eq!(prices(&orders, OrderType::Ask).len(), 5);
+    }
+
+    #[test_case(u64::MAX, 0, &[990, 980, 970], &[1010, 1020, 1030]; "no limits")]
+    #[test_case(990, 1010, &[980, 970], &[1020, 1030]; "limits on the innermost levels")]
+    #[test_case(991, 1009, &[990, 980, 970], &[1010, 1020, 1030]; "limits just beyond the innermost levels")]
+    #[test_case(985, 1015, &[980, 970], &[1020, 1030]; "limits between levels")]
+    #[test_case(960, 1040, &[], &[]; "limits beyond the ladder")]
+    #[test_case(10, 0, &[], &[1010, 1020, 1030]; "no bids below the limit")]
+    fn build_orders_price_limit_tests(
+        max_buy_price: u64,
+        min_sell_price: u64,
+        expected_bids: &[u64],
+        expected_asks: &[u64],
+    ) {
+        let config = Config {
+            max_buy_price: Price::from_scaled(max_buy_price),
+            min_sell_price: Price::from_scaled(min_sell_price),
+            min_orders_per_direction: 0,
+            max_orders_per_direction: 3,
+            ..test_config()
+        };
+
+        let (required, optional) = build_orders(Price::from_scaled(1000), &balances(), &config);
+        let orders: Vec<_> = required.into_iter().chain(optional).collect();
+
+        assert!(prices(&orders, OrderType::Bid)
+            .iter()
+            .all(|p| *p < max_buy_price));
+        assert!(prices(&orders, OrderType::Ask)
+            .iter()
+            .all(|p| *p > min_sell_price));
+        assert_eq!(prices(&orders, OrderType::Bid), expected_bids);
+        assert_eq!(prices(&orders, OrderType::Ask), expected_asks);
+    }
+
+    #[test_case(u64::MAX, 0, &[990, 980, 970, 960, 950], &[1010, 1020, 1030, 1040, 1050])]
+    #[test_case(975, 1025, &[970, 960, 950], &[1030, 1040, 1050])]
+    #[test_case(955, 1045, &[950, 940, 930], &[1050, 1060, 1070])]
+    #[test_case(500, 2000, &[490, 480, 470], &[2010, 2020, 2030])]
+    #[test_case(25, 0, &[20, 10], &[1010, 1020, 1030, 1040, 1050])]
+    fn build_orders_min_orders_per_direction_tests(
+        max_buy_price: u64,
+        min_sell_price: u64,
+        expected_bids: &[u64],
+        expected_asks: &[u64],
+    ) {
+        let config = Config {
+            max_buy_price: Price::from_scaled(max_buy_price),
+            min_sell_price: Price::from_scaled(min_sell_price),
+            min_orders_per_direction: 3,
+            max_orders_per_direction: 5,
+            ..test_config()
+        };
+
+        let (required, optional) = build_orders(Price::from_scaled(1000), &balances(), &config);
+
+        assert_eq!(
+            required.len(),
+            expected_bids.len().min(3) + expected_asks.len().min(3)
+        );
+        let orders: Vec<_> = required.into_iter().chain(optional).collect();
+        assert_eq!(prices(&orders, OrderType::Bid), expected_bids);
+        assert_eq!(prices(&orders, OrderType::Ask), expected_asks);
+    }
+
+    #[test_case(None, 2000, &[990, 980], &[1010, 1020])]
+    #[test_case(Some(1000), 1000, &[990, 980], &[1010, 1020])]
+    #[test_case(Some(1000), 1500, &[970, 960], &[990, 1000])]
+    #[test_case(Some(1000), 5000, &[950, 940], &[970, 980])]
+    #[test_case(Some(1000), 0, &[1030, 1020], &[1050, 1060])]
+    fn build_orders_inventory_skew_tests(
+        target_base: Option<u64>,
+        base_balance: u64,
+        expected_bids: &[u64],
+        expected_asks: &[u64],
+    ) {
+        let config = Config {
+            max_orders_per_direction: 2,
+            inventory_skew: target_base.map(|target_base| InventorySkew {
+                target_base,
+                skew_factor: 4.0,
+            }),
+            ..test_config()
+        };
+        let balances = Balances {
+            base: base_balance,
+            quote: u64::MAX,
+        };
+
+        let (required, optional) = build_orders(Price::from_scaled(1000), &balances, &config);
+        let orders: Vec<_> = required.into_iter().chain(optional).collect();
+
+        assert_eq!(prices(&orders, OrderType::Bid), expected_bids);
+        assert_eq!(prices(&orders, OrderType::Ask), expected_asks);
+    }
+
+    #[test_case(ExchangeError::Network(String::new()), 1, true, 2)]
+    #[test_case(ExchangeError::Network(String::new()), 2, true, 3)]
+    #[test_case(ExchangeError::Network(String::new()), 3, false, 3)]
+    #[test_case(ExchangeError::Other(String::new()), 1, false, 1)]
+    #[test_case(ExchangeError::InsufficientBalance(String::new()), 1, false, 1)]
+    #[tokio::test]
+    async fn retry_tests(
+        error: ExchangeError,
+        failures: u32,
+        expected_success: bool,
+        expected_attempts: u32,
+    ) {
+        let config = Config {
+            max_retries: 2,
+            ..test_config()
+        };
+        let mut attempts = 0;
+
+        let result = retry(&config, || {
+            attempts += 1;
+            let result = if attempts <= failures {
+                Err(error.clone())
+            } else {
+                Ok(())
+            };
+            async move { result }
+        })
+        .await;
+
+        assert_eq!(result.is_ok(), expected_success);
+        assert_eq!(attempts, expected_attempts);
+    }
+
+    #[test_case(1000, 1000, 0.0)]
+    #[test_case(1000, 1100, 10.0)]
+    #[test_case(1000, 900, 10.0)]
+    #[test_case(1000, 3000, 200.0)]
+    #[test_case(0, 0, 0.0)]
+    #[test_case(0, 100, f64::INFINITY)]
+    fn price_move_pct_tests(previous_price: u64, price: u64, expected: f64) {
+        assert_eq!(
+            price_move_pct(
+                Price::from_scaled(previous_price),
+                Price::from_scaled(price)
+            ),
+            expected
+        );
+    }
+
+    #[test_case(OrderType::Bid, Some((970, 990)))]
+    #[test_case(OrderType::Ask, Some((1010, 1010)))]
+    fn price_range_tests(order_type: OrderType, expected: Option<(u64, u64)>) {
+        let orders: Vec<_> = [
+            (OrderType::Bid, 990),
+            (OrderType::Bid, 970),
+            (OrderType::Bid, 980),
+            (OrderType::Ask, 1010),
+        ]
+        .into_iter()
+        .map(|(order_type, price)| MakeOrderRequest {
+            order_type,
+            price: Price::from_scaled(price),
+            amount: 100,
+            post_only: true,
+        })
+        .collect();
+
+        assert_eq!(
+            price_range(orders.iter(), order_type).map(|(min, max)| (min.scaled(), max.scaled())),
+            expected
+        );
+        assert_eq!(price_range(std::iter::empty(), order_type), None);
+    }
+
+    #[test_case(PriceAnchor::LatestPrice, Some(90), Some(110), 95)]
+    #[test_case(PriceAnchor::BookMid, Some(90), Some(110), 100)]
+    #[test_case(PriceAnchor::BookMid, Some(91), Some(110), 100)]
+    #[test_case(PriceAnchor::BookMid, Some(91), Some(111), 101)]
+    #[test_case(PriceAnchor::BookMid, None, None, 95)]
+    #[test_case(PriceAnchor::BookMid, Some(90), None, 95)]
+    #[test_case(PriceAnchor::BookMid, None, Some(110), 95)]
+    #[test_case(PriceAnchor::Blended { mid_weight: 0.0 }, Some(90), Some(110), 95)]
+    #[test_case(PriceAnchor::Blended { mid_weight: 0.6 }, Some(90), Some(110), 98)]
+    #[test_case(PriceAnchor::Blended { mid_weight: 1.0 }, Some(90), Some(110), 100)]
+    #[test_case(PriceAnchor::Blended { mid_weight: 0.6 }, Some(90), None, 95)]
+    #[test_case(PriceAnchor::Blended { mid_weight: 0.6 }, None, None, 95)]
+    fn anchor_price_tests(
+        anchor: PriceAnchor,
+        best_bid: Option<u64>,
+        best_ask: Option<u64>,
+        expected: u64,
+    ) {
+        let stats = Stats {
+            latest_price: Price::from_scaled(95),
+            latest_trade_at: None,
+            volume_24h: None,
+            best_bid: best_bid.map(Price::from_scaled),
+            best_ask: best_ask.map(Price::from_scaled),
+            open_orders: Vec::new(),
+        };
+
+        assert_eq!(anchor_price(&stats, anchor).scaled(), expected)
+    }
+
+    #[test_case(OrderType::Bid, 1000, false ; "bid below the best ask")]
+    #[test_case(OrderType::Bid, 1015, false ; "bid within the slippage")]
+    #[test_case(OrderType::Bid, 1016, true ; "bid beyond the slippage")]
+    #[test_case(OrderType::Ask, 1000, false ; "ask above the best bid")]
+    #[test_case(OrderType::Ask, 985, false ; "ask within the slippage")]
+    #[test_case(OrderType::Ask, 984, true ; "ask beyond the slippage")]
+    fn check_slippage_tests(order_type: OrderType, price: u64, rejected: bool) {
+        let order = MakeOrderRequest {
+            order_type,
+            price: Price::from_scaled(price),
+            amount: 100,
+            post_only: true,
+        };
+        let best_bid = Some(Price::from_scaled(990));
+        let best_ask = Some(Price::from_scaled(1010));
+
+        let result = check_slippage(&order, best_bid, best_ask, Price::from_scaled(5));
+
+        assert_eq!(matches!(result, Err(ExchangeError::Slippage(_))), rejected);
+        assert!(check_slippage(&order, None, None, Price::ZERO).is_ok());
+    }
+
+    fn balances() -> Balances {
+        Balances {
+            base: u64::MAX,
+            quote: u64::MAX,
+        }
+    }
+
+    fn prices(orders: &[MakeOrderRequest], order_type: OrderType) -> Vec<u64> {
+        orders
+            .iter()
+            .filter(|o| o.order_type == order_type)
+            .map(|o| o.price.scaled())
+            .collect()
     }
 }
@@ -1,47 +1,413 @@
 use candid::Principal;
-use ic_agent::agent::http_transport::ReqwestHttpReplicaV2Transport;
-use ic_agent::identity::BasicIdentity;
-use ic_agent::Agent;
-use simple_market_maker::{log, Config, ICDex};
+use clap::{Parser, Subcommand};
+use simple_market_maker::{
+    order_grid, trading_limit_warnings, Balances, Config, Dip20Ledger, DryRun, EventSink, Exchange,
+    ICDex, NoopEventSink, ObserveOnly, OrderSizing, OrderType, Price, PriceAnchor, QuoteSide,
+    RateLimiter, RoundingMode, Spacing,
+};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
 
 pub type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 
+/// Everything other than these flags is configured through environment variables (which may be
+/// set in a `.env` file)
+#[derive(Parser)]
+struct Args {
+    /// Run a single iteration and exit, with a non-zero status if the iteration fails
+    #[arg(long)]
+    once: bool,
+    /// Check the config, and that it fits within the exchange's trading limits, then exit without
+    /// making any orders. Exits with a non-zero status if any problems are found.
+    #[arg(long)]
+    validate_config: bool,
+    /// With `--validate-config`, only check the config itself without querying the exchange
+    #[arg(long, requires = "validate_config")]
+    offline: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the grid of orders which a config produces at the given price, without connecting to
+    /// the exchange
+    Simulate {
+        /// The price to quote off, as a decimal, eg. 0.05
+        #[arg(long)]
+        price: f64,
+        /// The config file to read, in the same format as CONFIG_FILE
+        #[arg(long)]
+        config: PathBuf,
+        /// The base token balance, which only matters if `inventory_skew` or
+        /// `target_base_fraction` is set. Defaults to the skew's target, so that no skew is
+        /// applied.
+        #[arg(long)]
+        base_balance: Option<u64>,
+        /// The quote token balance, which only matters if `target_base_fraction` is set, in which
+        /// case it defaults to zero.
+        #[arg(long)]
+        quote_balance: Option<u64>,
+    },
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
+    let args = Args::parse();
+    if let Some(Command::Simulate {
+        price,
+        config,
+        base_balance,
+        quote_balance,
+    }) = &args.command
+    {
+        return simulate(*price, config, *base_balance, *quote_balance);
+    }
     dotenv::dotenv()?;
 
+    // Levels are controlled via `RUST_LOG`, eg. `RUST_LOG=simple_market_maker=debug`
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    if dotenv::var("LOG_FORMAT").is_ok_and(|v| v == "json") {
+        tracing_subscriber::fmt()
+            .json()
+            .with_env_filter(filter)
+            .init();
+    } else {
+        tracing_subscriber::fmt().with_env_filter(filter).init();
+    }
+
     let ic_identity_pem = dotenv::var("IC_IDENTITY_PEM")?;
     let ic_url = "https://icp-api.io";
     let dex_canister_id = Principal::from_text(dotenv::var("DEX_CANISTER_ID")?).unwrap();
     let trader_canister_id = Principal::from_text(dotenv::var("TRADER_CANISTER_ID")?).unwrap();
+    // The ledgers are read as ICRC-1 unless BASE_TOKEN_STANDARD or QUOTE_TOKEN_STANDARD is "dip20"
+    let base_ledger_canister_id =
+        Principal::from_text(dotenv::var("BASE_LEDGER_CANISTER_ID")?).unwrap();
+    let quote_ledger_canister_id =
+        Principal::from_text(dotenv::var("QUOTE_LEDGER_CANISTER_ID")?).unwrap();
+    let dry_run = dotenv::var("DRY_RUN").is_ok_and(|v| v == "true");
+    // If set, no orders are made or cancelled and the orders which would have been are appended to
+    // this file as JSON lines instead, taking precedence over DRY_RUN
+    let observe_file = dotenv::var("OBSERVE_FILE").ok().map(PathBuf::from);
+    let price_scale = dotenv::var("ICDEX_PRICE_SCALE").map_or(Ok(10), |v| v.parse())?;
+    let price_rounding = match dotenv::var("ICDEX_PRICE_ROUNDING").as_deref() {
+        Ok("floor") => RoundingMode::Floor,
+        Ok("ceil") => RoundingMode::Ceil,
+        Ok("round") | Err(_) => RoundingMode::Round,
+        Ok(other) => return Err(format!("Invalid ICDEX_PRICE_ROUNDING: {other}").into()),
+    };
+    // Calls to the IC are unlimited unless this is set, in which case up to a second's worth of
+    // calls may be made at once
+    let max_calls_per_second: Option<f64> = dotenv::var("ICDEX_MAX_CALLS_PER_SECOND")
+        .ok()
+        .map(|v| v.parse())
+        .transpose()?;
+    // The agent is rebuilt after this many consecutive transport failures
+    let max_transport_failures =
+        dotenv::var("ICDEX_RECONNECT_AFTER").map_or(Ok(5), |v| v.parse())?;
+    let fee_bps = dotenv::var("FEE_BPS").map_or(Ok(0), |v| v.parse())?;
+    let iteration_jitter_ms = dotenv::var("ITERATION_JITTER_MS").map_or(Ok(0), |v| v.parse())?;
+
+    let agent = ICDex::build_agent(ic_url, &ic_identity_pem)?;
+
+    let mut icdex = ICDex::new(
+        agent,
+        dex_canister_id,
+        trader_canister_id,
+        base_ledger_canister_id,
+        quote_ledger_canister_id,
+    )
+    .with_price_scale(price_scale)
+    .with_price_rounding(price_rounding)
+    .with_reconnect(ic_url.to_string(), ic_identity_pem, max_transport_failures);
+
+    if is_dip20("BASE_TOKEN_STANDARD")? {
+        icdex = icdex.with_base_ledger(Dip20Ledger::new(base_ledger_canister_id));
+    }
+    if is_dip20("QUOTE_TOKEN_STANDARD")? {
+        icdex = icdex.with_quote_ledger(Dip20Ledger::new(quote_ledger_canister_id));
+    }
 
-    let transport = ReqwestHttpReplicaV2Transport::create(ic_url)?;
-    let timeout = Duration::from_secs(60 * 5);
+    if let Some(calls_per_second) = max_calls_per_second {
+        if !(calls_per_second.is_finite() && calls_per_second > 0.0) {
+            return Err(format!("Invalid ICDEX_MAX_CALLS_PER_SECOND: {calls_per_second}").into());
+        }
+        let burst = calls_per_second.ceil() as u32;
+        icdex = icdex.with_rate_limiter(Arc::new(RateLimiter::new(calls_per_second, burst)));
+    }
 
-    let agent = Agent::builder()
-        .with_transport(transport)
-        .with_identity(BasicIdentity::from_pem(ic_identity_pem.as_bytes()).unwrap())
-        .with_ingress_expiry(Some(timeout))
-        .build()?;
+    // If set, the config is read from this file and reloaded whenever it changes
+    let config_file = dotenv::var("CONFIG_FILE").ok().map(PathBuf::from);
 
-    let icdex = ICDex::new(agent, dex_canister_id, trader_canister_id);
+    // These only feed into the config below, so the config file takes precedence over them.
+    // ICDEX_PRICE_SCALE is still used by the exchange, but no longer sets the tick size.
+    if config_file.is_some() {
+        let ignored: Vec<_> = [
+            "FEE_BPS",
+            "ICDEX_PRICE_SCALE",
+            "KILL_FILE",
+            "STATE_FILE",
+            "ITERATION_JITTER_MS",
+        ]
+        .into_iter()
+        .filter(|name| dotenv::var(name).is_ok())
+        .collect();
+        if !ignored.is_empty() {
+            warn!(
+                ?ignored,
+                "CONFIG_FILE is set, so these environment variables are ignored in favour of the \
+                 config file"
+            );
+        }
+    }
 
+    // Only used if there is no config file
     let config = Config {
-        increment: 100000,
-        order_size: 10000000,
+        increment: Price::from_decimal(0.001),
+        increment_pct: None,
+        ask_increment: None,
+        spacing: Spacing::Arithmetic,
+        tick_size: Some(Price::from_scaled(price_scale)),
+        // Taken from the exchange's trading limits
+        lot_size: None,
+        fee_bps,
+        order_sizing: OrderSizing::FixedAmount(10000000),
+        bid_order_sizing: None,
+        ask_order_sizing: None,
+        size_scaling: None,
+        size_jitter_pct: None,
+        size_jitter_seed: rand::random(),
+        volume_scaling: None,
         min_order_size: 1000000,
-        max_buy_price: 8000000,
-        min_sell_price: 4000000,
+        order_size_tolerance: 1000000,
+        max_order_age: None,
+        fill_cooldown_iterations: 2,
+        max_buy_price: Price::from_decimal(0.08),
+        min_sell_price: Price::from_decimal(0.04),
+        max_total_bid_notional: None,
+        max_total_ask_notional: None,
+        max_daily_volume: None,
         min_orders_per_direction: 5,
         max_orders_per_direction: 10,
+        max_distance_from_mid: None,
         max_orders_to_make_per_iteration: 10,
         max_orders_to_cancel_per_iteration: 10,
+        recenter_threshold: None,
+        orphan_iterations: None,
+        cancel_orphans: false,
+        max_total_open_orders: None,
         iteration_interval: Duration::from_secs(5),
+        iteration_jitter: Duration::from_millis(iteration_jitter_ms),
+        iteration_timeout: Some(Duration::from_secs(60)),
+        price_anchor: PriceAnchor::LatestPrice,
+        warmup_iterations: 3,
+        side: QuoteSide::Both,
+        cancel_orders_on_shutdown: true,
+        kill_file: dotenv::var("KILL_FILE").ok().map(PathBuf::from),
+        state_file: dotenv::var("STATE_FILE").ok().map(PathBuf::from),
+        max_retries: 3,
+        retry_backoff: Duration::from_millis(500),
+        inventory_skew: None,
+        target_base_fraction: None,
+        max_price_move_pct: Some(10.0),
+        max_price_age: None,
+        max_slippage: None,
+        volatility_cooldown: None,
+        min_book_spread: None,
+        cancel_orders_when_book_tight: false,
     };
 
-    log("Initialization complete");
+    if args.validate_config {
+        return validate_config(&icdex, config_file.as_deref(), &config, args.offline).await;
+    }
 
-    simple_market_maker::run(&icdex, &config).await;
+    let token = CancellationToken::new();
+    let shutdown_token = token.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("Shutdown requested");
+        shutdown_token.cancel();
+    });
+
+    #[cfg(feature = "metrics")]
+    if let Ok(metrics_addr) = dotenv::var("METRICS_ADDR") {
+        let metrics_addr = metrics_addr.parse()?;
+        tokio::spawn(async move {
+            if let Err(error) = simple_market_maker::metrics::serve(metrics_addr).await {
+                tracing::error!(%error, "Metrics server failed");
+            }
+        });
+        info!(%metrics_addr, "Serving metrics");
+    }
+
+    if let Err(error) = icdex.healthcheck().await {
+        return Err(format!("Healthcheck failed: {error}").into());
+    }
+
+    info!("Initialization complete");
+
+    if let Some(observe_file) = observe_file {
+        info!(
+            ?observe_file,
+            "Running in observe only mode, no orders will be made or cancelled"
+        );
+        let exchange = ObserveOnly::new(icdex, &observe_file)?;
+        run(
+            &exchange,
+            &args,
+            config_file.as_deref(),
+            &config,
+            &exchange,
+            token,
+        )
+        .await
+    } else if dry_run {
+        info!("Running in dry run mode, no orders will be made or cancelled");
+        let exchange = DryRun::new(icdex);
+        run(
+            &exchange,
+            &args,
+            config_file.as_deref(),
+            &config,
+            &NoopEventSink,
+            token,
+        )
+        .await
+    } else {
+        run(
+            &icdex,
+            &args,
+            config_file.as_deref(),
+            &config,
+            &NoopEventSink,
+            token,
+        )
+        .await
+    }
+}
+
+async fn run<E: Exchange>(
+    exchange: &E,
+    args: &Args,
+    config_file: Option<&Path>,
+    config: &Config,
+    events: &dyn EventSink,
+    token: CancellationToken,
+) -> Result<(), Error> {
+    if args.once {
+        let config = load_config(config_file, config)?;
+        simple_market_maker::run_iteration_with_events(exchange, None, &config, events).await?;
+        return Ok(());
+    }
+
+    match config_file {
+        Some(path) => {
+            simple_market_maker::run_with_reload_and_events_until(
+                exchange, None, path, events, token,
+            )
+            .await?
+        }
+        None => {
+            simple_market_maker::run_with_events_until(exchange, None, config, events, token)
+                .await?
+        }
+    }
     Ok(())
 }
+
+fn simulate(
+    price: f64,
+    config_file: &Path,
+    base_balance: Option<u64>,
+    quote_balance: Option<u64>,
+) -> Result<(), Error> {
+    let config = Config::from_file(config_file)?;
+    let price = Price::try_from_decimal(price, RoundingMode::Round)
+        .ok_or_else(|| format!("Invalid price: {price}"))?;
+    let balances = Balances {
+        base: base_balance
+            .or(config.inventory_skew.map(|s| s.target_base))
+            .unwrap_or_default(),
+        quote: quote_balance.unwrap_or(if config.target_base_fraction.is_some() {
+            0
+        } else {
+            u64::MAX
+        }),
+    };
+
+    // Printed like an order book, with the highest price at the top
+    let (bids, asks): (Vec<_>, Vec<_>) = order_grid(price, &balances, &config)
+        .into_iter()
+        .partition(|o| o.order_type == OrderType::Bid);
+    println!("{:<4} {:>20} {:>20}", "type", "price", "amount");
+    for order in asks.iter().rev().chain(&bids) {
+        println!(
+            "{:<4} {:>20} {:>20}",
+            format!("{:?}", order.order_type),
+            order.price.to_string(),
+            order.amount
+        );
+    }
+    Ok(())
+}
+
+// The config from the file if there is one, otherwise the built-in config, after validating it
+fn load_config(config_file: Option<&Path>, config: &Config) -> Result<Config, Error> {
+    match config_file {
+        Some(path) => Ok(Config::from_file(path)?),
+        None => {
+            config.validate()?;
+            Ok(config.clone())
+        }
+    }
+}
+
+async fn validate_config<E: Exchange>(
+    exchange: &E,
+    config_file: Option<&Path>,
+    config: &Config,
+    offline: bool,
+) -> Result<(), Error> {
+    let config = load_config(config_file, config)?;
+
+    if !offline {
+        let trading_limits = exchange.trading_limits().await?;
+        // The bot fits the config to the limits when it starts, so these aren't errors
+        for warning in trading_limit_warnings(&config, &trading_limits) {
+            eprintln!("{warning}");
+        }
+    }
+
+    println!("Config is valid");
+    Ok(())
+}
+
+// Whether the variable says a token implements DIP20 ("dip20") rather than ICRC-1 ("icrc1", the
+// default)
+fn is_dip20(var: &str) -> Result<bool, Error> {
+    match dotenv::var(var).as_deref() {
+        Ok("dip20") => Ok(true),
+        Ok("icrc1") | Err(_) => Ok(false),
+        Ok(other) => Err(format!("Invalid {var}: {other}").into()),
+    }
+}
+
+// Resolves once SIGINT (Ctrl+C) or, on Unix, SIGTERM is received
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sigterm = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = sigterm.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
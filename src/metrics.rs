@@ -0,0 +1,186 @@
+use crate::{Decision, Price};
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+struct Metrics {
+    registry: Registry,
+    orders_made: IntCounter,
+    orders_cancelled: IntCounter,
+    latest_price: IntGauge,
+    open_orders: IntGaugeVec,
+    iteration_duration: Histogram,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let orders_made = IntCounter::new("orders_made_total", "Number of orders made").unwrap();
+        let orders_cancelled =
+            IntCounter::new("orders_cancelled_total", "Number of orders cancelled").unwrap();
+        let latest_price =
+            IntGauge::new("latest_price", "Price used to build the latest orders").unwrap();
+        let open_orders = IntGaugeVec::new(
+            Opts::new("open_orders", "Number of open orders per side"),
+            &["side"],
+        )
+        .unwrap();
+        let iteration_duration = Histogram::with_opts(HistogramOpts::new(
+            "iteration_duration_seconds",
+            "Time taken to complete each iteration",
+        ))
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(orders_made.clone())).unwrap();
+        registry
+            .register(Box::new(orders_cancelled.clone()))
+            .unwrap();
+        registry.register(Box::new(latest_price.clone())).unwrap();
+        registry.register(Box::new(open_orders.clone())).unwrap();
+        registry
+            .register(Box::new(iteration_duration.clone()))
+            .unwrap();
+
+        Metrics {
+            registry,
+            orders_made,
+            orders_cancelled,
+            latest_price,
+            open_orders,
+            iteration_duration,
+        }
+    }
+}
+
+fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+// The most recent iteration's decision for each market, by the market's index in `run_many`,
+// served as JSON from `/decision/{index}`
+static LAST_DECISIONS: Mutex<BTreeMap<usize, Decision>> = Mutex::new(BTreeMap::new());
+
+tokio::task_local! {
+    // The index of the market which the current task is running. Unset outside `run_many`, where
+    // there is only one market, whose decisions are recorded as market 0.
+    pub(crate) static MARKET: usize;
+}
+
+pub(crate) fn record_stats(latest_price: Price, open_bids: usize, open_asks: usize) {
+    let metrics = metrics();
+    metrics.latest_price.set(latest_price.scaled() as i64);
+    metrics
+        .open_orders
+        .with_label_values(&["bid"])
+        .set(open_bids as i64);
+    metrics
+        .open_orders
+        .with_label_values(&["ask"])
+        .set(open_asks as i64);
+}
+
+pub(crate) fn record_orders_made(count: usize) {
+    metrics().orders_made.inc_by(count as u64);
+}
+
+pub(crate) fn record_orders_cancelled(count: usize) {
+    metrics().orders_cancelled.inc_by(count as u64);
+}
+
+pub(crate) fn record_iteration_duration(duration: Duration) {
+    metrics().iteration_duration.observe(duration.as_secs_f64());
+}
+
+pub(crate) fn record_decision(decision: &Decision) {
+    let market = MARKET.try_with(|market| *market).unwrap_or_default();
+    LAST_DECISIONS
+        .lock()
+        .unwrap()
+        .insert(market, decision.clone());
+}
+
+/// Serves each market's latest `Decision` as JSON on `/decision/{index}`, where `index` is the
+/// market's position in the list passed to `run_many` (`null` until the first one is made).
+/// `/decision` serves the first market's, which is the only one when a single market is run. The
+/// metrics are served in the Prometheus text format on every other path of the given address.
+pub async fn serve(addr: SocketAddr) -> Result<(), hyper::Error> {
+    let make_service =
+        make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle_request)) });
+
+    Server::bind(&addr).serve(make_service).await
+}
+
+async fn handle_request(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if let Some(market) = decision_market(request.uri().path()) {
+        let body = serde_json::to_vec(&LAST_DECISIONS.lock().unwrap().get(&market)).unwrap();
+        return Ok(Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap());
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder
+        .encode(&metrics().registry.gather(), &mut buffer)
+        .unwrap();
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(buffer))
+        .unwrap())
+}
+
+fn decision_market(path: &str) -> Option<usize> {
+    match path.strip_prefix("/decision")? {
+        "" => Some(0),
+        index => index.strip_prefix('/')?.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("/decision", Some(0))]
+    #[test_case("/decision/2", Some(2))]
+    #[test_case("/decision/x", None)]
+    #[test_case("/decisions", None)]
+    #[test_case("/metrics", None)]
+    fn decision_market_tests(path: &str, expected: Option<usize>) {
+        assert_eq!(decision_market(path), expected);
+    }
+
+    #[tokio::test]
+    async fn records_decisions_per_market() {
+        let decision = |price| Decision {
+            latest_price: Price::from_scaled(price),
+            anchor_price: Price::from_scaled(price),
+            target_orders: Vec::new(),
+            open_orders: Vec::new(),
+            orders_to_cancel: Vec::new(),
+        };
+
+        MARKET
+            .scope(7, async { record_decision(&decision(1000)) })
+            .await;
+        MARKET
+            .scope(8, async { record_decision(&decision(2000)) })
+            .await;
+
+        let decisions = LAST_DECISIONS.lock().unwrap();
+        assert_eq!(decisions[&7].latest_price, Price::from_scaled(1000));
+        assert_eq!(decisions[&8].latest_price, Price::from_scaled(2000));
+    }
+}
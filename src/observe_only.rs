@@ -0,0 +1,258 @@
+use crate::dry_run::SimulatedOrders;
+use crate::{
+    Balances, CancelOrderRequest, Clock, Decision, Event, EventSink, Exchange, ExchangeError,
+    MakeOrderRequest, OrderId, Stats, SystemClock, TradingLimits,
+};
+use async_trait::async_trait;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::warn;
+
+/// Wraps an exchange so that its data is read as normal but no orders are made or cancelled.
+/// Instead, each batch of orders which would have been made or cancelled is appended to a file as
+/// a line of JSON, so that the decisions of a shadow deployment can be compared against another
+/// config running on the same market.
+///
+/// It is also an `EventSink`. Passed to `run_with_events_until` (or one of the other runners which
+/// publish events), it records every iteration's `Decision`, including iterations which neither
+/// make nor cancel anything, so that the full target grid can be compared too.
+///
+/// As with `DryRun`, the orders which would have been made are reported as open until they would
+/// have been cancelled. Replacing orders is left unsupported, so replacements are recorded as a
+/// cancel and a make. Records and orders are stamped with the time read from the clock.
+pub struct ObserveOnly<E, C = SystemClock> {
+    inner: E,
+    file: Mutex<File>,
+    orders: SimulatedOrders,
+    clock: C,
+}
+
+// A line of the file. `at` is in nanoseconds since the Unix epoch.
+#[derive(Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum Record<'a> {
+    Make {
+        at: u64,
+        orders: &'a [MakeOrderRequest],
+    },
+    Cancel {
+        at: u64,
+        ids: Vec<&'a OrderId>,
+    },
+    Decision {
+        at: u64,
+        #[serde(flatten)]
+        decision: &'a Decision,
+    },
+}
+
+impl<E: Exchange> ObserveOnly<E> {
+    /// Appends to the file at `path`, creating it if it doesn't exist
+    pub fn new(inner: E, path: &Path) -> std::io::Result<Self> {
+        ObserveOnly::with_clock(inner, path, SystemClock)
+    }
+}
+
+impl<E: Exchange, C: Clock> ObserveOnly<E, C> {
+    /// Like `new`, but reads the time from `clock`, which should be the clock the bot is run with
+    pub fn with_clock(inner: E, path: &Path, clock: C) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(ObserveOnly {
+            inner,
+            file: Mutex::new(file),
+            orders: SimulatedOrders::new("observe-only"),
+            clock,
+        })
+    }
+
+    // A failure to record a decision is logged rather than failing the iteration, as with the
+    // state file
+    fn record(&self, record: &Record) {
+        let mut line = serde_json::to_vec(record).unwrap();
+        line.push(b'\n');
+        if let Err(error) = self.file.lock().unwrap().write_all(&line) {
+            warn!(%error, "Failed to record decision");
+        }
+    }
+}
+
+#[async_trait]
+impl<E: Exchange + Sync, C: Clock> Exchange for ObserveOnly<E, C> {
+    async fn stats(&self) -> Result<Stats, ExchangeError> {
+        self.inner.stats().await.map(|s| self.orders.add_to(s))
+    }
+
+    async fn balances(&self) -> Result<Balances, ExchangeError> {
+        self.inner.balances().await
+    }
+
+    async fn trading_limits(&self) -> Result<TradingLimits, ExchangeError> {
+        self.inner.trading_limits().await
+    }
+
+    async fn make_orders(
+        &self,
+        orders: Vec<MakeOrderRequest>,
+    ) -> Vec<Result<OrderId, ExchangeError>> {
+        let now = self.clock.now();
+        if !orders.is_empty() {
+            self.record(&Record::Make {
+                at: now,
+                orders: &orders,
+            });
+        }
+        orders
+            .iter()
+            .map(|o| Ok(self.orders.make(o, now)))
+            .collect()
+    }
+
+    async fn cancel_orders(
+        &self,
+        orders: Vec<CancelOrderRequest>,
+    ) -> Vec<Result<OrderId, ExchangeError>> {
+        if !orders.is_empty() {
+            self.record(&Record::Cancel {
+                at: self.clock.now(),
+                ids: orders.iter().map(|o| &o.id).collect(),
+            });
+        }
+        for order in &orders {
+            self.orders.cancel(&order.id);
+        }
+        orders.into_iter().map(|o| Ok(o.id)).collect()
+    }
+}
+
+impl<E: Exchange + Sync, C: Clock> EventSink for ObserveOnly<E, C> {
+    fn publish(&self, event: Event) {
+        if let Event::Decision(decision) = event {
+            self.record(&Record::Decision {
+                at: self.clock.now(),
+                decision: &decision,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use crate::testing::{MockClock, MockExchange};
+    use crate::{run_once, OrderType, Price, State};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn records_decisions_without_touching_the_inner_exchange() {
+        let path = std::env::temp_dir().join(format!("smm-observe-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mock = MockExchange::new(
+            Stats {
+                latest_price: Price::from_scaled(1000),
+                latest_trade_at: None,
+                volume_24h: None,
+                best_bid: None,
+                best_ask: None,
+                open_orders: Vec::new(),
+            },
+            Balances { base: 0, quote: 0 },
+        );
+        let exchange = ObserveOnly::new(mock, &path).unwrap();
+
+        let order = MakeOrderRequest {
+            order_type: OrderType::Bid,
+            price: Price::from_scaled(990),
+            amount: 100,
+            post_only: true,
+        };
+        assert_eq!(exchange.make_orders(vec![order]).await.len(), 1);
+        assert!(exchange.make_orders(Vec::new()).await.is_empty());
+        let cancel = CancelOrderRequest {
+            id: OrderId::from(vec![1, 2]),
+        };
+        assert_eq!(
+            exchange.cancel_orders(vec![cancel]).await,
+            vec![Ok(OrderId::from(vec![1, 2]))]
+        );
+
+        assert!(exchange.inner.made_orders().is_empty());
+        assert!(exchange.inner.cancelled_orders().is_empty());
+
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["action"], "make");
+        assert_eq!(
+            lines[0]["orders"],
+            serde_json::json!([{ "order_type": "Bid", "price": 990, "amount": 100, "post_only": true }])
+        );
+        assert_eq!(lines[1]["action"], "cancel");
+        assert_eq!(lines[1]["ids"], serde_json::json!([{ "hex": "0102" }]));
+    }
+
+    #[tokio::test]
+    async fn records_every_iteration_and_keeps_the_orders_it_would_have_made_open() {
+        let path =
+            std::env::temp_dir().join(format!("smm-observe-runs-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let clock = MockClock::new(1_700_000_000_000_000_000);
+        let mock = MockExchange::at_price(Price::from_scaled(1000));
+        let exchange = ObserveOnly::with_clock(mock, &path, &clock).unwrap();
+        let config = test_config();
+
+        let state = run_once(
+            &exchange,
+            None,
+            &config,
+            &State::default(),
+            &exchange,
+            &clock,
+        )
+        .await
+        .unwrap();
+        clock.advance(Duration::from_secs(5));
+        let state = run_once(&exchange, None, &config, &state, &exchange, &clock)
+            .await
+            .unwrap();
+
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+
+        // The second iteration finds the orders made by the first still open, so it only records
+        // its decision
+        let actions: Vec<_> = lines
+            .iter()
+            .map(|l| l["action"].as_str().unwrap())
+            .collect();
+        assert_eq!(actions, ["decision", "make", "decision"]);
+        let times: Vec<_> = lines.iter().map(|l| l["at"].as_u64().unwrap()).collect();
+        assert_eq!(
+            times,
+            [
+                1_700_000_000_000_000_000,
+                1_700_000_000_000_000_000,
+                1_700_000_005_000_000_000
+            ]
+        );
+        assert_eq!(lines[2]["target_orders"], lines[0]["target_orders"]);
+        assert_eq!(
+            lines[2]["open_orders"].as_array().unwrap().len(),
+            lines[1]["orders"].as_array().unwrap().len()
+        );
+        assert!(exchange.inner.made_orders().is_empty());
+        assert_eq!(state.position.amount, 0);
+    }
+}
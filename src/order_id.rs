@@ -0,0 +1,155 @@
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::{Debug, Display, Formatter};
+
+/// The id which an exchange assigns to an order, held as the raw bytes the exchange uses.
+/// Exchanges whose ids are text hold the UTF-8 bytes of the text and remember that they are text,
+/// so that they are displayed and serialized as text. Other ids are displayed as hex and
+/// serialized as `{"hex": "..."}`.
+#[derive(Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct OrderId {
+    bytes: Vec<u8>,
+    is_text: bool,
+}
+
+impl OrderId {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    /// The id as text, if it was created from text
+    pub fn as_text(&self) -> Option<&str> {
+        if self.is_text {
+            std::str::from_utf8(&self.bytes).ok()
+        } else {
+            None
+        }
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(&self.bytes)
+    }
+
+    pub fn from_hex(hex: &str) -> Result<OrderId, hex::FromHexError> {
+        hex::decode(hex).map(OrderId::from)
+    }
+}
+
+impl From<Vec<u8>> for OrderId {
+    fn from(bytes: Vec<u8>) -> Self {
+        OrderId {
+            bytes,
+            is_text: false,
+        }
+    }
+}
+
+impl From<String> for OrderId {
+    fn from(text: String) -> Self {
+        OrderId {
+            bytes: text.into_bytes(),
+            is_text: true,
+        }
+    }
+}
+
+impl From<&str> for OrderId {
+    fn from(text: &str) -> Self {
+        OrderId::from(text.to_string())
+    }
+}
+
+impl Display for OrderId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.as_text() {
+            Some(text) => f.write_str(text),
+            None => f.write_str(&self.to_hex()),
+        }
+    }
+}
+
+impl Debug for OrderId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.as_text() {
+            Some(text) => f.debug_tuple("OrderId").field(&text).finish(),
+            None => f.debug_tuple("OrderId").field(&self.to_hex()).finish(),
+        }
+    }
+}
+
+// Text ids are plain strings so that they read naturally, while other ids are wrapped so that they
+// can't be mistaken for text
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Repr {
+    Text(String),
+    Bytes { hex: String },
+}
+
+impl Serialize for OrderId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.as_text() {
+            Some(text) => Repr::Text(text.to_string()),
+            None => Repr::Bytes { hex: self.to_hex() },
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for OrderId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match Repr::deserialize(deserializer)? {
+            Repr::Text(text) => Ok(OrderId::from(text)),
+            Repr::Bytes { hex } => OrderId::from_hex(&hex).map_err(D::Error::custom),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(&[], ""; "empty")]
+    #[test_case(&[0, 1, 255], "0001ff"; "bytes")]
+    fn hex_round_trip(bytes: &[u8], expected: &str) {
+        let id = OrderId::from(bytes.to_vec());
+
+        assert_eq!(id.to_hex(), expected);
+        assert_eq!(OrderId::from_hex(expected), Ok(id));
+    }
+
+    #[test]
+    fn from_hex_rejects_invalid_hex() {
+        assert!(OrderId::from_hex("0g").is_err());
+        assert!(OrderId::from_hex("123").is_err());
+    }
+
+    #[test]
+    fn text_ids_keep_their_text() {
+        assert_eq!(OrderId::from("mock-1").as_text(), Some("mock-1"));
+        assert_eq!(OrderId::from(vec![255]).as_text(), None);
+        // Bytes which happen to be valid UTF-8 aren't text
+        assert_eq!(OrderId::from(b"ab".to_vec()).as_text(), None);
+        assert_ne!(OrderId::from("ab"), OrderId::from(b"ab".to_vec()));
+    }
+
+    #[test_case(OrderId::from("123"), "123"; "text")]
+    #[test_case(OrderId::from(vec![1, 2]), "0102"; "bytes")]
+    fn displays_text_ids_as_text(id: OrderId, expected: &str) {
+        assert_eq!(id.to_string(), expected);
+    }
+
+    #[test_case(OrderId::from("123"), r#""123""#; "text")]
+    #[test_case(OrderId::from(vec![1, 2]), r#"{"hex":"0102"}"#; "bytes")]
+    fn serialization_round_trip(id: OrderId, expected: &str) {
+        let json = serde_json::to_string(&id).unwrap();
+
+        assert_eq!(json, expected);
+        assert_eq!(serde_json::from_str::<OrderId>(&json).unwrap(), id);
+    }
+}
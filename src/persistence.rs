@@ -0,0 +1,274 @@
+use crate::{CancelOrderRequest, MakeOrderRequest, Order, OrderId};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::path::Path;
+use tracing::{info, warn};
+
+/// The orders known to the bot, written to disk before each batch of orders is made so that, after
+/// a restart, orders whose confirmation was lost can be told apart from orders which failed
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct OrderState {
+    /// The format of the file. Files written before it was added have version 0, in which every
+    /// order id was written as hex, whether or not the exchange's ids are text.
+    #[serde(default)]
+    version: u32,
+    /// The open orders as of the last iteration, excluding those which were being cancelled
+    open_orders: Vec<Order>,
+    /// The orders which were being made in the last iteration, which don't yet have ids
+    unconfirmed_orders: Vec<MakeOrderRequest>,
+    /// The ids of the orders which were being cancelled in the last iteration. Missing from files
+    /// written before it was added.
+    #[serde(default)]
+    cancelling: Vec<OrderId>,
+}
+
+impl OrderState {
+    pub(crate) fn new(
+        open_orders: &[Order],
+        orders_to_cancel: &[CancelOrderRequest],
+        orders_to_make: &[MakeOrderRequest],
+    ) -> OrderState {
+        let cancelled: HashSet<_> = orders_to_cancel.iter().map(|o| &o.id).collect();
+
+        OrderState {
+            version: CURRENT_VERSION,
+            open_orders: open_orders
+                .iter()
+                .filter(|o| !cancelled.contains(&o.id))
+                .cloned()
+                .collect(),
+            unconfirmed_orders: orders_to_make.to_vec(),
+            cancelling: orders_to_cancel.iter().map(|o| o.id.clone()).collect(),
+        }
+    }
+
+    /// Whether the order was being cancelled in the last iteration, in which case it may have
+    /// gone because of the cancel rather than a fill
+    pub(crate) fn was_cancelling(&self, id: &OrderId) -> bool {
+        self.cancelling.iter().any(|c| self.is_same_id(c, id))
+    }
+
+    /// Returns `None` if the file doesn't exist
+    pub(crate) fn load(path: &Path) -> std::io::Result<Option<OrderState>> {
+        match std::fs::read(path) {
+            Ok(bytes) => Ok(Some(
+                serde_json::from_slice::<OrderState>(&bytes)?.migrate()?,
+            )),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    // Writes to a temporary file first so that a crash mid-write can't leave a truncated file
+    pub(crate) fn save(&self, path: &Path) -> std::io::Result<()> {
+        let temp_path = path.with_extension("tmp");
+        std::fs::write(&temp_path, serde_json::to_vec(self)?)?;
+        std::fs::rename(temp_path, path)
+    }
+
+    // Version 0 ids are read back as text holding the hex of the id, so they are decoded into their
+    // bytes and then compared with the live ids by bytes, since it isn't known which were text
+    fn migrate(mut self) -> std::io::Result<OrderState> {
+        if self.version == 0 {
+            let from_hex = |id: &mut OrderId| -> std::io::Result<()> {
+                let hex = id.as_text().unwrap_or_default();
+                *id = OrderId::from_hex(hex)
+                    .map_err(|error| std::io::Error::new(ErrorKind::InvalidData, error))?;
+                Ok(())
+            };
+            for order in &mut self.open_orders {
+                from_hex(&mut order.id)?;
+            }
+            for id in &mut self.cancelling {
+                from_hex(id)?;
+            }
+        }
+        Ok(self)
+    }
+
+    fn is_same_id(&self, saved: &OrderId, live: &OrderId) -> bool {
+        if self.version == 0 {
+            saved.as_bytes() == live.as_bytes()
+        } else {
+            saved == live
+        }
+    }
+
+    /// Compares the recorded orders with the orders which are actually open. Unconfirmed orders
+    /// are matched with open orders which weren't previously known by side and price.
+    pub(crate) fn reconcile(&self, live_orders: &[Order]) -> Reconciliation {
+        let is_known = |id: &OrderId| self.open_orders.iter().any(|o| self.is_same_id(&o.id, id));
+        let is_live = |id: &OrderId| live_orders.iter().any(|o| self.is_same_id(id, &o.id));
+
+        let mut new_orders: Vec<_> = live_orders.iter().filter(|o| !is_known(&o.id)).collect();
+
+        let mut confirmed = Vec::new();
+        let mut lost = Vec::new();
+        for order in &self.unconfirmed_orders {
+            match new_orders.iter().position(|o| matches(o, order)) {
+                Some(index) => confirmed.push(new_orders.swap_remove(index).clone()),
+                None => lost.push(order.clone()),
+            }
+        }
+
+        Reconciliation {
+            confirmed,
+            lost,
+            closed: self
+                .open_orders
+                .iter()
+                .filter(|o| !is_live(&o.id))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+const CURRENT_VERSION: u32 = 1;
+
+fn matches(order: &Order, request: &MakeOrderRequest) -> bool {
+    order.order_type == request.order_type && order.price == request.price
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Reconciliation {
+    /// Open orders which were being made when the state was saved
+    pub confirmed: Vec<Order>,
+    /// Orders which were being made but aren't open, either because they failed or because they
+    /// have since been filled or cancelled
+    pub lost: Vec<MakeOrderRequest>,
+    /// Orders which were open but no longer are
+    pub closed: Vec<Order>,
+}
+
+impl Reconciliation {
+    pub(crate) fn log(&self) {
+        for order in &self.confirmed {
+            info!(
+                id = %order.id,
+                order_type = ?order.order_type,
+                price = %order.price,
+                "Unconfirmed order is open"
+            );
+        }
+        for order in &self.lost {
+            warn!(
+                order_type = ?order.order_type,
+                price = %order.price,
+                "Unconfirmed order is not open"
+            );
+        }
+        for order in &self.closed {
+            info!(
+                id = %order.id,
+                order_type = ?order.order_type,
+                price = %order.price,
+                "Order is no longer open"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{OrderType, Price};
+
+    fn order(id: &str, order_type: OrderType, price: u64) -> Order {
+        Order {
+            order_type,
+            id: id.to_string().into(),
+            price: Price::from_scaled(price),
+            amount: 100,
+            placed_at: None,
+        }
+    }
+
+    fn request(order_type: OrderType, price: u64) -> MakeOrderRequest {
+        MakeOrderRequest {
+            order_type,
+            price: Price::from_scaled(price),
+            amount: 100,
+            post_only: true,
+        }
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let path = std::env::temp_dir().join(format!("smm-state-{}.json", std::process::id()));
+        let state = OrderState::new(
+            &[
+                order("1", OrderType::Bid, 990),
+                order("2", OrderType::Ask, 1010),
+            ],
+            &[CancelOrderRequest { id: "2".into() }],
+            &[request(OrderType::Ask, 1020)],
+        );
+
+        state.save(&path).unwrap();
+        let loaded = OrderState::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded, Some(state));
+        assert_eq!(OrderState::load(&path).unwrap(), None);
+    }
+
+    #[test]
+    fn loads_files_written_without_cancelling() {
+        let state: OrderState =
+            serde_json::from_str(r#"{"open_orders":[],"unconfirmed_orders":[]}"#).unwrap();
+
+        assert!(!state.was_cancelling(&"1".into()));
+    }
+
+    #[test]
+    fn migrates_files_written_with_hex_ids() {
+        let path = std::env::temp_dir().join(format!("smm-legacy-{}.json", std::process::id()));
+        // Written before the version was added, when "31" was the hex of the text id "1"
+        std::fs::write(
+            &path,
+            r#"{"open_orders":[{"order_type":"Bid","id":"31","price":990,"amount":100,"placed_at":null},{"order_type":"Ask","id":"0102","price":1010,"amount":100,"placed_at":null}],"unconfirmed_orders":[],"cancelling":["0102"]}"#,
+        )
+        .unwrap();
+        let state = OrderState::load(&path).unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let live_orders = [
+            order("1", OrderType::Bid, 990),
+            Order {
+                id: vec![1, 2].into(),
+                ..order("", OrderType::Ask, 1010)
+            },
+        ];
+
+        assert_eq!(state.reconcile(&live_orders), Reconciliation::default());
+        assert!(state.was_cancelling(&vec![1, 2].into()));
+    }
+
+    #[test]
+    fn reconcile_tests() {
+        let state = OrderState {
+            version: CURRENT_VERSION,
+            open_orders: vec![
+                order("1", OrderType::Bid, 990),
+                order("2", OrderType::Ask, 1010),
+            ],
+            unconfirmed_orders: vec![request(OrderType::Bid, 980), request(OrderType::Ask, 1020)],
+            cancelling: Vec::new(),
+        };
+        let live_orders = [
+            order("1", OrderType::Bid, 990),
+            order("3", OrderType::Bid, 980),
+            order("4", OrderType::Bid, 970),
+        ];
+
+        assert_eq!(
+            state.reconcile(&live_orders),
+            Reconciliation {
+                confirmed: vec![order("3", OrderType::Bid, 980)],
+                lost: vec![request(OrderType::Ask, 1020)],
+                closed: vec![order("2", OrderType::Ask, 1010)],
+            }
+        );
+    }
+}
@@ -0,0 +1,80 @@
+use crate::{OrderType, Price};
+
+/// The base position built up by fills, which is negative if more has been sold than bought, along
+/// with its cost in the quote token
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Position {
+    pub amount: i128,
+    pub cost: i128,
+    pub realised_pnl: i128,
+}
+
+impl Position {
+    /// Returns true if the fill reduced the existing position
+    pub fn fill(&mut self, order_type: OrderType, price: Price, amount: u64) -> bool {
+        let (amount, cost) = match order_type {
+            OrderType::Bid => (i128::from(amount), notional(price, amount)),
+            OrderType::Ask => (-i128::from(amount), -notional(price, amount)),
+        };
+
+        if self.amount == 0 || self.amount.signum() == amount.signum() {
+            self.amount += amount;
+            self.cost += cost;
+            return false;
+        }
+
+        let closed = amount.abs().min(self.amount.abs());
+        let closed_cost = self.cost * closed / self.amount.abs();
+        let closing_cost = cost * closed / amount.abs();
+
+        self.realised_pnl -= closed_cost + closing_cost;
+        self.amount += amount;
+        self.cost += cost - closed_cost - closing_cost;
+        true
+    }
+
+    /// The profit, in the quote token, which closing the position at `price` would realise
+    pub fn unrealised_pnl(&self, price: Price) -> i128 {
+        let value = notional(price, u64::try_from(self.amount.abs()).unwrap_or(u64::MAX));
+        value * self.amount.signum() - self.cost
+    }
+}
+
+// The notional of a single fill is below 2^128 / 10^8, so it always fits in an `i128`
+fn notional(price: Price, amount: u64) -> i128 {
+    price.wide_notional(amount) as i128
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test]
+    fn closing_fill_realises_pnl() {
+        let mut position = Position::default();
+
+        assert!(!position.fill(OrderType::Bid, Price::from_decimal(1.0), 200));
+        assert!(!position.fill(OrderType::Bid, Price::from_decimal(2.0), 200));
+        assert!(position.fill(OrderType::Ask, Price::from_decimal(2.5), 100));
+
+        // The average entry price is 1.5, so selling 100 at 2.5 realises 100
+        assert_eq!(position.realised_pnl, 100);
+        assert_eq!(position.amount, 300);
+        assert_eq!(position.cost, 450);
+    }
+
+    #[test_case(OrderType::Bid, 2.0, 100)]
+    #[test_case(OrderType::Bid, 0.5, -50)]
+    #[test_case(OrderType::Ask, 2.0, -100)]
+    #[test_case(OrderType::Ask, 0.5, 50)]
+    fn unrealised_pnl_tests(order_type: OrderType, price: f64, expected: i128) {
+        let mut position = Position::default();
+        position.fill(order_type, Price::from_decimal(1.0), 100);
+
+        assert_eq!(
+            position.unrealised_pnl(Price::from_decimal(price)),
+            expected
+        );
+    }
+}
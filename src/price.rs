@@ -0,0 +1,177 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+/// An amount of the quote token per whole unit of the base token, stored as an integer scaled by
+/// 10^`Price::DECIMALS`
+#[derive(
+    Copy, Clone, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Price(u64);
+
+impl Price {
+    pub const DECIMALS: u32 = 8;
+    pub const ZERO: Price = Price(0);
+    pub const MAX: Price = Price(u64::MAX);
+    const SCALE: u64 = 10u64.pow(Price::DECIMALS);
+
+    /// Wraps a price which is already scaled by 10^`Price::DECIMALS`
+    pub const fn from_scaled(scaled: u64) -> Price {
+        Price(scaled)
+    }
+
+    /// Converts a decimal price, rounding to the nearest representable price. Negative and NaN
+    /// values become zero and values too large to represent become `Price::MAX`.
+    pub fn from_decimal(value: f64) -> Price {
+        Price((value * Price::SCALE as f64).round() as u64)
+    }
+
+    /// Converts a decimal price using the given rounding, returning `None` if the value is NaN,
+    /// infinite, negative or too large to represent
+    pub fn try_from_decimal(value: f64, rounding: RoundingMode) -> Option<Price> {
+        let scaled = value * Price::SCALE as f64;
+        let rounded = match rounding {
+            RoundingMode::Floor => scaled.floor(),
+            RoundingMode::Round => scaled.round(),
+            RoundingMode::Ceil => scaled.ceil(),
+        };
+        // `u64::MAX as f64` rounds up to 2^64, which is out of range
+        (rounded >= 0.0 && rounded < u64::MAX as f64).then_some(Price(rounded as u64))
+    }
+
+    pub const fn scaled(self) -> u64 {
+        self.0
+    }
+
+    pub fn to_decimal(self) -> f64 {
+        self.0 as f64 / Price::SCALE as f64
+    }
+
+    /// The value in the quote token of `amount` of the base token at this price, rounded down and
+    /// capped at `u64::MAX`. Use `checked_notional` where a capped value would be wrong.
+    pub fn notional(self, amount: u64) -> u64 {
+        self.checked_notional(amount).unwrap_or(u64::MAX)
+    }
+
+    /// The value in the quote token of `amount` of the base token at this price, rounded down, or
+    /// `None` if it doesn't fit in a `u64`
+    pub fn checked_notional(self, amount: u64) -> Option<u64> {
+        self.wide_notional(amount).try_into().ok()
+    }
+
+    /// The value in the quote token of `amount` of the base token at this price, rounded down.
+    /// The product of two `u64`s always fits in a `u128`, so this can't overflow.
+    pub fn wide_notional(self, amount: u64) -> u128 {
+        u128::from(self.0) * u128::from(amount) / u128::from(Price::SCALE)
+    }
+
+    /// The amount of the base token worth `notional` of the quote token at this price, rounded
+    /// down, or zero if the price is zero
+    pub fn amount_for_notional(self, notional: u64) -> u64 {
+        if self.0 == 0 {
+            return 0;
+        }
+        (u128::from(notional) * u128::from(Price::SCALE) / u128::from(self.0))
+            .try_into()
+            .unwrap_or(u64::MAX)
+    }
+}
+
+/// How a decimal price is rounded when it is converted to a `Price`
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum RoundingMode {
+    Floor,
+    #[default]
+    Round,
+    Ceil,
+}
+
+impl Display for Price {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}.{:0width$}",
+            self.0 / Price::SCALE,
+            self.0 % Price::SCALE,
+            width = Price::DECIMALS as usize
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    // 0.29 * 10^8 is just below 29000000 in floating point
+    #[test_case(0.29, RoundingMode::Floor, Some(28999999))]
+    #[test_case(0.29, RoundingMode::Round, Some(29000000))]
+    #[test_case(0.29, RoundingMode::Ceil, Some(29000000))]
+    #[test_case(1.000000005, RoundingMode::Floor, Some(100000000))]
+    #[test_case(1.000000005, RoundingMode::Round, Some(100000001))]
+    #[test_case(1.000000005, RoundingMode::Ceil, Some(100000001))]
+    #[test_case(1.5, RoundingMode::Floor, Some(150000000))]
+    #[test_case(1.5, RoundingMode::Ceil, Some(150000000))]
+    #[test_case(0.0, RoundingMode::Round, Some(0))]
+    #[test_case(-0.0, RoundingMode::Round, Some(0); "negative zero")]
+    #[test_case(-1.0, RoundingMode::Round, None)]
+    #[test_case(f64::NAN, RoundingMode::Round, None)]
+    #[test_case(f64::INFINITY, RoundingMode::Round, None)]
+    #[test_case(1e12, RoundingMode::Round, None)]
+    fn try_from_decimal_tests(value: f64, rounding: RoundingMode, expected: Option<u64>) {
+        assert_eq!(
+            Price::try_from_decimal(value, rounding),
+            expected.map(Price::from_scaled)
+        );
+    }
+
+    #[test_case(0.05, 5000000)]
+    #[test_case(1.0, 100000000)]
+    #[test_case(0.123456789, 12345679)]
+    #[test_case(-1.0, 0)]
+    #[test_case(f64::NAN, 0)]
+    #[test_case(f64::INFINITY, u64::MAX)]
+    fn from_decimal_tests(value: f64, expected_scaled: u64) {
+        assert_eq!(
+            Price::from_decimal(value),
+            Price::from_scaled(expected_scaled)
+        );
+    }
+
+    #[test_case(5000000, 0.05)]
+    #[test_case(100000000, 1.0)]
+    #[test_case(0, 0.0)]
+    fn to_decimal_tests(scaled: u64, expected: f64) {
+        assert_eq!(Price::from_scaled(scaled).to_decimal(), expected);
+    }
+
+    #[test_case(100000000, 10, Some(10))]
+    #[test_case(5000000, 10000000, Some(500000))]
+    #[test_case(1, 1, Some(0))]
+    #[test_case(100000000, u64::MAX, Some(u64::MAX); "price of one with max amount")]
+    #[test_case(u64::MAX, 100000000, Some(u64::MAX); "max price with amount of one")]
+    #[test_case(100000001, u64::MAX, None; "just over a price of one with max amount")]
+    #[test_case(u64::MAX, u64::MAX, None; "max price and amount")]
+    fn checked_notional_tests(scaled: u64, amount: u64, expected: Option<u64>) {
+        let price = Price::from_scaled(scaled);
+
+        assert_eq!(price.checked_notional(amount), expected);
+        assert_eq!(price.notional(amount), expected.unwrap_or(u64::MAX));
+    }
+
+    #[test]
+    fn wide_notional_does_not_wrap_around() {
+        let price = Price::from_scaled(u64::MAX);
+
+        assert_eq!(
+            price.wide_notional(u64::MAX),
+            u128::from(u64::MAX) * u128::from(u64::MAX) / 100000000
+        );
+    }
+
+    #[test_case(5000000, "0.05000000")]
+    #[test_case(1234567890, "12.34567890")]
+    fn display_tests(scaled: u64, expected: &str) {
+        assert_eq!(Price::from_scaled(scaled).to_string(), expected);
+    }
+}
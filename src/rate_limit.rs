@@ -0,0 +1,99 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A token bucket which limits how often calls are made to the IC.
+///
+/// The bucket holds up to `burst` tokens and refills at `calls_per_second`. Each call takes a
+/// token, waiting for one to be refilled if the bucket is empty. Wrap it in an `Arc` to share a
+/// single limit between several exchanges, eg. when running multiple markets with one identity.
+pub struct RateLimiter {
+    calls_per_second: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    /// Allows `calls_per_second` calls each second on average, with up to `burst` calls made at
+    /// once after a quiet period. `burst` is at least 1.
+    pub fn new(calls_per_second: f64, burst: u32) -> Self {
+        assert!(
+            calls_per_second.is_finite() && calls_per_second > 0.0,
+            "calls_per_second must be positive"
+        );
+        let burst = burst.max(1) as f64;
+
+        RateLimiter {
+            calls_per_second,
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Waits until a call may be made
+    pub async fn acquire(&self) {
+        loop {
+            match self.try_acquire() {
+                Ok(()) => return,
+                Err(wait) => tokio::time::sleep(wait).await,
+            }
+        }
+    }
+
+    // Takes a token if there is one, otherwise returns how long until one is refilled
+    fn try_acquire(&self) -> Result<(), Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.calls_per_second).min(self.burst);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.calls_per_second,
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn try_acquire_allows_a_burst_then_waits() {
+        let limiter = RateLimiter::new(10.0, 3);
+
+        for _ in 0..3 {
+            assert_eq!(limiter.try_acquire(), Ok(()));
+        }
+        let wait = limiter.try_acquire().unwrap_err();
+        assert!(wait > Duration::from_millis(90) && wait <= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn acquire_is_shared_between_clones_of_an_arc() {
+        let limiter = Arc::new(RateLimiter::new(50.0, 1));
+        let other = limiter.clone();
+
+        let start = Instant::now();
+        limiter.acquire().await;
+        other.acquire().await;
+        limiter.acquire().await;
+
+        // The first call uses the initial token, the other two each wait 20ms for a refill
+        assert!(start.elapsed() >= Duration::from_millis(38));
+    }
+}
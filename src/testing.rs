@@ -0,0 +1,2076 @@
+use crate::{
+    Balances, CancelOrderRequest, Clock, Exchange, ExchangeError, MakeOrderRequest, Order, OrderId,
+    OrderType, Price, ReplaceOrderRequest, Stats,
+};
+use async_trait::async_trait;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// An in-memory exchange which returns the configured `Stats` and `Balances` and records every
+/// order it is asked to make or cancel, allowing strategies to be tested without a live agent.
+///
+/// Orders which are made are added to the open orders, orders which are cancelled are removed and
+/// orders which are replaced are updated in place, and `fill_at_price` simulates trades against the
+/// open orders.
+pub struct MockExchange {
+    stats: Mutex<Stats>,
+    queued_stats: Mutex<VecDeque<Stats>>,
+    balances: Mutex<Balances>,
+    made_orders: Mutex<Vec<MakeOrderRequest>>,
+    cancelled_orders: Mutex<Vec<CancelOrderRequest>>,
+    replaced_orders: Mutex<Vec<ReplaceOrderRequest>>,
+    make_error: Mutex<Option<ExchangeError>>,
+    next_order_id: Mutex<u64>,
+}
+
+impl MockExchange {
+    pub fn new(stats: Stats, balances: Balances) -> Self {
+        MockExchange {
+            stats: Mutex::new(stats),
+            queued_stats: Mutex::default(),
+            balances: Mutex::new(balances),
+            made_orders: Mutex::default(),
+            cancelled_orders: Mutex::default(),
+            replaced_orders: Mutex::default(),
+            make_error: Mutex::default(),
+            next_order_id: Mutex::default(),
+        }
+    }
+
+    /// An exchange whose latest price is `price`, with no open orders and unlimited balances
+    pub fn at_price(price: Price) -> Self {
+        MockExchange::new(
+            stats_at(price),
+            Balances {
+                base: u64::MAX,
+                quote: u64::MAX,
+            },
+        )
+    }
+
+    pub fn set_stats(&self, stats: Stats) {
+        *self.stats.lock().unwrap() = stats;
+    }
+
+    /// Queues stats to be returned by the next call to `stats`, ahead of the configured stats,
+    /// simulating the market moving between calls
+    pub fn queue_stats(&self, stats: Stats) {
+        self.queued_stats.lock().unwrap().push_back(stats);
+    }
+
+    pub fn set_balances(&self, balances: Balances) {
+        *self.balances.lock().unwrap() = balances;
+    }
+
+    /// Fails every order made with `error`, until it's cleared by passing `None`. Failed orders
+    /// are still recorded but aren't opened.
+    pub fn fail_orders_with(&self, error: Option<ExchangeError>) {
+        *self.make_error.lock().unwrap() = error;
+    }
+
+    pub fn made_orders(&self) -> Vec<MakeOrderRequest> {
+        self.made_orders.lock().unwrap().clone()
+    }
+
+    pub fn cancelled_orders(&self) -> Vec<CancelOrderRequest> {
+        self.cancelled_orders.lock().unwrap().clone()
+    }
+
+    pub fn replaced_orders(&self) -> Vec<ReplaceOrderRequest> {
+        self.replaced_orders.lock().unwrap().clone()
+    }
+
+    /// Simulates a trade at the given price, which becomes the latest price. Open bids at or above
+    /// the price and open asks at or below it are filled in full at their own price, removing them
+    /// from the open orders and updating the balances. Returns the orders which were filled.
+    pub fn fill_at_price(&self, price: Price) -> Vec<Order> {
+        let mut stats = self.stats.lock().unwrap();
+        let mut balances = self.balances.lock().unwrap();
+        stats.latest_price = price;
+
+        let (filled, open): (Vec<_>, Vec<_>) = std::mem::take(&mut stats.open_orders)
+            .into_iter()
+            .partition(|o| match o.order_type {
+                OrderType::Bid => o.price >= price,
+                OrderType::Ask => o.price <= price,
+            });
+        stats.open_orders = open;
+
+        for order in &filled {
+            let value = order.price.notional(order.amount);
+            match order.order_type {
+                OrderType::Bid => {
+                    balances.base = balances.base.saturating_add(order.amount);
+                    balances.quote = balances.quote.saturating_sub(value);
+                }
+                OrderType::Ask => {
+                    balances.base = balances.base.saturating_sub(order.amount);
+                    balances.quote = balances.quote.saturating_add(value);
+                }
+            }
+        }
+        filled
+    }
+}
+
+#[async_trait]
+impl Exchange for MockExchange {
+    async fn stats(&self) -> Result<Stats, ExchangeError> {
+        if let Some(stats) = self.queued_stats.lock().unwrap().pop_front() {
+            return Ok(stats);
+        }
+        Ok(self.stats.lock().unwrap().clone())
+    }
+
+    async fn balances(&self) -> Result<Balances, ExchangeError> {
+        Ok(self.balances.lock().unwrap().clone())
+    }
+
+    async fn make_orders(
+        &self,
+        orders: Vec<MakeOrderRequest>,
+    ) -> Vec<Result<OrderId, ExchangeError>> {
+        if let Some(error) = self.make_error.lock().unwrap().clone() {
+            let results = orders.iter().map(|_| Err(error.clone())).collect();
+            self.made_orders.lock().unwrap().extend(orders);
+            return results;
+        }
+        let mut stats = self.stats.lock().unwrap();
+        let mut next_order_id = self.next_order_id.lock().unwrap();
+        let mut results = Vec::new();
+        for order in &orders {
+            let id = OrderId::from(format!("mock-{next_order_id}"));
+            stats.open_orders.push(Order {
+                order_type: order.order_type,
+                id: id.clone(),
+                price: order.price,
+                amount: order.amount,
+                placed_at: None,
+            });
+            results.push(Ok(id));
+            *next_order_id += 1;
+        }
+        self.made_orders.lock().unwrap().extend(orders);
+        results
+    }
+
+    // Cancelling an order which isn't open fails, but is still recorded
+    async fn cancel_orders(
+        &self,
+        orders: Vec<CancelOrderRequest>,
+    ) -> Vec<Result<OrderId, ExchangeError>> {
+        let mut stats = self.stats.lock().unwrap();
+        let results = orders
+            .iter()
+            .map(
+                |c| match stats.open_orders.iter().position(|o| o.id == c.id) {
+                    Some(index) => Ok(stats.open_orders.remove(index).id),
+                    None => Err(ExchangeError::Other(format!("Order not found: {}", c.id))),
+                },
+            )
+            .collect();
+        self.cancelled_orders.lock().unwrap().extend(orders);
+        results
+    }
+
+    // Replaced orders keep their ids, and replacing an order which isn't open fails
+    async fn replace_orders(
+        &self,
+        orders: Vec<ReplaceOrderRequest>,
+    ) -> Vec<Result<OrderId, ExchangeError>> {
+        let mut stats = self.stats.lock().unwrap();
+        let results = orders
+            .iter()
+            .map(
+                |r| match stats.open_orders.iter_mut().find(|o| o.id == r.id) {
+                    Some(order) => {
+                        order.price = r.new_price;
+                        order.amount = r.new_amount;
+                        Ok(order.id.clone())
+                    }
+                    None => Err(ExchangeError::Other(format!("Order not found: {}", r.id))),
+                },
+            )
+            .collect();
+        self.replaced_orders.lock().unwrap().extend(orders);
+        results
+    }
+}
+
+/// Stats with the given latest price, no open orders and nothing else known about the market
+pub fn stats_at(price: Price) -> Stats {
+    Stats {
+        latest_price: price,
+        latest_trade_at: None,
+        volume_24h: None,
+        best_bid: None,
+        best_ask: None,
+        open_orders: Vec::new(),
+    }
+}
+
+/// A clock which only moves when it is told to
+pub struct MockClock {
+    now: AtomicU64,
+}
+
+impl MockClock {
+    /// Starts the clock at `now`, in nanoseconds since the Unix epoch
+    pub fn new(now: u64) -> Self {
+        MockClock {
+            now: AtomicU64::new(now),
+        }
+    }
+
+    pub fn set(&self, now: u64) {
+        self.now.store(now, Ordering::Relaxed);
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use crate::persistence::OrderState;
+    use crate::{
+        replace_orders_within_slippage, run_iteration, run_iteration_with_clock, run_many_until,
+        run_once, run_until, run_with_clock_until, run_with_events_until, send_within_slippage,
+        Config, Decision, Event, NoopEventSink, Order, OrderType, PriceSource, ReplaceOrderRequest,
+        RunError, Spacing, State, SystemClock, VolumeScaling,
+    };
+    use itertools::Itertools;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use test_case::test_case;
+    use tokio_util::sync::CancellationToken;
+
+    #[tokio::test]
+    async fn places_bids_and_asks_around_the_latest_price() {
+        let exchange = MockExchange::at_price(Price::from_scaled(1000));
+        let config = Config {
+            min_orders_per_direction: 3,
+            ..test_config()
+        };
+
+        run_once(
+            &exchange,
+            None,
+            &config,
+            &State::default(),
+            &NoopEventSink,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+
+        let made_orders = exchange.made_orders();
+        let (bids, asks): (Vec<_>, Vec<_>) = made_orders
+            .iter()
+            .partition(|o| o.order_type == OrderType::Bid);
+
+        // Only the orders required to reach `min_orders_per_direction` are made
+        assert_eq!(bids.len(), 3);
+        assert_eq!(asks.len(), 3);
+        assert!(bids.iter().all(|o| o.price < Price::from_scaled(1000)));
+        assert!(asks.iter().all(|o| o.price > Price::from_scaled(1000)));
+        assert!(exchange.cancelled_orders().is_empty());
+    }
+
+    #[tokio::test]
+    async fn spaces_orders_by_increment_pct() {
+        let exchange = MockExchange::at_price(Price::from_scaled(100000));
+        let config = Config {
+            increment: Price::ZERO,
+            increment_pct: Some(1.0),
+            min_orders_per_direction: 2,
+            ..test_config()
+        };
+
+        run_once(
+            &exchange,
+            None,
+            &config,
+            &State::default(),
+            &NoopEventSink,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+
+        let prices: Vec<_> = exchange
+            .made_orders()
+            .iter()
+            .map(|o| o.price.scaled())
+            .sorted()
+            .collect();
+        assert_eq!(prices, [98000, 99000, 101000, 102000]);
+    }
+
+    #[tokio::test]
+    async fn keeps_a_geometric_grid_in_place_while_the_price_is_unchanged() {
+        let exchange = MockExchange::at_price(Price::from_scaled(1000));
+        let config = Config {
+            spacing: Spacing::Geometric(1.1),
+            min_orders_per_direction: 4,
+            max_orders_per_direction: 4,
+            ..test_config()
+        };
+        let state = State::default();
+
+        for _ in 0..3 {
+            run_once(
+                &exchange,
+                None,
+                &config,
+                &state,
+                &NoopEventSink,
+                &SystemClock,
+            )
+            .await
+            .unwrap();
+        }
+
+        // Levels such as 744 and 1111 aren't multiples of the increment, but are still matched by
+        // the orders placed at them, so nothing is cancelled and remade
+        let prices: Vec<_> = exchange
+            .made_orders()
+            .iter()
+            .map(|o| o.price.scaled())
+            .sorted()
+            .collect();
+        assert_eq!(prices, [744, 818, 900, 990, 1010, 1111, 1222, 1344]);
+        assert!(exchange.cancelled_orders().is_empty());
+        assert!(exchange.replaced_orders().is_empty());
+    }
+
+    #[tokio::test]
+    async fn keeps_quoting_when_the_volume_falls_to_zero() {
+        let exchange = MockExchange::new(
+            Stats {
+                volume_24h: Some(0),
+                ..stats_at(Price::from_scaled(1000))
+            },
+            Balances {
+                base: u64::MAX,
+                quote: u64::MAX,
+            },
+        );
+        let config = Config {
+            volume_scaling: Some(VolumeScaling {
+                reference_volume: 1000,
+                max_factor: 1.0,
+                min_factor: 0.1,
+            }),
+            ..test_config()
+        };
+
+        run_once(
+            &exchange,
+            None,
+            &config,
+            &State::default(),
+            &NoopEventSink,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+
+        // Scaled down to `min_factor` rather than to nothing
+        let amounts: Vec<_> = exchange.made_orders().iter().map(|o| o.amount).collect();
+        assert_eq!(amounts, [10, 10]);
+    }
+
+    #[tokio::test]
+    async fn rebalancing_leaves_orders_alone_when_the_balances_drift_slightly() {
+        // At a price of 1000 (scaled), 10^8 of the base token is worth 1000 of the quote token
+        let exchange = MockExchange::new(
+            stats_at(Price::from_scaled(1000)),
+            Balances {
+                base: 100000000,
+                quote: 1000,
+            },
+        );
+        let config = Config {
+            target_base_fraction: Some(0.5),
+            min_order_size: 1,
+            ..test_config()
+        };
+
+        let state = run_once(
+            &exchange,
+            None,
+            &config,
+            &State::default(),
+            &NoopEventSink,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+        // 2% below the target, which would otherwise grow the bid by 2 and top it up
+        exchange.set_balances(Balances {
+            base: 92000000,
+            quote: 1000,
+        });
+        run_once(
+            &exchange,
+            None,
+            &config,
+            &state,
+            &NoopEventSink,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+
+        let amounts: Vec<_> = exchange.made_orders().iter().map(|o| o.amount).collect();
+        assert_eq!(amounts, [100, 100]);
+        assert!(exchange.cancelled_orders().is_empty());
+        assert!(exchange.replaced_orders().is_empty());
+    }
+
+    #[tokio::test]
+    async fn requotes_around_the_price_after_a_fill() {
+        let exchange = MockExchange::new(
+            stats_at(Price::from_scaled(1000)),
+            Balances {
+                base: 1000000,
+                quote: 1000000,
+            },
+        );
+        let config = Config {
+            max_orders_per_direction: 1,
+            ..test_config()
+        };
+
+        run_once(
+            &exchange,
+            None,
+            &config,
+            &State::default(),
+            &NoopEventSink,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+
+        let filled = exchange.fill_at_price(Price::from_scaled(990));
+
+        assert_eq!(filled.len(), 1);
+        assert_eq!(filled[0].order_type, OrderType::Bid);
+        assert_eq!(filled[0].price, Price::from_scaled(990));
+
+        run_once(
+            &exchange,
+            None,
+            &config,
+            &State::default(),
+            &NoopEventSink,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+
+        // The stale ask at 1010 is replaced by one at 1000, and a new bid is made below the fill
+        let cancelled_ids: Vec<_> = exchange
+            .cancelled_orders()
+            .into_iter()
+            .map(|o| o.id)
+            .collect();
+        let requoted_prices: Vec<_> = exchange.made_orders()[2..]
+            .iter()
+            .map(|o| o.price.scaled())
+            .collect();
+        assert_eq!(cancelled_ids, [OrderId::from("mock-1")]);
+        assert_eq!(requoted_prices, [980, 1000]);
+    }
+
+    #[tokio::test]
+    async fn quotes_around_the_price_source_when_provided() {
+        struct FixedPrice(Price);
+
+        #[async_trait]
+        impl PriceSource for FixedPrice {
+            async fn latest_price(&self) -> Result<Price, ExchangeError> {
+                Ok(self.0)
+            }
+        }
+
+        let exchange = MockExchange::at_price(Price::from_scaled(1000));
+
+        run_once(
+            &exchange,
+            Some(&FixedPrice(Price::from_scaled(2000))),
+            &test_config(),
+            &State::default(),
+            &NoopEventSink,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+
+        let prices: Vec<_> = exchange
+            .made_orders()
+            .iter()
+            .map(|o| o.price.scaled())
+            .collect();
+        assert_eq!(prices, [1990, 2010]);
+    }
+
+    #[tokio::test]
+    async fn circuit_breaker_cancels_all_orders_when_price_jumps() {
+        let exchange = MockExchange::new(
+            Stats {
+                open_orders: vec![Order {
+                    order_type: OrderType::Bid,
+                    id: "01".into(),
+                    price: Price::from_scaled(1190),
+                    amount: 100,
+                    placed_at: None,
+                }],
+                ..stats_at(Price::from_scaled(1200))
+            },
+            Balances {
+                base: u64::MAX,
+                quote: u64::MAX,
+            },
+        );
+        let config = Config {
+            max_price_move_pct: Some(10.0),
+            ..test_config()
+        };
+        let state = State {
+            previous_price: Some(Price::from_scaled(1000)),
+            ..State::default()
+        };
+
+        let next_state = run_once(
+            &exchange,
+            None,
+            &config,
+            &state,
+            &NoopEventSink,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(next_state.previous_price, Some(Price::from_scaled(1200)));
+        assert_eq!(exchange.cancelled_orders().len(), 1);
+        assert!(exchange.made_orders().is_empty());
+    }
+
+    #[test_case(None, false, 1, false; "no minimum")]
+    #[test_case(Some(10), false, 0, false; "book tight")]
+    #[test_case(Some(10), true, 0, true; "book tight and cancelling")]
+    #[test_case(Some(5), true, 1, false; "book wide enough")]
+    #[tokio::test]
+    async fn min_book_spread_tests(
+        min_book_spread: Option<u64>,
+        cancel_orders_when_book_tight: bool,
+        expected_bids_made: usize,
+        expected_cancelled: bool,
+    ) {
+        let exchange = MockExchange::new(
+            Stats {
+                best_bid: Some(Price::from_scaled(998)),
+                best_ask: Some(Price::from_scaled(1003)),
+                open_orders: vec![Order {
+                    order_type: OrderType::Ask,
+                    id: "01".into(),
+                    price: Price::from_scaled(1010),
+                    amount: 100,
+                    placed_at: None,
+                }],
+                ..stats_at(Price::from_scaled(1000))
+            },
+            Balances {
+                base: u64::MAX,
+                quote: u64::MAX,
+            },
+        );
+        let config = Config {
+            min_book_spread: min_book_spread.map(Price::from_scaled),
+            cancel_orders_when_book_tight,
+            ..test_config()
+        };
+
+        run_once(
+            &exchange,
+            None,
+            &config,
+            &State::default(),
+            &NoopEventSink,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(exchange.made_orders().len(), expected_bids_made);
+        assert_eq!(!exchange.cancelled_orders().is_empty(), expected_cancelled);
+    }
+
+    #[test_case(None, 6; "exchange doesn't report trade times")]
+    #[test_case(Some(Duration::from_secs(60)), 6; "recent trade")]
+    #[test_case(Some(Duration::from_secs(2 * 60 * 60)), 0; "stale trade")]
+    #[tokio::test]
+    async fn skips_quoting_when_latest_price_is_stale(
+        trade_age: Option<Duration>,
+        expected_made: usize,
+    ) {
+        let clock = MockClock::new(1_700_000_000_000_000_000);
+        let latest_trade_at = trade_age.map(|age| clock.now() - age.as_nanos() as u64);
+        let exchange = MockExchange::new(
+            Stats {
+                latest_trade_at,
+                ..stats_at(Price::from_scaled(1000))
+            },
+            Balances {
+                base: u64::MAX / 2,
+                quote: u64::MAX / 2,
+            },
+        );
+        let config = Config {
+            min_orders_per_direction: 3,
+            max_orders_per_direction: 3,
+            max_price_age: Some(Duration::from_secs(60 * 60)),
+            ..test_config()
+        };
+
+        run_once(
+            &exchange,
+            None,
+            &config,
+            &State::default(),
+            &NoopEventSink,
+            &clock,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(exchange.made_orders().len(), expected_made);
+    }
+
+    #[tokio::test]
+    async fn skips_orders_which_would_slip_after_the_price_moved() {
+        let stats = stats_at(Price::from_scaled(1000));
+        let exchange = MockExchange::new(
+            Stats {
+                best_ask: Some(Price::from_scaled(975)),
+                ..stats.clone()
+            },
+            Balances {
+                base: u64::MAX / 2,
+                quote: u64::MAX / 2,
+            },
+        );
+        // The iteration plans off the queued stats, then the best ask drops before submission
+        exchange.queue_stats(stats);
+        let config = Config {
+            min_orders_per_direction: 3,
+            max_orders_per_direction: 3,
+            max_slippage: Some(Price::from_scaled(10)),
+            ..test_config()
+        };
+
+        run_once(
+            &exchange,
+            None,
+            &config,
+            &State::default(),
+            &NoopEventSink,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+
+        let bids = exchange
+            .made_orders()
+            .into_iter()
+            .filter(|o| o.order_type == OrderType::Bid)
+            .map(|o| o.price.scaled())
+            .collect::<Vec<_>>();
+        assert_eq!(bids, vec![980, 970]);
+        assert_eq!(exchange.made_orders().len(), 5);
+    }
+
+    #[tokio::test]
+    async fn skips_replacements_which_would_slip() {
+        let open_bid = |id: &str, price| Order {
+            order_type: OrderType::Bid,
+            id: OrderId::from(id),
+            price: Price::from_scaled(price),
+            amount: 100,
+            placed_at: None,
+        };
+        let exchange = MockExchange::new(
+            Stats {
+                best_ask: Some(Price::from_scaled(975)),
+                open_orders: vec![open_bid("1", 900), open_bid("2", 910)],
+                ..stats_at(Price::from_scaled(1000))
+            },
+            Balances {
+                base: u64::MAX / 2,
+                quote: u64::MAX / 2,
+            },
+        );
+        let config = Config {
+            max_slippage: Some(Price::from_scaled(10)),
+            ..test_config()
+        };
+        let replace = |id: &str, price| ReplaceOrderRequest {
+            id: OrderId::from(id),
+            order_type: OrderType::Bid,
+            new_price: Price::from_scaled(price),
+            new_amount: 200,
+        };
+
+        let results = replace_orders_within_slippage(
+            &exchange,
+            vec![replace("1", 990), replace("2", 980)],
+            &config,
+        )
+        .await;
+
+        assert!(matches!(results[0], Err(ExchangeError::Slippage(_))));
+        assert_eq!(results[1], Ok(OrderId::from("2")));
+        assert_eq!(exchange.replaced_orders(), vec![replace("2", 980)]);
+    }
+
+    #[tokio::test]
+    async fn orders_missing_from_the_exchange_results_are_errors() {
+        let exchange = MockExchange::new(
+            stats_at(Price::from_scaled(1000)),
+            Balances { base: 0, quote: 0 },
+        );
+        let config = Config {
+            max_slippage: Some(Price::from_scaled(10)),
+            ..test_config()
+        };
+        let order = MakeOrderRequest {
+            order_type: OrderType::Bid,
+            price: Price::from_scaled(990),
+            amount: 100,
+            post_only: true,
+        };
+
+        let results = send_within_slippage(
+            &exchange,
+            vec![order.clone(), order],
+            MakeOrderRequest::clone,
+            &config,
+            |_| async { vec![Ok(OrderId::from("1"))] },
+        )
+        .await;
+
+        assert_eq!(
+            results,
+            vec![
+                Ok(OrderId::from("1")),
+                Err(ExchangeError::Decode("missing result".to_string()))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn does_not_make_bids_crossing_its_own_open_asks() {
+        // An ask left over from before the price rose sits below the new grid's innermost bid
+        let exchange = MockExchange::new(
+            Stats {
+                best_ask: Some(Price::from_scaled(985)),
+                open_orders: vec![Order {
+                    order_type: OrderType::Ask,
+                    id: OrderId::from("stale"),
+                    price: Price::from_scaled(985),
+                    amount: 100,
+                    placed_at: None,
+                }],
+                ..stats_at(Price::from_scaled(1000))
+            },
+            Balances {
+                base: u64::MAX / 2,
+                quote: u64::MAX / 2,
+            },
+        );
+        let config = Config {
+            min_orders_per_direction: 3,
+            max_orders_per_direction: 3,
+            ..test_config()
+        };
+
+        run_once(
+            &exchange,
+            None,
+            &config,
+            &State::default(),
+            &NoopEventSink,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+
+        let bids = exchange
+            .made_orders()
+            .into_iter()
+            .filter(|o| o.order_type == OrderType::Bid)
+            .map(|o| o.price.scaled())
+            .collect::<Vec<_>>();
+        assert_eq!(bids, vec![980, 970]);
+    }
+
+    #[tokio::test]
+    async fn pauses_quoting_after_unacceptable_volatility() {
+        let exchange = MockExchange::new(
+            stats_at(Price::from_scaled(1000)),
+            Balances {
+                base: u64::MAX / 2,
+                quote: u64::MAX / 2,
+            },
+        );
+        let clock = MockClock::new(1_700_000_000_000_000_000);
+        let config = Config {
+            min_orders_per_direction: 3,
+            max_orders_per_direction: 3,
+            volatility_cooldown: Some(Duration::from_secs(60 * 60)),
+            ..test_config()
+        };
+
+        exchange.fail_orders_with(Some(ExchangeError::Volatility("too volatile".to_string())));
+        let state = run_once(
+            &exchange,
+            None,
+            &config,
+            &State::default(),
+            &NoopEventSink,
+            &clock,
+        )
+        .await
+        .unwrap();
+        assert_eq!(exchange.made_orders().len(), 6);
+        assert!(state.volatility_paused_until.is_some());
+
+        // Nothing is made while the cooldown lasts, even though the exchange would now accept it
+        exchange.fail_orders_with(None);
+        let state = run_once(&exchange, None, &config, &state, &NoopEventSink, &clock)
+            .await
+            .unwrap();
+        assert_eq!(exchange.made_orders().len(), 6);
+
+        clock.advance(Duration::from_secs(60 * 60));
+        let state = run_once(&exchange, None, &config, &state, &NoopEventSink, &clock)
+            .await
+            .unwrap();
+        assert_eq!(exchange.made_orders().len(), 12);
+        assert_eq!(state.volatility_paused_until, None);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_volatility_pause_through_iterations_which_return_early() {
+        let clock = MockClock::new(1_700_000_000_000_000_000);
+        let stats = |latest_trade_at| Stats {
+            latest_trade_at: Some(latest_trade_at),
+            ..stats_at(Price::from_scaled(1000))
+        };
+        let exchange = MockExchange::new(
+            stats(clock.now()),
+            Balances {
+                base: u64::MAX / 2,
+                quote: u64::MAX / 2,
+            },
+        );
+        let config = Config {
+            volatility_cooldown: Some(Duration::from_secs(60 * 60)),
+            max_price_age: Some(Duration::from_secs(5 * 60)),
+            ..test_config()
+        };
+
+        exchange.fail_orders_with(Some(ExchangeError::Volatility("too volatile".to_string())));
+        let state = run_once(
+            &exchange,
+            None,
+            &config,
+            &State::default(),
+            &NoopEventSink,
+            &clock,
+        )
+        .await
+        .unwrap();
+        let paused_until = state.volatility_paused_until;
+        assert!(paused_until.is_some());
+        exchange.fail_orders_with(None);
+        let made = exchange.made_orders().len();
+
+        // The price goes stale, so the iteration returns before reaching the pause
+        clock.advance(Duration::from_secs(10 * 60));
+        let state = run_once(&exchange, None, &config, &state, &NoopEventSink, &clock)
+            .await
+            .unwrap();
+        assert_eq!(state.volatility_paused_until, paused_until);
+
+        // Once the price is fresh again the pause still holds
+        exchange.set_stats(stats(clock.now()));
+        let state = run_once(&exchange, None, &config, &state, &NoopEventSink, &clock)
+            .await
+            .unwrap();
+        assert_eq!(exchange.made_orders().len(), made);
+        assert_eq!(state.volatility_paused_until, paused_until);
+    }
+
+    #[tokio::test]
+    async fn stops_quoting_for_the_day_once_max_daily_volume_is_filled() {
+        let exchange = MockExchange::at_price(Price::from_scaled(1000));
+        let clock = MockClock::new(1_700_000_000_000_000_000);
+        let mut config = Config {
+            max_orders_per_direction: 1,
+            ..test_config()
+        };
+
+        let state = run_once(
+            &exchange,
+            None,
+            &config,
+            &State::default(),
+            &NoopEventSink,
+            &clock,
+        )
+        .await
+        .unwrap();
+        config.max_daily_volume = Some(exchange.made_orders()[0].amount);
+        exchange.fill_at_price(Price::from_scaled(990));
+
+        let state = run_once(&exchange, None, &config, &state, &NoopEventSink, &clock)
+            .await
+            .unwrap();
+
+        // The bid filled the whole day's volume, so the ask is cancelled and nothing is remade
+        assert_eq!(exchange.made_orders().len(), 2);
+        assert_eq!(exchange.cancelled_orders().len(), 1);
+        assert!(exchange.stats().await.unwrap().open_orders.is_empty());
+
+        // The volume starts again from zero on the next day
+        clock.advance(Duration::from_secs(24 * 60 * 60));
+        run_once(&exchange, None, &config, &state, &NoopEventSink, &clock)
+            .await
+            .unwrap();
+        assert_eq!(exchange.made_orders().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn limits_the_open_amount_per_side_to_the_remaining_daily_volume() {
+        let exchange = MockExchange::at_price(Price::from_scaled(1000));
+        let clock = MockClock::new(1_700_000_000_000_000_000);
+        let config = Config {
+            max_daily_volume: Some(250),
+            min_orders_per_direction: 5,
+            ..test_config()
+        };
+
+        let state = run_once(
+            &exchange,
+            None,
+            &config,
+            &State::default(),
+            &NoopEventSink,
+            &clock,
+        )
+        .await
+        .unwrap();
+
+        // Only two orders of 100 fit on each side, leaving 50 of the day's volume unused
+        let made = exchange.made_orders();
+        assert_eq!(made.len(), 4);
+        assert_eq!(
+            made.iter()
+                .filter(|o| o.order_type == OrderType::Bid)
+                .count(),
+            2
+        );
+
+        // Once a bid fills only 150 remains, so the outer ask is cancelled and no bid is remade
+        exchange.fill_at_price(Price::from_scaled(990));
+        run_once(&exchange, None, &config, &state, &NoopEventSink, &clock)
+            .await
+            .unwrap();
+        let open_orders = exchange.stats().await.unwrap().open_orders;
+        for order_type in [OrderType::Bid, OrderType::Ask] {
+            let open_amount: u64 = open_orders
+                .iter()
+                .filter(|o| o.order_type == order_type)
+                .map(|o| o.amount)
+                .sum();
+            assert!(open_amount <= 150, "{order_type:?} {open_amount}");
+        }
+    }
+
+    #[test_case(None, 2; "cancels are capped")]
+    #[test_case(Some(0.8), 5; "grid is recentered")]
+    #[tokio::test]
+    async fn recenters_grid_after_large_price_jump(
+        recenter_threshold: Option<f64>,
+        expected_cancelled: usize,
+    ) {
+        let exchange = MockExchange::new(
+            stats_at(Price::from_scaled(1000)),
+            Balances {
+                base: u64::MAX / 2,
+                quote: u64::MAX / 2,
+            },
+        );
+        let config = Config {
+            min_orders_per_direction: 5,
+            max_orders_to_cancel_per_iteration: 2,
+            recenter_threshold,
+            ..test_config()
+        };
+
+        let state = run_once(
+            &exchange,
+            None,
+            &config,
+            &State::default(),
+            &NoopEventSink,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+        assert_eq!(exchange.made_orders().len(), 10);
+
+        // The price jumps through every ask, leaving only bids which are far below the price
+        assert_eq!(exchange.fill_at_price(Price::from_scaled(2000)).len(), 5);
+        run_once(
+            &exchange,
+            None,
+            &config,
+            &state,
+            &NoopEventSink,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(exchange.cancelled_orders().len(), expected_cancelled);
+    }
+
+    #[test_case(false, 0; "orphans are only warned about")]
+    #[test_case(true, 3; "orphans are cancelled")]
+    #[tokio::test]
+    async fn detects_orphaned_orders_beyond_the_cancel_cap(
+        cancel_orphans: bool,
+        expected_cancelled: usize,
+    ) {
+        // Left behind by a previous config, far below the target grid
+        let stale_bid = |id: &str, price: u64| Order {
+            order_type: OrderType::Bid,
+            id: OrderId::from(id),
+            price: Price::from_scaled(price),
+            amount: 100,
+            placed_at: None,
+        };
+        let exchange = MockExchange::new(
+            Stats {
+                open_orders: vec![
+                    stale_bid("stale-1", 100),
+                    stale_bid("stale-2", 200),
+                    stale_bid("stale-3", 300),
+                ],
+                ..stats_at(Price::from_scaled(1000))
+            },
+            Balances {
+                base: u64::MAX / 2,
+                quote: u64::MAX / 2,
+            },
+        );
+        let config = Config {
+            max_orders_to_cancel_per_iteration: 0,
+            orphan_iterations: Some(2),
+            cancel_orphans,
+            ..test_config()
+        };
+
+        let mut state = State::default();
+        for _ in 0..2 {
+            state = run_once(
+                &exchange,
+                None,
+                &config,
+                &state,
+                &NoopEventSink,
+                &SystemClock,
+            )
+            .await
+            .unwrap();
+        }
+        assert!(exchange.cancelled_orders().is_empty());
+        assert!(state.orphan_counts.values().all(|count| *count == 2));
+
+        let state = run_once(
+            &exchange,
+            None,
+            &config,
+            &state,
+            &NoopEventSink,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+        assert_eq!(exchange.cancelled_orders().len(), expected_cancelled);
+        assert!(state.orphan_counts.values().all(|count| *count == 3));
+    }
+
+    #[tokio::test]
+    async fn waits_for_fill_cooldown_before_remaking_order() {
+        let exchange = MockExchange::at_price(Price::from_scaled(1000));
+        let config = Config {
+            fill_cooldown_iterations: 2,
+            ..test_config()
+        };
+        let bids_made_at_990 = || {
+            exchange
+                .made_orders()
+                .iter()
+                .filter(|o| o.order_type == OrderType::Bid && o.price.scaled() == 990)
+                .count()
+        };
+
+        let mut state = run_once(
+            &exchange,
+            None,
+            &config,
+            &State::default(),
+            &NoopEventSink,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+        assert_eq!(bids_made_at_990(), 1);
+
+        // Fill the innermost bid without moving the price
+        let mut stats = exchange.stats().await.unwrap();
+        stats.open_orders.retain(|o| o.price.scaled() != 990);
+        exchange.set_stats(stats);
+
+        for _ in 0..2 {
+            state = run_once(
+                &exchange,
+                None,
+                &config,
+                &state,
+                &NoopEventSink,
+                &SystemClock,
+            )
+            .await
+            .unwrap();
+            assert_eq!(bids_made_at_990(), 1);
+        }
+
+        run_once(
+            &exchange,
+            None,
+            &config,
+            &state,
+            &NoopEventSink,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+        assert_eq!(bids_made_at_990(), 2);
+    }
+
+    #[tokio::test]
+    async fn makes_no_orders_until_warmed_up() {
+        let exchange = MockExchange::at_price(Price::from_scaled(1000));
+        let config = Config {
+            warmup_iterations: 3,
+            ..test_config()
+        };
+        let mut state = State {
+            warmup_samples: Some(Vec::new()),
+            ..State::default()
+        };
+
+        for price in [1000, 1500] {
+            let mut stats = exchange.stats().await.unwrap();
+            stats.latest_price = Price::from_scaled(price);
+            exchange.set_stats(stats);
+            state = run_once(
+                &exchange,
+                None,
+                &config,
+                &state,
+                &NoopEventSink,
+                &SystemClock,
+            )
+            .await
+            .unwrap();
+            assert!(exchange.made_orders().is_empty());
+        }
+
+        let mut stats = exchange.stats().await.unwrap();
+        stats.latest_price = Price::from_scaled(1010);
+        exchange.set_stats(stats);
+        let state = run_once(
+            &exchange,
+            None,
+            &config,
+            &state,
+            &NoopEventSink,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+
+        // The orders are placed around the median rather than the outlier or the latest price
+        let made_orders = exchange.made_orders();
+        let best_bid = made_orders
+            .iter()
+            .filter(|o| o.order_type == OrderType::Bid)
+            .map(|o| o.price.scaled())
+            .max();
+        let best_ask = made_orders
+            .iter()
+            .filter(|o| o.order_type == OrderType::Ask)
+            .map(|o| o.price.scaled())
+            .min();
+        assert_eq!(best_bid, Some(1000));
+        assert_eq!(best_ask, Some(1020));
+        assert!(state.warmup_samples.is_none());
+    }
+
+    #[tokio::test]
+    async fn publishes_events() {
+        let exchange = MockExchange::at_price(Price::from_scaled(1000));
+        let config = Config {
+            min_orders_per_direction: 1,
+            max_orders_per_direction: 1,
+            ..test_config()
+        };
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        let state = run_once(
+            &exchange,
+            None,
+            &config,
+            &State::default(),
+            &sender,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+
+        let price_updated = Event::PriceUpdated {
+            latest_price: Price::from_scaled(1000),
+            anchor_price: Price::from_scaled(1000),
+        };
+        let open_orders = exchange.stats().await.unwrap().open_orders;
+        let decision = Decision {
+            latest_price: Price::from_scaled(1000),
+            anchor_price: Price::from_scaled(1000),
+            target_orders: exchange.made_orders(),
+            open_orders: Vec::new(),
+            orders_to_cancel: Vec::new(),
+        };
+        assert_eq!(receiver.try_recv(), Ok(price_updated.clone()));
+        assert_eq!(receiver.try_recv(), Ok(Event::Decision(decision.clone())));
+        assert_eq!(
+            receiver.try_recv(),
+            Ok(Event::OrdersMade(open_orders.clone()))
+        );
+        assert!(receiver.try_recv().is_err());
+
+        // Fill the bid and move the ask out of range so that it is cancelled
+        let (bid, ask) = (open_orders[0].clone(), open_orders[1].clone());
+        let moved_ask = Order {
+            price: Price::from_scaled(1050),
+            ..ask.clone()
+        };
+        exchange.set_stats(Stats {
+            open_orders: vec![moved_ask.clone()],
+            ..stats_at(Price::from_scaled(1000))
+        });
+
+        run_once(&exchange, None, &config, &state, &sender, &SystemClock)
+            .await
+            .unwrap();
+
+        // Both orders are remade, replacing the filled and the cancelled orders
+        let remade = exchange.stats().await.unwrap().open_orders;
+        assert_eq!(receiver.try_recv(), Ok(price_updated));
+        assert_eq!(receiver.try_recv(), Ok(Event::FillDetected(bid)));
+        assert_eq!(
+            receiver.try_recv(),
+            Ok(Event::Decision(Decision {
+                open_orders: vec![moved_ask.clone()],
+                orders_to_cancel: vec![ask.id.clone()],
+                ..decision
+            }))
+        );
+        assert_eq!(receiver.try_recv(), Ok(Event::OrdersMade(remade)));
+        assert_eq!(
+            receiver.try_recv(),
+            Ok(Event::OrdersCancelled(vec![ask.id]))
+        );
+    }
+
+    #[tokio::test]
+    async fn does_not_publish_events_from_a_timed_out_attempt() {
+        // Hangs the first time orders are made, after the iteration has published its decision
+        struct HangsOnce {
+            inner: MockExchange,
+            hung: AtomicBool,
+        }
+
+        #[async_trait]
+        impl Exchange for HangsOnce {
+            async fn stats(&self) -> Result<Stats, ExchangeError> {
+                self.inner.stats().await
+            }
+
+            async fn balances(&self) -> Result<Balances, ExchangeError> {
+                self.inner.balances().await
+            }
+
+            async fn make_orders(
+                &self,
+                orders: Vec<MakeOrderRequest>,
+            ) -> Vec<Result<OrderId, ExchangeError>> {
+                if !self.hung.swap(true, Ordering::Relaxed) {
+                    futures::future::pending::<()>().await;
+                }
+                self.inner.make_orders(orders).await
+            }
+
+            async fn cancel_orders(
+                &self,
+                orders: Vec<CancelOrderRequest>,
+            ) -> Vec<Result<OrderId, ExchangeError>> {
+                self.inner.cancel_orders(orders).await
+            }
+        }
+
+        let exchange = HangsOnce {
+            inner: MockExchange::at_price(Price::from_scaled(1000)),
+            hung: AtomicBool::new(false),
+        };
+        let config = Config {
+            iteration_interval: Duration::from_millis(10),
+            iteration_timeout: Some(Duration::from_millis(20)),
+            cancel_orders_on_shutdown: false,
+            ..test_config()
+        };
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let token = CancellationToken::new();
+        let cancel_token = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            cancel_token.cancel();
+        });
+
+        run_with_events_until(&exchange, None, &config, &sender, token)
+            .await
+            .unwrap();
+
+        // The first events come from the second attempt, which went on to make the orders
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok(Event::PriceUpdated { .. })
+        ));
+        assert!(matches!(receiver.try_recv(), Ok(Event::Decision(_))));
+        assert!(matches!(receiver.try_recv(), Ok(Event::OrdersMade(_))));
+    }
+
+    #[tokio::test]
+    async fn run_with_clock_until_reads_the_time_from_the_clock() {
+        // The latest trade is long in the past by the system clock, but fresh by the mock clock
+        let clock = MockClock::new(1_000_000_000);
+        let exchange = MockExchange::new(
+            Stats {
+                latest_trade_at: Some(clock.now()),
+                ..stats_at(Price::from_scaled(1000))
+            },
+            Balances {
+                base: u64::MAX,
+                quote: u64::MAX,
+            },
+        );
+        let config = Config {
+            iteration_interval: Duration::from_millis(10),
+            max_price_age: Some(Duration::from_secs(60)),
+            cancel_orders_on_shutdown: false,
+            ..test_config()
+        };
+        let token = CancellationToken::new();
+        let cancel_token = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            cancel_token.cancel();
+        });
+
+        run_with_clock_until(&exchange, None, &config, &NoopEventSink, &clock, token)
+            .await
+            .unwrap();
+
+        assert!(!exchange.made_orders().is_empty());
+    }
+
+    #[tokio::test]
+    async fn detects_partial_fills() {
+        let exchange = MockExchange::at_price(Price::from_scaled(1000));
+        let config = Config {
+            min_orders_per_direction: 1,
+            max_orders_per_direction: 1,
+            ..test_config()
+        };
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        let state = run_once(
+            &exchange,
+            None,
+            &config,
+            &State::default(),
+            &sender,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+        // Orders are only checked for partial fills once the exchange has reported their amounts
+        let state = run_once(&exchange, None, &config, &state, &sender, &SystemClock)
+            .await
+            .unwrap();
+        while receiver.try_recv().is_ok() {}
+
+        // 60 of the bid's 100 is filled
+        let mut open_orders = exchange.stats().await.unwrap().open_orders;
+        let bid = open_orders
+            .iter_mut()
+            .find(|o| o.order_type == OrderType::Bid)
+            .unwrap();
+        bid.amount = 40;
+        let bid = bid.clone();
+        exchange.set_stats(Stats {
+            open_orders,
+            ..stats_at(Price::from_scaled(1000))
+        });
+
+        let state = run_once(&exchange, None, &config, &state, &sender, &SystemClock)
+            .await
+            .unwrap();
+
+        let partial_fills: Vec<_> = std::iter::from_fn(|| receiver.try_recv().ok())
+            .filter(|e| matches!(e, Event::PartialFillDetected { .. }))
+            .collect();
+        assert_eq!(
+            partial_fills,
+            vec![Event::PartialFillDetected {
+                order: bid,
+                filled: 60
+            }]
+        );
+        assert_eq!(state.position.amount, 60);
+        assert_eq!(state.daily_volume.filled, 60);
+
+        // The fill isn't counted again while the amount stays the same
+        let state = run_once(&exchange, None, &config, &state, &sender, &SystemClock)
+            .await
+            .unwrap();
+
+        assert!(std::iter::from_fn(|| receiver.try_recv().ok())
+            .all(|e| !matches!(e, Event::PartialFillDetected { .. })));
+        assert_eq!(state.position.amount, 60);
+    }
+
+    #[tokio::test]
+    async fn does_not_mistake_an_amount_rounded_by_the_exchange_for_a_partial_fill() {
+        let exchange = MockExchange::at_price(Price::from_scaled(1000));
+        let config = Config {
+            min_orders_per_direction: 1,
+            max_orders_per_direction: 1,
+            ..test_config()
+        };
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let set_bid_amount = |amount| {
+            let mut stats = exchange.stats.lock().unwrap();
+            let bid = stats
+                .open_orders
+                .iter_mut()
+                .find(|o| o.order_type == OrderType::Bid)
+                .unwrap();
+            bid.amount = amount;
+            bid.clone()
+        };
+
+        let state = run_once(
+            &exchange,
+            None,
+            &config,
+            &State::default(),
+            &sender,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+        // The exchange rounded the bid's amount down when it was made
+        set_bid_amount(90);
+
+        let state = run_once(&exchange, None, &config, &state, &sender, &SystemClock)
+            .await
+            .unwrap();
+
+        assert!(std::iter::from_fn(|| receiver.try_recv().ok())
+            .all(|e| !matches!(e, Event::PartialFillDetected { .. })));
+        assert_eq!(state.position.amount, 0);
+
+        // A fill is measured from the amount the exchange reported
+        let bid = set_bid_amount(50);
+        let state = run_once(&exchange, None, &config, &state, &sender, &SystemClock)
+            .await
+            .unwrap();
+
+        let partial_fills: Vec<_> = std::iter::from_fn(|| receiver.try_recv().ok())
+            .filter(|e| matches!(e, Event::PartialFillDetected { .. }))
+            .collect();
+        assert_eq!(
+            partial_fills,
+            vec![Event::PartialFillDetected {
+                order: bid,
+                filled: 40
+            }]
+        );
+        assert_eq!(state.position.amount, 40);
+    }
+
+    #[tokio::test]
+    async fn run_iteration_makes_orders_once() {
+        let exchange = MockExchange::at_price(Price::from_scaled(1000));
+        let config = test_config();
+
+        run_iteration(&exchange, None, &config).await.unwrap();
+
+        assert_eq!(
+            exchange.made_orders().len(),
+            2 * config.min_orders_per_direction as usize
+        );
+    }
+
+    #[tokio::test]
+    async fn run_iteration_with_clock_reads_the_time_from_the_clock() {
+        // The latest trade is long in the past by the system clock, but fresh by the mock clock
+        let clock = MockClock::new(1_000_000_000);
+        let exchange = MockExchange::new(
+            Stats {
+                latest_trade_at: Some(clock.now()),
+                ..stats_at(Price::from_scaled(1000))
+            },
+            Balances {
+                base: u64::MAX,
+                quote: u64::MAX,
+            },
+        );
+        let config = Config {
+            max_price_age: Some(Duration::from_secs(60)),
+            ..test_config()
+        };
+
+        run_iteration_with_clock(&exchange, None, &config, &NoopEventSink, &clock)
+            .await
+            .unwrap();
+
+        assert!(!exchange.made_orders().is_empty());
+    }
+
+    #[tokio::test]
+    async fn run_iteration_times_out() {
+        // Never responds to queries
+        struct Hanging;
+
+        #[async_trait]
+        impl Exchange for Hanging {
+            async fn stats(&self) -> Result<Stats, ExchangeError> {
+                futures::future::pending().await
+            }
+
+            async fn balances(&self) -> Result<Balances, ExchangeError> {
+                futures::future::pending().await
+            }
+
+            async fn make_orders(
+                &self,
+                _orders: Vec<MakeOrderRequest>,
+            ) -> Vec<Result<OrderId, ExchangeError>> {
+                Vec::new()
+            }
+
+            async fn cancel_orders(
+                &self,
+                _orders: Vec<CancelOrderRequest>,
+            ) -> Vec<Result<OrderId, ExchangeError>> {
+                Vec::new()
+            }
+        }
+
+        let config = Config {
+            iteration_timeout: Some(Duration::from_millis(10)),
+            ..test_config()
+        };
+
+        let result = run_iteration(&Hanging, None, &config).await;
+
+        assert!(matches!(result, Err(ExchangeError::Other(_))));
+    }
+
+    #[test_case(ExchangeError::Fatal("Canister not found".to_string()), true; "fatal")]
+    #[test_case(ExchangeError::Other("Trapped".to_string()), false; "transient")]
+    #[tokio::test]
+    async fn stops_only_on_fatal_errors(error: ExchangeError, expected_stopped: bool) {
+        // Fails every query with the same error
+        struct Failing {
+            error: ExchangeError,
+            queries: Mutex<u32>,
+        }
+
+        #[async_trait]
+        impl Exchange for Failing {
+            async fn stats(&self) -> Result<Stats, ExchangeError> {
+                *self.queries.lock().unwrap() += 1;
+                Err(self.error.clone())
+            }
+
+            async fn balances(&self) -> Result<Balances, ExchangeError> {
+                Err(self.error.clone())
+            }
+
+            async fn make_orders(
+                &self,
+                _orders: Vec<MakeOrderRequest>,
+            ) -> Vec<Result<OrderId, ExchangeError>> {
+                Vec::new()
+            }
+
+            async fn cancel_orders(
+                &self,
+                _orders: Vec<CancelOrderRequest>,
+            ) -> Vec<Result<OrderId, ExchangeError>> {
+                Vec::new()
+            }
+        }
+
+        let exchange = Failing {
+            error: error.clone(),
+            queries: Mutex::default(),
+        };
+        let config = Config {
+            iteration_interval: Duration::from_millis(10),
+            cancel_orders_on_shutdown: false,
+            ..test_config()
+        };
+        let token = CancellationToken::new();
+        let cancel_token = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            cancel_token.cancel();
+        });
+
+        let result = run_until(&exchange, None, &config, token).await;
+
+        let queries = *exchange.queries.lock().unwrap();
+        if expected_stopped {
+            assert_eq!(result, Err(RunError::Fatal(error)));
+            assert_eq!(queries, 1);
+        } else {
+            assert_eq!(result, Ok(()));
+            assert!(queries > 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn orders_a_timed_out_iteration_was_cancelling_are_not_counted_as_fills() {
+        let order = |id: &str, order_type, price| Order {
+            order_type,
+            id: id.into(),
+            price: Price::from_scaled(price),
+            amount: 100,
+            placed_at: None,
+        };
+        let (bid, ask) = (
+            order("1", OrderType::Bid, 990),
+            order("2", OrderType::Ask, 1010),
+        );
+        let exchange = MockExchange::at_price(Price::from_scaled(1000));
+        // The timed out iteration saved its state before cancelling the ask, and both orders have
+        // since gone
+        let state = State {
+            previous_open_orders: vec![bid.clone(), ask.clone()],
+            restored_orders: Some(OrderState::new(
+                &[bid, ask.clone()],
+                &[CancelOrderRequest { id: ask.id }],
+                &[],
+            )),
+            ..State::default()
+        };
+
+        let state = run_once(
+            &exchange,
+            None,
+            &test_config(),
+            &state,
+            &NoopEventSink,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+
+        // Only the bid was filled
+        assert_eq!(state.position.amount, 100);
+        assert_eq!(state.daily_volume.filled, 100);
+    }
+
+    #[tokio::test]
+    async fn cancels_all_orders_on_shutdown() {
+        let exchange = MockExchange::new(
+            Stats {
+                open_orders: vec![
+                    Order {
+                        order_type: OrderType::Bid,
+                        id: "01".into(),
+                        price: Price::from_scaled(990),
+                        amount: 100,
+                        placed_at: None,
+                    },
+                    Order {
+                        order_type: OrderType::Ask,
+                        id: "02".into(),
+                        price: Price::from_scaled(1010),
+                        amount: 100,
+                        placed_at: None,
+                    },
+                ],
+                ..stats_at(Price::from_scaled(1000))
+            },
+            Balances { base: 0, quote: 0 },
+        );
+        let config = Config {
+            cancel_orders_on_shutdown: true,
+            ..test_config()
+        };
+        let token = CancellationToken::new();
+        token.cancel();
+
+        run_until(&exchange, None, &config, token).await.unwrap();
+
+        let cancelled_ids: Vec<_> = exchange
+            .cancelled_orders()
+            .into_iter()
+            .map(|o| o.id)
+            .collect();
+        assert_eq!(cancelled_ids, [OrderId::from("01"), OrderId::from("02")]);
+        assert!(exchange.made_orders().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancels_all_orders_and_stops_when_kill_file_exists() {
+        let exchange = MockExchange::new(
+            Stats {
+                open_orders: vec![Order {
+                    order_type: OrderType::Bid,
+                    id: "01".into(),
+                    price: Price::from_scaled(990),
+                    amount: 100,
+                    placed_at: None,
+                }],
+                ..stats_at(Price::from_scaled(1000))
+            },
+            Balances { base: 0, quote: 0 },
+        );
+        let kill_file = std::env::temp_dir().join(format!("smm-kill-{}", std::process::id()));
+        std::fs::write(&kill_file, "").unwrap();
+        let config = Config {
+            kill_file: Some(kill_file.clone()),
+            ..test_config()
+        };
+
+        // The token is never cancelled, so this only returns because of the kill file
+        let result = run_until(&exchange, None, &config, CancellationToken::new()).await;
+        std::fs::remove_file(kill_file).unwrap();
+
+        result.unwrap();
+        let cancelled_ids: Vec<_> = exchange
+            .cancelled_orders()
+            .into_iter()
+            .map(|o| o.id)
+            .collect();
+        assert_eq!(cancelled_ids, [OrderId::from("01")]);
+        assert!(exchange.made_orders().is_empty());
+    }
+
+    fn oversized_bid_exchange() -> MockExchange {
+        MockExchange::new(
+            Stats {
+                open_orders: vec![Order {
+                    order_type: OrderType::Bid,
+                    id: "01".into(),
+                    price: Price::from_scaled(990),
+                    amount: 150,
+                    placed_at: None,
+                }],
+                ..stats_at(Price::from_scaled(1000))
+            },
+            Balances {
+                base: 0,
+                quote: u64::MAX,
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn replaces_orders_which_only_need_a_size_change() {
+        let exchange = oversized_bid_exchange();
+
+        let state = run_once(
+            &exchange,
+            None,
+            &test_config(),
+            &State::default(),
+            &NoopEventSink,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            exchange.replaced_orders(),
+            [ReplaceOrderRequest {
+                id: "01".into(),
+                order_type: OrderType::Bid,
+                new_price: Price::from_scaled(990),
+                new_amount: 100,
+            }]
+        );
+        assert!(exchange.cancelled_orders().is_empty());
+        assert!(exchange.made_orders().is_empty());
+        assert_eq!(state.previous_open_orders.len(), 1);
+        assert_eq!(state.previous_open_orders[0].amount, 100);
+    }
+
+    #[tokio::test]
+    async fn cancels_and_makes_orders_when_replacing_is_unsupported() {
+        // Relies on the default `replace_orders`, which is unsupported
+        struct NoReplace(MockExchange);
+
+        #[async_trait]
+        impl Exchange for NoReplace {
+            async fn stats(&self) -> Result<Stats, ExchangeError> {
+                self.0.stats().await
+            }
+
+            async fn balances(&self) -> Result<Balances, ExchangeError> {
+                self.0.balances().await
+            }
+
+            async fn make_orders(
+                &self,
+                orders: Vec<MakeOrderRequest>,
+            ) -> Vec<Result<OrderId, ExchangeError>> {
+                self.0.make_orders(orders).await
+            }
+
+            async fn cancel_orders(
+                &self,
+                orders: Vec<CancelOrderRequest>,
+            ) -> Vec<Result<OrderId, ExchangeError>> {
+                self.0.cancel_orders(orders).await
+            }
+        }
+
+        let exchange = NoReplace(oversized_bid_exchange());
+        let config = test_config();
+
+        let state = run_once(
+            &exchange,
+            None,
+            &config,
+            &State::default(),
+            &NoopEventSink,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+
+        // As before replacements were supported, the order is cancelled and then made again by the
+        // next iteration
+        let cancelled_ids: Vec<_> = exchange
+            .0
+            .cancelled_orders()
+            .into_iter()
+            .map(|o| o.id)
+            .collect();
+        assert_eq!(cancelled_ids, [OrderId::from("01")]);
+        assert!(exchange.0.made_orders().is_empty());
+        assert!(state.previous_open_orders.is_empty());
+
+        run_once(
+            &exchange,
+            None,
+            &config,
+            &state,
+            &NoopEventSink,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            exchange.0.made_orders(),
+            [MakeOrderRequest {
+                order_type: OrderType::Bid,
+                price: Price::from_scaled(990),
+                amount: 100,
+                post_only: true,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn makes_remaining_orders_when_some_fail() {
+        // Rejects every ask, as if the base balance had run out
+        struct RejectingAsks(MockExchange);
+
+        #[async_trait]
+        impl Exchange for RejectingAsks {
+            async fn stats(&self) -> Result<Stats, ExchangeError> {
+                self.0.stats().await
+            }
+
+            async fn balances(&self) -> Result<Balances, ExchangeError> {
+                self.0.balances().await
+            }
+
+            async fn make_orders(
+                &self,
+                orders: Vec<MakeOrderRequest>,
+            ) -> Vec<Result<OrderId, ExchangeError>> {
+                // Results are returned in request order, as the `Exchange` contract requires
+                let mut results = Vec::with_capacity(orders.len());
+                for order in orders {
+                    results.push(match order.order_type {
+                        OrderType::Bid => self.0.make_orders(vec![order]).await.remove(0),
+                        OrderType::Ask => Err(ExchangeError::InsufficientBalance(String::new())),
+                    });
+                }
+                results
+            }
+
+            async fn cancel_orders(
+                &self,
+                orders: Vec<CancelOrderRequest>,
+            ) -> Vec<Result<OrderId, ExchangeError>> {
+                self.0.cancel_orders(orders).await
+            }
+        }
+
+        let exchange = RejectingAsks(MockExchange::at_price(Price::from_scaled(1000)));
+
+        let config = Config {
+            min_orders_per_direction: 3,
+            ..test_config()
+        };
+
+        let result = run_once(
+            &exchange,
+            None,
+            &config,
+            &State::default(),
+            &NoopEventSink,
+            &SystemClock,
+        )
+        .await;
+
+        assert!(result.is_ok());
+        let made_prices: Vec<_> = exchange
+            .0
+            .made_orders()
+            .into_iter()
+            .map(|o| (o.order_type, o.price.scaled()))
+            .collect();
+        assert_eq!(
+            made_prices,
+            [
+                (OrderType::Bid, 990),
+                (OrderType::Bid, 980),
+                (OrderType::Bid, 970),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn run_many_keeps_running_other_markets_when_one_panics() {
+        struct MaybePanicking {
+            inner: Arc<MockExchange>,
+            panic: bool,
+        }
+
+        #[async_trait]
+        impl Exchange for MaybePanicking {
+            async fn stats(&self) -> Result<Stats, ExchangeError> {
+                assert!(!self.panic, "Simulated panic");
+                self.inner.stats().await
+            }
+
+            async fn balances(&self) -> Result<Balances, ExchangeError> {
+                self.inner.balances().await
+            }
+
+            async fn make_orders(
+                &self,
+                orders: Vec<MakeOrderRequest>,
+            ) -> Vec<Result<OrderId, ExchangeError>> {
+                self.inner.make_orders(orders).await
+            }
+
+            async fn cancel_orders(
+                &self,
+                orders: Vec<CancelOrderRequest>,
+            ) -> Vec<Result<OrderId, ExchangeError>> {
+                self.inner.cancel_orders(orders).await
+            }
+        }
+
+        let market = |panic| {
+            let inner = Arc::new(MockExchange::at_price(Price::from_scaled(1000)));
+            let exchange = MaybePanicking {
+                inner: inner.clone(),
+                panic,
+            };
+            let config = Config {
+                iteration_interval: Duration::from_millis(10),
+                ..test_config()
+            };
+            (inner, (exchange, config))
+        };
+        let (_, panicking_market) = market(true);
+        let (healthy_exchange, healthy_market) = market(false);
+
+        let token = CancellationToken::new();
+        let cancel_token = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            cancel_token.cancel();
+        });
+
+        run_many_until(vec![panicking_market, healthy_market], token)
+            .await
+            .unwrap();
+
+        assert!(!healthy_exchange.made_orders().is_empty());
+    }
+}